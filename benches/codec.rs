@@ -0,0 +1,90 @@
+//! Encode and decode throughput of the packets on the hot path of the broker.
+//!
+//! Run with `cargo bench`. The encoders return a new Vec, so only the decoders
+//! work on a buffer prepared once outside the measured loop.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use mqtt_broker::packets::{
+    connect::ConnectPacket,
+    publish::PublishPacket,
+    qos::QoS,
+    subscribe::{SubscribePacket, SubscriptionOptions},
+    suback::SubAckPacket,
+};
+
+// Payload sizes of the PUBLISH benchmarks: a small reading, a typical message and a large one
+const PAYLOAD_SIZES: [usize; 3] = [16, 1024, 64 * 1024];
+
+fn connect_packet() -> ConnectPacket {
+    ConnectPacket::new(
+        "MQTT".to_string(),
+        5,
+        0b11000010, // Username, password and clean start flags
+        60,
+        "bench-client".to_string(),
+        None,
+        None,
+        Some("user".to_string()),
+        Some("password".to_string()),
+    )
+}
+
+fn subscribe_packet() -> SubscribePacket {
+    let options = SubscriptionOptions { qos: QoS::AtLeastOnce, ..Default::default() };
+    SubscribePacket::with_options(1, vec![
+        ("sensors/temperature".to_string(), options),
+        ("sensors/humidity".to_string(), options),
+        ("alerts/#".to_string(), options),
+    ])
+}
+
+fn bench_connect(c: &mut Criterion) {
+    let packet = connect_packet();
+    let encoded = packet.encode();
+
+    let mut group = c.benchmark_group("connect");
+    group.bench_function("encode", |b| b.iter(|| black_box(&packet).encode()));
+    group.bench_function("decode", |b| b.iter(|| ConnectPacket::decode(black_box(&encoded)).unwrap()));
+    group.finish();
+}
+
+fn bench_publish(c: &mut Criterion) {
+    let mut group = c.benchmark_group("publish");
+    for size in PAYLOAD_SIZES {
+        let packet = PublishPacket::new("sensors/temperature".to_string(), 1, QoS::AtLeastOnce, false, false, vec![b'A'; size]);
+        let encoded = packet.encode().unwrap();
+
+        group.throughput(Throughput::Bytes(encoded.len() as u64));
+        group.bench_with_input(BenchmarkId::new("encode", size), &packet, |b, packet| {
+            b.iter(|| black_box(packet).encode().unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("decode", size), &encoded, |b, encoded| {
+            b.iter(|| PublishPacket::decode(black_box(encoded)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_subscribe(c: &mut Criterion) {
+    let packet = subscribe_packet();
+    let encoded = packet.encode().unwrap();
+
+    let mut group = c.benchmark_group("subscribe");
+    group.bench_function("encode", |b| b.iter(|| black_box(&packet).encode().unwrap()));
+    group.bench_function("decode", |b| b.iter(|| SubscribePacket::decode(black_box(&encoded)).unwrap()));
+    group.finish();
+}
+
+fn bench_suback(c: &mut Criterion) {
+    let packet = SubAckPacket::new(1, vec![0x01, 0x01, 0x80]);
+    let encoded = packet.encode();
+
+    let mut group = c.benchmark_group("suback");
+    group.bench_function("encode", |b| b.iter(|| black_box(&packet).encode()));
+    group.bench_function("decode", |b| b.iter(|| SubAckPacket::decode(black_box(&encoded)).unwrap()));
+    group.finish();
+}
+
+criterion_group!(benches, bench_connect, bench_publish, bench_subscribe, bench_suback);
+criterion_main!(benches);
@@ -1,106 +1,56 @@
-use std::net::TcpStream;
-use std::io::{Read, Write};
+use std::io::{self, BufRead, Write};
 use std::thread;
 use std::time::{Duration, Instant};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::env;
 
+use mqtt_broker::client::{ClientEvent, ClientOptions, MqttClient, ProtocolMode, Will};
 use mqtt_broker::packets::{
-    connect::ConnectPacket,
-    connack::ConnAckPacket,
+    connect::WillProperties,
     publish::PublishPacket,
-    subscribe::SubscribePacket,
-    ping::PingReqPacket,
-    disconnect::{DisconnectPacket, DisconnectReasonCode},
+    qos::QoS,
+    subscribe::is_valid_topic_filter,
 };
 
-fn send_connect_packet(mut stream: TcpStream, client_id: String)
+// Shows the prompt and reads the answer, None once the input ends
+fn prompt(input: &mut impl BufRead, output: &mut impl Write, text: &str) -> Option<String>
 {
-    let connect_packet = ConnectPacket::new(
-        "MQTT".to_string(),
-        5,
-        0b00000010,
-        60,
-        client_id,
-        None,
-        None,
-        Some("user".to_string()),
-        Some("password".to_string()),
-    );
-
-    let packet = connect_packet.encode();
-    let _ = stream.write(&packet);
-}
-
-fn receive_connack_packet(mut stream: TcpStream)
-{
-    let mut buffer = [0u8; 1024];
-    let _ = stream.read(&mut buffer);
-    let _ = ConnAckPacket::decode(&buffer);
-}
-
-fn send_publish_packet(mut stream: TcpStream, topic: &str, message: &str)
-{
-    let publish_packet = PublishPacket::new(
-        topic.to_string(),
-        1,
-        1,
-        false,
-        false,
-        message.as_bytes().to_vec(),
-    );
-
-    let packet = publish_packet.encode();
-    let _ = stream.write(&packet);
-}
-
-fn send_subscribe_packet(mut stream: TcpStream, topic: &str)
-{
-    let subscribe_packet =
-        SubscribePacket::new(1, vec![topic.to_string()], vec![1]);
-
-    let packet = subscribe_packet.encode();
-    let _ = stream.write(&packet);
+    let _ = write!(output, "{}", text);
+    let _ = output.flush();
+    let mut line = String::new();
+    match input.read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line.trim().to_string()),
+    }
 }
 
-fn send_disconnect_packet(stream: &mut TcpStream)
+/// Asks for a topic filter and a QoS until both are valid, an empty QoS is QoS 1.
+/// Returns None once the input ends.
+fn read_subscription(input: &mut impl BufRead, output: &mut impl Write) -> Option<(String, QoS)>
 {
-    let packet =
-        DisconnectPacket::new(DisconnectReasonCode::NormalDisconnection)
-            .encode();
-
-    let _ = stream.write(&packet);
-}
+    let topic = loop {
+        let topic = prompt(input, output, "Topic filter to subscribe to: ")?;
+        if is_valid_topic_filter(&topic) {
+            break topic;
+        }
+        let _ = writeln!(output, "Invalid topic filter: {:?}", topic);
+    };
 
-fn packets_listener(mut stream: TcpStream, shutdown_flag: Arc<Mutex<bool>>)
-{
-    let mut buffer = [0u8; 1024];
-
-    loop {
-        let ping = PingReqPacket.encode();
-        let _ = stream.write(&ping);
-
-        match stream.read(&mut buffer) {
-            Ok(size) if size > 0 => {
-                let packet_type = buffer[0] >> 4;
-
-                if packet_type == 3 {
-                    if let Ok(packet) =
-                        PublishPacket::decode(&buffer[..size])
-                    {
-                        let _ =
-                            String::from_utf8(packet.payload).unwrap_or_default();
-                    }
-                }
-            }
+    let qos = loop {
+        let qos = prompt(input, output, "QoS (0, 1 or 2) [1]: ")?;
+        if qos.is_empty() {
+            break QoS::AtLeastOnce;
+        }
+        match qos.parse().map(QoS::from_u8) {
+            Ok(Ok(qos)) => break qos,
             _ => {
-                *shutdown_flag.lock().unwrap() = true;
-                break;
+                let _ = writeln!(output, "Invalid QoS: {:?}", qos);
             }
         }
+    };
 
-        thread::sleep(Duration::from_secs(5));
-    }
+    Some((topic, qos))
 }
 
 fn start_client()
@@ -108,20 +58,43 @@ fn start_client()
     let args: Vec<String> = env::args().collect();
     let mode = args.get(1).map(|s| s.as_str()).unwrap_or("sub");
 
-    let shutdown_flag = Arc::new(Mutex::new(false));
-
-    let client_id =
-        format!("client-{}", std::process::id());
+    let mut options = ClientOptions::new(format!("client-{}", std::process::id()));
+    options.username = Some("user".to_string());
+    options.password = Some("password".to_string());
+
+    // --will <topic> announces the client going offline, 5 seconds after it is lost
+    options.will = args.iter().position(|arg| arg == "--will").and_then(|i| args.get(i + 1)).map(|topic| Will {
+        topic: topic.clone(),
+        message: b"offline".to_vec(),
+        qos: QoS::AtLeastOnce,
+        retain: true,
+        properties: WillProperties {
+            will_delay_interval: Some(5),
+            message_expiry_interval: Some(3600),
+            content_type: Some("text/plain".to_string()),
+            ..WillProperties::default()
+        },
+    });
+    if args.iter().any(|arg| arg == "--strict") {
+        options.mode = ProtocolMode::Strict;
+    }
 
-    let mut stream =
-        TcpStream::connect("192.168.100.10:1883")
+    // The listener runs from the start to receive the SUBACKs and the PUBACKs
+    let client =
+        MqttClient::connect("192.168.100.10:1883", options)
             .expect("Connection failed");
 
-    send_connect_packet(stream.try_clone().unwrap(), client_id);
-    receive_connack_packet(stream.try_clone().unwrap());
+    // Taken before subscribing so no message of the subscription is missed
+    let events = client.take_events().expect("Connection lost");
 
     if mode == "sub" {
-        send_subscribe_packet(stream.try_clone().unwrap(), "test");
+        let stdin = io::stdin();
+        if let Some((topic, qos)) = read_subscription(&mut stdin.lock(), &mut io::stdout()) {
+            match client.subscribe(&topic, qos) {
+                Ok(qos) => println!("Subscribed to {} with QoS {}", topic, qos.to_u8()),
+                Err(e) => eprintln!("Subscribe failed: {}", e),
+            }
+        }
     }
 
     if mode == "pub" {
@@ -144,6 +117,7 @@ fn start_client()
             execution_time / frequency;
 
         let mut message_count = 0;
+        let acknowledged = Arc::new(AtomicUsize::new(0));
 
         let start = Instant::now();
 
@@ -153,10 +127,22 @@ fn start_client()
         {
             let publish_start = Instant::now();
 
-            send_publish_packet(
-                stream.try_clone().unwrap(),
+            // Messages are held back while the broker has a full window of them
+            while !client.can_publish() {
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            let acknowledged_clone = Arc::clone(&acknowledged);
+            client.publish_with_ack(
                 "test",
-                &payload,
+                payload.as_bytes(),
+                QoS::AtLeastOnce,
+                move |result| match result {
+                    Ok(()) => {
+                        acknowledged_clone.fetch_add(1, Ordering::SeqCst);
+                    }
+                    Err(e) => eprintln!("Publish not acknowledged: {}", e),
+                },
             );
 
             message_count += 1;
@@ -169,25 +155,66 @@ fn start_client()
         }
 
         println!("Total messages sent: {}", message_count);
+        println!(
+            "Total messages acknowledged: {}",
+            acknowledged.load(Ordering::SeqCst)
+        );
     }
 
-    let listener_stream = stream.try_clone().unwrap();
-    let listener_flag = Arc::clone(&shutdown_flag);
-
-    thread::spawn(move || {
-        packets_listener(listener_stream, listener_flag);
-    });
-
-    loop {
-        if *shutdown_flag.lock().unwrap() {
-            break;
+    for event in events {
+        match event {
+            ClientEvent::Message(packet) => println!("{}", message_line(&packet)),
+            ClientEvent::ConnectionLost(reason_code) => {
+                eprintln!("Connection lost: {:?}", reason_code);
+                break;
+            }
         }
-        thread::sleep(Duration::from_secs(1));
     }
 
-    send_disconnect_packet(&mut stream);
+    client.disconnect();
+}
+
+// Line printed for a received message, binary payloads are shown with replacement characters
+fn message_line(packet: &PublishPacket) -> String {
+    format!("Message received on {}: {}", packet.topic_name, String::from_utf8_lossy(&packet.payload))
 }
 
 fn main() {
     start_client();
-}
\ No newline at end of file
+}
+// The prompts live in this binary, so their tests cannot go under tests/ with the
+// ones of the library
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscription_is_asked_again_until_valid() {
+        let mut input = &b"sensors/#/temp\nsensors/+/temp\n3\n0\n"[..];
+        let mut output = Vec::new();
+        let subscription = read_subscription(&mut input, &mut output);
+        assert_eq!(subscription, Some(("sensors/+/temp".to_string(), QoS::AtMostOnce)));
+
+        // Both prompts were shown twice, after an error for each invalid answer
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.matches("Topic filter to subscribe to").count(), 2);
+        assert_eq!(output.matches("QoS (0, 1 or 2)").count(), 2);
+        assert!(output.contains("Invalid topic filter: \"sensors/#/temp\""));
+        assert!(output.contains("Invalid QoS: \"3\""));
+    }
+
+    #[test]
+    fn binary_payload_is_shown_with_replacement_characters() {
+        let packet = PublishPacket::new("raw".to_string(), 0, QoS::AtMostOnce, false, false, vec![b'o', b'k', 0xFF, 0xFE]);
+        assert_eq!(message_line(&packet), "Message received on raw: ok\u{FFFD}\u{FFFD}");
+    }
+
+    #[test]
+    fn empty_qos_is_qos_1_and_end_of_input_is_none() {
+        let mut input = &b"news\n\n"[..];
+        assert_eq!(read_subscription(&mut input, &mut Vec::new()), Some(("news".to_string(), QoS::AtLeastOnce)));
+
+        let mut input = &b"news\n"[..];
+        assert_eq!(read_subscription(&mut input, &mut Vec::new()), None);
+    }
+}
@@ -0,0 +1,330 @@
+//! Broker running its connections as Tokio tasks instead of one thread each.
+
+/*
+The synchronous Broker blocks a thread per client, which stops scaling after a
+few hundred connections. Here every connection is a task: its reader handles the
+packets of the client, and everything written to the client, the answers and the
+messages routed to it, goes through a channel to its writer task, so two packets
+are never interleaved. The packets are framed and decoded with the same code as
+the synchronous broker.
+
+Only the core of the protocol is handled: CONNECT, PUBLISH, SUBSCRIBE, UNSUBSCRIBE,
+PINGREQ and DISCONNECT. QoS 2 messages are acknowledged and forwarded at QoS 1, as
+the bridge does, and forwarded QoS 1 messages are not sent again. Retained messages,
+sessions and the options of BrokerConfig are left to the synchronous broker.
+*/
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc, RwLock};
+use crate::packets::{
+    connack::ConnAckPacket,
+    connect::ConnectPacket,
+    disconnect::{DisconnectPacket, DisconnectReasonCode},
+    fixed_header::{parse_fixed_header, PacketType},
+    framer::{FrameError, Framer, PROTOCOL_MAXIMUM_PACKET_SIZE},
+    ping::{PingReqPacket, PingRespPacket},
+    puback::PubAckPacket,
+    publish::PublishPacket,
+    qos::QoS,
+    qos2::{PubCompPacket, PubRecPacket, PubRelPacket},
+    suback::{SubAckPacket, TOPIC_FILTER_INVALID, UNSPECIFIED_ERROR},
+    subscribe::{is_valid_topic_filter, SubscribePacket},
+    unsubscribe::{UnsubAckPacket, UnsubscribePacket, NO_SUBSCRIPTION_EXISTED, SUCCESS},
+};
+use super::SubscriptionRegistry;
+
+// Bytes requested from the connection by every read
+const READ_CHUNK: usize = 4096;
+
+/// What the writer task of a connection sends to the client
+enum Outgoing {
+    Packet(Vec<u8>),        // A packet already encoded
+    Publish(PublishPacket), // A routed message, which gets its message ID from the writer
+}
+
+// Sends to the writer task of a connection
+type Outbox = mpsc::UnboundedSender<Outgoing>;
+
+/// State shared by the tasks of every connection
+#[derive(Default)]
+struct Shared {
+    subscriptions: RwLock<SubscriptionRegistry>, // Topic filters of the connected clients
+    outboxes: RwLock<HashMap<String, Outbox>>,   // Writer of each connected client, by client ID
+}
+
+/// Listens on the address and serves the connections until the task is dropped
+pub async fn run(addr: impl ToSocketAddrs) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    println!("\nAsync MQTT server started on {}\n", listener.local_addr()?);
+    serve(listener).await;
+    Ok(())
+}
+
+/// Handles the incoming connections of the listener, each one in its own task
+pub async fn serve(listener: TcpListener) {
+    let shared = Arc::new(Shared::default());
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer_addr)) => {
+                println!("[+]Client connected: {:?}\n", peer_addr);
+                let shared = Arc::clone(&shared);
+                tokio::spawn(async move {
+                    handle_connection(stream, peer_addr, &shared).await;
+                    println!("[+]Client disconnected: {:?}\n", peer_addr);
+                });
+            }
+            Err(e) => println!("[-]Error accepting connection: {}\n", e),
+        }
+    }
+}
+
+/// Reads the next whole packet of the connection
+async fn read_packet(reader: &mut OwnedReadHalf, framer: &mut Framer) -> Result<Vec<u8>, FrameError> {
+    loop {
+        if let Some(packet) = framer.next_packet()? {
+            return Ok(packet);
+        }
+
+        let mut chunk = [0u8; READ_CHUNK];
+        match reader.read(&mut chunk).await {
+            Ok(0) => return Err(FrameError::Closed),
+            Ok(size) => framer.extend(&chunk[..size]),
+            Err(e) => return Err(FrameError::Io(e)),
+        }
+    }
+}
+
+/// Writes what the connection tasks send until every sender is dropped, then closes
+/// the write half of the connection
+async fn write_packets(mut writer: OwnedWriteHalf, mut inbox: mpsc::UnboundedReceiver<Outgoing>) {
+    let mut next_message_id: u16 = 0;
+    while let Some(outgoing) = inbox.recv().await {
+        let data = match outgoing {
+            Outgoing::Packet(data) => data,
+            Outgoing::Publish(mut packet) => {
+                if packet.qos != QoS::AtMostOnce {
+                    next_message_id = next_message_id.checked_add(1).unwrap_or(1);
+                    packet.message_id = next_message_id;
+                }
+                match packet.encode() {
+                    Ok(data) => data,
+                    Err(e) => {
+                        eprintln!("[-]Error encoding PUBLISH packet: {}\n", e);
+                        continue;
+                    }
+                }
+            }
+        };
+        if let Err(e) = writer.write_all(&data).await {
+            eprintln!("[-]Error writing to the client: {}\n", e);
+            break;
+        }
+    }
+    let _ = writer.shutdown().await;
+}
+
+/// Sends the message to every client with a matching subscription, at most at QoS 1
+async fn route(shared: &Shared, packet: &PublishPacket) {
+    // The registry stays locked until the message is queued, so a client that
+    // unsubscribed meanwhile never gets it
+    let subscriptions = shared.subscriptions.read().await;
+    let outboxes = shared.outboxes.read().await;
+    for (client_id, qos) in subscriptions.matching(&packet.topic_name) {
+        if let Some(outbox) = outboxes.get(&client_id) {
+            let mut forwarded = packet.clone();
+            forwarded.retain = false;
+            forwarded.dup = false;
+            forwarded.topic_alias = None;
+            forwarded.qos = packet.qos.min(qos).min(QoS::AtLeastOnce);
+            let _ = outbox.send(Outgoing::Publish(forwarded));
+        }
+    }
+}
+
+/// Runs the CONNECT exchange and the packets of a client until it leaves
+async fn handle_connection(stream: TcpStream, peer_addr: SocketAddr, shared: &Shared) {
+    let (mut reader, writer) = stream.into_split();
+    let mut framer = Framer::new(PROTOCOL_MAXIMUM_PACKET_SIZE);
+
+    // The first packet must be a CONNECT
+    let connect_packet = match read_packet(&mut reader, &mut framer).await {
+        Ok(packet) => match ConnectPacket::decode(&packet) {
+            Ok(connect_packet) => connect_packet,
+            Err(e) => {
+                eprintln!("[-]Error decoding CONNECT packet: {}\n", e);
+                return;
+            }
+        },
+        Err(e) => {
+            eprintln!("[-]Error reading from stream: {}\n", e);
+            return;
+        }
+    };
+    println!("[+]Received CONNECT packet: {:?}\n", connect_packet);
+
+    let (outbox, inbox) = mpsc::unbounded_channel();
+    let writer_task = tokio::spawn(write_packets(writer, inbox));
+
+    // A client without ID is known by its address
+    let mut connack_builder = ConnAckPacket::builder();
+    let client_id = if connect_packet.client_id.is_empty() {
+        let client_id = peer_addr.to_string();
+        connack_builder = connack_builder.assigned_client_identifier(client_id.clone());
+        client_id
+    } else {
+        connect_packet.client_id.clone()
+    };
+    shared.outboxes.write().await.insert(client_id.clone(), outbox.clone());
+    let _ = outbox.send(Outgoing::Packet(connack_builder.build().encode()));
+
+    let keep_alive = Duration::from_secs(connect_packet.keep_alive as u64);
+    loop {
+        // A client silent for one and a half times its keep alive is gone
+        let read = read_packet(&mut reader, &mut framer);
+        let result = if keep_alive.is_zero() {
+            read.await
+        } else {
+            match tokio::time::timeout(keep_alive * 3 / 2, read).await {
+                Ok(result) => result,
+                Err(_) => {
+                    println!("[-]No packet received within the keep alive of {:?}. Closing connection.\n", keep_alive);
+                    let _ = outbox.send(Outgoing::Packet(DisconnectPacket::new(DisconnectReasonCode::KeepAliveTimeout).encode()));
+                    break;
+                }
+            }
+        };
+        let packet = match result {
+            Ok(packet) => packet,
+            Err(FrameError::Closed) => break,
+            Err(e) => {
+                eprintln!("[-]Error reading from stream: {}\n", e);
+                break;
+            }
+        };
+
+        if let Err(reason_code) = handle_packet(&packet, &client_id, &outbox, shared).await {
+            let _ = outbox.send(Outgoing::Packet(DisconnectPacket::new(reason_code).encode()));
+            break;
+        }
+        if parse_fixed_header(&packet).map(|header| header.packet_type) == Ok(PacketType::Disconnect) {
+            break;
+        }
+    }
+
+    // A client ID taken over by a newer connection keeps its subscriptions
+    let mut subscriptions = shared.subscriptions.write().await;
+    let mut outboxes = shared.outboxes.write().await;
+    if outboxes.get(&client_id).is_some_and(|current| current.same_channel(&outbox)) {
+        outboxes.remove(&client_id);
+        subscriptions.remove_client(&client_id);
+    }
+    drop(outboxes);
+    drop(subscriptions);
+
+    // The writer ends once the last packets queued for the client are written
+    drop(outbox);
+    let _ = writer_task.await;
+}
+
+/// Handles a packet of a connected client.
+///
+/// # Returns
+///
+/// The reason code of the DISCONNECT that closes the connection if the packet cannot
+/// be decoded or breaks a rule of the protocol.
+async fn handle_packet(packet: &[u8], client_id: &str, outbox: &Outbox, shared: &Shared) -> Result<(), DisconnectReasonCode> {
+    // The framer already parsed the fixed header of the packet
+    let packet_type = parse_fixed_header(packet).map_err(|e| e.disconnect_reason())?.packet_type;
+    let reply = |data: Vec<u8>| {
+        let _ = outbox.send(Outgoing::Packet(data));
+    };
+
+    match packet_type {
+        PacketType::Publish => {
+            let publish = PublishPacket::decode(packet).map_err(|e| {
+                eprintln!("[-]Error decoding PUBLISH packet: {}\n", e);
+                e.disconnect_reason()
+            })?;
+            println!("[+]Received PUBLISH packet: {:?}\n", publish);
+
+            // Routed before it is acknowledged
+            route(shared, &publish).await;
+            match publish.qos {
+                QoS::AtMostOnce => {}
+                QoS::AtLeastOnce => reply(PubAckPacket::new(publish.message_id).encode()),
+                QoS::ExactlyOnce => reply(PubRecPacket::new(publish.message_id).encode()),
+            }
+        }
+        PacketType::PubRel => {
+            let pubrel = PubRelPacket::decode(packet).map_err(|e| e.disconnect_reason())?;
+            reply(PubCompPacket::new(pubrel.packet_id).encode());
+        }
+        // Forwarded messages are not sent again, their acknowledgements need no handling
+        PacketType::PubAck | PacketType::PubRec | PacketType::PubComp => {}
+        PacketType::Subscribe => {
+            let subscribe = SubscribePacket::decode(packet).map_err(|e| {
+                eprintln!("[-]Error decoding SUBSCRIBE packet: {}\n", e);
+                e.disconnect_reason()
+            })?;
+            println!("[+]Received SUBSCRIBE packet: {:?}\n", subscribe);
+
+            // The filters are registered before the SUBACK, every one on its own
+            let mut subscriptions = shared.subscriptions.write().await;
+            let return_codes = subscribe
+                .topic_filters
+                .iter()
+                .zip(subscribe.options())
+                .map(|(topic, options)| match options {
+                    Ok(_) if !is_valid_topic_filter(topic) => TOPIC_FILTER_INVALID,
+                    Ok(options) => {
+                        subscriptions.subscribe_with_options(client_id, topic, options);
+                        options.qos.to_u8()
+                    }
+                    Err(_) => UNSPECIFIED_ERROR,
+                })
+                .collect();
+            drop(subscriptions);
+            reply(SubAckPacket::new(subscribe.packet_id, return_codes).encode());
+        }
+        PacketType::Unsubscribe => {
+            let unsubscribe = UnsubscribePacket::decode(packet).map_err(|e| {
+                eprintln!("[-]Error decoding UNSUBSCRIBE packet: {}\n", e);
+                e.disconnect_reason()
+            })?;
+            println!("[+]Received UNSUBSCRIBE packet: {:?}\n", unsubscribe);
+
+            let mut subscriptions = shared.subscriptions.write().await;
+            let reason_codes = unsubscribe
+                .topic_filters
+                .iter()
+                .map(|topic| {
+                    if !is_valid_topic_filter(topic) {
+                        TOPIC_FILTER_INVALID
+                    } else if subscriptions.unsubscribe(client_id, topic) {
+                        SUCCESS
+                    } else {
+                        NO_SUBSCRIPTION_EXISTED
+                    }
+                })
+                .collect();
+            drop(subscriptions);
+            reply(UnsubAckPacket::new(unsubscribe.packet_id, reason_codes).encode());
+        }
+        PacketType::PingReq => {
+            PingReqPacket::decode(packet).map_err(|e| e.disconnect_reason())?;
+            reply(PingRespPacket.encode());
+        }
+        PacketType::Disconnect => println!("[+]Received DISCONNECT packet from {}\n", client_id),
+        // A second CONNECT is a protocol error
+        PacketType::Connect => return Err(DisconnectReasonCode::ProtocolError),
+        packet_type => println!("[-]Unknown or unsupported packet type: {:?}\n", packet_type),
+    }
+    Ok(())
+}
@@ -0,0 +1,278 @@
+//! Bridge that connects the broker to an upstream broker as one more client.
+
+/*
+The bridge opens a client connection to the upstream broker the first time it is
+needed and reuses it for every message, a connection that fails is dropped and
+opened again for the next one. Outgoing, the local publishes that match one of the
+topic filters are sent upstream. Incoming, the bridge subscribes to the topic
+filters upstream and routes what it receives to the local subscribers, which are
+never sent back upstream. Messages go upstream at QoS 0 or 1 and are not sent
+again if the upstream never acknowledges them.
+*/
+
+use std::io::{self, ErrorKind, Write};
+use std::net::TcpStream;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use crate::packets::{
+    connack::{ConnAckPacket, ConnAckReasonCode},
+    connect::ConnectPacket,
+    fixed_header::{parse_fixed_header, PacketType},
+    puback::PubAckPacket,
+    publish::PublishPacket,
+    qos::QoS,
+    subscribe::{topic_matches, SubscribePacket, SubscriptionOptions},
+};
+use super::{lock, Broker, Transport};
+
+// Time the upstream broker has to answer the CONNECT of the bridge
+const CONNACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Direction the messages of the bridged topics travel in
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum BridgeDirection {
+    #[default]
+    Out,  // Local publishes are sent to the upstream broker
+    In,   // Upstream publishes are routed to the local subscribers
+    Both, // Both of the above
+}
+
+impl BridgeDirection {
+    fn outgoing(&self) -> bool {
+        matches!(self, BridgeDirection::Out | BridgeDirection::Both)
+    }
+
+    fn incoming(&self) -> bool {
+        matches!(self, BridgeDirection::In | BridgeDirection::Both)
+    }
+}
+
+/// Upstream broker and the topics bridged with it
+#[derive(Debug, Clone)]
+pub struct BridgeConfig {
+    pub upstream: String,           // Address of the upstream broker, as host:port
+    pub topic_filters: Vec<String>, // Topics bridged, wildcards allowed
+    pub direction: BridgeDirection, // Direction the messages travel in
+    pub client_id: String,          // Client ID of the bridge in the upstream broker
+}
+
+impl BridgeConfig {
+    /// Bridges the topic filters with the upstream broker in the given direction
+    pub fn new(upstream: impl Into<String>, topic_filters: Vec<String>, direction: BridgeDirection) -> Self {
+        BridgeConfig {
+            upstream: upstream.into(),
+            topic_filters,
+            direction,
+            client_id: "mqtt-broker-bridge".to_string(),
+        }
+    }
+
+    /// Returns whether the topic is one of the bridged ones
+    pub fn matches(&self, topic: &str) -> bool {
+        self.topic_filters.iter().any(|filter| topic_matches(filter, topic))
+    }
+}
+
+/// Opens a new connection to the upstream broker
+pub type Connector = Box<dyn Fn() -> io::Result<Box<dyn Transport>> + Send + Sync>;
+
+/// Connection to the upstream broker and the message IDs used on it
+struct UpstreamConnection {
+    stream: Box<dyn Transport>,
+    next_message_id: u16,
+}
+
+/// Client connection of the broker to an upstream broker
+pub struct Bridge {
+    config: BridgeConfig,
+    connector: Connector,
+    connection: Mutex<Option<UpstreamConnection>>, // None until needed or after a failure
+}
+
+impl Bridge {
+    /// Creates a bridge that opens its connections with the connector
+    pub fn new(config: BridgeConfig, connector: Connector) -> Self {
+        Bridge {
+            config,
+            connector,
+            connection: Mutex::new(None),
+        }
+    }
+
+    /// Creates a bridge that connects to the upstream address of the configuration over TCP
+    pub fn tcp(config: BridgeConfig) -> Self {
+        let upstream = config.upstream.clone();
+        Bridge::new(config, Box::new(move || {
+            let stream: Box<dyn Transport> = Box::new(TcpStream::connect(&upstream)?);
+            Ok(stream)
+        }))
+    }
+
+    /// Returns the configuration of the bridge
+    pub fn config(&self) -> &BridgeConfig {
+        &self.config
+    }
+
+    /// Connects right away when the bridge receives messages, since nothing else would
+    pub(crate) fn start(&self, broker: &Broker) {
+        if self.config.direction.incoming() {
+            let mut connection = lock(&self.connection);
+            if let Err(e) = self.ensure_connected(&mut connection, broker) {
+                eprintln!("[-]Error connecting the bridge to {}: {}\n", self.config.upstream, e);
+            }
+        }
+    }
+
+    /// Sends a local publish to the upstream broker if its topic is bridged outwards
+    pub(crate) fn forward(&self, packet: &PublishPacket, broker: &Broker) {
+        if !self.config.direction.outgoing() || !self.config.matches(&packet.topic_name) {
+            return;
+        }
+
+        let mut connection = lock(&self.connection);
+        let result = self.ensure_connected(&mut connection, broker).and_then(|upstream| {
+            let mut forwarded = packet.clone();
+            forwarded.dup = false;
+            forwarded.topic_alias = None;
+            // The bridge does not complete the QoS 2 exchange, those messages go at QoS 1
+            forwarded.qos = forwarded.qos.min(QoS::AtLeastOnce);
+            forwarded.message_id = 0;
+            if forwarded.qos == QoS::AtLeastOnce {
+                upstream.next_message_id = upstream.next_message_id.checked_add(1).unwrap_or(1);
+                forwarded.message_id = upstream.next_message_id;
+            }
+            upstream.stream.write_all(&forwarded.encode()?)
+        });
+
+        match result {
+            Ok(_) => println!("[+]Bridged PUBLISH to {}: {}\n", self.config.upstream, packet.topic_name),
+            Err(e) => {
+                eprintln!("[-]Error bridging PUBLISH to {}: {}\n", self.config.upstream, e);
+                // Opened again for the next message
+                *connection = None;
+            }
+        }
+    }
+
+    /// Closes the connection to the upstream broker, which stops its reader thread
+    pub(crate) fn close(&self) {
+        if let Some(upstream) = lock(&self.connection).take() {
+            if let Err(e) = upstream.stream.close() {
+                eprintln!("[-]Error closing the bridge connection: {}\n", e);
+            }
+        }
+    }
+
+    /// Returns the open connection, connecting first if there is none
+    fn ensure_connected<'a>(
+        &self,
+        connection: &'a mut Option<UpstreamConnection>,
+        broker: &Broker,
+    ) -> io::Result<&'a mut UpstreamConnection> {
+        if connection.is_none() {
+            *connection = Some(self.connect(broker)?);
+        }
+        Ok(connection.as_mut().unwrap())
+    }
+
+    /// Opens a connection, completes the CONNECT / CONNACK exchange, subscribes to the
+    /// topic filters if the bridge receives messages and starts reading the connection
+    fn connect(&self, broker: &Broker) -> io::Result<UpstreamConnection> {
+        let mut stream = (self.connector)()?;
+
+        // Keep alive 0, the upstream broker does not expect PINGREQ from the bridge
+        let connect = ConnectPacket::new(
+            "MQTT".to_string(),
+            5,
+            0x02, // Clean start
+            0,
+            self.config.client_id.clone(),
+            None,
+            None,
+            None,
+            None,
+        );
+        stream.write_all(&connect.encode())?;
+
+        stream.set_read_timeout(Some(CONNACK_TIMEOUT))?;
+        let connack = read_packet(stream.as_mut())?;
+        let connack = ConnAckPacket::decode(&connack).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        if connack.reason_code != ConnAckReasonCode::Success {
+            return Err(io::Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("upstream refused the bridge: {:?}", connack.reason_code),
+            ));
+        }
+        stream.set_read_timeout(None)?;
+        println!("[+]Bridge connected to {}\n", self.config.upstream);
+
+        let mut next_message_id = 0;
+        if self.config.direction.incoming() {
+            next_message_id += 1;
+            let options = SubscriptionOptions { qos: QoS::AtLeastOnce, no_local: true, ..Default::default() };
+            let filters = self.config.topic_filters.iter().map(|filter| (filter.clone(), options)).collect();
+            stream.write_all(&SubscribePacket::with_options(next_message_id, filters).encode()?)?;
+        }
+
+        // The upstream acknowledgements are read even when nothing comes in, so they
+        // never fill the connection
+        let reader = stream.box_clone()?;
+        let incoming = self.config.direction.incoming();
+        let broker = broker.clone();
+        thread::spawn(move || read_upstream(reader, broker, incoming));
+
+        Ok(UpstreamConnection { stream, next_message_id })
+    }
+}
+
+/// Reads the packets of the upstream broker until the connection closes, routing the
+/// PUBLISH packets to the local subscribers if the bridge receives messages
+fn read_upstream(mut stream: Box<dyn Transport>, broker: Broker, incoming: bool) {
+    loop {
+        let packet = match read_packet(stream.as_mut()) {
+            Ok(packet) => packet,
+            Err(e) => {
+                println!("[-]Bridge connection closed: {}\n", e);
+                return;
+            }
+        };
+
+        match parse_fixed_header(&packet).map(|header| header.packet_type) {
+            Ok(PacketType::Publish) if incoming => match PublishPacket::decode(&packet) {
+                Ok(publish) => {
+                    if publish.qos == QoS::AtLeastOnce {
+                        if let Err(e) = stream.write_all(&PubAckPacket::new(publish.message_id).encode()) {
+                            eprintln!("[-]Error sending PUBACK to the upstream broker: {}\n", e);
+                        }
+                    }
+                    println!("[+]Received bridged PUBLISH: {}\n", publish.topic_name);
+                    broker.route_local(publish, None);
+                }
+                Err(e) => broker.reject_packet(&packet, &e),
+            },
+            Ok(packet_type) => println!("[+]Received {:?} from the upstream broker\n", packet_type),
+            Err(e) => broker.reject_packet(&packet, &e),
+        }
+    }
+}
+
+/// Reads one whole packet, its fixed header gives the bytes left to read
+fn read_packet(stream: &mut dyn Transport) -> io::Result<Vec<u8>> {
+    let mut packet = vec![0; 1];
+    stream.read_exact(&mut packet)?;
+    loop {
+        let mut byte = [0; 1];
+        stream.read_exact(&mut byte)?;
+        packet.push(byte[0]);
+        if byte[0] & 0x80 == 0 || packet.len() > 4 {
+            break;
+        }
+    }
+
+    let header = parse_fixed_header(&packet).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    let mut rest = vec![0; header.remaining_length];
+    stream.read_exact(&mut rest)?;
+    packet.extend(rest);
+    Ok(packet)
+}
@@ -0,0 +1,22 @@
+//! Dead-letter hook for the packets rejected by the broker.
+
+/*
+Every packet that fails to decode in a client thread is handed to the registered
+sink together with the raw bytes that were received, which helps to debug
+interoperability problems with other clients.
+*/
+
+use crate::packets::DecodeError;
+
+/// Receives the packets the broker could not decode
+pub trait DeadLetterSink: Send + Sync {
+    /// Called with the raw bytes of the rejected packet and the decoding error
+    fn on_rejected(&self, raw: &[u8], err: &DecodeError);
+}
+
+/// Default sink, rejected packets are only logged by the broker
+pub struct DiscardDeadLetters;
+
+impl DeadLetterSink for DiscardDeadLetters {
+    fn on_rejected(&self, _raw: &[u8], _err: &DecodeError) {}
+}
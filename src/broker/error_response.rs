@@ -0,0 +1,41 @@
+//! Answer of the broker to a packet it could not decode.
+
+/*
+A packet that fails to decode is answered on the wire before the connection
+closes, so the client learns why. Before the connection is accepted the answer is
+a CONNACK refusing it, afterwards it is a DISCONNECT. Both carry the reason code
+matching the error: a packet breaking a protocol rule is a Protocol Error, a topic
+with forbidden characters is Topic Name Invalid, a packet over the maximum packet
+size is Packet Too Large, and any other failure, truncated fields, invalid UTF-8 or
+a broken variable length among them, is a Malformed Packet.
+*/
+
+use crate::packets::{connack::ConnAckReasonCode, disconnect::DisconnectReasonCode, DecodeError};
+
+/// Point of the connection a packet was received at
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionPhase {
+    Connect, // The packet is the CONNECT, the connection is not accepted yet
+    Session, // The CONNECT was accepted, the client is connected
+}
+
+/// Packet the broker sends before closing a connection, with its reason code
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerAction {
+    ConnAck(ConnAckReasonCode),       // Refuse the connection with a CONNACK
+    Disconnect(DisconnectReasonCode), // End the session with a DISCONNECT
+}
+
+/// Returns how the broker answers a packet that failed to decode with the error,
+/// depending on the phase of the connection it was received in
+pub fn error_response(err: &DecodeError, phase: ConnectionPhase) -> ServerAction {
+    match phase {
+        ConnectionPhase::Connect => ServerAction::ConnAck(match err {
+            DecodeError::ProtocolError(_) => ConnAckReasonCode::ProtocolError,
+            DecodeError::InvalidTopic(_) => ConnAckReasonCode::TopicNameInvalid,
+            DecodeError::PacketTooLarge(_) => ConnAckReasonCode::PacketTooLarge,
+            _ => ConnAckReasonCode::MalformedPacket,
+        }),
+        ConnectionPhase::Session => ServerAction::Disconnect(err.disconnect_reason()),
+    }
+}
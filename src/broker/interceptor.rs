@@ -0,0 +1,33 @@
+//! Hook to inspect, transform or drop the messages published to the broker.
+
+/*
+Every PUBLISH received from a client goes through the registered interceptor
+before it is retained, routed and acknowledged, so it can be used for redaction,
+format conversion or filtering. Dropped messages are still acknowledged to the
+publisher, they are just never delivered. A panicking interceptor closes the
+connection before the acknowledgement, so the publisher sends the message again.
+The interceptor also decides which topic filters a client may subscribe to.
+*/
+
+use crate::packets::publish::PublishPacket;
+
+/// Inspects every PUBLISH before the broker routes it
+pub trait Interceptor: Send + Sync {
+    /// Returns the packet to route, possibly modified, or None to drop it
+    fn on_publish(&self, packet: PublishPacket) -> Option<PublishPacket>;
+
+    /// Returns whether the client may subscribe to the topic filter, a refused
+    /// filter gets the Not Authorized code in the SUBACK. Every filter is allowed by default.
+    fn on_subscribe(&self, _client_id: &str, _topic_filter: &str) -> bool {
+        true
+    }
+}
+
+/// Default interceptor, every message is routed unchanged
+pub struct PassThrough;
+
+impl Interceptor for PassThrough {
+    fn on_publish(&self, packet: PublishPacket) -> Option<PublishPacket> {
+        Some(packet)
+    }
+}
@@ -0,0 +1,48 @@
+//! Connection a log record of the broker is about.
+
+/*
+Every connection is served by its own thread, so the records of different clients
+interleave in the log. The handler of a connection starts each of its records with
+the LogContext of the connection: the address of the peer and, once the CONNECT is
+read, the client ID, written as client@address. The lines of one client can then be
+picked out of the log with the client ID or the address.
+*/
+
+use std::fmt;
+use std::net::SocketAddr;
+
+/// Client ID and peer address written at the start of the records of a connection
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogContext {
+    peer_addr: SocketAddr,     // Address of the peer of the connection
+    client_id: Option<String>, // Client ID of the CONNECT, None until it is read
+}
+
+impl LogContext {
+    /// Creates the context of a connection whose CONNECT was not read yet
+    pub fn new(peer_addr: SocketAddr) -> Self {
+        LogContext { peer_addr, client_id: None }
+    }
+
+    /// Adds the client ID of the CONNECT to the records that follow
+    pub fn set_client_id(&mut self, client_id: &str) {
+        self.client_id = Some(client_id.to_string());
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    pub fn client_id(&self) -> Option<&str> {
+        self.client_id.as_deref()
+    }
+}
+
+impl fmt::Display for LogContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.client_id {
+            Some(ref client_id) => write!(f, "{}@{}", client_id, self.peer_addr),
+            None => write!(f, "{}", self.peer_addr),
+        }
+    }
+}
@@ -0,0 +1,1861 @@
+//! MQTT broker that handles every client connection in its own thread.
+
+/*
+The broker accepts TCP connections, answers the CONNECT of every client and then
+routes the PUBLISH packets it receives to the clients subscribed to their topic.
+The state shared between the client threads lives in the Broker struct, which is
+cheap to clone since every field is reference counted.
+*/
+
+#[cfg(feature = "async")]
+pub mod async_server;
+pub mod bridge;
+pub mod dead_letter;
+pub mod error_response;
+pub mod interceptor;
+pub mod log_context;
+pub mod persistence;
+pub mod sessions;
+pub mod subscriptions;
+pub mod topic_tree;
+#[cfg(feature = "tls")]
+pub mod tls;
+mod trace;
+pub mod transport;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
+use std::collections::{HashMap, HashSet, VecDeque}; // For storing subscriptions per topic and queued messages
+use std::sync::{Arc, Mutex, MutexGuard}; // Provides thread-safe sharing of data between threads
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering}; // Counters updated by every client thread
+use std::net::{SocketAddr, TcpListener, TcpStream}; // Provides TCP networking capabilities
+use std::thread; // Provides threading utilities for concurrent execution
+use std::io::{self, ErrorKind, Write}; // The connections are written through their Transport
+use std::time::{Duration, Instant};
+use std::path::PathBuf;
+use crate::packets::{
+    fixed_header::{parse_fixed_header, PacketType}, // For identifying the received packets
+    framer::{FrameError, Framer}, // For splitting the received bytes into packets
+    connect::ConnectPacket, // For handling MQTT CONNECT packets
+    connack::{ConnAckPacket, ConnAckReasonCode}, // For creating CONNACK response packets
+    publish::PublishPacket, // For handling MQTT PUBLISH packets
+    puback::{PubAckPacket, NOT_AUTHORIZED, SUCCESS},
+    qos::QoS,
+    qos2::{PubCompPacket, PubRecPacket, PubRelPacket},
+    subscribe::{is_valid_topic_filter, parse_shared_filter, topic_matches, SubscribePacket, SubscriptionOptions},
+    suback::{SubAckPacket, TOPIC_FILTER_INVALID, UNSPECIFIED_ERROR},
+    unsubscribe::{UnsubAckPacket, UnsubscribePacket, NO_SUBSCRIPTION_EXISTED},
+    ping::{PingReqPacket, PingRespPacket},
+    disconnect::{DisconnectPacket, DisconnectReasonCode},
+    DecodeError, // For telling malformed packets from protocol errors
+};
+
+pub use bridge::{Bridge, BridgeConfig, BridgeDirection};
+pub use dead_letter::{DeadLetterSink, DiscardDeadLetters};
+pub use error_response::{error_response, ConnectionPhase, ServerAction};
+pub use interceptor::{Interceptor, PassThrough};
+pub use log_context::LogContext;
+pub use persistence::{FilePersistence, Persistence};
+pub use sessions::{SessionStore, StoredSession};
+pub use subscriptions::SubscriptionRegistry;
+pub use topic_tree::TopicTree;
+pub use transport::Transport;
+use trace::Traced;
+use log::{debug, error, info, warn};
+
+// Time a subscriber has to acknowledge a forwarded QoS 1 PUBLISH before it is sent again
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(5);
+// How often a client thread wakes up from a blocking read to check for expired messages
+const RETRANSMIT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// How often the listener checks for new connections and for a shutdown
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// Highest QoS the broker sends messages with, it has no outbound QoS 2 flow yet
+const MAX_OUTBOUND_QOS: QoS = QoS::AtLeastOnce;
+
+/// Another server the broker sends its clients to, announced in the CONNACK
+#[derive(Debug, Clone)]
+pub enum Redirect {
+    UseAnotherServer(String), // Temporary redirect to the given server reference
+    ServerMoved(String),      // Permanent redirect to the given server reference
+}
+
+impl Redirect {
+    /// CONNACK reason code and server reference of the redirect
+    fn connack_fields(&self) -> (ConnAckReasonCode, &str) {
+        match self {
+            Redirect::UseAnotherServer(reference) => (ConnAckReasonCode::UseAnotherServer, reference),
+            Redirect::ServerMoved(reference) => (ConnAckReasonCode::ServerMoved, reference),
+        }
+    }
+}
+
+/// Rule the client ID of a CONNECT must follow to be accepted
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ClientIdPolicy {
+    #[default]
+    Lenient,  // Any client ID is accepted, as MQTT 5 allows
+    Strict23, // 1 to 23 characters of 0-9, a-z and A-Z, as required by MQTT 3.1.1
+}
+
+impl ClientIdPolicy {
+    /// Returns whether the client ID is accepted by the policy
+    pub fn allows(&self, client_id: &str) -> bool {
+        match self {
+            ClientIdPolicy::Lenient => true,
+            ClientIdPolicy::Strict23 => {
+                (1..=23).contains(&client_id.len())
+                    && client_id.chars().all(|c| c.is_ascii_alphanumeric())
+            }
+        }
+    }
+}
+
+/// What the broker does with a client PUBLISH to a topic starting with `$`,
+/// which are reserved to the broker itself
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ReservedTopicPolicy {
+    #[default]
+    Reject, // Acknowledged with the Not Authorized reason code
+    Drop,   // Acknowledged as if accepted, but never routed
+}
+
+/// What the broker does with a packet of a valid type it does not handle, such as an
+/// AUTH, or a packet only a server sends
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum UnsupportedPacketPolicy {
+    #[default]
+    Ignore,     // The packet is logged and skipped, the connection stays open
+    Disconnect, // The connection is closed with Implementation Specific Error
+}
+
+/// Broker settings shared by every client thread
+#[derive(Debug, Clone)]
+pub struct BrokerConfig {
+    pub bind_addr: SocketAddr, // Address and port the server listens on, port 0 lets the system choose
+    pub allow_anonymous: bool, // Accept clients that connect without a username
+    pub connect_timeout: Duration, // Time a new connection has to send its CONNECT
+    pub redirect: Option<Redirect>, // Refuse every client, pointing it to another server
+    pub persistence_dir: Option<PathBuf>, // Directory where the state is saved to survive restarts
+    pub loopback: bool, // Deliver publishes back to the publisher even if it subscribed with No Local, for testing
+    pub max_keep_alive: Option<u16>, // Longest keep alive granted to a client, in seconds
+    pub keep_alive_default: u16, // Keep alive given to the clients that connect without one, 0 leaves them without
+    pub max_qos: QoS, // Highest QoS accepted in client publishes and granted to subscriptions
+    pub client_id_policy: ClientIdPolicy, // Rule the client IDs must follow
+    pub reserved_topic_policy: ReservedTopicPolicy, // Handling of client publishes to $ topics
+    pub bridge: Option<BridgeConfig>, // Upstream broker the bridged topics are exchanged with
+    pub topic_alias_maximum: u16, // Highest topic alias accepted from a client, 0 disables them
+    pub receive_maximum: u16, // QoS 2 publishes a client may have unreleased at once, announced in the CONNACK
+    pub max_packet_size: u32, // Largest packet accepted from a client, in bytes
+    pub disconnect_grace: Duration, // Time the client has to read a DISCONNECT before the connection closes
+    pub max_connection_duration: Option<Duration>, // Longest a connection may stay open, whatever its activity
+    pub unsupported_packet_policy: UnsupportedPacketPolicy, // Handling of the packet types the broker does not implement
+    #[cfg(feature = "websocket")]
+    pub websocket_bind_addr: Option<SocketAddr>, // Address and port of the MQTT over WebSocket clients, none disables them
+    #[cfg(feature = "tls")]
+    pub tls: Option<tls::TlsConfig>, // Certificate and address of the MQTT over TLS listener, none disables it
+}
+
+impl Default for BrokerConfig {
+    fn default() -> Self {
+        BrokerConfig {
+            bind_addr: SocketAddr::from(([0, 0, 0, 0], 1883)),
+            allow_anonymous: true,
+            connect_timeout: Duration::from_secs(10),
+            redirect: None,
+            persistence_dir: None,
+            loopback: false,
+            max_keep_alive: None,
+            keep_alive_default: 0,
+            max_qos: QoS::ExactlyOnce,
+            client_id_policy: ClientIdPolicy::Lenient,
+            reserved_topic_policy: ReservedTopicPolicy::Reject,
+            bridge: None,
+            topic_alias_maximum: 10,
+            receive_maximum: 100,
+            max_packet_size: 1024 * 1024,
+            disconnect_grace: Duration::from_millis(100),
+            max_connection_duration: None,
+            unsupported_packet_policy: UnsupportedPacketPolicy::Ignore,
+            #[cfg(feature = "websocket")]
+            websocket_bind_addr: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+}
+
+impl BrokerConfig {
+    /// Builds the configuration from the command line arguments of the server
+    pub fn from_args(args: &[String]) -> Self {
+        let mut config = BrokerConfig::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--bind" => match args.next().map(|addr| addr.parse()) {
+                    Some(Ok(addr)) => config.bind_addr = addr,
+                    _ => eprintln!("[-]Missing or invalid address for {}, expected host:port\n", arg),
+                },
+                "--no-anonymous" => config.allow_anonymous = false,
+                "--use-another-server" => match args.next() {
+                    Some(reference) => config.redirect = Some(Redirect::UseAnotherServer(reference.clone())),
+                    None => eprintln!("[-]Missing server reference for {}\n", arg),
+                },
+                "--server-moved" => match args.next() {
+                    Some(reference) => config.redirect = Some(Redirect::ServerMoved(reference.clone())),
+                    None => eprintln!("[-]Missing server reference for {}\n", arg),
+                },
+                "--loopback" => config.loopback = true,
+                "--strict-client-id" => config.client_id_policy = ClientIdPolicy::Strict23,
+                "--drop-reserved-topics" => config.reserved_topic_policy = ReservedTopicPolicy::Drop,
+                "--disconnect-unsupported" => config.unsupported_packet_policy = UnsupportedPacketPolicy::Disconnect,
+                "--max-keep-alive" => match args.next().map(|secs| secs.parse()) {
+                    Some(Ok(secs)) => config.max_keep_alive = Some(secs),
+                    _ => eprintln!("[-]Missing or invalid seconds for {}\n", arg),
+                },
+                "--keep-alive-default" => match args.next().map(|secs| secs.parse()) {
+                    Some(Ok(secs)) => config.keep_alive_default = secs,
+                    _ => eprintln!("[-]Missing or invalid seconds for {}\n", arg),
+                },
+                "--max-qos" => match args.next().map(|qos| qos.parse().map(QoS::from_u8)) {
+                    Some(Ok(Ok(qos))) => config.max_qos = qos,
+                    _ => eprintln!("[-]Missing or invalid QoS for {}, expected 0, 1 or 2\n", arg),
+                },
+                "--bridge" => match (args.next(), args.next()) {
+                    (Some(upstream), Some(filters)) => {
+                        let filters = filters.split(',').map(|filter| filter.to_string()).collect();
+                        config.bridge = Some(BridgeConfig::new(upstream.clone(), filters, BridgeDirection::Out));
+                    }
+                    _ => eprintln!("[-]Missing upstream address or topic filters for {}\n", arg),
+                },
+                "--bridge-direction" => {
+                    let direction = match args.next().map(|direction| direction.as_str()) {
+                        Some("out") => Some(BridgeDirection::Out),
+                        Some("in") => Some(BridgeDirection::In),
+                        Some("both") => Some(BridgeDirection::Both),
+                        _ => None,
+                    };
+                    match (direction, config.bridge.as_mut()) {
+                        (Some(direction), Some(bridge)) => bridge.direction = direction,
+                        (None, _) => eprintln!("[-]Missing or invalid direction for {}, expected in, out or both\n", arg),
+                        (_, None) => eprintln!("[-]{} given before --bridge\n", arg),
+                    }
+                }
+                "--topic-alias-maximum" => match args.next().map(|maximum| maximum.parse()) {
+                    Some(Ok(maximum)) => config.topic_alias_maximum = maximum,
+                    _ => eprintln!("[-]Missing or invalid maximum for {}\n", arg),
+                },
+                "--receive-maximum" => match args.next().map(|maximum| maximum.parse()) {
+                    Some(Ok(maximum)) if maximum > 0 => config.receive_maximum = maximum,
+                    _ => eprintln!("[-]Missing or invalid maximum for {}, expected 1 to 65535\n", arg),
+                },
+                "--max-packet-size" => match args.next().map(|size| size.parse()) {
+                    Some(Ok(size)) => config.max_packet_size = size,
+                    _ => eprintln!("[-]Missing or invalid size for {}\n", arg),
+                },
+                "--disconnect-grace" => match args.next().map(|ms| ms.parse()) {
+                    Some(Ok(ms)) => config.disconnect_grace = Duration::from_millis(ms),
+                    _ => eprintln!("[-]Missing or invalid milliseconds for {}\n", arg),
+                },
+                "--max-connection-duration" => match args.next().map(|secs| secs.parse()) {
+                    Some(Ok(secs)) => config.max_connection_duration = Some(Duration::from_secs(secs)),
+                    _ => eprintln!("[-]Missing or invalid seconds for {}\n", arg),
+                },
+                "--persistence-dir" => match args.next() {
+                    Some(dir) => config.persistence_dir = Some(PathBuf::from(dir)),
+                    None => eprintln!("[-]Missing directory for {}\n", arg),
+                },
+                #[cfg(feature = "websocket")]
+                "--websocket" => match args.next().map(|addr| addr.parse()) {
+                    Some(Ok(addr)) => config.websocket_bind_addr = Some(addr),
+                    _ => eprintln!("[-]Missing or invalid address for {}, expected host:port\n", arg),
+                },
+                #[cfg(feature = "tls")]
+                "--tls" => match (args.next(), args.next()) {
+                    (Some(cert), Some(key)) => config.tls = Some(tls::TlsConfig::new(PathBuf::from(cert), PathBuf::from(key))),
+                    _ => eprintln!("[-]Missing certificate or key file for {}\n", arg),
+                },
+                #[cfg(feature = "tls")]
+                "--tls-bind" => match (args.next().map(|addr| addr.parse()), config.tls.as_mut()) {
+                    (Some(Ok(addr)), Some(tls)) => tls.bind_addr = addr,
+                    (Some(Ok(_)), None) => eprintln!("[-]{} given before --tls\n", arg),
+                    _ => eprintln!("[-]Missing or invalid address for {}, expected host:port\n", arg),
+                },
+                _ => eprintln!("[-]Ignoring unknown argument: {}\n", arg),
+            }
+        }
+        config
+    }
+}
+
+/// A QoS 1 PUBLISH forwarded to a subscriber which is still waiting for its PUBACK
+struct InflightMessage {
+    packet: PublishPacket, // The packet as it was sent to the subscriber
+    sent_at: Instant,      // Last time the packet was written to the subscriber
+}
+
+/// Outbound state kept by the broker for every subscriber connection
+#[derive(Default)]
+struct OutboundState {
+    client_id: String,                         // Client ID sent by the subscriber in its CONNECT
+    next_message_id: u16,                      // Last message ID assigned to a forwarded packet
+    inflight: HashMap<u16, InflightMessage>,   // Forwarded QoS 1 packets waiting for a PUBACK
+    queue: VecDeque<PublishPacket>,            // Packets accepted for the subscriber and not written yet
+    paused: bool,                              // Delivery paused, the packets wait in the queue
+    last_activity: Option<Instant>,            // Last time a packet was received from the client
+    receive_maximum: u16,                      // QoS 1 packets the subscriber accepts in flight at once
+}
+
+impl OutboundState {
+    /// Takes the next queued packet that may be written, None if the delivery is paused
+    /// or the queue is empty. A QoS 1 packet gets its message ID and goes in flight,
+    /// unless the subscriber already has its receive maximum of them: it then waits
+    /// for a PUBACK, along with the packets behind it so the order is kept.
+    fn next_to_send(&mut self) -> Option<PublishPacket> {
+        if self.paused {
+            return None;
+        }
+        let front = self.queue.front()?;
+        if front.qos == QoS::AtLeastOnce && self.inflight.len() >= self.receive_maximum as usize {
+            return None;
+        }
+
+        let mut packet = self.queue.pop_front()?;
+        if packet.qos == QoS::AtLeastOnce {
+            // A packet sent again after a reconnect keeps the message ID it was first sent with
+            let resent = packet.dup && packet.message_id != 0 && !self.inflight.contains_key(&packet.message_id);
+            if !resent {
+                packet.message_id = self.allocate_message_id();
+            }
+            self.inflight.insert(packet.message_id, InflightMessage {
+                packet: packet.clone(),
+                sent_at: Instant::now(),
+            });
+        }
+        Some(packet)
+    }
+
+    /// Puts back first in the queue a packet that could not be written, out of flight
+    fn requeue(&mut self, packet: PublishPacket) {
+        if packet.qos == QoS::AtLeastOnce {
+            self.inflight.remove(&packet.message_id);
+        }
+        self.queue.push_front(packet);
+    }
+
+    /// Returns the next message ID that is not in use, 0 is skipped since it is not a valid ID
+    fn allocate_message_id(&mut self) -> u16 {
+        loop {
+            self.next_message_id = self.next_message_id.wrapping_add(1);
+            if self.next_message_id != 0 && !self.inflight.contains_key(&self.next_message_id) {
+                return self.next_message_id;
+            }
+        }
+    }
+}
+
+/// Outbound state of a subscriber connection and the handle its messages are written to.
+///
+/// The state is only locked for short updates. The writer stays locked for as long as
+/// a write blocks, so a slow subscriber holds up the threads writing to it, never the
+/// ones routing messages to other subscribers or reading its state.
+struct Outbound {
+    state: Mutex<OutboundState>,
+    writer: Mutex<Option<Box<dyn Transport>>>, // None if the connection could not be cloned
+}
+
+impl Outbound {
+    fn new(client_id: &str, receive_maximum: u16, writer: Option<Box<dyn Transport>>) -> Self {
+        Outbound {
+            state: Mutex::new(OutboundState {
+                client_id: client_id.to_string(),
+                receive_maximum,
+                ..Default::default()
+            }),
+            writer: Mutex::new(writer),
+        }
+    }
+
+    /// Queues a PUBLISH for the subscriber without writing it, QoS 1 packets get a message
+    /// ID of the subscriber once written and stay in flight until its PUBACK arrives.
+    /// Returns the QoS the packet is sent with.
+    fn enqueue(&self, mut packet: PublishPacket) -> QoS {
+        packet.dup = false;
+        self.enqueue_stored(packet)
+    }
+
+    /// Same as enqueue for a packet of a stored session, which keeps its DUP flag and
+    /// message ID if it was already sent to the client once
+    fn enqueue_stored(&self, mut packet: PublishPacket) -> QoS {
+        // Topic aliases belong to the connection they were set on
+        packet.topic_alias = None;
+        // The broker does not send QoS 2 yet, those messages are forwarded at QoS 1
+        packet.qos = packet.qos.min(MAX_OUTBOUND_QOS);
+
+        let qos = packet.qos;
+        lock(&self.state).queue.push_back(packet);
+        qos
+    }
+
+    /// Returns the messages the client must still get if its session is kept: the packets
+    /// in flight, which it may have received and are sent again as duplicates, followed by
+    /// the QoS 1 packets never written
+    fn stored_queue(&self) -> Vec<PublishPacket> {
+        let state = lock(&self.state);
+        let mut inflight: Vec<&InflightMessage> = state.inflight.values().collect();
+        inflight.sort_by_key(|message| message.packet.message_id);
+        let mut queue: Vec<PublishPacket> =
+            inflight.into_iter().map(|message| PublishPacket { dup: true, ..message.packet.clone() }).collect();
+        queue.extend(state.queue.iter().filter(|packet| packet.qos != QoS::AtMostOnce).cloned());
+        queue
+    }
+
+    /// Writes the queued packets in order
+    fn flush(&self) {
+        self.flush_with(&mut lock(&self.writer));
+    }
+
+    /// Writes the queued packets in order through the locked writer. A packet that fails
+    /// to be written stays first in the queue so the ones behind it are never sent before
+    /// it, and the connection is closed: its client thread then ends and removes the
+    /// subscriber.
+    fn flush_with(&self, writer: &mut Option<Box<dyn Transport>>) {
+        let writer = match writer.as_mut() {
+            Some(writer) => writer,
+            None => return,
+        };
+        loop {
+            // The writer lock keeps the packets in order, the state is not held during the write
+            let packet = match lock(&self.state).next_to_send() {
+                Some(packet) => packet,
+                None => return,
+            };
+            let data = match packet.encode() {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!("Error encoding PUBLISH packet: {}", e);
+                    continue;
+                }
+            };
+            match writer.write_all(&data) {
+                Ok(_) => debug!("Sent PUBLISH packet to subscriber: {:?}", writer.peer_addr()),
+                Err(e) => {
+                    warn!("Error sending PUBLISH packet, closing the connection: {}", e);
+                    lock(&self.state).requeue(packet);
+                    close_connection(writer.as_ref());
+                    return;
+                }
+            }
+        }
+    }
+}
+
+// Outbound state of every subscriber, identified by its peer address
+type OutboundMap = Arc<Mutex<HashMap<SocketAddr, Arc<Outbound>>>>;
+
+// Connection of every subscribed client, by client ID
+type SubscriberMap = Arc<Mutex<HashMap<String, Box<dyn Transport>>>>;
+
+/// Locks a mutex of the shared state, recovering it if a client thread panicked while
+/// holding it. The state is still usable, so one failing connection does not take the
+/// other ones down with it.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Snapshot of the broker counters
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct BrokerStats {
+    pub dropped_no_subscriber: u64, // Publishes that reached no subscriber
+}
+
+/// The MQTT broker and the state shared by all of its client threads
+#[derive(Clone)]
+pub struct Broker {
+    config: Arc<BrokerConfig>,
+    clients: Arc<Mutex<Vec<Box<dyn Transport>>>>, // Connected clients
+    subscriptions: Arc<Mutex<SubscriptionRegistry>>, // Topic filters of every client
+    subscribers: SubscriberMap, // Connections the messages of the subscriptions are sent to
+    outbound: OutboundMap, // In-flight messages forwarded to each subscriber
+    retained: Arc<Mutex<HashMap<String, PublishPacket>>>, // Last retained message per topic
+    dropped_no_subscriber: Arc<AtomicU64>, // Publishes that reached no subscriber
+    dead_letter_sink: Arc<dyn DeadLetterSink>, // Receives the packets that fail to decode
+    interceptor: Arc<dyn Interceptor>, // Inspects every PUBLISH before it is routed
+    persistence: Option<Arc<dyn Persistence>>, // Storage for the state that survives restarts
+    sessions: Arc<Mutex<SessionStore>>, // Sessions of the clients away, loaded ones included
+    bridge: Option<Arc<Bridge>>, // Connection to the upstream broker of the bridged topics
+    shutdown: Arc<AtomicBool>, // Set once the broker stops accepting connections
+    local_addr: Arc<Mutex<Option<SocketAddr>>>, // Address the listener is bound to, once serving
+}
+
+impl Broker {
+    /// Creates a broker with the given configuration and no connected clients
+    pub fn new(config: BrokerConfig) -> Self {
+        let persistence_dir = config.persistence_dir.clone();
+        let bridge = config.bridge.clone();
+        let mut broker = Broker {
+            config: Arc::new(config),
+            clients: Arc::new(Mutex::new(Vec::new())),
+            subscriptions: Arc::new(Mutex::new(SubscriptionRegistry::new())),
+            subscribers: Arc::new(Mutex::new(HashMap::new())),
+            outbound: Arc::new(Mutex::new(HashMap::new())),
+            retained: Arc::new(Mutex::new(HashMap::new())),
+            dropped_no_subscriber: Arc::new(AtomicU64::new(0)),
+            dead_letter_sink: Arc::new(DiscardDeadLetters),
+            interceptor: Arc::new(PassThrough),
+            persistence: None,
+            sessions: Arc::new(Mutex::new(SessionStore::new())),
+            bridge: None,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            local_addr: Arc::new(Mutex::new(None)),
+        };
+
+        if let Some(dir) = persistence_dir {
+            match FilePersistence::new(&dir) {
+                Ok(persistence) => broker.set_persistence(Arc::new(persistence)),
+                Err(e) => error!("Error opening the persistence directory {:?}: {}", dir, e),
+            }
+        }
+        if let Some(bridge) = bridge {
+            broker.set_bridge(Bridge::tcp(bridge));
+        }
+        broker
+    }
+
+    /// Sets the storage of the broker state and reloads the retained messages and
+    /// session queues saved in it
+    pub fn set_persistence(&mut self, persistence: Arc<dyn Persistence>) {
+        match persistence.load_retained() {
+            Ok(packets) => {
+                let mut retained = lock(&self.retained);
+                for packet in packets {
+                    retained.insert(packet.topic_name.clone(), packet);
+                }
+            }
+            Err(e) => error!("Error loading the retained messages: {}", e),
+        }
+
+        match persistence.load_sessions() {
+            Ok(stored) => {
+                let mut sessions = lock(&self.sessions);
+                for (client_id, session) in stored {
+                    sessions.save(&client_id, session);
+                }
+            }
+            Err(e) => error!("Error loading the sessions: {}", e),
+        }
+
+        self.persistence = Some(persistence);
+    }
+
+    /// Registers the sink that receives every packet rejected by a client thread
+    pub fn set_dead_letter_sink(&mut self, sink: Arc<dyn DeadLetterSink>) {
+        self.dead_letter_sink = sink;
+    }
+
+    /// Registers the interceptor that may transform or drop every PUBLISH before it is routed
+    pub fn set_interceptor(&mut self, interceptor: Arc<dyn Interceptor>) {
+        self.interceptor = interceptor;
+    }
+
+    /// Sets the bridge to an upstream broker, which connects right away if it
+    /// receives messages from the upstream broker
+    pub fn set_bridge(&mut self, bridge: Bridge) {
+        bridge.start(self);
+        self.bridge = Some(Arc::new(bridge));
+    }
+
+    /// Returns the longest keep alive granted to a client, None if clients choose their own
+    pub fn max_keep_alive(&self) -> Option<u16> {
+        self.config.max_keep_alive
+    }
+
+    /// Returns the current value of the broker counters
+    pub fn stats(&self) -> BrokerStats {
+        BrokerStats {
+            dropped_no_subscriber: self.dropped_no_subscriber.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns every topic filter with at least one subscriber and every topic with a
+    /// retained message, sorted
+    pub fn active_topics(&self) -> Vec<String> {
+        let mut topics = lock(&self.subscriptions).filters();
+        topics.extend(lock(&self.retained).keys().cloned());
+
+        topics.sort();
+        topics.dedup();
+        topics
+    }
+
+    /// Binds the server and handles the incoming connections, each one in a new thread,
+    /// until the broker is shut down
+    pub fn run(&self) {
+        // Bind the server to the configured address and port
+        let listener = match TcpListener::bind(self.config.bind_addr) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Error starting the server on {}: {}", self.config.bind_addr, e);
+                return;
+            }
+        };
+
+        #[cfg(feature = "websocket")]
+        if let Some(addr) = self.config.websocket_bind_addr {
+            match TcpListener::bind(addr) {
+                Ok(listener) => {
+                    let broker = self.clone();
+                    thread::spawn(move || broker.serve_websocket(listener));
+                }
+                Err(e) => error!("Error starting the WebSocket server on {}: {}", addr, e),
+            }
+        }
+
+        #[cfg(feature = "tls")]
+        if let Some(ref config) = self.config.tls {
+            let server_config = match tls::load_server_config(&config.cert_path, &config.key_path) {
+                Ok(server_config) => Some(server_config),
+                Err(e) => {
+                    error!("Error loading the TLS certificate and key: {}", e);
+                    None
+                }
+            };
+            match (server_config, TcpListener::bind(config.bind_addr)) {
+                (Some(server_config), Ok(listener)) => {
+                    let broker = self.clone();
+                    thread::spawn(move || broker.serve_tls(listener, server_config));
+                }
+                (None, _) => {}
+                (_, Err(e)) => error!("Error starting the TLS server on {}: {}", config.bind_addr, e),
+            }
+        }
+
+        self.serve(listener);
+    }
+
+    /// Returns the address the broker accepts connections on once it is serving,
+    /// with the port chosen by the system when the configured one is 0
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        *lock(&self.local_addr)
+    }
+
+    /// Handles the incoming connections of the listener until the broker is shut down
+    pub fn serve(&self, listener: TcpListener) {
+        match listener.local_addr() {
+            Ok(addr) => {
+                info!("MQTT server started on {}", addr);
+                *lock(&self.local_addr) = Some(addr);
+            }
+            Err(e) => error!("Error reading the address of the listener: {}", e),
+        }
+
+        self.accept_connections(&listener, |stream| self.accept(stream));
+        info!("Server stopped accepting connections");
+    }
+
+    /// Handles the incoming MQTT over WebSocket connections of the listener until the
+    /// broker is shut down. The upgrade of every connection is answered in its own
+    /// thread, within the time given to a new connection to send its CONNECT.
+    #[cfg(feature = "websocket")]
+    pub fn serve_websocket(&self, listener: TcpListener) {
+        match listener.local_addr() {
+            Ok(addr) => info!("MQTT over WebSocket server started on {}", addr),
+            Err(e) => error!("Error reading the address of the listener: {}", e),
+        }
+
+        self.accept_connections(&listener, |stream| {
+            let broker = self.clone();
+            thread::spawn(move || match websocket::accept(stream, broker.config.connect_timeout) {
+                Ok(stream) => handle_client(stream, broker),
+                Err(e) => warn!("Error in the WebSocket handshake: {}", e),
+            });
+        });
+        info!("Server stopped accepting WebSocket connections");
+    }
+
+    /// Handles the incoming MQTT over TLS connections of the listener until the broker
+    /// is shut down. The handshake of every connection runs in its own thread, within
+    /// the time given to a new connection to send its CONNECT.
+    #[cfg(feature = "tls")]
+    pub fn serve_tls(&self, listener: TcpListener, server_config: Arc<rustls::ServerConfig>) {
+        match listener.local_addr() {
+            Ok(addr) => info!("MQTT over TLS server started on {}", addr),
+            Err(e) => error!("Error reading the address of the listener: {}", e),
+        }
+
+        self.accept_connections(&listener, |stream| {
+            let broker = self.clone();
+            let server_config = Arc::clone(&server_config);
+            thread::spawn(move || match tls::accept(stream, server_config, broker.config.connect_timeout) {
+                Ok(stream) => handle_client(stream, broker),
+                Err(e) => warn!("Error in the TLS handshake: {}", e),
+            });
+        });
+        info!("Server stopped accepting TLS connections");
+    }
+
+    // Passes the connections of the listener on, blocking, until the broker is shut down
+    fn accept_connections(&self, listener: &TcpListener, accept: impl Fn(TcpStream)) {
+        // The listener is polled so the shutdown flag is checked between connections
+        if let Err(e) = listener.set_nonblocking(true) {
+            error!("Error setting the listener non-blocking: {}", e);
+        }
+
+        // Accept incoming connections in a loop
+        while !self.shutdown.load(Ordering::SeqCst)
+        {
+            match listener.accept()
+            {
+                Ok((stream, _)) =>
+                {
+                    debug!("Client connected: {:?}", stream.peer_addr());
+                    if let Err(e) = stream.set_nonblocking(false) {
+                        error!("Error setting the connection blocking: {}", e);
+                    }
+                    accept(stream);
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock =>
+                {
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
+                }
+                Err(e) =>
+                {
+                    warn!("Error accepting connection: {}", e); // Log errors during connection acceptance
+                }
+            }
+        }
+    }
+
+    /// Stops accepting connections, then sends a DISCONNECT with the Server Shutting Down
+    /// reason code to every connected client and closes its connection
+    pub fn shutdown(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+
+        for client in lock(&self.clients).iter_mut() {
+            send_disconnect_packet(client.as_mut(), DisconnectReasonCode::ServerShuttingDown);
+        }
+
+        // A single grace period for every client, without holding the lock the client
+        // threads need to finish
+        thread::sleep(self.config.disconnect_grace);
+        for client in lock(&self.clients).iter() {
+            close_connection(client.as_ref());
+        }
+
+        if let Some(ref bridge) = self.bridge {
+            bridge.close();
+        }
+    }
+
+    /// Serves a new connection in its own thread. Every transport goes through here,
+    /// and since subscribers are stored as Transport objects a publish is routed to
+    /// the subscribers of every transport alike.
+    pub fn accept<S: Transport>(&self, stream: S) {
+        // Handle the client in a separate thread with its own handle to the shared state
+        let broker = self.clone();
+        thread::spawn(move || {
+            handle_client(stream, broker);
+        });
+    }
+
+    /// Saves the retained messages, if persistence is set
+    fn persist_retained(&self) {
+        let persistence = match self.persistence {
+            Some(ref persistence) => persistence,
+            None => return,
+        };
+
+        let retained: Vec<PublishPacket> = lock(&self.retained).values().cloned().collect();
+        if let Err(e) = persistence.save_retained(&retained) {
+            error!("Error saving the retained messages: {}", e);
+        }
+    }
+
+    /// Saves the session of a single client, if persistence is set. A connected client is
+    /// saved with its subscriptions and the messages of its connection, so it gets them
+    /// back if the broker restarts before it leaves; a client away with its stored session.
+    /// A client with neither subscriptions nor messages has its saved session removed.
+    fn persist_session(&self, client_id: &str, outbound: Option<&Outbound>) {
+        let persistence = match self.persistence {
+            Some(ref persistence) => persistence,
+            None => return,
+        };
+
+        let session = match outbound {
+            Some(outbound) => StoredSession {
+                subscriptions: lock(&self.subscriptions).subscriptions_of(client_id),
+                queue: outbound.stored_queue(),
+                expires_at: None,
+            },
+            None => lock(&self.sessions).get(client_id).cloned().unwrap_or_default(),
+        };
+        let result = if session.subscriptions.is_empty() && session.queue.is_empty() {
+            persistence.remove_session(client_id)
+        } else {
+            persistence.save_session(client_id, &session)
+        };
+        if let Err(e) = result {
+            error!("Error saving the session of {}: {}", client_id, e);
+        }
+    }
+
+    /// Creates the outbound state of a connected client and, if it picks up a stored
+    /// session, subscribes it again and delivers the messages queued for it
+    fn resume_session(
+        &self,
+        stream: &mut dyn Transport,
+        peer_addr: &SocketAddr,
+        client_id: &str,
+        receive_maximum: u16,
+        session: Option<StoredSession>,
+    ) {
+        let writer = match stream.box_clone() {
+            Ok(writer) => Some(writer),
+            Err(e) => {
+                warn!("Error cloning the connection for its outbound messages: {}", e);
+                None
+            }
+        };
+        let outbound = Arc::new(Outbound::new(client_id, receive_maximum, writer));
+        lock(&self.outbound).insert(*peer_addr, outbound.clone());
+
+        // Saved even without a session, a Clean Start discarded the one saved before
+        let session = match session {
+            Some(session) => session,
+            None => return self.persist_session(client_id, Some(&outbound)),
+        };
+        if !session.subscriptions.is_empty() {
+            let mut subscriptions = lock(&self.subscriptions);
+            match stream.box_clone() {
+                Ok(subscriber) => {
+                    lock(&self.subscribers).insert(client_id.to_string(), subscriber);
+                }
+                Err(e) => warn!("Error registering the subscriber: {}", e),
+            }
+            for (filter, options) in &session.subscriptions {
+                subscriptions.subscribe_with_options(client_id, filter, *options);
+            }
+        }
+        for packet in session.queue {
+            outbound.enqueue_stored(packet);
+        }
+        outbound.flush();
+        self.persist_session(client_id, Some(&outbound));
+    }
+
+    /// Keeps the subscriptions of a client leaving the connection and the messages it
+    /// did not acknowledge, for its next connection without Clean Start. The session
+    /// expires after the interval in seconds, the largest one or none meaning never.
+    fn store_session(&self, client_id: &str, peer_addr: &SocketAddr, session_expiry: Option<u32>) {
+        let subscriptions = lock(&self.subscriptions).subscriptions_of(client_id);
+        let queue = self.outbound_of(peer_addr).map(|outbound| outbound.stored_queue()).unwrap_or_default();
+        let expires_at = match session_expiry {
+            None | Some(u32::MAX) => None,
+            Some(interval) => Some(Instant::now() + Duration::from_secs(interval as u64)),
+        };
+        lock(&self.sessions).save(client_id, StoredSession { subscriptions, queue, expires_at });
+    }
+
+    /// Returns the outbound state of the connection, None if it is not connected
+    fn outbound_of(&self, peer_addr: &SocketAddr) -> Option<Arc<Outbound>> {
+        lock(&self.outbound).get(peer_addr).cloned()
+    }
+
+    /// Returns the outbound state of every connection, so each one is locked on its own
+    /// without holding the map
+    fn outbound_states(&self) -> Vec<Arc<Outbound>> {
+        lock(&self.outbound).values().cloned().collect()
+    }
+
+    /// Sends a PUBLISH to the subscriber connected from the address
+    ///
+    /// Every packet goes through the outbound queue of the subscriber, which is
+    /// written while its writer is locked, so the subscriber receives the messages
+    /// in the order the broker accepted them whatever their QoS and publisher.
+    fn deliver(&self, subscriber_addr: &SocketAddr, packet: PublishPacket) {
+        let outbound = match self.outbound_of(subscriber_addr) {
+            Some(outbound) => outbound,
+            None => {
+                warn!("Error sending PUBLISH packet: {} is not connected", subscriber_addr);
+                return;
+            }
+        };
+        let qos = outbound.enqueue(packet);
+        outbound.flush();
+
+        if qos == QoS::AtLeastOnce {
+            let client_id = lock(&outbound.state).client_id.clone();
+            self.persist_session(&client_id, Some(&outbound));
+        }
+    }
+
+    /// Writes a packet to the connection after the messages already queued for it, so
+    /// none of them arrives after the packet
+    fn write_after_queue(&self, stream: &mut dyn Transport, peer_addr: &SocketAddr, data: &[u8]) -> io::Result<()> {
+        match self.outbound_of(peer_addr) {
+            Some(outbound) => {
+                let mut writer = lock(&outbound.writer);
+                outbound.flush_with(&mut writer);
+                stream.write_all(data)
+            }
+            None => stream.write_all(data),
+        }
+    }
+
+    /// Publishes a message of the broker itself, which may use the topics starting
+    /// with `$` that are reserved to it
+    pub fn publish(&self, packet: PublishPacket) {
+        self.route(packet, None);
+    }
+
+    /// Returns the last time a packet was received from the client, the most recent
+    /// of its connections, or None if the client is not connected
+    pub fn last_activity(&self, client_id: &str) -> Option<Instant> {
+        self.outbound_states()
+            .iter()
+            .map(|outbound| lock(&outbound.state))
+            .filter(|state| state.client_id == client_id)
+            .filter_map(|state| state.last_activity)
+            .max()
+    }
+
+    // Records the time of the last packet received from a connection
+    fn record_activity(&self, peer_addr: &SocketAddr, time: Instant) {
+        if let Some(outbound) = self.outbound_of(peer_addr) {
+            lock(&outbound.state).last_activity = Some(time);
+        }
+    }
+
+    /// Stops writing messages to the connections of the client, which are kept in
+    /// their outbound queue until the delivery is resumed. Returns false if the
+    /// client is not connected.
+    pub fn pause_delivery(&self, client_id: &str) -> bool {
+        let mut paused = false;
+        for outbound in self.outbound_states() {
+            let mut state = lock(&outbound.state);
+            if state.client_id == client_id {
+                state.paused = true;
+                paused = true;
+            }
+        }
+        paused
+    }
+
+    /// Resumes the delivery to the connections of the client and writes the messages
+    /// queued while it was paused. Returns false if the client is not connected.
+    pub fn resume_delivery(&self, client_id: &str) -> bool {
+        let mut resumed = false;
+        for outbound in self.outbound_states() {
+            let mut state = lock(&outbound.state);
+            if state.client_id != client_id {
+                continue;
+            }
+            state.paused = false;
+            resumed = true;
+            drop(state);
+
+            outbound.flush();
+        }
+        resumed
+    }
+
+    /// Routes the message to the local subscribers and sends it upstream if its topic is bridged
+    fn route(&self, packet: PublishPacket, publisher: Option<&str>) {
+        if let Some(ref bridge) = self.bridge {
+            bridge.forward(&packet, self);
+        }
+        self.route_local(packet, publisher);
+    }
+
+    /// Retains the message if asked and forwards it to the subscribers of its topic. The
+    /// publisher, given by client ID, gets its own message back through its subscriptions
+    /// without the No Local option, or through any of them in loopback mode
+    fn route_local(&self, packet: PublishPacket, publisher: Option<&str>) {
+        // Subscribers already present get the live message with the retain flag cleared,
+        // unless they subscribed with Retain As Published
+        let retain = packet.retain;
+        let mut forwarded = packet.clone();
+        forwarded.retain = false;
+        forwarded.dup = false;
+
+        // Every client gets the message once, at most at the QoS granted to its subscriptions.
+        // The message is queued for the subscribers while the registry is locked, so a client
+        // that unsubscribed meanwhile never gets it, and written once the registry is released,
+        // so a subscriber slow to read never holds up the routing of other messages
+        let mut delivered = 0;
+        let mut targets = Vec::new();
+        let subscriptions = lock(&self.subscriptions);
+        // Stored under the registry lock, so a client subscribing meanwhile gets the message
+        // either live or as a retained message, never both
+        if retain {
+            self.retain_message(&packet);
+        }
+        let subscribers = lock(&self.subscribers);
+        let no_local_publisher = if self.config.loopback { None } else { publisher };
+        for (client_id, options) in subscriptions.matching_from(&packet.topic_name, no_local_publisher) {
+            let outbound = match subscribers.get(&client_id).map(|subscriber| subscriber.peer_addr()) {
+                Some(Ok(addr)) => self.outbound_of(&addr),
+                Some(Err(e)) => {
+                    warn!("Error sending PUBLISH packet: {}", e);
+                    continue;
+                }
+                None => continue,
+            };
+            let outbound = match outbound {
+                Some(outbound) => outbound,
+                None => continue,
+            };
+            let mut packet = forwarded.clone();
+            packet.qos = packet.qos.min(options.qos);
+            packet.retain = options.retain_as_published && retain;
+            let qos = outbound.enqueue(packet);
+            targets.push((client_id, outbound, qos));
+            delivered += 1;
+        }
+        drop(subscribers);
+        drop(subscriptions);
+
+        for (_, outbound, _) in &targets {
+            outbound.flush();
+        }
+        // Clients away with a stored session get the message when they come back
+        let queued = lock(&self.sessions).queue(&forwarded);
+        delivered += queued.len();
+
+        // Only the sessions that got a message to keep are saved again
+        if retain {
+            self.persist_retained();
+        }
+        for (client_id, outbound, qos) in &targets {
+            if *qos == QoS::AtLeastOnce {
+                self.persist_session(client_id, Some(outbound));
+            }
+        }
+        for client_id in &queued {
+            self.persist_session(client_id, None);
+        }
+
+        if delivered > 0 {
+            debug!("Message sent to topic: {}", packet.topic_name);
+        } else {
+            // Counted to detect publishers sending to topics nobody listens to
+            self.dropped_no_subscriber.fetch_add(1, Ordering::Relaxed);
+            debug!("No subscribers for topic: {}", packet.topic_name);
+        }
+    }
+
+    /// Stores the retained message of a topic, an empty payload removes it. The caller
+    /// persists the change once it released the registry.
+    fn retain_message(&self, packet: &PublishPacket) {
+        let mut retained = lock(&self.retained);
+        if packet.payload.is_empty() {
+            retained.remove(&packet.topic_name);
+        } else {
+            retained.insert(packet.topic_name.clone(), packet.clone());
+        }
+    }
+
+    /// Returns the retained messages whose topic matches the topic filter, wildcards
+    /// included, sorted by topic so a subscriber gets them in a stable order
+    fn retained_matching(&self, filter: &str) -> Vec<PublishPacket> {
+        let mut matching: Vec<PublishPacket> = lock(&self.retained)
+            .values()
+            .filter(|retained| topic_matches(filter, &retained.topic_name))
+            .cloned()
+            .collect();
+        matching.sort_by(|a, b| a.topic_name.cmp(&b.topic_name));
+        matching
+    }
+
+    /// Sends a DISCONNECT with the reason code and closes the connection once the client
+    /// had the grace period to read it
+    fn disconnect(&self, stream: &mut dyn Transport, reason_code: DisconnectReasonCode) {
+        send_disconnect_packet(stream, reason_code);
+        thread::sleep(self.config.disconnect_grace);
+        close_connection(stream);
+    }
+
+    /// Logs a packet that could not be decoded and hands it to the dead-letter sink
+    fn reject_packet(&self, raw: &[u8], err: &DecodeError) {
+        warn!("Error decoding packet: {}", err);
+        self.dead_letter_sink.on_rejected(raw, err);
+    }
+
+    /// Rejects a packet that could not be decoded and answers it as `error_response`
+    /// says for the phase of the connection, which the caller then closes
+    fn refuse_packet(&self, stream: &mut dyn Transport, raw: &[u8], err: &DecodeError, phase: ConnectionPhase) {
+        self.reject_packet(raw, err);
+        match error_response(err, phase) {
+            ServerAction::ConnAck(reason_code) => {
+                let connack_packet = ConnAckPacket::builder().reason(reason_code).build();
+                if let Err(e) = stream.write_all(&connack_packet.encode()) {
+                    warn!("Error sending the CONNACK package: {}", e);
+                }
+            }
+            ServerAction::Disconnect(reason_code) => self.disconnect(stream, reason_code),
+        }
+    }
+
+    // Remove a client from the shared client list
+    fn remove_client(&self, peer_addr: &SocketAddr) {
+        let mut clients_guard = lock(&self.clients);
+        if let Some(pos) = clients_guard.iter().position(|x|
+            {
+            match x.peer_addr()
+             {
+                Ok(addr) => addr == *peer_addr,
+                Err(_) => false, // Ignore if peer address retrieval fails
+            }
+        })
+        {
+            clients_guard.remove(pos);
+        }
+        drop(clients_guard);
+
+        self.remove_subscriptions(peer_addr);
+    }
+
+    /// Removes every subscription of the client on the connection. The subscriptions
+    /// of a client ID that connected again on another connection are kept.
+    fn remove_subscriptions(&self, peer_addr: &SocketAddr) {
+        let mut subscriptions = lock(&self.subscriptions);
+        let mut subscribers = lock(&self.subscribers);
+        // A subscriber whose address cannot be read anymore is a closed connection
+        let closed: Vec<String> = subscribers
+            .iter()
+            .filter(|(_, subscriber)| match subscriber.peer_addr() {
+                Ok(addr) => addr == *peer_addr,
+                Err(_) => true,
+            })
+            .map(|(client_id, _)| client_id.clone())
+            .collect();
+
+        for client_id in closed {
+            subscribers.remove(&client_id);
+            subscriptions.remove_client(&client_id);
+        }
+    }
+
+    /// Sends again, with the DUP flag set, every in-flight message of the client whose PUBACK timed out
+    /// and writes the packets left in its queue, such as the ones queued while its writer was busy
+    fn retransmit_expired(&self, stream: &mut dyn Transport, peer_addr: &SocketAddr) {
+        let outbound = match self.outbound_of(peer_addr) {
+            Some(outbound) => outbound,
+            None => return,
+        };
+        let mut writer = lock(&outbound.writer);
+        outbound.flush_with(&mut writer);
+
+        let mut expired = Vec::new();
+        for message in lock(&outbound.state).inflight.values_mut() {
+            if message.sent_at.elapsed() >= RETRANSMIT_TIMEOUT {
+                message.packet.dup = true;
+                message.sent_at = Instant::now();
+                expired.push(message.packet.clone());
+            }
+        }
+        // Written while the writer is locked, so they never cut into a packet of another thread
+        for packet in expired {
+            let data = match packet.encode() {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!("Error encoding PUBLISH packet: {}", e);
+                    continue;
+                }
+            };
+            match stream.write_all(&data) {
+                Ok(_) => debug!("Retransmitted PUBLISH with message ID: {}", packet.message_id),
+                Err(e) => warn!("Error retransmitting PUBLISH packet: {}", e),
+            }
+        }
+    }
+}
+
+fn send_disconnect_packet(stream: &mut dyn Transport, reason_code: DisconnectReasonCode) {
+    let disconnect_packet = DisconnectPacket::new(reason_code);
+
+    let packet = disconnect_packet.encode();
+
+    // Send the Disconnect packet to the server, flushed so none of it is left behind on close
+    match stream.write_all(&packet).and_then(|_| stream.flush()) {
+        Ok(_) => debug!("DISCONNECT packet sent: {:?}", disconnect_packet),
+        Err(e) => warn!("Failed to send DISCONNECT: {}", e),
+    }
+}
+
+// Closes both directions of a connection, which also ends the thread reading it
+fn close_connection(stream: &dyn Transport) {
+    if let Err(e) = stream.close() {
+        warn!("Error closing the connection: {}", e);
+    }
+}
+
+/// Gives the PUBLISH its topic name and clears its topic alias, so it is routed like any other.
+///
+/// A topic alias with a topic name sets the alias, an empty topic name takes the topic of
+/// the alias. An empty topic name without a known alias is a protocol error, as is an
+/// alias outside 1 to the topic alias maximum announced in the CONNACK.
+fn resolve_topic_alias(
+    mut packet: PublishPacket,
+    aliases: &mut HashMap<u16, String>,
+    maximum: u16,
+) -> Result<PublishPacket, DisconnectReasonCode> {
+    match packet.topic_alias.take() {
+        Some(alias) if alias == 0 || alias > maximum => Err(DisconnectReasonCode::TopicAliasInvalid),
+        Some(alias) if packet.topic_name.is_empty() => match aliases.get(&alias) {
+            Some(topic) => {
+                packet.topic_name = topic.clone();
+                Ok(packet)
+            }
+            None => Err(DisconnectReasonCode::ProtocolError),
+        },
+        Some(alias) => {
+            aliases.insert(alias, packet.topic_name.clone());
+            Ok(packet)
+        }
+        None if packet.topic_name.is_empty() => Err(DisconnectReasonCode::ProtocolError),
+        None => Ok(packet),
+    }
+}
+
+/// Serves one client connection until it disconnects, over any transport
+pub fn handle_client<S: Transport>(stream: S, broker: Broker)
+{
+    let mut stream = Traced::new(stream); // Every packet written is dumped at trace level
+    let mut framer = Framer::new(broker.config.max_packet_size as usize); // Splits the incoming bytes into packets
+    let peer_addr = stream.peer_addr().unwrap_or_else(|_| "0.0.0.0:0".parse().unwrap());
+    let mut log_context = LogContext::new(peer_addr); // Starts every record of the connection
+
+    // Add the new client to the list
+    match stream.box_clone() {
+        Ok(client) => lock(&broker.clients).push(client),
+        Err(e) => error!("{}: Error registering the client: {}", log_context, e),
+    }
+
+    // A client that opens the connection but never sends its CONNECT is dropped
+    if let Err(e) = stream.set_read_timeout(Some(broker.config.connect_timeout)) {
+        error!("{}: Error setting the read timeout: {}", log_context, e);
+    }
+
+    // Initial read to check for a CONNECT packet from the client, which
+    // gives the keep alive of the connection if the client is accepted
+    let connected = match framer.read_packet(&mut stream)
+     {
+        Ok(buffer) =>
+        {
+            let size = buffer.len();
+            trace::dump("received from", Some(peer_addr), &buffer);
+            // Decode the received data as a CONNECT packet
+            match ConnectPacket::decode(&buffer[0..size])
+            {
+                Ok(connect_packet) =>
+                 {
+                    log_context.set_client_id(&connect_packet.client_id);
+                    info!("{}: Received CONNECT packet: {:?}", log_context, connect_packet);
+
+                    // Create a CONNACK packet as a response
+                    let mut connack_builder = ConnAckPacket::builder();
+
+                    let reason_code = if let Some(ref redirect) = broker.config.redirect {
+                        // Every client is sent to the server reference of the redirect
+                        let (reason_code, server_reference) = redirect.connack_fields();
+                        connack_builder = connack_builder.server_reference(server_reference);
+                        reason_code
+                    } else if !broker.config.allow_anonymous && connect_packet.username.is_none() {
+                        // Clients without credentials are refused when anonymous access is disabled
+                        ConnAckReasonCode::NotAuthorized
+                    } else if !broker.config.client_id_policy.allows(&connect_packet.client_id) {
+                        ConnAckReasonCode::ClientIdentifierNotValid
+                    } else {
+                        ConnAckReasonCode::Success
+                    };
+
+                    // A client without a keep alive is given the default one, if any
+                    let mut keep_alive = connect_packet.keep_alive;
+                    if reason_code == ConnAckReasonCode::Success && keep_alive == 0 && broker.config.keep_alive_default > 0 {
+                        keep_alive = broker.config.keep_alive_default;
+                        connack_builder = connack_builder.server_keep_alive(keep_alive);
+                    }
+
+                    // A keep alive above the maximum, or none at all, is replaced by the
+                    // maximum, which the client must use once it is in the CONNACK
+                    if let Some(max_keep_alive) = broker.config.max_keep_alive {
+                        if reason_code == ConnAckReasonCode::Success && (keep_alive == 0 || keep_alive > max_keep_alive) {
+                            keep_alive = max_keep_alive;
+                            connack_builder = connack_builder.server_keep_alive(max_keep_alive);
+                        }
+                    }
+
+                    // Clients must not send packets larger than the maximum
+                    if reason_code == ConnAckReasonCode::Success {
+                        connack_builder = connack_builder.maximum_packet_size(broker.config.max_packet_size);
+                    }
+
+                    // Clients may replace the topic names of their PUBLISH packets by aliases
+                    if reason_code == ConnAckReasonCode::Success && broker.config.topic_alias_maximum > 0 {
+                        connack_builder = connack_builder.topic_alias_maximum(broker.config.topic_alias_maximum);
+                    }
+                    if reason_code == ConnAckReasonCode::Success {
+                        connack_builder = connack_builder.receive_maximum(broker.config.receive_maximum);
+                    }
+
+                    // Without the property the client may use any QoS
+                    if reason_code == ConnAckReasonCode::Success && broker.config.max_qos < QoS::ExactlyOnce {
+                        connack_builder = connack_builder.maximum_qos(broker.config.max_qos.to_u8());
+                    }
+
+                    // A Clean Start discards the stored session, otherwise the client gets it back
+                    let clean_start = connect_packet.connect_flags & 0x02 != 0;
+                    let session = if reason_code != ConnAckReasonCode::Success {
+                        None
+                    } else if clean_start {
+                        lock(&broker.sessions).remove(&connect_packet.client_id);
+                        None
+                    } else {
+                        lock(&broker.sessions).take(&connect_packet.client_id)
+                    };
+                    connack_builder = connack_builder.session_present(session.is_some());
+
+                    let connack_packet = connack_builder.reason(reason_code).build();
+
+                    let response = connack_packet.encode(); // Encode the CONNACK packet
+
+                    // Send the CONNACK packet back to the client
+                    match stream.write_all(&response)
+                    {
+                        Ok(_) => info!("{}: Sent CONNACK package: {:?}", log_context, connack_packet),
+                        Err(e) => error!("{}: Error sending the CONNACK package: {}", log_context, e),
+                    }
+
+                    if reason_code != ConnAckReasonCode::Success {
+                        warn!("{}: Connection refused: {:?}", log_context, reason_code);
+                        None
+                    } else {
+                        // Without the property the client accepts as many messages in flight as there are IDs
+                        let receive_maximum = connect_packet.properties.receive_maximum.unwrap_or(u16::MAX);
+                        broker.resume_session(&mut stream, &peer_addr, &connect_packet.client_id, receive_maximum, session);
+                        let session_expiry = connect_packet.properties.session_expiry_interval;
+                        Some((keep_alive, connect_packet.client_id, clean_start, session_expiry))
+                    }
+                }
+                Err(e) =>
+                {
+                    // The client is told why its CONNECT is refused before the connection closes
+                    broker.refuse_packet(&mut stream, &buffer[0..size], &e, ConnectionPhase::Connect);
+                    None
+                }
+            }
+        }
+        Err(FrameError::Closed) =>
+        {
+            info!("{}: Client disconnected", log_context); // Handle empty read (disconnection)
+            None
+        }
+        Err(FrameError::TooLarge(size)) =>
+        {
+            warn!("{}: CONNECT of {} bytes exceeds the maximum packet size", log_context, size);
+            let err = DecodeError::PacketTooLarge(size);
+            broker.refuse_packet(&mut stream, framer.buffered(), &err, ConnectionPhase::Connect);
+            None
+        }
+        Err(FrameError::Malformed(e)) =>
+        {
+            warn!("{}: Malformed fixed header: {}", log_context, e);
+            broker.refuse_packet(&mut stream, framer.buffered(), &e, ConnectionPhase::Connect);
+            None
+        }
+        Err(FrameError::Io(ref e)) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+        {
+            warn!("{}: No CONNECT received within {:?}, closing connection", log_context, broker.config.connect_timeout);
+            None
+        }
+        Err(e) =>
+        {
+            error!("{}: Error reading from stream: {}", log_context, e); // Log reading errors
+            None
+        }
+    };
+
+    // Close the connections that did not complete the CONNECT
+    let (keep_alive, client_id, clean_start, mut session_expiry) = match connected {
+        Some((keep_alive, client_id, clean_start, session_expiry)) => {
+            (Duration::from_secs(keep_alive as u64), client_id, clean_start, session_expiry)
+        }
+        None => {
+            broker.remove_client(&peer_addr);
+            close_connection(&stream);
+            return;
+        }
+    };
+
+    // Time of the last packet received, any packet resets the keep alive timer
+    let connected_at = Instant::now();
+    let mut last_activity = connected_at;
+    broker.record_activity(&peer_addr, last_activity);
+
+    // Packet IDs of the QoS 2 messages received from the client and not released yet,
+    // they survive any other packet the client sends in the middle of the exchange
+    let mut qos2_received: HashSet<u16> = HashSet::new();
+
+    // Topic names the client set for its topic aliases, only valid on this connection
+    let mut topic_aliases: HashMap<u16, String> = HashMap::new();
+
+    // Wake up periodically from the read to retransmit unacknowledged messages
+    if let Err(e) = stream.set_read_timeout(Some(RETRANSMIT_CHECK_INTERVAL)) {
+        error!("{}: Error setting the read timeout: {}", log_context, e);
+    }
+
+    // Enter a loop to continuously read packets from the client
+    loop
+    {
+        broker.retransmit_expired(&mut stream, &peer_addr);
+
+        // The client is gone after one and a half keep alive intervals without any packet,
+        // a keep alive of 0 disables the check
+        if !keep_alive.is_zero() && last_activity.elapsed() > keep_alive * 3 / 2
+        {
+            broker.disconnect(&mut stream, DisconnectReasonCode::KeepAliveTimeout);
+            warn!("{}: No packet received within the keep alive of {:?}. Closing connection.", log_context, keep_alive);
+            break;
+        }
+
+        // Operators cap the life of a connection, for instance to have the credentials checked again
+        if let Some(max_duration) = broker.config.max_connection_duration.filter(|max| connected_at.elapsed() > *max)
+        {
+            broker.disconnect(&mut stream, DisconnectReasonCode::MaximumConnectTime);
+            warn!("{}: Connection open for longer than {:?}. Closing connection.", log_context, max_duration);
+            break;
+        }
+
+        match framer.read_packet(&mut stream)
+        {
+            Ok(buffer) =>
+            {
+                let size = buffer.len();
+                trace::dump("received from", Some(peer_addr), &buffer);
+
+                // Determine the packet type from the fixed header
+                let header = match parse_fixed_header(&buffer[..size])
+                {
+                    Ok(header) => header,
+                    Err(e) =>
+                    {
+                        broker.refuse_packet(&mut stream, &buffer[..size], &e, ConnectionPhase::Session);
+                        break;
+                    }
+                };
+
+                last_activity = Instant::now();
+                broker.record_activity(&peer_addr, last_activity);
+
+                match header.packet_type
+                {
+                    PacketType::Publish =>
+                    {
+                        // PUBLISH packet
+                        match PublishPacket::decode(&buffer[..size])
+                        {
+                            Ok(packet) =>
+                            {
+                                info!("{}: Received PUBLISH packet: {:?}", log_context, packet);
+
+                                // The topic name comes from the alias when the client leaves it empty
+                                let packet = match resolve_topic_alias(packet, &mut topic_aliases, broker.config.topic_alias_maximum) {
+                                    Ok(packet) => packet,
+                                    Err(reason_code) => {
+                                        warn!("{}: Invalid topic of PUBLISH packet: {:?}", log_context, reason_code);
+                                        broker.disconnect(&mut stream, reason_code);
+                                        break;
+                                    }
+                                };
+
+                                // The CONNACK told the client the highest QoS it may publish with
+                                if packet.qos > broker.config.max_qos {
+                                    warn!("{}: PUBLISH with QoS {} above the maximum of the broker", log_context, packet.qos.to_u8());
+                                    broker.disconnect(&mut stream, DisconnectReasonCode::QoSNotSupported);
+                                    break;
+                                }
+
+                                // Clients may not publish to the topics reserved to the broker
+                                let reserved = packet.topic_name.starts_with('$');
+                                let reason_code = if reserved && broker.config.reserved_topic_policy == ReservedTopicPolicy::Reject {
+                                    NOT_AUTHORIZED
+                                } else {
+                                    SUCCESS
+                                };
+
+                                // A QoS 2 message sent again before its PUBREL was already routed
+                                let duplicate = packet.qos == QoS::ExactlyOnce && qos2_received.contains(&packet.message_id);
+                                let message_id = packet.message_id;
+                                let qos = packet.qos;
+
+                                // A new QoS 2 message past the receive maximum announced in the CONNACK
+                                // closes the connection, QoS 1 messages are acknowledged right away
+                                if qos == QoS::ExactlyOnce && !duplicate && qos2_received.len() >= broker.config.receive_maximum as usize {
+                                    broker.disconnect(&mut stream, DisconnectReasonCode::ReceiveMaximumExceeded);
+                                    break;
+                                }
+
+                                /*
+                                The message is routed before it is acknowledged: once the PUBACK or
+                                PUBREC is written, the message is in the outbound queue of every
+                                subscriber and persisted if it is in flight. If routing fails halfway,
+                                a panic in the interceptor for instance, the thread stops before the
+                                acknowledgement and the publisher sends the message again, so a
+                                message is never acknowledged without being routed.
+                                */
+                                if reserved {
+                                    warn!("{}: PUBLISH to reserved topic {} not routed", log_context, packet.topic_name);
+                                } else if duplicate {
+                                    warn!("{}: Duplicate QoS 2 PUBLISH with message ID: {}", log_context, message_id);
+                                } else {
+                                    // The interceptor may transform the message or drop it before routing
+                                    match broker.interceptor.on_publish(packet) {
+                                        Some(packet) => broker.route(packet, Some(&client_id)),
+                                        None => warn!("{}: PUBLISH dropped by the interceptor", log_context),
+                                    }
+                                }
+
+                                if qos == QoS::ExactlyOnce {
+                                    // QoS 2 messages are acknowledged with a PUBREC and kept until the PUBREL,
+                                    // a refused message ends the exchange since there is no PUBREL to wait for
+                                    if reason_code == SUCCESS {
+                                        qos2_received.insert(message_id);
+                                    }
+                                    let pubrec_response = PubRecPacket::with_reason(message_id, reason_code).encode();
+                                    match stream.write_all(&pubrec_response)
+                                    {
+                                        Ok(_) => info!("{}: Sent PUBREC packet for message ID: {}", log_context, message_id),
+                                        Err(e) => error!("{}: Error sending PUBREC packet: {}", log_context, e),
+                                    }
+                                } else {
+                                    // Send PUBACK packet back to the sender
+                                    let puback_packet = PubAckPacket::with_reason(message_id, reason_code);
+                                    let puback_response = puback_packet.encode();
+                                    match stream.write_all(&puback_response)
+                                    {
+                                        Ok(_) => info!("{}: Sent PUBACK packet for message ID: {}", log_context, message_id),
+                                        Err(e) => error!("{}: Error sending PUBACK packet: {}", log_context, e),
+                                    }
+                                }
+                            }
+                            Err(e) =>
+                            {
+                                // A PUBLISH that cannot be decoded closes the connection, with Topic Name
+                                // Invalid for a topic holding wildcards and Malformed Packet for the rest
+                                broker.refuse_packet(&mut stream, &buffer[..size], &e, ConnectionPhase::Session);
+                                break;
+                            }
+                        }
+                    }
+
+                    PacketType::PubRel =>
+                    {
+                        // PUBREL packet, the client releases a QoS 2 message so its ID can be reused
+                        match PubRelPacket::decode(&buffer[..size])
+                        {
+                            Ok(packet) =>
+                            {
+                                if !qos2_received.remove(&packet.packet_id) {
+                                    warn!("{}: PUBREL for unknown message ID: {}", log_context, packet.packet_id);
+                                }
+
+                                let pubcomp_response = PubCompPacket::new(packet.packet_id).encode();
+                                match stream.write_all(&pubcomp_response)
+                                {
+                                    Ok(_) => info!("{}: Sent PUBCOMP packet for message ID: {}", log_context, packet.packet_id),
+                                    Err(e) => error!("{}: Error sending PUBCOMP packet: {}", log_context, e),
+                                }
+                            }
+                            Err(e) =>
+                            {
+                                broker.refuse_packet(&mut stream, &buffer[..size], &e, ConnectionPhase::Session);
+                                break;
+                            }
+                        }
+                    }
+
+                    PacketType::PubAck =>
+                    {
+                        // PUBACK packet from a subscriber for a forwarded PUBLISH
+                        match PubAckPacket::decode(&buffer[..size])
+                        {
+                            Ok(packet) =>
+                            {
+                                let acknowledged = broker
+                                    .outbound_of(&peer_addr)
+                                    .and_then(|outbound| lock(&outbound.state).inflight.remove(&packet.packet_id));
+                                match acknowledged {
+                                    Some(_) => {
+                                        info!("{}: Received PUBACK for message ID: {}", log_context, packet.packet_id);
+                                        // The freed slot of the in-flight window lets the next queued message go
+                                        let outbound = broker.outbound_of(&peer_addr);
+                                        if let Some(ref outbound) = outbound {
+                                            outbound.flush();
+                                        }
+                                        broker.persist_session(&client_id, outbound.as_deref());
+                                    }
+                                    None => warn!("{}: Received PUBACK for unknown message ID: {}", log_context, packet.packet_id),
+                                }
+                            }
+                            Err(e) =>
+                            {
+                                broker.refuse_packet(&mut stream, &buffer[..size], &e, ConnectionPhase::Session);
+                                break;
+                            }
+                        }
+                    }
+
+                    PacketType::Subscribe =>
+                    {
+                        // SUBSCRIBE packet
+                        match SubscribePacket::decode(&buffer[..size])
+                        {
+                            Ok(packet) =>
+                            {
+                                info!("{}: Received SUBSCRIBE packet: {:?}", log_context, packet);
+
+                                // Every filter is granted or refused on its own, a partial failure
+                                // is reported in the SUBACK and never closes the connection
+                                let outcomes: Vec<Result<SubscriptionOptions, u8>> = packet
+                                    .topic_filters
+                                    .iter()
+                                    .zip(packet.options())
+                                    .map(|(topic, options)| {
+                                        let options = options.map_err(|_| UNSPECIFIED_ERROR)?;
+                                        if !is_valid_topic_filter(topic) {
+                                            return Err(TOPIC_FILTER_INVALID);
+                                        }
+                                        if !broker.interceptor.on_subscribe(&client_id, topic) {
+                                            return Err(NOT_AUTHORIZED);
+                                        }
+                                        // A subscription above the maximum QoS is granted the maximum, and
+                                        // never more than the QoS the broker sends messages with
+                                        let qos = options.qos.min(broker.config.max_qos).min(MAX_OUTBOUND_QOS);
+                                        Ok(SubscriptionOptions { qos, ..options })
+                                    })
+                                    .collect();
+
+                                // Grant the QoS of the subscription, ignoring the other option bits, or return the failure code
+                                let return_codes: Vec<u8> = outcomes
+                                    .iter()
+                                    .map(|outcome| match outcome {
+                                        Ok(options) => options.qos.to_u8(),
+                                        Err(code) => *code,
+                                    })
+                                    .collect();
+
+                                // The messages of the subscriptions go to this connection. The registry
+                                // is locked first, like everywhere both are, so routing sees either none
+                                // or all of the changes
+                                let mut subscriptions = lock(&broker.subscriptions);
+                                if outcomes.iter().any(Result::is_ok) {
+                                    match stream.box_clone() {
+                                        Ok(subscriber) => {
+                                            lock(&broker.subscribers).insert(client_id.clone(), subscriber);
+                                        }
+                                        Err(e) => error!("{}: Error registering the subscriber: {}", log_context, e),
+                                    }
+                                }
+
+                                // Subscribing again to a filter replaces its QoS, never adds a second subscription.
+                                // Retained messages are sent with the retain flag set, as the Retain Handling
+                                // option (bits 4-5) of each subscription asks. They are picked while the registry
+                                // is locked, so a message published meanwhile comes either live or retained
+                                let mut retained_messages = Vec::new();
+                                for (topic, outcome) in packet.topic_filters.iter().zip(&outcomes) {
+                                    let options = match outcome {
+                                        Ok(options) => options,
+                                        Err(_) => continue, // Filters refused in the SUBACK are not subscribed
+                                    };
+                                    let is_new = subscriptions.subscribe_with_options(&client_id, topic, *options);
+                                    if is_new {
+                                        info!("{}: Added to topic list: {}", log_context, topic);
+                                    }
+
+                                    let send_retained = match options.retain_handling {
+                                        0 => true,   // Always send the retained messages
+                                        1 => is_new, // Only when the subscription did not exist yet
+                                        _ => false,  // Never send them
+                                    };
+                                    // A shared subscription never gets them
+                                    if send_retained && parse_shared_filter(topic).is_none() {
+                                        retained_messages.extend(broker.retained_matching(topic));
+                                    }
+                                }
+                                drop(subscriptions);
+
+                                // Echo the packet_id from the SUBSCRIBE packet with the computed return codes,
+                                // only once the subscriptions are registered, so a client that has its SUBACK
+                                // gets every message published from then on
+                                let suback_packet = SubAckPacket::new(packet.packet_id, return_codes);
+                                let suback_response = suback_packet.encode();
+
+                                // Send the SUBACK packet back to the client
+                                match stream.write_all(&suback_response)
+                                {
+                                    Ok(_) => info!("{}: Sent SUBACK : {:?}", log_context, suback_response),
+                                    Err(e) => error!("{}: Error sending SUBACK packet: {}", log_context, e),
+                                }
+
+                                for retained in retained_messages {
+                                    broker.deliver(&peer_addr, retained);
+                                }
+                                broker.persist_session(&client_id, broker.outbound_of(&peer_addr).as_deref());
+                            }
+                            Err(e) =>
+                            {
+                                // A SUBSCRIBE breaking a protocol rule, such as one without topic
+                                // filters, or with a null character in a filter closes the connection
+                                broker.refuse_packet(&mut stream, &buffer[..size], &e, ConnectionPhase::Session);
+                                break;
+                            }
+                        }
+                    }
+                    PacketType::Unsubscribe =>
+                    {
+                        match UnsubscribePacket::decode(&buffer[..size])
+                        {
+                            Ok(packet) =>
+                            {
+                                info!("{}: Received UNSUBSCRIBE packet: {:?}", log_context, packet);
+
+                                // All the filters are removed in one critical section, before the
+                                // UNSUBACK, so no message of them is routed to the client after it
+                                let mut subscriptions = lock(&broker.subscriptions);
+                                let reason_codes: Vec<u8> = packet
+                                    .topic_filters
+                                    .iter()
+                                    .map(|topic| {
+                                        if !is_valid_topic_filter(topic) {
+                                            TOPIC_FILTER_INVALID
+                                        } else if subscriptions.unsubscribe(&client_id, topic) {
+                                            info!("{}: Removed from topic list: {}", log_context, topic);
+                                            SUCCESS
+                                        } else {
+                                            NO_SUBSCRIPTION_EXISTED
+                                        }
+                                    })
+                                    .collect();
+                                drop(subscriptions);
+
+                                // The messages routed before the filters were removed may still be
+                                // queued, they are written first
+                                let unsuback_response = UnsubAckPacket::new(packet.packet_id, reason_codes).encode();
+                                match broker.write_after_queue(&mut stream, &peer_addr, &unsuback_response)
+                                {
+                                    Ok(_) => info!("{}: Sent UNSUBACK : {:?}", log_context, unsuback_response),
+                                    Err(e) => error!("{}: Error sending UNSUBACK packet: {}", log_context, e),
+                                }
+                                broker.persist_session(&client_id, broker.outbound_of(&peer_addr).as_deref());
+                            }
+                            Err(e) =>
+                            {
+                                broker.refuse_packet(&mut stream, &buffer[..size], &e, ConnectionPhase::Session);
+                                break;
+                            }
+                        }
+                    }
+                    PacketType::PingReq =>
+                    {
+                        // A PINGREQ is only its fixed header, anything after it is malformed
+                        if let Err(e) = PingReqPacket::decode(&buffer[..size]) {
+                            broker.refuse_packet(&mut stream, &buffer[..size], &e, ConnectionPhase::Session);
+                            break;
+                        }
+
+                        // Valid PINGREQ packet received, the activity is already recorded
+                        // Respond with PINGRESP packet
+                        let pingresp_packet = PingRespPacket; // Create an instance of PingRespPacket
+                        let pingresp_response = pingresp_packet.encode(); // Encode the PINGRESP packet
+                        match stream.write_all(&pingresp_response) {
+                            Ok(_) => {},
+                            Err(e) => error!("{}: Error sending PINGRESP packet: {}", log_context, e),
+                        }
+
+                    }
+
+                    PacketType::Disconnect =>
+                    {
+                        match DisconnectPacket::decode(&buffer[..size]) {
+                            Ok(packet) => {
+                                info!("{}: Received DISCONNECT packet: {:?}", log_context, packet);
+                                // The client may change how long its session is kept, but not give a
+                                // lifetime to a session its CONNECT declared no or a zero interval for
+                                if let Some(interval) = packet.session_expiry_interval() {
+                                    if session_expiry.unwrap_or(0) == 0 && interval != 0 {
+                                        broker.disconnect(&mut stream, DisconnectReasonCode::ProtocolError);
+                                        break;
+                                    }
+                                    session_expiry = Some(interval);
+                                }
+                                break;
+                            }
+                            Err(e) =>
+                            {
+                                broker.refuse_packet(&mut stream, &buffer[..size], &e, ConnectionPhase::Session);
+                                break;
+                            }
+                        }
+                    }
+
+                    packet_type => {
+                        warn!("{}: Unknown or unsupported packet type: {:?}", log_context, packet_type);
+                        // A client that keeps sending packets the broker cannot handle may be closed
+                        if broker.config.unsupported_packet_policy == UnsupportedPacketPolicy::Disconnect {
+                            broker.disconnect(&mut stream, DisconnectReasonCode::ImplementationSpecificError);
+                            break;
+                        }
+                    }
+                }
+
+            }
+            Err(FrameError::Closed) =>
+            {
+                // A zero-byte read is the end of file, the client already closed the connection
+                // so there is nobody left to send a DISCONNECT to
+                info!("{}: Client closed the connection", log_context);
+                break;
+            }
+            Err(FrameError::Io(ref e)) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+            {
+                // No data yet, go back to check the in-flight messages
+                continue;
+            }
+            Err(FrameError::TooLarge(size)) =>
+            {
+                warn!("{}: Packet of {} bytes exceeds the maximum packet size. Closing connection.", log_context, size);
+                let err = DecodeError::PacketTooLarge(size);
+                broker.refuse_packet(&mut stream, framer.buffered(), &err, ConnectionPhase::Session);
+                break;
+            }
+            Err(FrameError::Malformed(e)) =>
+            {
+                // The end of the packet is unknown, so the stream cannot be read any further
+                warn!("{}: Malformed fixed header: {}", log_context, e);
+                broker.refuse_packet(&mut stream, framer.buffered(), &e, ConnectionPhase::Session);
+                break;
+            }
+            Err(e) =>
+            {
+                error!("{}: Error reading from stream: {}", log_context, e); // Log reading errors
+                break;
+            }
+        }
+    }
+
+    // The session outlives the connection for a non-zero session expiry interval or, without
+    // one, when the client did not ask for a Clean Start. Otherwise the messages still waiting
+    // for this client's PUBACK are dropped
+    let keep_session = match session_expiry {
+        Some(interval) => interval > 0,
+        None => !clean_start,
+    };
+    if keep_session {
+        broker.store_session(&client_id, &peer_addr, session_expiry);
+    }
+    lock(&broker.outbound).remove(&peer_addr);
+    broker.persist_session(&client_id, None);
+
+    // Remove the disconnected client from the shared client list
+    broker.remove_client(&peer_addr);
+}
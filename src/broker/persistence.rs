@@ -0,0 +1,201 @@
+//! Storage of the broker state that must survive a restart.
+
+/*
+The broker saves its retained messages, the topic filters every session is
+subscribed to and the QoS 1 messages still in flight for every client whenever
+they change, and loads them back when it starts. Every session has a file of its
+own, so a change to one client rewrites only that file.
+Packets are stored with the output of PublishPacket::encode, so the files
+hold plain MQTT packets which are framed again with their fixed header. A file
+that cannot be read fails with an I/O error, one that holds invalid packets with
+the decode error of the first of them.
+*/
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Cursor, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use crate::error::MqttResult;
+use crate::packets::{
+    fixed_header::parse_fixed_header,
+    publish::PublishPacket,
+    qos::QoS,
+    read_string,
+    subscribe::SubscriptionOptions,
+    DecodeError,
+};
+use super::lock;
+use super::sessions::StoredSession;
+
+// Subdirectory holding the file of every session
+const SESSIONS_DIR: &str = "sessions";
+
+/// Storage backend for the retained messages and the session queues
+pub trait Persistence: Send + Sync {
+    /// Replaces the stored retained messages
+    fn save_retained(&self, retained: &[PublishPacket]) -> MqttResult<()>;
+    /// Returns the stored retained messages
+    fn load_retained(&self) -> MqttResult<Vec<PublishPacket>>;
+    /// Replaces the stored session of a client: its subscriptions and the messages still
+    /// in flight or queued for it. The expiry time is not stored.
+    fn save_session(&self, client_id: &str, session: &StoredSession) -> MqttResult<()>;
+    /// Removes the stored session of a client, if there is one
+    fn remove_session(&self, client_id: &str) -> MqttResult<()>;
+    /// Returns the stored sessions by client ID
+    fn load_sessions(&self) -> MqttResult<HashMap<String, StoredSession>>;
+}
+
+/// Persistence backed by files in a directory.
+///
+/// `retained.mqtt` holds the retained PUBLISH packets one after the other.
+/// `sessions/` holds a file per session, named after the client ID in hexadecimal:
+/// the number of filters, each filter (length-prefixed) and the byte of its
+/// subscription options, followed by the queued packets.
+pub struct FilePersistence {
+    dir: PathBuf,
+    write_lock: Mutex<()>, // Client threads save concurrently through the same temporary files
+}
+
+impl FilePersistence {
+    /// Creates the persistence, the directory is created if it does not exist
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(dir.join(SESSIONS_DIR))?;
+        Ok(FilePersistence {
+            dir,
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    /// Writes a file through a temporary one, so a crash never leaves it half written
+    fn write_file(&self, name: &str, data: &[u8]) -> io::Result<()> {
+        let path = self.dir.join(name);
+        let tmp_path = self.dir.join(format!("{}.tmp", name));
+        let _guard = lock(&self.write_lock);
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, path)
+    }
+
+    /// Reads a file, a missing file means nothing was stored yet
+    fn read_file(&self, name: &str) -> io::Result<Vec<u8>> {
+        match fs::read(self.dir.join(name)) {
+            Ok(data) => Ok(data),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Persistence for FilePersistence {
+    fn save_retained(&self, retained: &[PublishPacket]) -> MqttResult<()> {
+        let mut data = Vec::new();
+        for packet in retained {
+            data.extend(encode_stored(packet)?);
+        }
+        Ok(self.write_file("retained.mqtt", &data)?)
+    }
+
+    fn load_retained(&self) -> MqttResult<Vec<PublishPacket>> {
+        let data = self.read_file("retained.mqtt")?;
+        let mut cursor = Cursor::new(data.as_slice());
+        let mut retained = Vec::new();
+        while (cursor.position() as usize) < data.len() {
+            retained.push(read_packet(&mut cursor)?);
+        }
+        Ok(retained)
+    }
+
+    fn save_session(&self, client_id: &str, session: &StoredSession) -> MqttResult<()> {
+        let mut data = Vec::new();
+        data.write_u16::<BigEndian>(session.subscriptions.len() as u16)?;
+        for (filter, options) in &session.subscriptions {
+            data.write_u16::<BigEndian>(filter.len() as u16)?;
+            data.extend_from_slice(filter.as_bytes());
+            data.push(options.to_byte());
+        }
+        for packet in &session.queue {
+            data.extend(encode_stored(packet)?);
+        }
+        Ok(self.write_file(&session_file(client_id), &data)?)
+    }
+
+    fn remove_session(&self, client_id: &str) -> MqttResult<()> {
+        let _guard = lock(&self.write_lock);
+        match fs::remove_file(self.dir.join(session_file(client_id))) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn load_sessions(&self) -> MqttResult<HashMap<String, StoredSession>> {
+        let mut sessions = HashMap::new();
+        for entry in fs::read_dir(self.dir.join(SESSIONS_DIR))? {
+            let name = entry?.file_name();
+            // Temporary files left by a crash are skipped, as any file not named after a client ID
+            let client_id = match name.to_str().and_then(client_id_of) {
+                Some(client_id) => client_id,
+                None => continue,
+            };
+
+            let data = self.read_file(&session_file(&client_id))?;
+            let mut cursor = Cursor::new(data.as_slice());
+            let mut session = StoredSession::default();
+            let filter_count = cursor.read_u16::<BigEndian>().map_err(DecodeError::from)?;
+            for _ in 0..filter_count {
+                let filter = read_string(&mut cursor)?;
+                let options = SubscriptionOptions::from_byte(cursor.read_u8().map_err(DecodeError::from)?)?;
+                session.subscriptions.push((filter, options));
+            }
+            while (cursor.position() as usize) < data.len() {
+                session.queue.push(read_packet(&mut cursor)?);
+            }
+            sessions.insert(client_id, session);
+        }
+        Ok(sessions)
+    }
+}
+
+/// Returns the path of the file of a session within the directory. The client ID is
+/// written in hexadecimal, as it may hold characters a file name cannot
+fn session_file(client_id: &str) -> String {
+    let hex: String = client_id.bytes().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}/{}.mqtt", SESSIONS_DIR, hex)
+}
+
+/// Returns the client ID a session file is named after, None if the name is not one
+fn client_id_of(file_name: &str) -> Option<String> {
+    let hex = file_name.strip_suffix(".mqtt")?;
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    let bytes: Option<Vec<u8>> =
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect();
+    String::from_utf8(bytes?).ok()
+}
+
+/// Encodes a packet to store. A QoS 1 or 2 message that was never sent has no packet ID
+/// yet, it is stored with 1 in its place and gets a free one when it is sent
+fn encode_stored(packet: &PublishPacket) -> MqttResult<Vec<u8>> {
+    if packet.message_id == 0 && packet.qos != QoS::AtMostOnce {
+        return Ok(PublishPacket { message_id: 1, ..packet.clone() }.encode()?);
+    }
+    Ok(packet.encode()?)
+}
+
+/// Reads the next PUBLISH packet of a file, using its fixed header to find where it ends
+fn read_packet(cursor: &mut Cursor<&[u8]>) -> Result<PublishPacket, DecodeError> {
+    let start = cursor.position() as usize;
+    let data = &cursor.get_ref()[start..];
+    let header = parse_fixed_header(data)?;
+    if header.packet_len() > data.len() {
+        return Err(DecodeError::UnexpectedEof);
+    }
+
+    let packet = PublishPacket::decode(&data[..header.packet_len()])?;
+    cursor.set_position((start + header.packet_len()) as u64);
+    Ok(packet)
+}
@@ -0,0 +1,96 @@
+//! Sessions of the clients that are not connected, kept by client ID.
+
+/*
+A client that connects without Clean Start keeps its session when it leaves: the
+topic filters it was subscribed to and the QoS 1 and QoS 2 messages it did not
+acknowledge. The messages published to those filters while it is away are queued
+in the session too. When the client connects again without Clean Start it gets
+the session back, a connection with Clean Start discards it. A session stored with
+an expiry time is discarded once it passes, the others are kept until the client
+comes back.
+*/
+
+use std::collections::HashMap;
+use std::time::Instant;
+use crate::packets::{publish::PublishPacket, qos::QoS, subscribe::{topic_matches, SubscriptionOptions}};
+
+/// State of a client kept between two of its connections
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct StoredSession {
+    pub subscriptions: Vec<(String, SubscriptionOptions)>, // Topic filters and the options granted for them
+    pub queue: Vec<PublishPacket>,         // Messages to deliver when the client returns, oldest first
+    pub expires_at: Option<Instant>,       // Time the session is discarded at, None if it never expires
+}
+
+impl StoredSession {
+    /// Returns whether the session expired and must not be given back
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| Instant::now() >= expires_at)
+    }
+}
+
+/// Sessions of the clients that are away, by client ID
+#[derive(Debug, Default)]
+pub struct SessionStore {
+    sessions: HashMap<String, StoredSession>,
+}
+
+impl SessionStore {
+    /// Creates a store without sessions
+    pub fn new() -> Self {
+        SessionStore::default()
+    }
+
+    /// Keeps the session of a client that left, replacing the one stored before
+    pub fn save(&mut self, client_id: &str, session: StoredSession) {
+        self.sessions.insert(client_id.to_string(), session);
+    }
+
+    /// Takes the session of a client that connects again, if one is stored and did not expire
+    pub fn take(&mut self, client_id: &str) -> Option<StoredSession> {
+        self.sessions.remove(client_id).filter(|session| !session.is_expired())
+    }
+
+    /// Discards the session of a client, returning whether there was one
+    pub fn remove(&mut self, client_id: &str) -> bool {
+        self.sessions.remove(client_id).is_some()
+    }
+
+    /// Returns the session of a client, if one is stored
+    pub fn get(&self, client_id: &str) -> Option<&StoredSession> {
+        self.sessions.get(client_id)
+    }
+
+    /// Queues a message in every session subscribed to its topic, at the highest QoS
+    /// granted to the matching filters. QoS 0 messages are not kept for clients away.
+    ///
+    /// # Returns
+    ///
+    /// The client IDs of the sessions the message was queued in.
+    pub fn queue(&mut self, packet: &PublishPacket) -> Vec<String> {
+        self.sessions.retain(|_, session| !session.is_expired());
+
+        let mut queued = Vec::new();
+        for (client_id, session) in self.sessions.iter_mut() {
+            let granted = session
+                .subscriptions
+                .iter()
+                .filter(|(filter, _)| topic_matches(filter, &packet.topic_name))
+                .map(|(_, options)| options.qos)
+                .max();
+            let qos = match granted {
+                Some(granted) => packet.qos.min(granted),
+                None => continue,
+            };
+            if qos == QoS::AtMostOnce {
+                continue;
+            }
+
+            let mut queued_packet = packet.clone();
+            queued_packet.qos = qos;
+            session.queue.push(queued_packet);
+            queued.push(client_id.clone());
+        }
+        queued
+    }
+}
@@ -0,0 +1,182 @@
+//! Subscriptions of the clients, kept by client ID.
+
+/*
+A client has at most one subscription per topic filter: subscribing again to the
+same filter replaces the QoS granted before instead of adding a second entry. A
+topic is matched with the MQTT wildcards through a TopicTree holding the same
+subscriptions, so only the filters it can match are looked at, and a client whose
+filters overlap gets the message once, at the highest QoS among the subscriptions
+that match it. The messages a client publishes are left out of its subscriptions
+with the No Local option.
+
+A shared subscription, $share/{share name}/{filter}, makes its client a member of
+the share of that name and filter. Each message matching the filter goes to one
+member of the share only, taking turns in the order of their client IDs, while
+every other subscription still gets it.
+*/
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::packets::{qos::QoS, subscribe::{parse_shared_filter, topic_matches, SubscriptionOptions}};
+use super::TopicTree;
+
+/// Topic filters each client is subscribed to, with the options granted for each of them
+#[derive(Debug, Default)]
+pub struct SubscriptionRegistry {
+    clients: HashMap<String, HashMap<String, SubscriptionOptions>>, // Filters and their options by client ID
+    tree: TopicTree, // The same subscriptions by topic level, to match the published topics
+    shares: HashMap<(String, String), Share>, // Members of the shared subscriptions by share name and filter
+}
+
+/// Clients of a shared subscription, which take turns receiving its messages
+#[derive(Debug, Default)]
+struct Share {
+    members: BTreeMap<String, SubscriptionOptions>, // Options of each member by client ID
+    next: AtomicUsize, // Turn of the next message, counted so that matching needs no mutable access
+}
+
+impl SubscriptionRegistry {
+    /// Creates a registry without subscriptions
+    pub fn new() -> Self {
+        SubscriptionRegistry::default()
+    }
+
+    /// Subscribes the client to the topic filter, replacing the QoS of an existing
+    /// subscription to the same filter.
+    ///
+    /// # Returns
+    ///
+    /// True if the client was not subscribed to the filter yet.
+    pub fn subscribe(&mut self, client_id: &str, filter: &str, qos: QoS) -> bool {
+        self.subscribe_with_options(client_id, filter, SubscriptionOptions { qos, ..Default::default() })
+    }
+
+    /// Same as subscribe, keeping every option of the subscription and not only its QoS
+    pub fn subscribe_with_options(&mut self, client_id: &str, filter: &str, options: SubscriptionOptions) -> bool {
+        match parse_shared_filter(filter) {
+            Some((share_name, filter)) => {
+                let share = self.shares.entry((share_name.to_string(), filter.to_string())).or_default();
+                share.members.insert(client_id.to_string(), options);
+            }
+            None => {
+                self.tree.insert(filter, client_id, options);
+            }
+        }
+        self.clients
+            .entry(client_id.to_string())
+            .or_default()
+            .insert(filter.to_string(), options)
+            .is_none()
+    }
+
+    /// Removes the subscription of the client to the topic filter, returning whether it existed
+    pub fn unsubscribe(&mut self, client_id: &str, filter: &str) -> bool {
+        let filters = match self.clients.get_mut(client_id) {
+            Some(filters) => filters,
+            None => return false,
+        };
+        let removed = filters.remove(filter).is_some();
+        // A client left without subscriptions is dropped so clients that churn do not leak entries
+        if filters.is_empty() {
+            self.clients.remove(client_id);
+        }
+        self.remove_from_index(client_id, filter);
+        removed
+    }
+
+    /// Removes every subscription of the client
+    pub fn remove_client(&mut self, client_id: &str) {
+        for filter in self.clients.remove(client_id).unwrap_or_default().keys() {
+            self.remove_from_index(client_id, filter);
+        }
+    }
+
+    // Removes the subscription from the tree, or the client from the share of a shared one
+    fn remove_from_index(&mut self, client_id: &str, filter: &str) {
+        let (share_name, filter) = match parse_shared_filter(filter) {
+            Some(shared) => shared,
+            None => {
+                self.tree.remove(filter, client_id);
+                return;
+            }
+        };
+        let key = (share_name.to_string(), filter.to_string());
+        if let Some(share) = self.shares.get_mut(&key) {
+            share.members.remove(client_id);
+            if share.members.is_empty() {
+                self.shares.remove(&key);
+            }
+        }
+    }
+
+    /// Returns the clients with a subscription matching the topic, sorted by client ID,
+    /// each once with the highest QoS of its matching subscriptions
+    pub fn matching(&self, topic: &str) -> Vec<(String, QoS)> {
+        self.matching_from(topic, None)
+            .into_iter()
+            .map(|(client_id, options)| (client_id, options.qos))
+            .collect()
+    }
+
+    /// Same as matching for a message published by the given client, whose subscriptions
+    /// with the No Local option do not count. The options of each client are those of
+    /// its matching subscriptions merged: the highest QoS, and Retain As Published if
+    /// any of them asks for it. Every shared subscription matching the topic adds the
+    /// member whose turn it is.
+    pub fn matching_from(&self, topic: &str, publisher: Option<&str>) -> Vec<(String, SubscriptionOptions)> {
+        let shared = self
+            .shares
+            .iter()
+            .filter(|((_, filter), _)| topic_matches(filter, topic))
+            .filter_map(|(_, share)| {
+                let members: Vec<(&String, &SubscriptionOptions)> = share
+                    .members
+                    .iter()
+                    .filter(|(client_id, options)| !(publisher == Some(client_id.as_str()) && options.no_local))
+                    .collect();
+                if members.is_empty() {
+                    return None;
+                }
+                let (client_id, options) = members[share.next.fetch_add(1, Ordering::Relaxed) % members.len()];
+                Some((client_id.as_str(), options))
+            });
+
+        let mut merged: HashMap<&str, SubscriptionOptions> = HashMap::new();
+        for (client_id, &options) in self.tree.matching(topic).chain(shared) {
+            if publisher == Some(client_id) && options.no_local {
+                continue;
+            }
+            merged
+                .entry(client_id)
+                .and_modify(|merged| {
+                    merged.qos = merged.qos.max(options.qos);
+                    merged.retain_as_published |= options.retain_as_published;
+                })
+                .or_insert(options);
+        }
+
+        let mut matches: Vec<(String, SubscriptionOptions)> =
+            merged.into_iter().map(|(client_id, options)| (client_id.to_string(), options)).collect();
+        matches.sort_by(|a, b| a.0.cmp(&b.0));
+        matches
+    }
+
+    /// Returns the topic filters of the client with their options, sorted by filter
+    pub fn subscriptions_of(&self, client_id: &str) -> Vec<(String, SubscriptionOptions)> {
+        let mut subscriptions: Vec<(String, SubscriptionOptions)> = self
+            .clients
+            .get(client_id)
+            .map(|filters| filters.iter().map(|(filter, &options)| (filter.clone(), options)).collect())
+            .unwrap_or_default();
+        subscriptions.sort_by(|a, b| a.0.cmp(&b.0));
+        subscriptions
+    }
+
+    /// Returns every topic filter with at least one subscriber, sorted and without duplicates
+    pub fn filters(&self) -> Vec<String> {
+        let mut filters: Vec<String> = self.clients.values().flat_map(|filters| filters.keys().cloned()).collect();
+        filters.sort();
+        filters.dedup();
+        filters
+    }
+}
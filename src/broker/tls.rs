@@ -0,0 +1,166 @@
+//! MQTT over TLS, so the credentials of the CONNECT and the messages are encrypted.
+
+/*
+The TLS session of a connection is shared by the handle that reads and the handles
+of box_clone the broker writes to, behind a lock. Holding it through a blocking read
+would stop the writers until the client sends something, so a read only takes it
+to pick up the plaintext already decrypted and to decrypt the bytes a peek at the
+socket found waiting. Waiting for the client happens on the socket, without the
+lock, and within its read timeout like for a plain TCP connection.
+*/
+
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, ServerConnection};
+
+use super::Transport;
+
+/// Certificate and key of the TLS listener, and the address it listens on
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsConfig {
+    pub bind_addr: SocketAddr, // Address and port of the TLS clients
+    pub cert_path: PathBuf,    // PEM file with the certificate chain, the broker's certificate first
+    pub key_path: PathBuf,     // PEM file with the private key of the certificate
+}
+
+impl TlsConfig {
+    /// Creates the configuration of a listener on the port registered for MQTT over TLS
+    pub fn new(cert_path: PathBuf, key_path: PathBuf) -> Self {
+        TlsConfig { bind_addr: SocketAddr::from(([0, 0, 0, 0], 8883)), cert_path, key_path }
+    }
+}
+
+/// Reads the certificate chain and the private key from their PEM files.
+///
+/// # Returns
+///
+/// The configuration of the TLS sessions, or an error if a file cannot be read or
+/// does not hold a certificate or key rustls accepts.
+pub fn load_server_config(cert_path: &Path, key_path: &Path) -> io::Result<Arc<ServerConfig>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?)).collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, format!("no private key in {}", key_path.display())))?;
+    server_config(certs, key)
+}
+
+/// Builds the configuration of the TLS sessions from a certificate chain and its key,
+/// without client certificates
+pub fn server_config(certs: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> io::Result<Arc<ServerConfig>> {
+    let config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_data)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(invalid_data)?;
+    Ok(Arc::new(config))
+}
+
+fn invalid_data(e: rustls::Error) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+// TLS session with the socket it runs over
+struct Session {
+    connection: ServerConnection,
+    socket: TcpStream,
+}
+
+/// Connection of a TLS client, read and written as the plaintext of its MQTT packets
+pub struct TlsStream {
+    session: Arc<Mutex<Session>>, // Shared by every handle to the connection
+    socket: TcpStream,            // Waited on for the next bytes of the client, without the lock
+}
+
+/// Runs the TLS handshake of a new connection.
+///
+/// # Returns
+///
+/// The connection once the handshake is done, or an error if it fails or the client
+/// does not finish it in time.
+pub fn accept(socket: TcpStream, config: Arc<ServerConfig>, timeout: Duration) -> io::Result<TlsStream> {
+    // A client that stops halfway through the handshake does not hold the thread forever
+    socket.set_read_timeout(Some(timeout))?;
+    let mut session = Session { connection: ServerConnection::new(config).map_err(invalid_data)?, socket };
+    while session.connection.is_handshaking() {
+        session.connection.complete_io(&mut session.socket)?;
+    }
+
+    let socket = session.socket.try_clone()?;
+    Ok(TlsStream { session: Arc::new(Mutex::new(session)), socket })
+}
+
+impl TlsStream {
+    fn lock(&self) -> MutexGuard<'_, Session> {
+        self.session.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let mut session = self.lock();
+                match session.connection.reader().read(buf) {
+                    Ok(size) => return Ok(size),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                    // The client closed the socket without a close_notify
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(0),
+                    Err(e) => return Err(e),
+                }
+            }
+
+            // Fails with the read timeout of the socket while the client is silent
+            self.socket.peek(&mut [0; 1])?;
+
+            let mut session = self.lock();
+            let Session { connection, socket } = &mut *session;
+            connection.read_tls(socket)?;
+            let state = connection.process_new_packets();
+            // Alerts and key updates are answered right away
+            while connection.wants_write() {
+                connection.write_tls(socket)?;
+            }
+            state.map_err(invalid_data)?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = self.lock();
+        let Session { connection, socket } = &mut *session;
+        connection.writer().write_all(buf)?;
+        while connection.wants_write() {
+            connection.write_tls(socket)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+impl Transport for TlsStream {
+    fn box_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(TlsStream { session: Arc::clone(&self.session), socket: self.socket.try_clone()? }))
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.peer_addr()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    fn close(&self) -> io::Result<()> {
+        self.socket.shutdown(Shutdown::Both)
+    }
+}
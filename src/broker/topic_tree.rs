@@ -0,0 +1,145 @@
+//! Subscriptions kept in a trie of topic levels, so a topic is matched without
+//! testing every filter.
+
+/*
+Each node of the tree is a level of the topic filters, and holds the clients
+subscribed to the filter that ends there. Matching a topic walks its levels from
+the root and follows, at every node, the child named after the level, the + child
+and the # child, whose subscribers match whatever remains of the topic. A filter
+that ends in # also matches its parent level, so sensors/# matches sensors. The
+topics starting with $ are only matched by filters starting with the same level.
+*/
+
+use std::collections::{hash_map, HashMap};
+use crate::packets::subscribe::SubscriptionOptions;
+
+/// Level of the tree, with the subscribers of the filter that ends at it
+#[derive(Debug, Default)]
+struct Node {
+    children: HashMap<String, Node>,                  // Next levels of the filters, wildcards included
+    subscribers: HashMap<String, SubscriptionOptions>, // Options of the subscription by client ID
+}
+
+impl Node {
+    fn is_empty(&self) -> bool {
+        self.children.is_empty() && self.subscribers.is_empty()
+    }
+}
+
+/// Topic filters of the clients, arranged by level for matching
+#[derive(Debug, Default)]
+pub struct TopicTree {
+    root: Node,
+}
+
+impl TopicTree {
+    /// Creates a tree without subscriptions
+    pub fn new() -> Self {
+        TopicTree::default()
+    }
+
+    /// Subscribes the client to the topic filter, replacing the options of an existing
+    /// subscription to the same filter.
+    ///
+    /// # Returns
+    ///
+    /// True if the client was not subscribed to the filter yet.
+    pub fn insert(&mut self, filter: &str, client_id: &str, options: SubscriptionOptions) -> bool {
+        let mut node = &mut self.root;
+        for level in filter.split('/') {
+            node = node.children.entry(level.to_string()).or_default();
+        }
+        node.subscribers.insert(client_id.to_string(), options).is_none()
+    }
+
+    /// Removes the subscription of the client to the topic filter, returning whether it existed
+    pub fn remove(&mut self, filter: &str, client_id: &str) -> bool {
+        let levels: Vec<&str> = filter.split('/').collect();
+        remove_from(&mut self.root, &levels, client_id)
+    }
+
+    /// Returns whether no client is subscribed to anything
+    pub fn is_empty(&self) -> bool {
+        self.root.is_empty()
+    }
+
+    /// Returns the subscriptions whose filter matches the topic, as the client ID and
+    /// the options of each. A client subscribed to several matching filters comes
+    /// once for each of them.
+    pub fn matching<'a>(&'a self, topic: &'a str) -> Matching<'a> {
+        Matching {
+            levels: topic.split('/').collect(),
+            system_topic: topic.starts_with('$'),
+            stack: vec![Visit::Descend(&self.root, 0)],
+            subscribers: None,
+        }
+    }
+}
+
+// Removes the subscription under the remaining levels, pruning the nodes it leaves empty
+fn remove_from(node: &mut Node, levels: &[&str], client_id: &str) -> bool {
+    let (level, rest) = match levels.split_first() {
+        Some(split) => split,
+        None => return node.subscribers.remove(client_id).is_some(),
+    };
+    let child = match node.children.get_mut(*level) {
+        Some(child) => child,
+        None => return false,
+    };
+    let removed = remove_from(child, rest, client_id);
+    // Filters that come and go do not leave their levels behind
+    if child.is_empty() {
+        node.children.remove(*level);
+    }
+    removed
+}
+
+// Work left to the traversal of the tree
+enum Visit<'a> {
+    Descend(&'a Node, usize), // Node reached after this many levels of the topic
+    Yield(&'a Node),          // Node of a # whose subscribers all match
+}
+
+/// Iterator over the subscriptions matching a topic, walking only the branches of
+/// the tree the topic can reach
+pub struct Matching<'a> {
+    levels: Vec<&'a str>, // Levels of the topic
+    system_topic: bool,   // Whether the topic starts with $, which the wildcards at the root do not match
+    stack: Vec<Visit<'a>>,
+    subscribers: Option<hash_map::Iter<'a, String, SubscriptionOptions>>, // Subscribers of the node being yielded
+}
+
+impl<'a> Iterator for Matching<'a> {
+    type Item = (&'a str, &'a SubscriptionOptions);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((client_id, options)) = self.subscribers.as_mut().and_then(|subscribers| subscribers.next()) {
+                return Some((client_id.as_str(), options));
+            }
+
+            match self.stack.pop()? {
+                Visit::Yield(node) => self.subscribers = Some(node.subscribers.iter()),
+                Visit::Descend(node, depth) => {
+                    let wildcards = !(depth == 0 && self.system_topic);
+                    if let Some(multi_level) = node.children.get("#").filter(|_| wildcards) {
+                        self.stack.push(Visit::Yield(multi_level));
+                    }
+
+                    // Every level of the topic is matched, the filters ending here match it
+                    if depth == self.levels.len() {
+                        self.subscribers = Some(node.subscribers.iter());
+                        continue;
+                    }
+
+                    if let Some(single_level) = node.children.get("+").filter(|_| wildcards) {
+                        self.stack.push(Visit::Descend(single_level, depth + 1));
+                    }
+                    if let Some(child) = node.children.get(self.levels[depth]) {
+                        self.stack.push(Visit::Descend(child, depth + 1));
+                    }
+                }
+            }
+        }
+    }
+}
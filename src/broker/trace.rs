@@ -0,0 +1,87 @@
+//! Hex dumps of the packets exchanged with the clients, logged at trace level.
+
+/*
+Every packet read from a client and every packet written to it is logged with its
+direction, the address of the peer and its packet type, followed by its bytes in
+hex. The dump is only formatted when a logger has trace enabled for this module,
+otherwise the cost is a check of the log level. The written packets are caught by
+wrapping the connection in a Traced transport, since the broker writes every
+packet with a single write_all.
+*/
+
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+use log::{log_enabled, trace, Level};
+use crate::packets::fixed_header::parse_fixed_header;
+use super::Transport;
+
+/// Logs the bytes of a packet read from or written to the peer
+pub(crate) fn dump(direction: &str, peer_addr: Option<SocketAddr>, packet: &[u8]) {
+    if !log_enabled!(Level::Trace) {
+        return;
+    }
+
+    let packet_type = match parse_fixed_header(packet) {
+        Ok(header) => format!("{:?}", header.packet_type),
+        Err(_) => "Unknown".to_string(),
+    };
+    let mut hex = String::with_capacity(packet.len() * 3);
+    for byte in packet {
+        let _ = write!(hex, "{:02x} ", byte);
+    }
+    let peer = peer_addr.map_or_else(|| "unknown peer".to_string(), |addr| addr.to_string());
+    trace!("{} {} {} ({} bytes): {}", direction, peer, packet_type, packet.len(), hex.trim_end());
+}
+
+/// Connection that logs every packet written to it
+pub(crate) struct Traced<S> {
+    inner: S,
+}
+
+impl<S: Transport> Traced<S> {
+    pub(crate) fn new(inner: S) -> Self {
+        Traced { inner }
+    }
+}
+
+impl<S: Transport> Read for Traced<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Transport> Write for Traced<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    // Every packet is written whole with write_all, so each call is one packet
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        dump("sent to", self.inner.peer_addr().ok(), buf);
+        self.inner.write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: Transport> Transport for Traced<S> {
+    fn box_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(Traced { inner: self.inner.box_clone()? }))
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn close(&self) -> io::Result<()> {
+        self.inner.close()
+    }
+}
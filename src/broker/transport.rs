@@ -0,0 +1,195 @@
+//! Connections the broker can serve clients over.
+
+/*
+The client threads only need to read and write bytes, clone the connection to
+register it as a subscriber, know the address of the peer, wake up from a
+blocking read periodically and be closed when the broker shuts down. The Transport trait captures exactly that, so the
+same handle_client serves TCP sockets and the in-memory DuplexStream used to
+drive the broker without real sockets.
+*/
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::time::Duration;
+
+/// A bidirectional byte stream connecting the broker with one client
+pub trait Transport: Read + Write + Send + 'static {
+    /// Returns a new handle to the same connection, used to register subscribers
+    fn box_clone(&self) -> io::Result<Box<dyn Transport>>;
+
+    /// Address of the client, which identifies its connection in the broker
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Sets how long a read blocks before failing with `WouldBlock` or `TimedOut`
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Closes both directions of the connection, for every handle to it
+    fn close(&self) -> io::Result<()>;
+}
+
+impl Transport for TcpStream {
+    fn box_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn close(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+// A boxed connection is a connection too, so wrappers can hold the handles of box_clone
+impl Transport for Box<dyn Transport> {
+    fn box_clone(&self) -> io::Result<Box<dyn Transport>> {
+        (**self).box_clone()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        (**self).peer_addr()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+
+    fn close(&self) -> io::Result<()> {
+        (**self).close()
+    }
+}
+
+#[cfg(feature = "testing")]
+pub use duplex::DuplexStream;
+
+#[cfg(feature = "testing")]
+mod duplex {
+    use super::Transport;
+    use std::collections::VecDeque;
+    use std::io::{self, ErrorKind, Read, Write};
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicU16, Ordering};
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::time::Duration;
+
+    // Ports given to the ends of the pairs, so every end has its own peer address
+    static NEXT_PORT: AtomicU16 = AtomicU16::new(1);
+
+    /// Bytes travelling in one direction of a DuplexStream
+    #[derive(Default)]
+    struct Pipe {
+        data: Mutex<(VecDeque<u8>, bool)>, // Bytes not read yet and whether the pipe is closed
+        ready: Condvar,                    // Signalled when bytes arrive or the pipe closes
+    }
+
+    /// One end of an in-memory connection, what is written to it is read from the other end.
+    ///
+    /// Clones share the same pipes and read timeout, like the handles returned by
+    /// `TcpStream::try_clone`. Reads block until data arrives, the read timeout
+    /// expires (`WouldBlock`) or the connection is shut down (end of file).
+    #[derive(Clone)]
+    pub struct DuplexStream {
+        incoming: Arc<Pipe>,
+        outgoing: Arc<Pipe>,
+        peer_addr: SocketAddr,
+        read_timeout: Arc<Mutex<Option<Duration>>>,
+    }
+
+    impl DuplexStream {
+        /// Creates the two connected ends, each one reports the other as its peer
+        pub fn pair() -> (DuplexStream, DuplexStream) {
+            let first_port = NEXT_PORT.fetch_add(2, Ordering::Relaxed);
+            let a_to_b = Arc::new(Pipe::default());
+            let b_to_a = Arc::new(Pipe::default());
+
+            let a = DuplexStream {
+                incoming: Arc::clone(&b_to_a),
+                outgoing: Arc::clone(&a_to_b),
+                peer_addr: SocketAddr::from(([127, 0, 0, 1], first_port + 1)),
+                read_timeout: Arc::new(Mutex::new(None)),
+            };
+            let b = DuplexStream {
+                incoming: a_to_b,
+                outgoing: b_to_a,
+                peer_addr: SocketAddr::from(([127, 0, 0, 1], first_port)),
+                read_timeout: Arc::new(Mutex::new(None)),
+            };
+            (a, b)
+        }
+
+        /// Closes both directions, pending bytes can still be read before the end of file
+        pub fn shutdown(&self) {
+            for pipe in [&self.incoming, &self.outgoing] {
+                pipe.data.lock().unwrap().1 = true;
+                pipe.ready.notify_all();
+            }
+        }
+    }
+
+    impl Read for DuplexStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let timeout = *self.read_timeout.lock().unwrap();
+            let mut data = self.incoming.data.lock().unwrap();
+
+            while data.0.is_empty() && !data.1 {
+                data = match timeout {
+                    Some(timeout) => {
+                        let (data, result) = self.incoming.ready.wait_timeout(data, timeout).unwrap();
+                        if result.timed_out() && data.0.is_empty() && !data.1 {
+                            return Err(io::Error::new(ErrorKind::WouldBlock, "read timed out"));
+                        }
+                        data
+                    }
+                    None => self.incoming.ready.wait(data).unwrap(),
+                };
+            }
+
+            let size = buf.len().min(data.0.len());
+            for (byte, value) in buf.iter_mut().zip(data.0.drain(..size)) {
+                *byte = value;
+            }
+            Ok(size)
+        }
+    }
+
+    impl Write for DuplexStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut data = self.outgoing.data.lock().unwrap();
+            if data.1 {
+                return Err(io::Error::new(ErrorKind::BrokenPipe, "connection shut down"));
+            }
+            data.0.extend(buf);
+            self.outgoing.ready.notify_all();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for DuplexStream {
+        fn box_clone(&self) -> io::Result<Box<dyn Transport>> {
+            Ok(Box::new(self.clone()))
+        }
+
+        fn peer_addr(&self) -> io::Result<SocketAddr> {
+            Ok(self.peer_addr)
+        }
+
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            *self.read_timeout.lock().unwrap() = timeout;
+            Ok(())
+        }
+
+        fn close(&self) -> io::Result<()> {
+            DuplexStream::shutdown(self);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! MQTT over WebSocket, for the clients that cannot open a TCP connection such as
+//! the browsers.
+
+/*
+A client upgrades its HTTP connection with the mqtt subprotocol and then sends the
+MQTT packets in binary frames, one packet spread over several frames or several
+packets in one frame alike. WebSocketStream turns those frames back into a byte
+stream, so handle_client reads it with the same Framer as a TCP socket, and every
+write of the broker becomes one binary frame.
+
+The handle that reads owns the WebSocket state, while the handles of box_clone,
+which the broker only writes to, format their frames themselves. A lock around the
+socket writes keeps the frames of the handles, and the control frames the reading
+handle answers with, from interleaving.
+*/
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderValue, StatusCode};
+use tungstenite::protocol::frame::coding::{Data, OpCode};
+use tungstenite::protocol::frame::Frame;
+use tungstenite::{Error, Message, WebSocket};
+
+use super::Transport;
+
+/// Subprotocol the clients must ask for in their upgrade request
+pub const SUBPROTOCOL: &str = "mqtt";
+
+// Socket shared by every handle, each write sent whole under the lock
+#[derive(Debug)]
+struct LockedSocket {
+    stream: TcpStream,
+    write_lock: Arc<Mutex<()>>,
+}
+
+impl LockedSocket {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(LockedSocket { stream: self.stream.try_clone()?, write_lock: Arc::clone(&self.write_lock) })
+    }
+}
+
+impl Read for LockedSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for LockedSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let _guard = self.write_lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        self.stream.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Connection of a WebSocket client, read and written as the bytes of its MQTT packets
+pub struct WebSocketStream {
+    socket: LockedSocket,                    // Written by every handle
+    reader: Option<WebSocket<LockedSocket>>, // Only on the handle returned by accept
+    pending: Vec<u8>,                        // Bytes of a frame not read yet
+}
+
+/// Answers the upgrade request of a client, which must ask for the mqtt subprotocol.
+///
+/// # Returns
+///
+/// The connection once the handshake is done, or an error if the request is not a
+/// WebSocket upgrade for MQTT or the client does not finish it in time.
+pub fn accept(stream: TcpStream, timeout: Duration) -> io::Result<WebSocketStream> {
+    // A client that stops halfway through its request does not hold the thread forever
+    stream.set_read_timeout(Some(timeout))?;
+    let socket = LockedSocket { stream, write_lock: Arc::new(Mutex::new(())) };
+    let writer = socket.try_clone()?;
+
+    let reader = tungstenite::accept_hdr(socket, negotiate_subprotocol)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+    Ok(WebSocketStream { socket: writer, reader: Some(reader), pending: Vec::new() })
+}
+
+// Agrees on the mqtt subprotocol, refusing the clients that do not offer it. The
+// error type is the one tungstenite expects of a handshake callback.
+#[allow(clippy::result_large_err)]
+fn negotiate_subprotocol(request: &Request, mut response: Response) -> Result<Response, ErrorResponse> {
+    let offered = request
+        .headers()
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|protocol| protocol.trim() == SUBPROTOCOL);
+
+    if !offered {
+        let mut refusal = ErrorResponse::new(Some(format!("the {} subprotocol is required", SUBPROTOCOL)));
+        *refusal.status_mut() = StatusCode::BAD_REQUEST;
+        return Err(refusal);
+    }
+    response.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(SUBPROTOCOL));
+    Ok(response)
+}
+
+impl Read for WebSocketStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let reader = self
+            .reader
+            .as_mut()
+            .ok_or_else(|| io::Error::new(ErrorKind::Unsupported, "only the accepted handle reads"))?;
+
+        while self.pending.is_empty() {
+            match reader.read() {
+                Ok(Message::Binary(data)) => self.pending = data,
+                // MQTT is only carried in binary frames
+                Ok(Message::Text(_)) => return Err(io::Error::new(ErrorKind::InvalidData, "text frame from an MQTT client")),
+                Ok(Message::Close(_)) | Err(Error::ConnectionClosed) | Err(Error::AlreadyClosed) => return Ok(0),
+                // Pings are answered by the WebSocket itself
+                Ok(_) => {}
+                Err(Error::Io(e)) => return Err(e),
+                Err(e) => return Err(io::Error::new(ErrorKind::InvalidData, e.to_string())),
+            }
+        }
+
+        let size = buf.len().min(self.pending.len());
+        buf[..size].copy_from_slice(&self.pending[..size]);
+        self.pending.drain(..size);
+        Ok(size)
+    }
+}
+
+impl Write for WebSocketStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Frames of a server are not masked
+        let mut frame = Vec::with_capacity(buf.len() + 10);
+        Frame::message(buf.to_vec(), OpCode::Data(Data::Binary), true)
+            .format(&mut frame)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        self.socket.write_all(&frame)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+impl Transport for WebSocketStream {
+    fn box_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(WebSocketStream { socket: self.socket.try_clone()?, reader: None, pending: Vec::new() }))
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.stream.peer_addr()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.stream.set_read_timeout(timeout)
+    }
+
+    fn close(&self) -> io::Result<()> {
+        self.socket.stream.shutdown(Shutdown::Both)
+    }
+}
@@ -0,0 +1,834 @@
+//! MQTT client: the connection to a broker and the state kept for the messages it publishes.
+
+/*
+A QoS 1 PUBLISH is in flight from the moment it is written until the broker
+answers it with a PUBACK carrying the same packet ID. A message whose PUBACK does
+not arrive in time is sent again with the DUP flag set, under the same packet ID,
+so the broker can tell it is a retransmission. The tracker only keeps the state,
+writing the packets is left to the client, which decides the timeout and how many
+times a message is retried.
+
+An MqttClient owns the connection. Once the CONNECT is answered, a listener thread
+reads every packet of the broker: it matches the PUBACKs and SUBACKs with the
+publishes and subscribes waiting for them, acknowledges the messages of the
+subscriptions, sends the PINGREQs of the keep alive and retransmits the publishes
+whose PUBACK is late. Every packet is written while the write half of the
+connection is locked, so the packets of the listener and of the caller never
+interleave. Once the listener stops, the operations still waiting for an
+acknowledgement fail with ConnectionLost.
+
+The messages of the subscriptions reach the application through the receiver of
+take_events, followed by a ConnectionLost event if the connection ends without the
+client disconnecting. The channel is only created once the receiver is taken, so
+the events of an application that never reads them do not pile up.
+*/
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{debug, warn};
+use crate::error::MqttResult;
+use crate::packets::{
+    Encode,
+    fixed_header::{parse_fixed_header, PacketType},
+    framer::{FrameError, Framer, PROTOCOL_MAXIMUM_PACKET_SIZE},
+    connect::{ConnectPacket, WillProperties},
+    connack::{ConnAckPacket, ConnAckReasonCode},
+    publish::PublishPacket,
+    puback::PubAckPacket,
+    qos::QoS,
+    subscribe::{is_valid_topic_filter, is_valid_topic_name, SubscribePacket, SubscriptionOptions},
+    suback::SubAckPacket,
+    ping::PingReqPacket,
+    disconnect::{DisconnectPacket, DisconnectReasonCode},
+};
+
+/// A QoS 1 PUBLISH waiting for its PUBACK
+#[derive(Debug, Clone)]
+struct InFlight {
+    packet: PublishPacket, // The packet as it was last sent
+    sent_at: Instant,      // Last time the packet was written
+    retransmits: u32,      // Times the packet has been sent again
+}
+
+/// QoS 1 publishes sent by a client that wait for their PUBACK, by packet ID
+#[derive(Debug, Default)]
+pub struct PendingPublishes {
+    publishes: HashMap<u16, InFlight>,
+}
+
+impl PendingPublishes {
+    /// Creates a tracker without messages in flight
+    pub fn new() -> Self {
+        PendingPublishes::default()
+    }
+
+    /// Records a PUBLISH that was just sent, replacing a message in flight with the
+    /// same packet ID. Only QoS 1 packets are answered with a PUBACK, the others are
+    /// not recorded.
+    ///
+    /// # Returns
+    ///
+    /// True if the packet was recorded.
+    pub fn track(&mut self, packet: PublishPacket) -> bool {
+        if packet.qos != QoS::AtLeastOnce {
+            return false;
+        }
+        self.publishes.insert(packet.message_id, InFlight {
+            packet,
+            sent_at: Instant::now(),
+            retransmits: 0,
+        });
+        true
+    }
+
+    /// Clears the message acknowledged by a PUBACK, returning it if it was in flight
+    pub fn acknowledge(&mut self, packet_id: u16) -> Option<PublishPacket> {
+        self.publishes.remove(&packet_id).map(|in_flight| in_flight.packet)
+    }
+
+    /// Returns the packet IDs of the messages sent longer than the timeout ago
+    /// without a PUBACK, sorted
+    pub fn timed_out(&self, timeout: Duration) -> Vec<u16> {
+        let mut packet_ids: Vec<u16> = self
+            .publishes
+            .iter()
+            .filter(|(_, in_flight)| in_flight.sent_at.elapsed() >= timeout)
+            .map(|(&packet_id, _)| packet_id)
+            .collect();
+        packet_ids.sort();
+        packet_ids
+    }
+
+    /// Marks a message in flight as sent again: the DUP flag is set and its timeout
+    /// starts over.
+    ///
+    /// # Returns
+    ///
+    /// The packet to write, or None if no message is in flight with the packet ID.
+    pub fn retransmit(&mut self, packet_id: u16) -> Option<PublishPacket> {
+        let in_flight = self.publishes.get_mut(&packet_id)?;
+        in_flight.packet.dup = true;
+        in_flight.sent_at = Instant::now();
+        in_flight.retransmits += 1;
+        Some(in_flight.packet.clone())
+    }
+
+    /// Returns the times the message was sent again, or None if it is not in flight
+    pub fn retransmits(&self, packet_id: u16) -> Option<u32> {
+        self.publishes.get(&packet_id).map(|in_flight| in_flight.retransmits)
+    }
+
+    /// Returns the message in flight with the packet ID
+    pub fn get(&self, packet_id: u16) -> Option<&PublishPacket> {
+        self.publishes.get(&packet_id).map(|in_flight| &in_flight.packet)
+    }
+
+    /// Returns whether a message is in flight with the packet ID
+    pub fn contains(&self, packet_id: u16) -> bool {
+        self.publishes.contains_key(&packet_id)
+    }
+
+    /// Returns the packet IDs of every message in flight, sorted
+    pub fn packet_ids(&self) -> Vec<u16> {
+        let mut packet_ids: Vec<u16> = self.publishes.keys().copied().collect();
+        packet_ids.sort();
+        packet_ids
+    }
+
+    /// Returns the number of messages in flight
+    pub fn len(&self) -> usize {
+        self.publishes.len()
+    }
+
+    /// Returns whether no message is in flight
+    pub fn is_empty(&self) -> bool {
+        self.publishes.is_empty()
+    }
+}
+
+/// Keep alive announced in the CONNECT unless the options give another one, in seconds
+pub const DEFAULT_KEEP_ALIVE: u16 = 60;
+// Time to wait for a PUBACK before sending the PUBLISH again, and for a SUBACK
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+// Retransmissions of a PUBLISH before its delivery is reported as failed
+const MAX_RETRANSMITS: u32 = 3;
+// Receive maximum of a broker whose CONNACK does not announce one
+const DEFAULT_RECEIVE_MAXIMUM: u16 = u16::MAX;
+// Longest read of the listener when there is no keep alive to check
+const IDLE_READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How the client reacts to a broker that does not follow the protocol
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ProtocolMode {
+    #[default]
+    Lenient, // Unexpected packets are ignored
+    Strict,  // Unexpected packets close the connection with a Protocol Error
+}
+
+/// Reasons why a publish was not acknowledged by the broker
+#[derive(Debug)]
+pub enum PublishError {
+    Io(io::Error),        // The PUBLISH packet could not be written
+    Timeout,              // No PUBACK arrived after the retransmit limit
+    ConnectionLost,       // The connection closed before the PUBACK arrived
+    InvalidTopic(String), // The topic name is empty or has wildcards
+    QoSNotSupported,      // The client only publishes at QoS 0 and 1
+    Rejected(u8),         // The broker answered with a PUBACK of a failure reason code
+}
+
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PublishError::Io(e) => write!(f, "failed to send PUBLISH: {}", e),
+            PublishError::Timeout => write!(f, "no PUBACK after {} retransmissions", MAX_RETRANSMITS),
+            PublishError::ConnectionLost => write!(f, "connection lost before the PUBACK"),
+            PublishError::InvalidTopic(topic) => write!(f, "invalid topic name {:?}", topic),
+            PublishError::QoSNotSupported => write!(f, "QoS 2 publishes are not supported"),
+            PublishError::Rejected(code) => write!(f, "PUBACK with reason code 0x{:02X}", code),
+        }
+    }
+}
+
+impl std::error::Error for PublishError {}
+
+/// Reasons why a subscription was not granted by the broker
+#[derive(Debug)]
+pub enum SubscribeError {
+    Io(io::Error),         // The SUBSCRIBE packet could not be written
+    Timeout,               // No SUBACK arrived within ACK_TIMEOUT
+    ConnectionLost,        // The connection closed before the SUBACK arrived
+    Refused(u8),           // The SUBACK carried a failure reason code
+    InvalidFilter(String), // The topic filter is empty or misplaces its wildcards
+}
+
+impl fmt::Display for SubscribeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SubscribeError::Io(e) => write!(f, "failed to send SUBSCRIBE: {}", e),
+            SubscribeError::Timeout => write!(f, "no SUBACK within {:?}", ACK_TIMEOUT),
+            SubscribeError::ConnectionLost => write!(f, "connection lost before the SUBACK"),
+            SubscribeError::Refused(code) => write!(f, "subscription refused with reason code 0x{:02x}", code),
+            SubscribeError::InvalidFilter(filter) => write!(f, "invalid topic filter {:?}", filter),
+        }
+    }
+}
+
+impl std::error::Error for SubscribeError {}
+
+/// Event of the connection delivered to the application
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientEvent {
+    Message(PublishPacket), // PUBLISH of one of the subscriptions
+    ConnectionLost(Option<DisconnectReasonCode>), // The connection ended, with the reason of the broker's DISCONNECT if it sent one
+}
+
+// Sender of the events, set once the application takes the receiver
+type EventSender = Arc<Mutex<Option<mpsc::Sender<ClientEvent>>>>;
+
+/// How the packets listener stopped
+enum ListenerExit {
+    Disconnected,                       // The client sent its DISCONNECT
+    Lost(Option<DisconnectReasonCode>), // The broker closed the connection, stopped answering or broke the protocol
+}
+
+// Callback invoked once the delivery of a publish is known
+type AckCallback = Box<dyn FnOnce(Result<(), PublishError>) + Send>;
+
+/// Publishes and subscribes sent by the client that still wait for their acknowledgement
+#[derive(Default)]
+struct PendingAcks {
+    next_message_id: u16,
+    publishes: PendingPublishes,               // QoS 1 publishes waiting for their PUBACK
+    on_ack: HashMap<u16, AckCallback>,         // Delivery receipt callback of each of those publishes
+    subacks: HashMap<u16, mpsc::Sender<SubAckPacket>>, // Waiters of a SUBACK by packet ID
+    closed: bool, // The listener stopped, no acknowledgement will arrive anymore
+}
+
+impl PendingAcks {
+    /// Returns the next message ID that is not in use, 0 is skipped since it is not a valid ID
+    fn allocate_message_id(&mut self) -> u16 {
+        loop {
+            self.next_message_id = self.next_message_id.wrapping_add(1);
+            if self.next_message_id != 0
+                && !self.publishes.contains(self.next_message_id)
+                && !self.subacks.contains_key(&self.next_message_id)
+            {
+                return self.next_message_id;
+            }
+        }
+    }
+
+    /// Clears a publish that is no longer in flight, returning its delivery receipt callback
+    fn acknowledge(&mut self, message_id: u16) -> Option<AckCallback> {
+        self.publishes.acknowledge(message_id)?;
+        self.on_ack.remove(&message_id)
+    }
+
+    /// Marks the connection as closed, dropping the SUBACK waiters and returning the
+    /// callbacks of the publishes still in flight
+    fn close(&mut self) -> Vec<AckCallback> {
+        self.closed = true;
+        self.subacks.clear();
+        self.publishes = PendingPublishes::new();
+        self.on_ack.drain().map(|(_, on_ack)| on_ack).collect()
+    }
+}
+
+// Write half of the connection shared by the caller and the packets listener, every
+// packet is written while it is locked so packets of two threads never interleave
+type Writer = Arc<Mutex<TcpStream>>;
+
+/// Encodes a packet and writes it whole to the broker
+fn send<P: Encode + fmt::Debug>(writer: &Mutex<TcpStream>, packet: &P) -> io::Result<()>
+{
+    let mut stream = writer.lock().unwrap();
+    stream.write_all(&packet.encode()?)?;
+    stream.flush()?;
+    debug!("Sent {:?}", packet);
+    Ok(())
+}
+
+/// Message the broker publishes for the client if it leaves without a DISCONNECT
+#[derive(Debug, Clone)]
+pub struct Will {
+    pub topic: String,
+    pub message: Vec<u8>, // Payload, any bytes like the one of a PUBLISH
+    pub qos: QoS,
+    pub retain: bool,
+    pub properties: WillProperties, // Sent in the will properties block of the MQTT 5 CONNECT
+}
+
+/// Settings of the CONNECT a client opens its connection with
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    pub client_id: String,        // Empty to let the broker assign one
+    pub keep_alive: u16,          // Seconds announced in the CONNECT, 0 disables the keep alive
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub will: Option<Will>,       // Published by the broker if the client is lost
+    pub mode: ProtocolMode,       // Reaction to packets the broker should not send
+}
+
+impl ClientOptions {
+    /// Options of a client with the given ID, without credentials nor will
+    pub fn new(client_id: impl Into<String>) -> Self {
+        ClientOptions {
+            client_id: client_id.into(),
+            keep_alive: DEFAULT_KEEP_ALIVE,
+            username: None,
+            password: None,
+            will: None,
+            mode: ProtocolMode::default(),
+        }
+    }
+
+    // Builds the CONNECT of the options, always with Clean Start
+    fn connect_packet(&self) -> ConnectPacket {
+        let mut connect_flags = 0x02; // Clean start
+        if self.username.is_some() {
+            connect_flags |= 0x80;
+        }
+        if self.password.is_some() {
+            connect_flags |= 0x40;
+        }
+
+        let mut connect_packet = ConnectPacket::new(
+            "MQTT".to_string(),
+            5,
+            connect_flags,
+            self.keep_alive,
+            self.client_id.clone(),
+            None,
+            None,
+            self.username.clone(),
+            self.password.clone(),
+        );
+
+        if let Some(ref will) = self.will {
+            connect_packet.connect_flags |= 0x04; // Will flag
+            connect_packet.will_topic = Some(will.topic.clone());
+            connect_packet.will_message = Some(will.message.clone());
+            connect_packet.will_qos = will.qos.to_u8();
+            connect_packet.will_retain = will.retain;
+            connect_packet.will_properties = Some(will.properties.clone());
+        }
+        connect_packet
+    }
+}
+
+/// Connection to the broker that ends with a DISCONNECT, so the broker never
+/// takes a client that simply goes away for an abnormal disconnection
+pub struct MqttClient {
+    reader: TcpStream, // Read half, cloned for the packets listener
+    writer: Writer,    // Write half shared by every thread
+    disconnected: Arc<AtomicBool>, // A DISCONNECT was already sent, by this or the listener thread
+    listener: Option<thread::JoinHandle<()>>, // Packets listener thread, joined once the client disconnects
+    pending: Arc<Mutex<PendingAcks>>, // Publishes and subscribes waiting for their acknowledgement
+    receive_maximum: u16, // QoS 1 and QoS 2 publishes the broker accepts unacknowledged
+    events: EventSender, // Feeds the receiver of take_events
+}
+
+impl MqttClient {
+    /// Opens the connection, completes the CONNECT / CONNACK exchange and starts the
+    /// packets listener. A CONNACK refusing the client is a ConnectionRefused error.
+    pub fn connect(addr: &str, options: ClientOptions) -> MqttResult<Self>
+    {
+        let mut reader = TcpStream::connect(addr)?;
+        let writer = Arc::new(Mutex::new(reader.try_clone()?));
+        send(&writer, &options.connect_packet())?;
+
+        // The framer keeps the bytes the broker sent after the CONNACK for the packets listener
+        let mut framer = Framer::new(PROTOCOL_MAXIMUM_PACKET_SIZE);
+        reader.set_read_timeout(Some(ACK_TIMEOUT))?;
+        let connack = ConnAckPacket::decode(&framer.read_packet(&mut reader)?)?;
+        if connack.reason_code != ConnAckReasonCode::Success {
+            let message = format!("connection refused: {:?}", connack.reason_code);
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, message).into());
+        }
+
+        // The broker may replace the keep alive of the CONNECT with its own
+        let properties = connack.properties.unwrap_or_default();
+        let keep_alive = properties.server_keep_alive.unwrap_or(options.keep_alive);
+
+        let mut client = MqttClient {
+            reader,
+            writer,
+            disconnected: Arc::new(AtomicBool::new(false)),
+            listener: None,
+            pending: Arc::new(Mutex::new(PendingAcks::default())),
+            receive_maximum: properties.receive_maximum.unwrap_or(DEFAULT_RECEIVE_MAXIMUM),
+            events: Arc::new(Mutex::new(None)),
+        };
+        client.spawn_listener(framer, options.mode, Duration::from_secs(keep_alive as u64))?;
+        Ok(client)
+    }
+
+    /// Starts the packets listener, which receives the acknowledgements and the
+    /// messages of the subscriptions until the connection is lost
+    fn spawn_listener(&mut self, framer: Framer, mode: ProtocolMode, keep_alive: Duration) -> io::Result<()>
+    {
+        let stream = self.reader.try_clone()?;
+        let writer = Arc::clone(&self.writer);
+        let pending = Arc::clone(&self.pending);
+        let disconnected = Arc::clone(&self.disconnected);
+        let events = Arc::clone(&self.events);
+
+        self.listener = Some(thread::spawn(move || {
+            let exit = packets_listener(stream, framer, &writer, &pending, &events, &disconnected, mode, keep_alive);
+
+            // Nothing waits for an acknowledgement that can no longer arrive
+            let expired = pending.lock().unwrap().close();
+            for on_ack in expired {
+                on_ack(Err(PublishError::ConnectionLost));
+            }
+
+            // The sender is dropped, so the receiver ends after the last event
+            if let Some(sender) = events.lock().unwrap().take() {
+                if let ListenerExit::Lost(reason_code) = exit {
+                    let _ = sender.send(ClientEvent::ConnectionLost(reason_code));
+                }
+            }
+        }));
+        Ok(())
+    }
+
+    /// Stops the packets listener once the DISCONNECT is sent: the read half is shut
+    /// down so a read waiting for the broker returns at once, then the thread is joined
+    fn stop_listener(&mut self)
+    {
+        let _ = self.reader.shutdown(Shutdown::Read);
+        if let Some(listener) = self.listener.take() {
+            let _ = listener.join();
+        }
+    }
+
+    // Locks the pending acknowledgements, failing the operation once the listener stopped
+    fn pending_while_connected(&self) -> Option<MutexGuard<'_, PendingAcks>> {
+        let pending = self.pending.lock().unwrap();
+        if pending.closed {
+            return None;
+        }
+        Some(pending)
+    }
+
+    /// Returns the receiver of the messages of the subscriptions and of the loss of
+    /// the connection, which ends once the listener stops. Only the first call gets
+    /// it, and the messages received before it are dropped, so it is taken before
+    /// subscribing.
+    pub fn take_events(&self) -> Option<mpsc::Receiver<ClientEvent>>
+    {
+        let mut events = self.events.lock().unwrap();
+        // Once the listener stopped the receiver would never get anything
+        if events.is_some() || !self.is_connected() {
+            return None;
+        }
+        let (sender, receiver) = mpsc::channel();
+        *events = Some(sender);
+        Some(receiver)
+    }
+
+    /// Returns whether the packets listener still runs, it stops once the connection
+    /// is lost or the client disconnects
+    pub fn is_connected(&self) -> bool
+    {
+        !self.pending.lock().unwrap().closed
+    }
+
+    /// Subscribes to a topic filter and blocks until the broker acknowledges it.
+    ///
+    /// The SUBACK is matched with the SUBSCRIBE by packet ID in the packets listener.
+    /// Returns the QoS granted by the broker, or `SubscribeError::Timeout` if no
+    /// SUBACK arrives within `ACK_TIMEOUT`.
+    pub fn subscribe(&self, topic: &str, qos: QoS) -> Result<QoS, SubscribeError>
+    {
+        if !is_valid_topic_filter(topic) {
+            return Err(SubscribeError::InvalidFilter(topic.to_string()));
+        }
+        let options = SubscriptionOptions { qos, ..Default::default() };
+        let (sender, receiver) = mpsc::channel();
+
+        // The waiter is registered before writing so a fast SUBACK always finds it
+        let mut pending_guard = self.pending_while_connected().ok_or(SubscribeError::ConnectionLost)?;
+        let packet_id = pending_guard.allocate_message_id();
+        pending_guard.subacks.insert(packet_id, sender);
+        drop(pending_guard);
+
+        let subscribe_packet = SubscribePacket::with_options(packet_id, vec![(topic.to_string(), options)]);
+        if let Err(e) = send(&self.writer, &subscribe_packet) {
+            self.pending.lock().unwrap().subacks.remove(&packet_id);
+            return Err(SubscribeError::Io(e));
+        }
+
+        let suback = match receiver.recv_timeout(ACK_TIMEOUT) {
+            Ok(suback) => suback,
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Err(SubscribeError::ConnectionLost),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                self.pending.lock().unwrap().subacks.remove(&packet_id);
+                return Err(SubscribeError::Timeout);
+            }
+        };
+
+        // Reason codes from 0x80 are failures, the lower ones are the granted QoS
+        match suback.return_codes.first() {
+            Some(&code) if code < 0x80 => QoS::from_u8(code).map_err(|_| SubscribeError::Refused(code)),
+            Some(&code) => Err(SubscribeError::Refused(code)),
+            None => Err(SubscribeError::Refused(0x80)),
+        }
+    }
+
+    /// Publishes a message and blocks until its delivery is known: once the packet
+    /// is written for QoS 0, once the PUBACK arrives for QoS 1
+    pub fn publish(&self, topic: &str, payload: &[u8], qos: QoS) -> Result<(), PublishError>
+    {
+        let (sender, receiver) = mpsc::channel();
+        self.publish_with_ack(topic, payload, qos, move |result| {
+            let _ = sender.send(result);
+        });
+        // The callback always runs, at the latest when the listener stops
+        receiver.recv().unwrap_or(Err(PublishError::ConnectionLost))
+    }
+
+    /// Publishes a message and invokes `on_ack` once the broker acknowledges it.
+    ///
+    /// QoS 0 messages are never acknowledged, so the callback runs as soon as the
+    /// packet is written. QoS 1 messages are matched with their PUBACK by message ID
+    /// in the packets listener, which retransmits them and finally reports a
+    /// `PublishError::Timeout` if no PUBACK arrives.
+    pub fn publish_with_ack(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        on_ack: impl FnOnce(Result<(), PublishError>) + Send + 'static,
+    )
+    {
+        if !is_valid_topic_name(topic) {
+            on_ack(Err(PublishError::InvalidTopic(topic.to_string())));
+            return;
+        }
+        let mut publish_packet = PublishPacket::new(
+            topic.to_string(),
+            0,
+            qos,
+            false,
+            false,
+            payload.to_vec(),
+        );
+
+        match qos {
+            QoS::AtMostOnce => {
+                let result = send(&self.writer, &publish_packet).map_err(PublishError::Io);
+                on_ack(result);
+                return;
+            }
+            QoS::AtLeastOnce => {}
+            QoS::ExactlyOnce => {
+                on_ack(Err(PublishError::QoSNotSupported));
+                return;
+            }
+        }
+
+        // The packet is registered before writing so a fast PUBACK always finds it
+        let Some(mut pending_guard) = self.pending_while_connected() else {
+            on_ack(Err(PublishError::ConnectionLost));
+            return;
+        };
+        publish_packet.message_id = pending_guard.allocate_message_id();
+        let message_id = publish_packet.message_id;
+        pending_guard.publishes.track(publish_packet.clone());
+        pending_guard.on_ack.insert(message_id, Box::new(on_ack));
+        drop(pending_guard);
+
+        if let Err(e) = send(&self.writer, &publish_packet) {
+            let on_ack = self.pending.lock().unwrap().acknowledge(message_id);
+            if let Some(on_ack) = on_ack {
+                on_ack(Err(PublishError::Io(e)));
+            }
+        }
+    }
+
+    /// Returns the number of QoS 1 publishes still waiting for their PUBACK
+    pub fn inflight_count(&self) -> usize
+    {
+        self.pending.lock().unwrap().publishes.len()
+    }
+
+    /// Returns whether one more QoS 1 publish fits in the receive maximum of the
+    /// broker, so the caller can hold its messages back instead of overloading it
+    pub fn can_publish(&self) -> bool
+    {
+        self.inflight_count() < self.receive_maximum as usize
+    }
+
+    /// Leaves normally, the broker discards the will
+    pub fn disconnect(self)
+    {
+        self.disconnect_with(DisconnectReasonCode::NormalDisconnection);
+    }
+
+    /// Sends a DISCONNECT with the given reason and flushes it before the socket closes
+    pub fn disconnect_with(mut self, reason: DisconnectReasonCode)
+    {
+        self.send_disconnect(reason);
+    }
+
+    fn send_disconnect(&mut self, reason: DisconnectReasonCode)
+    {
+        send_disconnect_once(&self.writer, &self.disconnected, reason);
+    }
+}
+
+impl Drop for MqttClient {
+    // A client dropped without an explicit disconnect leaves normally
+    fn drop(&mut self)
+    {
+        self.send_disconnect(DisconnectReasonCode::NormalDisconnection);
+        self.stop_listener();
+    }
+}
+
+// Sends a DISCONNECT unless one was already sent on the connection
+fn send_disconnect_once(writer: &Mutex<TcpStream>, disconnected: &AtomicBool, reason: DisconnectReasonCode)
+{
+    if disconnected.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let disconnect_packet = DisconnectPacket::new(reason);
+    let _ = send(writer, &disconnect_packet);
+}
+
+/// Sends again the publishes whose PUBACK timed out, reporting a failure once
+/// a message runs out of retransmissions
+fn retransmit_pending(writer: &Mutex<TcpStream>, pending: &Mutex<PendingAcks>)
+{
+    let mut expired = Vec::new();
+    let mut pending_guard = pending.lock().unwrap();
+
+    for message_id in pending_guard.publishes.timed_out(ACK_TIMEOUT) {
+        if pending_guard.publishes.retransmits(message_id) >= Some(MAX_RETRANSMITS) {
+            if let Some(on_ack) = pending_guard.acknowledge(message_id) {
+                expired.push(on_ack);
+            }
+            continue;
+        }
+        if let Some(packet) = pending_guard.publishes.retransmit(message_id) {
+            let _ = send(writer, &packet);
+        }
+    }
+    drop(pending_guard);
+
+    // Callbacks run without the lock so they can publish again
+    for on_ack in expired {
+        on_ack(Err(PublishError::Timeout));
+    }
+}
+
+// Reads the packets of the broker until the connection is lost or the client disconnects
+#[allow(clippy::too_many_arguments)]
+fn packets_listener(
+    mut stream: TcpStream,
+    mut framer: Framer,
+    writer: &Mutex<TcpStream>,
+    pending: &Mutex<PendingAcks>,
+    events: &Mutex<Option<mpsc::Sender<ClientEvent>>>,
+    disconnected: &AtomicBool,
+    mode: ProtocolMode,
+    keep_alive: Duration,
+) -> ListenerExit
+{
+    let mut last_ping_sent: Option<Instant> = None;
+    // Time by which the PINGRESP of the oldest unanswered PINGREQ must arrive
+    let mut pingresp_deadline: Option<Instant> = None;
+
+    // The read returns periodically so the keep alive runs even if the broker is silent
+    let read_timeout = if keep_alive.is_zero() { IDLE_READ_TIMEOUT } else { keep_alive / 4 };
+    let _ = stream.set_read_timeout(Some(read_timeout));
+
+    loop {
+        // The client sent its DISCONNECT, nothing is read from the broker anymore
+        if disconnected.load(Ordering::SeqCst) {
+            return ListenerExit::Disconnected;
+        }
+
+        // A PINGREQ is sent every half keep alive interval, a keep alive of 0 disables them
+        if !keep_alive.is_zero() && last_ping_sent.is_none_or(|sent| sent.elapsed() >= keep_alive / 2) {
+            let _ = send(writer, &PingReqPacket);
+            last_ping_sent = Some(Instant::now());
+            pingresp_deadline.get_or_insert(Instant::now() + keep_alive / 2);
+        }
+
+        // The broker is considered gone once a PINGRESP is late, which is checked at
+        // every read timeout, so within three quarters of the keep alive of silence
+        if pingresp_deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            warn!("No PINGRESP from the broker within the keep alive, disconnecting");
+            let _ = stream.shutdown(Shutdown::Both);
+            return ListenerExit::Lost(None);
+        }
+
+        retransmit_pending(writer, pending);
+
+        match framer.read_packet(&mut stream) {
+            Ok(buffer) => {
+                let size = buffer.len();
+
+                let packet_type = parse_fixed_header(&buffer[..size])
+                    .map(|header| header.packet_type);
+
+                // A PINGRESP nobody asked for is not activity of a healthy broker
+                if packet_type == Ok(PacketType::PingResp) && pingresp_deadline.is_none() {
+                    if mode == ProtocolMode::Strict {
+                        warn!("Unsolicited PINGRESP from the broker, disconnecting");
+                        send_disconnect_once(writer, disconnected, DisconnectReasonCode::ProtocolError);
+                        return ListenerExit::Lost(None);
+                    }
+                    warn!("Ignoring an unsolicited PINGRESP from the broker");
+                    continue;
+                }
+                if packet_type == Ok(PacketType::PingResp) {
+                    pingresp_deadline = None;
+                }
+
+                if packet_type == Ok(PacketType::Publish) {
+                    if let Ok(packet) =
+                        PublishPacket::decode(&buffer[..size])
+                    {
+                        // QoS 1 messages must be acknowledged to the broker
+                        if packet.qos == QoS::AtLeastOnce {
+                            let puback = PubAckPacket::new(packet.message_id);
+                            let _ = send(writer, &puback);
+                        }
+
+                        // Messages are dropped until the application takes the receiver
+                        if let Some(sender) = events.lock().unwrap().as_ref() {
+                            let _ = sender.send(ClientEvent::Message(packet));
+                        }
+                    }
+                }
+
+                // The broker closes the connection after its DISCONNECT
+                if packet_type == Ok(PacketType::Disconnect) {
+                    let reason_code = DisconnectPacket::decode(&buffer[..size])
+                        .ok()
+                        .map(|packet| packet.reason_code().clone());
+                    return ListenerExit::Lost(reason_code);
+                }
+
+                if packet_type == Ok(PacketType::SubAck) {
+                    if let Ok(packet) =
+                        SubAckPacket::decode(&buffer[..size])
+                    {
+                        let waiter = pending
+                            .lock()
+                            .unwrap()
+                            .subacks
+                            .remove(&packet.packet_id);
+
+                        // The waiter may be gone if the subscribe already timed out
+                        if let Some(waiter) = waiter {
+                            let _ = waiter.send(packet);
+                        }
+                    }
+                }
+
+                if packet_type == Ok(PacketType::PubAck) {
+                    if let Ok(packet) =
+                        PubAckPacket::decode(&buffer[..size])
+                    {
+                        let on_ack = pending
+                            .lock()
+                            .unwrap()
+                            .acknowledge(packet.packet_id);
+
+                        // Reason codes from 0x80 are failures, the message was not accepted
+                        if let Some(on_ack) = on_ack {
+                            if packet.reason_code >= 0x80 {
+                                on_ack(Err(PublishError::Rejected(packet.reason_code)));
+                            } else {
+                                on_ack(Ok(()));
+                            }
+                        }
+                    }
+                }
+            }
+            Err(FrameError::Io(ref e))
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                // Nothing received yet, go back to the keep alive checks
+            }
+            // A read failing after the client's DISCONNECT is not a lost connection
+            _ if disconnected.load(Ordering::SeqCst) => return ListenerExit::Disconnected,
+            _ => return ListenerExit::Lost(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn send_writes_the_encoded_packet_or_refuses_it_whole() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let writer = Mutex::new(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+        let (mut broker, _) = listener.accept().unwrap();
+
+        // A QoS 1 PUBLISH without a message ID cannot be encoded, nothing is written
+        let invalid = PublishPacket::new("t".to_string(), 0, QoS::AtLeastOnce, false, false, Vec::new());
+        assert_eq!(send(&writer, &invalid).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        send(&writer, &PingReqPacket).unwrap();
+        let mut received = [0; 2];
+        broker.read_exact(&mut received).unwrap();
+        assert_eq!(received, [0xC0, 0x00]);
+    }
+}
@@ -0,0 +1,82 @@
+//! Error type shared by the public APIs of the crate.
+
+/*
+The packet decoders and the framer keep their own error types, which tell exactly
+what was wrong with the bytes. MqttError gathers them with the I/O errors and the
+protocol errors, so code going through several layers can use `?` on all of them
+and match a single type.
+*/
+
+use std::fmt;
+use std::io;
+use crate::packets::{disconnect::DisconnectReasonCode, framer::FrameError, DecodeError, EncodeError};
+
+/// Result of the operations that can fail for any of the reasons of an MqttError
+pub type MqttResult<T> = Result<T, MqttError>;
+
+/// Reason an MQTT operation failed
+#[derive(Debug)]
+pub enum MqttError {
+    Io(io::Error),                   // Reading or writing the connection or a file failed
+    Decode(DecodeError),             // Bytes that are not a valid packet
+    Encode(EncodeError),             // A packet the protocol does not allow to send
+    Protocol(DisconnectReasonCode),  // A valid packet breaking a rule, with the reason code it is answered with
+}
+
+impl fmt::Display for MqttError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MqttError::Io(e) => write!(f, "I/O error: {}", e),
+            MqttError::Decode(e) => write!(f, "Decode error: {}", e),
+            MqttError::Encode(e) => write!(f, "Encode error: {}", e),
+            MqttError::Protocol(reason_code) => write!(f, "Protocol error: {:?}", reason_code),
+        }
+    }
+}
+
+impl std::error::Error for MqttError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MqttError::Io(e) => Some(e),
+            MqttError::Decode(e) => Some(e),
+            MqttError::Encode(e) => Some(e),
+            MqttError::Protocol(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for MqttError {
+    fn from(e: io::Error) -> Self {
+        MqttError::Io(e)
+    }
+}
+
+impl From<DecodeError> for MqttError {
+    fn from(e: DecodeError) -> Self {
+        MqttError::Decode(e)
+    }
+}
+
+impl From<EncodeError> for MqttError {
+    fn from(e: EncodeError) -> Self {
+        MqttError::Encode(e)
+    }
+}
+
+impl From<DisconnectReasonCode> for MqttError {
+    fn from(reason_code: DisconnectReasonCode) -> Self {
+        MqttError::Protocol(reason_code)
+    }
+}
+
+// A connection closed by the peer is an I/O error for the code that wanted a packet
+impl From<FrameError> for MqttError {
+    fn from(e: FrameError) -> Self {
+        match e {
+            FrameError::Io(e) => MqttError::Io(e),
+            FrameError::Closed => MqttError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed")),
+            FrameError::TooLarge(_) => MqttError::Protocol(DisconnectReasonCode::PacketTooLarge),
+            FrameError::Malformed(e) => MqttError::Decode(e),
+        }
+    }
+}
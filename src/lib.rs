@@ -1,11 +1,21 @@
 // Import all the packets from their modules
 pub mod packets;
+// Broker that routes the packets between the connected clients
+pub mod broker;
+// Error type shared by the public APIs
+pub mod error;
+// Client connection to a broker, and the state it keeps for the messages it publishes
+pub mod client;
+
+pub use client::{ClientEvent, ClientOptions, MqttClient};
+pub use error::{MqttError, MqttResult};
 
 pub use packets::{
     connect::ConnectPacket,
     connack::ConnAckPacket,
     
     publish::PublishPacket,
+    qos::QoS,
     puback:: PubAckPacket, //PubRecPacket, PubRelPacket, PubCompPacket
     subscribe::SubscribePacket, // UnsubscribePacket
     suback::SubAckPacket, //UnsubAckPacket
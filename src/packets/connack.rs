@@ -1,11 +1,12 @@
-/// MQTT ConnAck packet implementation for MQTT version 5.0.
+//! MQTT ConnAck packet implementation for MQTT version 5.0.
 /*
 The CONNACK packet is sent by the broker in response to a CONNECT packet from the client.
 It indicates the success or failure of the connection attempt and provides additional
  properties as per MQTT 5.0. */
 
-use std::io::{Read};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use super::fixed_header::{first_packet, read_bytes, read_variable_length, write_variable_length};
+use super::{read_binary, read_string, DecodeError};
 
 /// Represents the CONNACK packet in MQTT v5.0.
 #[derive(Debug, PartialEq, Clone)]
@@ -44,7 +45,7 @@ pub enum ConnAckReasonCode {
 
 impl ConnAckReasonCode {
     /// Decodes a reason code from a byte.
-    pub fn from_byte(byte: u8) -> Result<Self, String> {
+    pub fn from_byte(byte: u8) -> Result<Self, DecodeError> {
         match byte {
             0x00 => Ok(ConnAckReasonCode::Success),
             0x80 => Ok(ConnAckReasonCode::UnspecifiedError),
@@ -68,7 +69,7 @@ impl ConnAckReasonCode {
             0x9C => Ok(ConnAckReasonCode::UseAnotherServer),
             0x9D => Ok(ConnAckReasonCode::ServerMoved),
             0x9F => Ok(ConnAckReasonCode::ConnectionRateExceeded),
-            _ => Err(format!("Unknown reason code: {}", byte)),
+            _ => Err(DecodeError::InvalidReasonCode(byte)),
         }
     }
 
@@ -80,11 +81,13 @@ impl ConnAckReasonCode {
 }
 
 /// Properties specific to the CONNACK packet in MQTT v5.0.
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Default)]
 pub struct ConnAckProperties {
     pub session_expiry_interval: Option<u32>, // Optional session expiry interval
     pub receive_maximum: Option<u16>,        // Maximum number of QoS 1 or QoS 2 messages
     pub maximum_packet_size: Option<u32>,    // Maximum size of a packet
+    pub topic_alias_maximum: Option<u16>,    // Highest topic alias the client may send
+    pub maximum_qos: Option<u8>,             // Highest QoS the broker accepts, absent means QoS 2
     pub assigned_client_identifier: Option<String>, // Assigned client ID from broker
     pub reason_string: Option<String>,       // Human-readable reason for connection result
     pub server_keep_alive: Option<u16>,      // Server-determined keep-alive interval
@@ -95,6 +98,16 @@ pub struct ConnAckProperties {
 }
 
 impl ConnAckPacket {
+    /// Returns a builder for a successful CONNACK without properties, which the
+    /// chainable setters complete.
+    pub fn builder() -> ConnAckBuilder {
+        ConnAckBuilder {
+            session_present: false,
+            reason_code: ConnAckReasonCode::Success,
+            properties: ConnAckProperties::default(),
+        }
+    }
+
     // Constructor for a ConnectPacket, with all fields as parameters
     pub fn new(
         session_present: bool,          
@@ -128,35 +141,77 @@ impl ConnAckPacket {
         if let Some(ref props) = self.properties {
             if let Some(interval) = props.session_expiry_interval {
                 properties.push(0x11); // Property identifier for session expiry interval
-                properties.write_u32::<BigEndian>(interval).map_err(|e| e.to_string()).unwrap();
+                properties.write_u32::<BigEndian>(interval).unwrap();
             }
 
             if let Some(maximum) = props.receive_maximum {
                 properties.push(0x21); // Property identifier for receive maximum
-                properties.write_u16::<BigEndian>(maximum).map_err(|e| e.to_string()).unwrap();
+                properties.write_u16::<BigEndian>(maximum).unwrap();
             }
 
             if let Some(size) = props.maximum_packet_size {
                 properties.push(0x27); // Property identifier for maximum packet size
-                properties.write_u32::<BigEndian>(size).map_err(|e| e.to_string()).unwrap();
+                properties.write_u32::<BigEndian>(size).unwrap();
+            }
+
+            if let Some(maximum) = props.topic_alias_maximum {
+                properties.push(0x22); // Property identifier for topic alias maximum
+                properties.write_u16::<BigEndian>(maximum).unwrap();
+            }
+
+            if let Some(qos) = props.maximum_qos {
+                properties.push(0x24); // Property identifier for maximum QoS
+                properties.push(qos);
             }
 
             if let Some(ref client_id) = props.assigned_client_identifier {
                 properties.push(0x12); // Property identifier for assigned client ID
-                properties.push(client_id.len() as u8);
+                properties.write_u16::<BigEndian>(client_id.len() as u16).unwrap();
                 properties.extend_from_slice(client_id.as_bytes());
             }
 
-            // Additional properties can be added similarly...
+            if let Some(ref reason) = props.reason_string {
+                properties.push(0x1F); // Property identifier for reason string
+                properties.write_u16::<BigEndian>(reason.len() as u16).unwrap();
+                properties.extend_from_slice(reason.as_bytes());
+            }
+
+            if let Some(keep_alive) = props.server_keep_alive {
+                properties.push(0x13); // Property identifier for server keep alive
+                properties.write_u16::<BigEndian>(keep_alive).unwrap();
+            }
+
+            if let Some(ref response_information) = props.response_information {
+                properties.push(0x1A); // Property identifier for response information
+                properties.write_u16::<BigEndian>(response_information.len() as u16).unwrap();
+                properties.extend_from_slice(response_information.as_bytes());
+            }
+
+            if let Some(ref server_reference) = props.server_reference {
+                properties.push(0x1C); // Property identifier for server reference
+                properties.write_u16::<BigEndian>(server_reference.len() as u16).unwrap();
+                properties.extend_from_slice(server_reference.as_bytes());
+            }
+
+            if let Some(ref method) = props.authentication_method {
+                properties.push(0x15); // Property identifier for authentication method
+                properties.write_u16::<BigEndian>(method.len() as u16).unwrap();
+                properties.extend_from_slice(method.as_bytes());
+            }
+
+            if let Some(ref data) = props.authentication_data {
+                properties.push(0x16); // Property identifier for authentication data
+                properties.write_u16::<BigEndian>(data.len() as u16).unwrap();
+                properties.extend_from_slice(data);
+            }
         }
 
-        // Add properties length and properties to variable header
-        variable_header.push(properties.len() as u8);
+        // Add properties length (VLQ) and properties to variable header
+        write_variable_length(&mut variable_header, properties.len());
         variable_header.extend_from_slice(&properties);
 
-        // Calculate remaining length
-        let remaining_length = variable_header.len();
-        packet.push(remaining_length as u8);
+        // Remaining length (VLQ), a CONNACK with several properties can exceed 127 bytes
+        write_variable_length(&mut packet, variable_header.len());
 
         // Add variable header to packet
         packet.extend(variable_header);
@@ -165,39 +220,32 @@ impl ConnAckPacket {
     }
 
     /// Decodes a CONNACK packet from bytes.
-    pub fn decode(data: &[u8]) -> Result<Self, String> {
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        // Bytes after the remaining length belong to the next packet
+        let data = first_packet(data)?;
         let mut cursor = std::io::Cursor::new(data);
-        cursor.set_position(2);
+
+        // Skip the packet type and the remaining length (VLQ)
+        cursor.read_u8()?;
+        read_variable_length(&mut cursor)?;
+
         // Read session present flag
-        let session_present = match cursor.read_u8().map_err(|e| e.to_string())? {
+        let session_present = match cursor.read_u8()? {
             0 => false,
             1 => true,
-            _ => return Err("Invalid session present flag".to_string()),
+            _ => return Err(DecodeError::InvalidFlags("session present flag other than 0 or 1".to_string())),
         };
 
 
         // Read reason code
-        let reason_code = ConnAckReasonCode::from_byte(cursor.read_u8().map_err(|e| e.to_string())?)?;
+        let reason_code = ConnAckReasonCode::from_byte(cursor.read_u8()?)?;
 
         // Read properties (if any)
         let mut properties = None;
-        let properties_length = cursor.read_u8().map_err(|e| e.to_string())? as usize;
+        let properties_length = read_variable_length(&mut cursor)?;
         if properties_length > 0 {
-            let mut properties_data = vec![0; properties_length];
-            cursor.read_exact(&mut properties_data).map_err(|e| e.to_string())?;
-            // Decode properties (similar to encoding logic)
-            properties = Some(ConnAckProperties {
-                session_expiry_interval: None, 
-                receive_maximum: None,        
-                maximum_packet_size: None,    
-                assigned_client_identifier: None, 
-                reason_string: None,          
-                server_keep_alive: None,      
-                response_information: None,   
-                server_reference: None,       
-                authentication_method: None,  
-                authentication_data: None,    
-            });
+            let properties_data = read_bytes(&mut cursor, properties_length)?;
+            properties = Some(decode_properties(&properties_data)?);
         }
 
         Ok(ConnAckPacket {
@@ -207,3 +255,197 @@ impl ConnAckPacket {
         })
     }
 }
+
+/// Builder for a CONNACK packet, which avoids writing the properties struct by hand.
+#[derive(Debug, Clone)]
+pub struct ConnAckBuilder {
+    session_present: bool,
+    reason_code: ConnAckReasonCode,
+    properties: ConnAckProperties,
+}
+
+impl ConnAckBuilder {
+    /// Sets the Session Present flag
+    pub fn session_present(mut self, session_present: bool) -> Self {
+        self.session_present = session_present;
+        self
+    }
+
+    /// Sets the reason code of the connection result
+    pub fn reason(mut self, reason_code: ConnAckReasonCode) -> Self {
+        self.reason_code = reason_code;
+        self
+    }
+
+    /// Sets the keep alive the client must use instead of its own
+    pub fn server_keep_alive(mut self, keep_alive: u16) -> Self {
+        self.properties.server_keep_alive = Some(keep_alive);
+        self
+    }
+
+    /// Sets the maximum number of QoS 1 and QoS 2 messages the broker processes at once
+    pub fn receive_maximum(mut self, maximum: u16) -> Self {
+        self.properties.receive_maximum = Some(maximum);
+        self
+    }
+
+    /// Sets the largest packet the client may send, in bytes
+    pub fn maximum_packet_size(mut self, size: u32) -> Self {
+        self.properties.maximum_packet_size = Some(size);
+        self
+    }
+
+    /// Sets the highest topic alias the client may use in its PUBLISH packets
+    pub fn topic_alias_maximum(mut self, maximum: u16) -> Self {
+        self.properties.topic_alias_maximum = Some(maximum);
+        self
+    }
+
+    /// Sets the highest QoS the client may publish and subscribe with, 0 or 1
+    pub fn maximum_qos(mut self, qos: u8) -> Self {
+        self.properties.maximum_qos = Some(qos);
+        self
+    }
+
+    /// Sets the client ID assigned by the broker
+    pub fn assigned_client_identifier(mut self, client_id: impl Into<String>) -> Self {
+        self.properties.assigned_client_identifier = Some(client_id.into());
+        self
+    }
+
+    /// Sets the human-readable reason of the connection result
+    pub fn reason_string(mut self, reason: impl Into<String>) -> Self {
+        self.properties.reason_string = Some(reason.into());
+        self
+    }
+
+    /// Sets the server the client should use instead
+    pub fn server_reference(mut self, server_reference: impl Into<String>) -> Self {
+        self.properties.server_reference = Some(server_reference.into());
+        self
+    }
+
+    /// Builds the packet, the properties are only included if at least one was set
+    pub fn build(self) -> ConnAckPacket {
+        let properties = if self.properties == ConnAckProperties::default() {
+            None
+        } else {
+            Some(self.properties)
+        };
+
+        ConnAckPacket {
+            session_present: self.session_present,
+            reason_code: self.reason_code,
+            properties,
+        }
+    }
+}
+
+/// Decodes the CONNACK properties, without their length
+fn decode_properties(data: &[u8]) -> Result<ConnAckProperties, DecodeError> {
+    let mut cursor = std::io::Cursor::new(data);
+    let mut properties = ConnAckProperties::default();
+
+    while (cursor.position() as usize) < data.len() {
+        let identifier = cursor.read_u8()?;
+        match identifier {
+            0x11 => properties.session_expiry_interval = Some(cursor.read_u32::<BigEndian>()?),
+            0x21 => properties.receive_maximum = Some(cursor.read_u16::<BigEndian>()?),
+            0x27 => properties.maximum_packet_size = Some(cursor.read_u32::<BigEndian>()?),
+            0x22 => properties.topic_alias_maximum = Some(cursor.read_u16::<BigEndian>()?),
+            0x24 => match cursor.read_u8()? {
+                // The property is only sent for QoS 0 or 1
+                qos @ (0 | 1) => properties.maximum_qos = Some(qos),
+                qos => return Err(DecodeError::ProtocolError(format!("maximum QoS of {}", qos))),
+            },
+            0x12 => properties.assigned_client_identifier = Some(read_string(&mut cursor)?),
+            0x1F => properties.reason_string = Some(read_string(&mut cursor)?),
+            0x13 => properties.server_keep_alive = Some(cursor.read_u16::<BigEndian>()?),
+            0x1A => properties.response_information = Some(read_string(&mut cursor)?),
+            0x1C => properties.server_reference = Some(read_string(&mut cursor)?),
+            0x15 => properties.authentication_method = Some(read_string(&mut cursor)?),
+            0x16 => properties.authentication_data = Some(read_binary(&mut cursor)?),
+            _ => return Err(DecodeError::UnsupportedProperty(identifier)),
+        }
+    }
+
+    Ok(properties)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connack_without_properties() {
+        let packet = ConnAckPacket::new(false, ConnAckReasonCode::Success, None);
+        assert_eq!(ConnAckPacket::decode(&packet.encode()).unwrap(), packet);
+
+        let packet = ConnAckPacket::new(true, ConnAckReasonCode::NotAuthorized, None);
+        assert_eq!(ConnAckPacket::decode(&packet.encode()).unwrap(), packet);
+    }
+
+    #[test]
+    fn connack_with_every_property() {
+        let properties = ConnAckProperties {
+            session_expiry_interval: Some(120),
+            receive_maximum: Some(20),
+            maximum_packet_size: Some(1 << 20),
+            topic_alias_maximum: Some(10),
+            maximum_qos: Some(1),
+            assigned_client_identifier: Some("auto-1".to_string()),
+            reason_string: Some("welcome".to_string()),
+            server_keep_alive: Some(45),
+            response_information: Some("responses/".to_string()),
+            server_reference: Some("other.example:1883".to_string()),
+            authentication_method: Some("SCRAM-SHA-1".to_string()),
+            authentication_data: Some(vec![0x00, 0xFF]),
+        };
+        let packet = ConnAckPacket::new(true, ConnAckReasonCode::Success, Some(properties));
+
+        assert_eq!(ConnAckPacket::decode(&packet.encode()).unwrap(), packet);
+    }
+
+    #[test]
+    fn connack_with_a_remaining_length_over_255() {
+        let packet = ConnAckPacket::builder()
+            .reason(ConnAckReasonCode::ServerMoved)
+            .reason_string("r".repeat(200))
+            .server_reference("other.example:1883")
+            .assigned_client_identifier("auto-".repeat(10))
+            .receive_maximum(20)
+            .build();
+        let encoded = packet.encode();
+
+        // 2 bytes of flags and reason code, 2 of property length and 280 of properties
+        // (3 + 53 + 203 + 21): 284, 0x9C 0x02 as a VLQ
+        assert_eq!(&encoded[1..3], &[0x9C, 0x02]);
+        assert_eq!(encoded.len(), 3 + 284);
+        assert_eq!(ConnAckPacket::decode(&encoded).unwrap(), packet);
+    }
+
+    #[test]
+    fn builder_encodes_like_a_connack_built_by_hand() {
+        let built = ConnAckPacket::builder()
+            .session_present(true)
+            .receive_maximum(10)
+            .maximum_qos(1)
+            .server_keep_alive(30)
+            .build();
+
+        let properties = ConnAckProperties {
+            receive_maximum: Some(10),
+            maximum_qos: Some(1),
+            server_keep_alive: Some(30),
+            ..ConnAckProperties::default()
+        };
+        let by_hand = ConnAckPacket::new(true, ConnAckReasonCode::Success, Some(properties));
+        assert_eq!(built.encode(), by_hand.encode());
+
+        // Receive Maximum 10, Maximum QoS 1 and Server Keep Alive 30, in the encoding order
+        assert_eq!(
+            built.encode(),
+            vec![0x20, 0x0B, 0x01, 0x00, 0x08, 0x21, 0x00, 0x0A, 0x24, 0x01, 0x13, 0x00, 0x1E]
+        );
+    }
+}
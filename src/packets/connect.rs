@@ -1,4 +1,4 @@
-/// MQTT Connect packet implementation for MQTT version 5.0.
+//! MQTT Connect packet implementation for MQTT version 5.0.
 
 /* 
 The CONNECT packet is used to establish a connection between a client and a broker.
@@ -6,8 +6,9 @@ This packet includes several fields that identify the client, specify connection
      and allow for the broker to acknowledge the connection.
 */
 
-use std::io::{Read};
 use byteorder::{BigEndian, WriteBytesExt, ReadBytesExt};
+use super::fixed_header::{read_bytes, read_variable_length, write_variable_length};
+use super::{read_binary, read_string, write_binary, DecodeError};
 
 /*
 Implement traits for:
@@ -25,14 +26,215 @@ pub struct ConnectPacket {
     pub client_id: String,       // Unique identifier for the client
     //Option fields could take Some(value) or None
     pub will_topic: Option<String>,   // Will topic (optional)
-    pub will_message: Option<String>, // Will message (optional)
+    pub will_message: Option<Vec<u8>>, // Will message (optional), binary like a PUBLISH payload
+    pub will_qos: u8,                 // QoS of the will message, bits 3-4 of the connect flags
+    pub will_retain: bool,            // Retain flag of the will message, bit 5 of the connect flags
     pub username: Option<String>,     // Username for authentication (optional)
     pub password: Option<String>,     // Password for authentication (optional)
-    
+    pub will_properties: Option<WillProperties>, // Will properties, only sent by MQTT 5 clients with a will
+    pub properties: ConnectProperties, // Connect properties, only part of MQTT 5 packets
+}
+
+/// Properties of the will message, placed before the will topic in MQTT 5
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct WillProperties {
+    pub will_delay_interval: Option<u32>,     // Seconds to wait before publishing the will
+    pub payload_format_indicator: Option<u8>, // 1 if the will message is UTF-8 text
+    pub message_expiry_interval: Option<u32>, // Lifetime of the will message in seconds
+    pub content_type: Option<String>,         // MIME type of the will message
+    pub response_topic: Option<String>,       // Topic for a response to the will message
+    pub correlation_data: Option<Vec<u8>>,    // Data to match the response with the will
+    pub user_properties: Vec<(String, String)>, // Name-value pairs defined by the application
+}
+
+impl WillProperties {
+    /// Encodes the properties without their length
+    fn encode(&self) -> Vec<u8> {
+        let mut properties = Vec::new();
+
+        if let Some(delay) = self.will_delay_interval {
+            properties.push(0x18); // Property identifier for will delay interval
+            properties.write_u32::<BigEndian>(delay).unwrap();
+        }
+
+        if let Some(format) = self.payload_format_indicator {
+            properties.push(0x01); // Property identifier for payload format indicator
+            properties.push(format);
+        }
+
+        if let Some(expiry) = self.message_expiry_interval {
+            properties.push(0x02); // Property identifier for message expiry interval
+            properties.write_u32::<BigEndian>(expiry).unwrap();
+        }
+
+        if let Some(ref content_type) = self.content_type {
+            properties.push(0x03); // Property identifier for content type
+            write_binary(&mut properties, content_type.as_bytes());
+        }
+
+        if let Some(ref response_topic) = self.response_topic {
+            properties.push(0x08); // Property identifier for response topic
+            write_binary(&mut properties, response_topic.as_bytes());
+        }
+
+        if let Some(ref correlation_data) = self.correlation_data {
+            properties.push(0x09); // Property identifier for correlation data
+            write_binary(&mut properties, correlation_data);
+        }
+
+        for (name, value) in &self.user_properties {
+            properties.push(0x26); // Property identifier for user property
+            write_binary(&mut properties, name.as_bytes());
+            write_binary(&mut properties, value.as_bytes());
+        }
+
+        properties
+    }
+
+    /// Decodes the properties block, length included, at the cursor position
+    fn decode(cursor: &mut std::io::Cursor<&[u8]>) -> Result<Self, DecodeError> {
+        let properties_len = read_variable_length(cursor)?;
+        let end = cursor.position() + properties_len as u64;
+        let mut properties = WillProperties::default();
+
+        while cursor.position() < end {
+            let identifier = cursor.read_u8()?;
+            match identifier {
+                0x18 => properties.will_delay_interval = Some(cursor.read_u32::<BigEndian>()?),
+                0x01 => properties.payload_format_indicator = Some(cursor.read_u8()?),
+                0x02 => properties.message_expiry_interval = Some(cursor.read_u32::<BigEndian>()?),
+                0x03 => properties.content_type = Some(read_string(cursor)?),
+                0x08 => properties.response_topic = Some(read_string(cursor)?),
+                0x09 => properties.correlation_data = Some(read_binary(cursor)?),
+                0x26 => {
+                    let name = read_string(cursor)?;
+                    let value = read_string(cursor)?;
+                    properties.user_properties.push((name, value));
+                }
+                _ => return Err(DecodeError::UnsupportedProperty(identifier)),
+            }
+        }
+
+        if cursor.position() != end {
+            return Err(DecodeError::Malformed("will properties overrun their length".to_string()));
+        }
+
+        Ok(properties)
+    }
+}
+
+/// Properties of an MQTT 5 CONNECT, each one is optional
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ConnectProperties {
+    pub session_expiry_interval: Option<u32>,     // Seconds the session is kept after the connection closes
+    pub receive_maximum: Option<u16>,             // QoS 1 and QoS 2 publishes the client accepts unacknowledged
+    pub maximum_packet_size: Option<u32>,         // Largest packet the client accepts, in bytes
+    pub topic_alias_maximum: Option<u16>,         // Highest topic alias the client accepts
+    pub request_response_information: Option<u8>, // 1 if the CONNACK may carry response information
+    pub request_problem_information: Option<u8>,  // 0 if reason strings may only be sent in a CONNACK or DISCONNECT
+    pub user_properties: Vec<(String, String)>,   // Name-value pairs defined by the application
+    pub authentication_method: Option<String>,    // Name of the extended authentication method
+    pub authentication_data: Option<Vec<u8>>,     // Data of the extended authentication
+}
+
+impl ConnectProperties {
+    /// Encodes the properties without their length
+    fn encode(&self) -> Vec<u8> {
+        let mut properties = Vec::new();
+
+        if let Some(interval) = self.session_expiry_interval {
+            properties.push(0x11); // Property identifier for session expiry interval
+            properties.write_u32::<BigEndian>(interval).unwrap();
+        }
+
+        if let Some(maximum) = self.receive_maximum {
+            properties.push(0x21); // Property identifier for receive maximum
+            properties.write_u16::<BigEndian>(maximum).unwrap();
+        }
+
+        if let Some(size) = self.maximum_packet_size {
+            properties.push(0x27); // Property identifier for maximum packet size
+            properties.write_u32::<BigEndian>(size).unwrap();
+        }
+
+        if let Some(maximum) = self.topic_alias_maximum {
+            properties.push(0x22); // Property identifier for topic alias maximum
+            properties.write_u16::<BigEndian>(maximum).unwrap();
+        }
+
+        if let Some(request) = self.request_response_information {
+            properties.push(0x19); // Property identifier for request response information
+            properties.push(request);
+        }
+
+        if let Some(request) = self.request_problem_information {
+            properties.push(0x17); // Property identifier for request problem information
+            properties.push(request);
+        }
+
+        for (name, value) in &self.user_properties {
+            properties.push(0x26); // Property identifier for user property
+            write_binary(&mut properties, name.as_bytes());
+            write_binary(&mut properties, value.as_bytes());
+        }
+
+        if let Some(ref method) = self.authentication_method {
+            properties.push(0x15); // Property identifier for authentication method
+            write_binary(&mut properties, method.as_bytes());
+        }
+
+        if let Some(ref data) = self.authentication_data {
+            properties.push(0x16); // Property identifier for authentication data
+            write_binary(&mut properties, data);
+        }
+
+        properties
+    }
+
+    /// Decodes the properties block, length included, at the cursor position
+    fn decode(cursor: &mut std::io::Cursor<&[u8]>) -> Result<Self, DecodeError> {
+        let properties_len = read_variable_length(cursor)?;
+        let end = cursor.position() + properties_len as u64;
+        let mut properties = ConnectProperties::default();
+
+        while cursor.position() < end {
+            let identifier = cursor.read_u8()?;
+            match identifier {
+                0x11 => properties.session_expiry_interval = Some(cursor.read_u32::<BigEndian>()?),
+                0x21 => {
+                    // A client that accepts no publish at all could never be sent one
+                    let maximum = cursor.read_u16::<BigEndian>()?;
+                    if maximum == 0 {
+                        return Err(DecodeError::ProtocolError("receive maximum of 0".to_string()));
+                    }
+                    properties.receive_maximum = Some(maximum);
+                }
+                0x27 => properties.maximum_packet_size = Some(cursor.read_u32::<BigEndian>()?),
+                0x22 => properties.topic_alias_maximum = Some(cursor.read_u16::<BigEndian>()?),
+                0x19 => properties.request_response_information = Some(cursor.read_u8()?),
+                0x17 => properties.request_problem_information = Some(cursor.read_u8()?),
+                0x26 => {
+                    let name = read_string(cursor)?;
+                    let value = read_string(cursor)?;
+                    properties.user_properties.push((name, value));
+                }
+                0x15 => properties.authentication_method = Some(read_string(cursor)?),
+                0x16 => properties.authentication_data = Some(read_binary(cursor)?),
+                _ => return Err(DecodeError::UnsupportedProperty(identifier)),
+            }
+        }
+
+        if cursor.position() != end {
+            return Err(DecodeError::Malformed("connect properties overrun their length".to_string()));
+        }
+
+        Ok(properties)
+    }
 }
 
 impl ConnectPacket {
     // Constructor for a ConnectPacket, with all fields as parameters
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         protocol_name: String,
         protocol_level: u8,
@@ -40,7 +242,7 @@ impl ConnectPacket {
         keep_alive: u16,
         client_id: String,
         will_topic: Option<String>,
-        will_message: Option<String>,
+        will_message: Option<Vec<u8>>,
         username: Option<String>,
         password: Option<String>,
     ) -> Self {
@@ -52,11 +254,39 @@ impl ConnectPacket {
             client_id,
             will_topic,
             will_message,
+            will_qos: (connect_flags >> 3) & 0x03,
+            will_retain: connect_flags & 0x20 != 0,
             username,
             password,
+            will_properties: None,
+            properties: ConnectProperties::default(),
         }
     }
 
+    /// Sets a will with a text message, the common case of a binary will message,
+    /// and the will flag of the connect flags
+    pub fn with_text_will(mut self, will_topic: &str, will_message: &str) -> Self {
+        self.connect_flags |= 0x04;
+        self.will_topic = Some(will_topic.to_string());
+        self.will_message = Some(will_message.as_bytes().to_vec());
+        self
+    }
+
+    /// Returns the connect flags with the will QoS and retain bits taken from their
+    /// fields, when the packet carries a will
+    fn encoded_connect_flags(&self) -> u8 {
+        if self.connect_flags & 0x04 == 0 {
+            return self.connect_flags;
+        }
+        (self.connect_flags & !0x38) | (self.will_qos & 0x03) << 3 | (self.will_retain as u8) << 5
+    }
+
+    /// Returns true if the will properties block is part of the packet, which is
+    /// the case for MQTT 5 packets that carry a will
+    fn has_will_properties(&self) -> bool {
+        self.protocol_level == 5 && self.connect_flags & 0x04 != 0
+    }
+
     /// Encodes the Connect packet into bytes to send to the broker.
     pub fn encode(&self) -> Vec<u8> {
         let mut packet = Vec::new();
@@ -65,26 +295,44 @@ impl ConnectPacket {
         packet.push(0x10);
 
         // Variable header length calculation
-        let mut remaining_length = 2 + self.protocol_name.len() as u16 + 1 // Protocol name & protocol level
+        let mut remaining_length = 2 + self.protocol_name.len() + 1 // Protocol name & protocol level
             + 1 // Connect flags byte
             + 2 // Keep alive
             + 2 // Client ID len field
-            + self.client_id.len() as u16; // Client ID
+            + self.client_id.len(); // Client ID
+
+        // Connect properties, MQTT 5 packets always have the block, even empty
+        let mut properties = Vec::new();
+        if self.protocol_level == 5 {
+            let encoded = self.properties.encode();
+            write_variable_length(&mut properties, encoded.len());
+            properties.extend(encoded);
+            remaining_length += properties.len();
+        }
+
+        // Will properties, encoded before the will topic
+        let mut will_properties = Vec::new();
+        if self.has_will_properties() {
+            let properties = self.will_properties.clone().unwrap_or_default().encode();
+            write_variable_length(&mut will_properties, properties.len());
+            will_properties.extend(properties);
+            remaining_length += will_properties.len();
+        }
 
         //Evaluates if there are some optional fields
         if let Some(ref will_topic) = self.will_topic {
             //Will topic len field + will_topic len + will message len field + will_message len
-            remaining_length += 2 + will_topic.len() as u16 + 2 + self.will_message.as_ref().unwrap().len() as u16;
+            remaining_length += 2 + will_topic.len() + 2 + self.will_message.as_ref().unwrap().len();
         }
 
         if let Some(ref username) = self.username {
             //Username len field + username len
-            remaining_length += 2 + username.len() as u16;
+            remaining_length += 2 + username.len();
         }
 
         if let Some(ref password) = self.password {
             //Password len field + password len
-            remaining_length += 2 + password.len() as u16;
+            remaining_length += 2 + password.len();
         }
 
         // Encode the remaining length with VLQ codification
@@ -118,46 +366,52 @@ impl ConnectPacket {
 
         // Protocol Name
         packet.push((self.protocol_name.len() >> 8) as u8); // Length of protocol name high byte
-        packet.push(self.protocol_name.len() as u8 & 0xFF); // Length of protocol name low byte
+        packet.push((self.protocol_name.len() & 0xFF) as u8); // Length of protocol name low byte
         packet.extend_from_slice(self.protocol_name.as_bytes());
 
         // Protocol Level (always 5 for MQTT v5.0)
         packet.push(self.protocol_level);
 
         // Connect Flags
-        packet.push(self.connect_flags);
+        packet.push(self.encoded_connect_flags());
 
         // Keep Alive
         packet.write_u16::<BigEndian>(self.keep_alive).unwrap();
 
+        // Connect Properties (MQTT 5 only)
+        packet.extend(properties);
+
         // Client ID length and value
         packet.push((self.client_id.len() >> 8) as u8); // High byte of client ID length
-        packet.push(self.client_id.len() as u8 & 0xFF); // Low byte of client ID length
+        packet.push((self.client_id.len() & 0xFF) as u8); // Low byte of client ID length
         packet.extend_from_slice(self.client_id.as_bytes());
 
+        // Will Properties (if present)
+        packet.extend(will_properties);
+
         // Will Topic and Message (if present)
         if let Some(ref will_topic) = self.will_topic {
             packet.push((will_topic.len() >> 8) as u8);
-            packet.push(will_topic.len() as u8 & 0xFF);
+            packet.push((will_topic.len() & 0xFF) as u8);
             packet.extend_from_slice(will_topic.as_bytes());
 
             let will_message = self.will_message.as_ref().unwrap();
             packet.push((will_message.len() >> 8) as u8);
-            packet.push(will_message.len() as u8 & 0xFF);
-            packet.extend_from_slice(will_message.as_bytes());
+            packet.push((will_message.len() & 0xFF) as u8);
+            packet.extend_from_slice(will_message);
         }
 
         // Username (if present)
         if let Some(ref username) = self.username {
             packet.push((username.len() >> 8) as u8);
-            packet.push(username.len() as u8 & 0xFF);
+            packet.push((username.len() & 0xFF) as u8);
             packet.extend_from_slice(username.as_bytes());
         }
 
         // Password (if present)
         if let Some(ref password) = self.password {
             packet.push((password.len() >> 8) as u8);
-            packet.push(password.len() as u8 & 0xFF);
+            packet.push((password.len() & 0xFF) as u8);
             packet.extend_from_slice(password.as_bytes());
         }
 
@@ -173,67 +427,94 @@ impl ConnectPacket {
     /// # Returns
     ///
     /// This function returns a result containing either a decoded `ConnectPacket` or an error if decoding fails.
-    pub fn decode(data: &[u8]) -> Result<Self, String> {
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
         let mut cursor = std::io::Cursor::new(data);
-        //Move two places due to the fixed header
-        cursor.set_position(2);
+        //Skip the packet type, the remaining length (VLQ) takes 1 to 4 bytes
+        cursor.read_u8()?;
+        let remaining_length = read_variable_length(&mut cursor)?;
+        let available = data.len() - cursor.position() as usize;
+        if remaining_length > available {
+            return Err(DecodeError::LengthExceeded { declared: remaining_length, available });
+        }
+        let packet_end = cursor.position() as usize + remaining_length;
  
         // Extracts the protocol name length 
-        let protocol_name_len = cursor.read_u16::<BigEndian>().map_err(|e| e.to_string())? as usize;
-        //Sets a mut vector of the size extracted
-        let mut protocol_name = vec![0; protocol_name_len];
-        //Parse the name into a string and saves it into the mut vector
-        cursor.read_exact(&mut protocol_name).map_err(|e| e.to_string())?;
-        let protocol_name = String::from_utf8(protocol_name).map_err(|e| e.to_string())?;
+        let protocol_name_len = cursor.read_u16::<BigEndian>()? as usize;
+        //Reads the name, its length is checked against the packet before allocating
+        let protocol_name = read_bytes(&mut cursor, protocol_name_len)?;
+        let protocol_name = String::from_utf8(protocol_name)?;
 
         // Extract the protocol level
-        let protocol_level = cursor.read_u8().map_err(|e| e.to_string())?;
+        let protocol_level = cursor.read_u8()?;
 
         // Extract the connect flags
-        let connect_flags = cursor.read_u8().map_err(|e| e.to_string())?;
+        let connect_flags = cursor.read_u8()?;
+        // The will QoS (bits 3-4) and will retain (bit 5) only exist along with the will flag
+        if connect_flags & 0x04 == 0 && connect_flags & 0x38 != 0 {
+            return Err(DecodeError::InvalidFlags("will QoS or retain set without the will flag".to_string()));
+        }
+        let will_qos = (connect_flags >> 3) & 0x03;
+        if will_qos == 3 {
+            return Err(DecodeError::InvalidQoS(will_qos));
+        }
+        let will_retain = connect_flags & 0x20 != 0;
 
         // Extract keep alive time
-        let keep_alive = cursor.read_u16::<BigEndian>().map_err(|e| e.to_string())?;
+        let keep_alive = cursor.read_u16::<BigEndian>()?;
+
+        // Connect properties, placed by MQTT 5 between the keep alive and the client ID
+        let properties = if protocol_level == 5 {
+            ConnectProperties::decode(&mut cursor)?
+        } else {
+            ConnectProperties::default()
+        };
 
         // Read client ID length and value
-        let client_id_len = cursor.read_u16::<BigEndian>().map_err(|e| e.to_string())? as usize;
-        let mut client_id = vec![0; client_id_len];
-        cursor.read_exact(&mut client_id).map_err(|e| e.to_string())?;
-        let client_id = String::from_utf8(client_id).map_err(|e| e.to_string())?;
+        let client_id_len = cursor.read_u16::<BigEndian>()? as usize;
+        let client_id = read_bytes(&mut cursor, client_id_len)?;
+        let client_id = String::from_utf8(client_id)?;
 
         // Parse optional fields: Will, Username, Password
         let mut will_topic = None;
         let mut will_message = None;
         let mut username = None;
         let mut password = None;
+        let mut will_properties = None;
+
+        // Will Properties, MQTT 5 places them before the will topic
+        if protocol_level == 5 && connect_flags & 0x04 != 0 {
+            will_properties = Some(WillProperties::decode(&mut cursor)?);
+        }
 
         // Will Topic and Message
         if connect_flags & 0x04 != 0 {
-            let will_topic_len = cursor.read_u16::<BigEndian>().map_err(|e| e.to_string())? as usize;
-            let mut will_topic_bytes = vec![0; will_topic_len];
-            cursor.read_exact(&mut will_topic_bytes).map_err(|e| e.to_string())?;
-            will_topic = Some(String::from_utf8(will_topic_bytes).map_err(|e| e.to_string())?);
+            let will_topic_len = cursor.read_u16::<BigEndian>()? as usize;
+            let will_topic_bytes = read_bytes(&mut cursor, will_topic_len)?;
+            will_topic = Some(String::from_utf8(will_topic_bytes)?);
 
-            let will_message_len = cursor.read_u16::<BigEndian>().map_err(|e| e.to_string())? as usize;
-            let mut will_message_bytes = vec![0; will_message_len];
-            cursor.read_exact(&mut will_message_bytes).map_err(|e| e.to_string())?;
-            will_message = Some(String::from_utf8(will_message_bytes).map_err(|e| e.to_string())?);
+            let will_message_len = cursor.read_u16::<BigEndian>()? as usize;
+            let will_message_bytes = read_bytes(&mut cursor, will_message_len)?;
+            will_message = Some(will_message_bytes);
         }
 
         // Username
         if connect_flags & 0x80 != 0 {
-            let username_len = cursor.read_u16::<BigEndian>().map_err(|e| e.to_string())? as usize;
-            let mut username_bytes = vec![0; username_len];
-            cursor.read_exact(&mut username_bytes).map_err(|e| e.to_string())?;
-            username = Some(String::from_utf8(username_bytes).map_err(|e| e.to_string())?);
+            let username_len = cursor.read_u16::<BigEndian>()? as usize;
+            let username_bytes = read_bytes(&mut cursor, username_len)?;
+            username = Some(String::from_utf8(username_bytes)?);
         }
 
         // Password
         if connect_flags & 0x40 != 0 {
-            let password_len = cursor.read_u16::<BigEndian>().map_err(|e| e.to_string())? as usize;
-            let mut password_bytes = vec![0; password_len];
-            cursor.read_exact(&mut password_bytes).map_err(|e| e.to_string())?;
-            password = Some(String::from_utf8(password_bytes).map_err(|e| e.to_string())?);
+            let password_len = cursor.read_u16::<BigEndian>()? as usize;
+            let password_bytes = read_bytes(&mut cursor, password_len)?;
+            password = Some(String::from_utf8(password_bytes)?);
+        }
+
+        // Every byte must belong to a field announced by the connect flags, bytes left
+        // over are fields whose flag is clear, such as a will without the will flag
+        if cursor.position() as usize != packet_end {
+            return Err(DecodeError::Malformed("the CONNECT fields do not match the connect flags".to_string()));
         }
 
         //Return the connect packet with the parsed information
@@ -245,8 +526,76 @@ impl ConnectPacket {
             client_id,
             will_topic,
             will_message,
+            will_qos,
+            will_retain,
             username,
             password,
+            will_properties,
+            properties,
         })
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connect_without_optional_fields() {
+        let packet = ConnectPacket::new("MQTT".to_string(), 5, 0x02, 60, "client".to_string(), None, None, None, None);
+        assert_eq!(ConnectPacket::decode(&packet.encode()).unwrap(), packet);
+
+        // The client ID may be empty, the broker then assigns one
+        let packet = ConnectPacket::new("MQTT".to_string(), 5, 0x02, 0, String::new(), None, None, None, None);
+        assert_eq!(ConnectPacket::decode(&packet.encode()).unwrap(), packet);
+    }
+
+    #[test]
+    fn connect_with_every_field() {
+        // Will flag, will QoS 1, will retain, password and username flags
+        let mut packet = ConnectPacket::new(
+            "MQTT".to_string(),
+            5,
+            0xEC,
+            30,
+            "client".to_string(),
+            Some("clients/client/status".to_string()),
+            Some(b"offline".to_vec()),
+            Some("user".to_string()),
+            Some("secret".to_string()),
+        );
+        packet.will_properties = Some(WillProperties {
+            will_delay_interval: Some(10),
+            payload_format_indicator: Some(1),
+            message_expiry_interval: Some(3600),
+            content_type: Some("text/plain".to_string()),
+            response_topic: Some("clients/client/response".to_string()),
+            correlation_data: Some(vec![0x01, 0x02, 0x03]),
+            user_properties: vec![("origin".to_string(), "test".to_string())],
+        });
+        packet.properties = ConnectProperties {
+            session_expiry_interval: Some(300),
+            receive_maximum: Some(2),
+            maximum_packet_size: Some(1 << 16),
+            topic_alias_maximum: Some(5),
+            request_response_information: Some(1),
+            request_problem_information: Some(0),
+            user_properties: vec![("region".to_string(), "eu".to_string())],
+            authentication_method: Some("SCRAM-SHA-1".to_string()),
+            authentication_data: Some(vec![0x01]),
+        };
+
+        assert_eq!(ConnectPacket::decode(&packet.encode()).unwrap(), packet);
+    }
+
+    #[test]
+    fn connect_with_a_two_byte_remaining_length() {
+        let packet = ConnectPacket::new("MQTT".to_string(), 5, 0x02, 60, "c".repeat(200), None, None, None, None);
+        let encoded = packet.encode();
+
+        // 11 bytes of variable header and 202 of client ID: 213, 0xD5 0x01 as a VLQ
+        assert_eq!(&encoded[1..3], &[0xD5, 0x01]);
+        assert_eq!(encoded.len(), 3 + 213);
+        assert_eq!(ConnectPacket::decode(&encoded).unwrap(), packet);
+    }
+}
@@ -1,36 +1,51 @@
-use std::collections::HashMap;
+//! MQTT DISCONNECT packet for MQTT version 5.0.
 
-#[derive(Debug, Clone)]
+/*
+The DISCONNECT is the last packet either side sends before closing the connection.
+Its variable header is a reason code followed by the properties, their length
+first as a Variable Length Quantity, and each property value is written in the
+type of the property: a four byte integer for the session expiry interval, length
+prefixed UTF-8 strings for the others. A Normal Disconnection without properties
+may leave out everything after the fixed header, and the property length alone may
+be left out after the reason code.
+*/
+
+use std::io::Cursor;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use super::fixed_header::{first_packet, read_variable_length, write_variable_length};
+use super::{read_string, write_string, DecodeError};
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum DisconnectReasonCode {
     NormalDisconnection = 0x00,
     DisconnectWithWillMessage = 0x04,
     /*
-    UnspecifiedError = 0x80,
+    UnspecifiedError = 0x80,*/
     MalformedPacket = 0x81,
     ProtocolError = 0x82,
     ImplementationSpecificError = 0x83,
-    NotAuthorized = 0x87,
+    /*NotAuthorized = 0x87,
     ServerBusy = 0x89,*/
     ServerShuttingDown = 0x8B,
     KeepAliveTimeout = 0x8D,
     /*SessionTakenOver = 0x8E,
-    TopicFilterInvalid = 0x8F,
+    TopicFilterInvalid = 0x8F,*/
     TopicNameInvalid = 0x90,
     ReceiveMaximumExceeded = 0x93,
     TopicAliasInvalid = 0x94,
     PacketTooLarge = 0x95,
-    MessageRateTooHigh = 0x96,
+    /*MessageRateTooHigh = 0x96,
     QuotaExceeded = 0x97,
     AdministrativeAction = 0x98,
     PayloadFormatInvalid = 0x99,
-    RetainNotSupported = 0x9A,
+    RetainNotSupported = 0x9A,*/
     QoSNotSupported = 0x9B,
-    UseAnotherServer = 0x9C,
+    /*UseAnotherServer = 0x9C,
     ServerMoved = 0x9D,
     SharedSubscriptionNotSupported = 0x9E,
-    ConnectionRateExceeded = 0x9F,
+    ConnectionRateExceeded = 0x9F,*/
     MaximumConnectTime = 0xA0,
-    SubscriptionIdentifiersNotSupported = 0xA1,
+    /*SubscriptionIdentifiersNotSupported = 0xA1,
     WildcardSubscriptionsNotSupported = 0xA2,*/
 }
 
@@ -39,18 +54,36 @@ impl DisconnectReasonCode {
         match value {
             0x00 => Some(DisconnectReasonCode::NormalDisconnection),
             0x04 => Some(DisconnectReasonCode::DisconnectWithWillMessage),
+            0x81 => Some(DisconnectReasonCode::MalformedPacket),
+            0x82 => Some(DisconnectReasonCode::ProtocolError),
+            0x83 => Some(DisconnectReasonCode::ImplementationSpecificError),
             0x8B => Some(DisconnectReasonCode::ServerShuttingDown),
             0x8D => Some(DisconnectReasonCode::KeepAliveTimeout),
+            0x90 => Some(DisconnectReasonCode::TopicNameInvalid),
+            0x93 => Some(DisconnectReasonCode::ReceiveMaximumExceeded),
+            0x94 => Some(DisconnectReasonCode::TopicAliasInvalid),
+            0x95 => Some(DisconnectReasonCode::PacketTooLarge),
+            0x9B => Some(DisconnectReasonCode::QoSNotSupported),
+            0xA0 => Some(DisconnectReasonCode::MaximumConnectTime),
             //Future cases ...
             _ => None,
         }
     }
 }
 
-#[derive(Debug)]
+// Property identifiers allowed in a DISCONNECT
+const SESSION_EXPIRY_INTERVAL: u8 = 0x11;
+const REASON_STRING: u8 = 0x1F;
+const SERVER_REFERENCE: u8 = 0x1C;
+const USER_PROPERTY: u8 = 0x26;
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct DisconnectPacket {
     reason_code: DisconnectReasonCode,
-    properties: HashMap<u8, Vec<u8>>, // Key-value properties
+    pub session_expiry_interval: Option<u32>,   // New expiry of the session, only a client sends it
+    pub reason_string: Option<String>,          // Human-readable reason of the disconnection
+    pub server_reference: Option<String>,       // Server the client should use instead
+    pub user_properties: Vec<(String, String)>, // Name and value pairs, in order
 }
 
 impl DisconnectPacket {
@@ -58,82 +91,173 @@ impl DisconnectPacket {
     pub fn new(reason_code: DisconnectReasonCode) -> Self {
         Self {
             reason_code,
-            properties: HashMap::new(),
+            session_expiry_interval: None,
+            reason_string: None,
+            server_reference: None,
+            user_properties: Vec::new(),
         }
     }
 
-    /// Add a property to the disconnect packet
-    pub fn add_property(&mut self, property_identifier: u8, value: Vec<u8>) {
-        self.properties.insert(property_identifier, value);
+    /// Returns why the sender closes the connection
+    pub fn reason_code(&self) -> &DisconnectReasonCode {
+        &self.reason_code
+    }
+
+    /// Returns the session expiry interval the client sets for its session, in seconds,
+    /// replacing the one of its CONNECT. Only a client may send it.
+    pub fn session_expiry_interval(&self) -> Option<u32> {
+        self.session_expiry_interval
+    }
+
+    /// Sets the session expiry interval property, in seconds
+    pub fn set_session_expiry_interval(&mut self, interval: u32) {
+        self.session_expiry_interval = Some(interval);
     }
 
     /// Encode the disconnect packet into bytes
     pub fn encode(&self) -> Vec<u8> {
-        let mut buffer = vec![];
-
-        // Fixed header
-        buffer.push(0xE0); // Disconnect packet type and flags
-        let variable_header_len = 1 + self.properties.iter().map(|(_k, v)| 1 + v.len()).sum::<usize>();
-        buffer.push(variable_header_len as u8);
+        // Properties, each identifier followed by its value in the type of the property
+        let mut properties = Vec::new();
+        if let Some(interval) = self.session_expiry_interval {
+            properties.push(SESSION_EXPIRY_INTERVAL);
+            properties.write_u32::<BigEndian>(interval).unwrap();
+        }
+        if let Some(ref reason) = self.reason_string {
+            properties.push(REASON_STRING);
+            write_string(&mut properties, reason);
+        }
+        if let Some(ref server_reference) = self.server_reference {
+            properties.push(SERVER_REFERENCE);
+            write_string(&mut properties, server_reference);
+        }
+        for (name, value) in &self.user_properties {
+            properties.push(USER_PROPERTY);
+            write_string(&mut properties, name);
+            write_string(&mut properties, value);
+        }
 
-        // Variable header
-        buffer.push(self.reason_code.clone() as u8);
+        // Variable header: reason code and the property length (VLQ)
+        let mut variable_header = vec![self.reason_code.clone() as u8];
+        write_variable_length(&mut variable_header, properties.len());
+        variable_header.extend(properties);
 
-        // Properties
-        for (key, value) in &self.properties {
-            buffer.push(*key);
-            buffer.extend(value);
-        }
+        // Fixed header: Disconnect packet type and flags, remaining length (VLQ)
+        let mut buffer = vec![0xE0];
+        write_variable_length(&mut buffer, variable_header.len());
+        buffer.extend(variable_header);
 
         buffer
     }
 
     /// Decode a disconnect packet from a byte slice
-    pub fn decode(packet: &[u8]) -> Result<Self, &'static str> {
-        if packet.len() < 3 {
-            return Err("Packet too short to decode");
-        }
+    pub fn decode(packet: &[u8]) -> Result<Self, DecodeError> {
+        // Bytes after the remaining length belong to the next packet
+        let packet = first_packet(packet)?;
+        let mut cursor = Cursor::new(packet);
 
-        let mut index = 1; // Skip the fixed header byte
+        // Skip the packet type and the remaining length (VLQ)
+        cursor.read_u8()?;
+        let remaining_length = read_variable_length(&mut cursor)?;
 
-        // Get the length of the variable header
-        let variable_header_len = packet[index] as usize;
-        if packet.len() < variable_header_len + 2 {
-            return Err("Packet length mismatch");
+        // The minimal DISCONNECT is the fixed header alone, a Normal Disconnection without properties
+        if remaining_length == 0 {
+            return Ok(DisconnectPacket::new(DisconnectReasonCode::NormalDisconnection));
         }
-        index += 1; // Move to the reason code
 
-        // Extract the reason code (1 byte)
-        let reason_code_value = packet[index];
+        let reason_code_value = cursor.read_u8()?;
         let reason_code = DisconnectReasonCode::from_u8(reason_code_value)
-            .ok_or("Invalid reason code")?;
-        index += 1; // Move to properties
-
-        // Extract properties
-        let mut properties = HashMap::new();
-        while index < packet.len() {
-            if index + 1 >= packet.len() {
-                return Err("Property length missing");
-            }
+            .ok_or(DecodeError::InvalidReasonCode(reason_code_value))?;
+        let mut disconnect = DisconnectPacket::new(reason_code);
 
-            let property_identifier = packet[index];
-            index += 1;
+        // The property length may be left out along with the properties
+        if remaining_length == 1 {
+            return Ok(disconnect);
+        }
 
-            let property_length = packet[index] as usize;
-            index += 1;
+        // The properties take the rest of the packet
+        let properties_length = read_variable_length(&mut cursor)?;
+        let available = packet.len() - cursor.position() as usize;
+        if properties_length > available {
+            return Err(DecodeError::LengthExceeded { declared: properties_length, available });
+        }
+        if properties_length < available {
+            return Err(DecodeError::Malformed(format!("{} bytes after the properties", available - properties_length)));
+        }
 
-            if index + property_length > packet.len() {
-                return Err("Property data out of bounds");
+        while (cursor.position() as usize) < packet.len() {
+            let identifier = cursor.read_u8()?;
+            match identifier {
+                SESSION_EXPIRY_INTERVAL => disconnect.session_expiry_interval = Some(cursor.read_u32::<BigEndian>()?),
+                REASON_STRING => disconnect.reason_string = Some(read_string(&mut cursor)?),
+                SERVER_REFERENCE => disconnect.server_reference = Some(read_string(&mut cursor)?),
+                USER_PROPERTY => {
+                    let name = read_string(&mut cursor)?;
+                    disconnect.user_properties.push((name, read_string(&mut cursor)?));
+                }
+                _ => return Err(DecodeError::UnsupportedProperty(identifier)),
             }
-
-            let property_value = packet[index..index + property_length].to_vec();
-            properties.insert(property_identifier, property_value);
-            index += property_length;
         }
 
-        Ok(DisconnectPacket {
-            reason_code,
-            properties,
-        })
+        Ok(disconnect)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disconnect_without_and_with_properties() {
+        let packet = DisconnectPacket::new(DisconnectReasonCode::NormalDisconnection);
+        assert_eq!(packet.encode(), vec![0xE0, 0x02, 0x00, 0x00]);
+        assert_eq!(DisconnectPacket::decode(&packet.encode()).unwrap(), packet);
+
+        let mut packet = DisconnectPacket::new(DisconnectReasonCode::ServerShuttingDown);
+        packet.set_session_expiry_interval(60);
+        packet.reason_string = Some("maintenance".to_string());
+        packet.server_reference = Some("other.example:1883".to_string());
+        packet.user_properties = vec![("region".to_string(), "eu".to_string()), ("region".to_string(), "us".to_string())];
+        assert_eq!(DisconnectPacket::decode(&packet.encode()).unwrap(), packet);
+    }
+
+    #[test]
+    fn disconnect_in_the_layout_of_the_specification() {
+        // Reason code and an empty property block
+        let packet = DisconnectPacket::decode(&[0xE0, 0x02, 0x00, 0x00]).unwrap();
+        assert_eq!(packet, DisconnectPacket::new(DisconnectReasonCode::NormalDisconnection));
+
+        // Fixed header alone, and reason code without the property length
+        assert_eq!(DisconnectPacket::decode(&[0xE0, 0x00]).unwrap(), DisconnectPacket::new(DisconnectReasonCode::NormalDisconnection));
+        assert_eq!(DisconnectPacket::decode(&[0xE0, 0x01, 0x8B]).unwrap(), DisconnectPacket::new(DisconnectReasonCode::ServerShuttingDown));
+
+        // Reason string with no length byte between the identifier and the string
+        let packet = DisconnectPacket::decode(&[0xE0, 0x08, 0x8B, 0x06, 0x1F, 0x00, 0x03, b'b', b'y', b'e']).unwrap();
+        assert_eq!(packet.reason_string.as_deref(), Some("bye"));
+
+        // Property length longer than the packet
+        assert!(matches!(
+            DisconnectPacket::decode(&[0xE0, 0x03, 0x00, 0x05, 0x11]),
+            Err(DecodeError::LengthExceeded { declared: 5, available: 1 })
+        ));
+    }
+
+    #[test]
+    fn minimal_disconnect_is_a_normal_disconnection() {
+        let packet = DisconnectPacket::decode(&[0xE0, 0x00]).unwrap();
+        assert!(matches!(packet.reason_code(), DisconnectReasonCode::NormalDisconnection));
+        assert_eq!(packet.session_expiry_interval(), None);
+        assert_eq!(packet.reason_string, None);
+        assert_eq!(packet.server_reference, None);
+        assert!(packet.user_properties.is_empty());
+    }
+
+    #[test]
+    fn disconnect_with_a_multi_byte_remaining_length() {
+        let mut packet = DisconnectPacket::new(DisconnectReasonCode::NormalDisconnection);
+        packet.reason_string = Some("x".repeat(200));
+        let encoded = packet.encode();
+        // 1 reason code + 2 property length + 3 + 200 of the reason string
+        assert_eq!(&encoded[1..3], &[0xCE, 0x01]);
+        assert_eq!(DisconnectPacket::decode(&encoded).unwrap(), packet);
+    }
+}
@@ -0,0 +1,112 @@
+//! Errors returned by the packet decoders and encoders.
+
+/*
+Every decoder returns a DecodeError, so callers can tell a truncated packet from
+one with invalid flags or one that breaks a protocol rule, and answer each with
+the right reason code. The reads of the cursors over the packet bytes can only
+fail by running out of bytes, so an io::Error becomes UnexpectedEof.
+
+The encoders only fail for a packet the protocol does not allow to send, they
+return an EncodeError instead of writing bytes the peer would have to reject.
+*/
+
+use std::fmt;
+use std::io;
+use std::string::FromUtf8Error;
+use super::disconnect::DisconnectReasonCode;
+use super::fixed_header::PacketType;
+
+/// Reason a packet could not be decoded
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    UnexpectedEof,                                        // The packet ends in the middle of a field
+    InvalidPacketType { got: u8 },                        // The first byte is not of the packet type decoded
+    InvalidUtf8,                                          // A string is not valid UTF-8
+    MalformedRemainingLength,                             // A variable length takes more than 4 bytes
+    InvalidFlags(String),                                 // Flags or reserved bits set to a forbidden value
+    LengthExceeded { declared: usize, available: usize }, // A declared length goes past the end of the packet
+    InvalidQoS(u8),                                       // QoS level 3, which does not exist
+    InvalidReasonCode(u8),                                // Reason code not defined for the packet
+    UnsupportedProperty(u8),                              // Property identifier not allowed in the packet
+    InvalidTopic(String),                                 // Topic with characters it may not contain
+    ProtocolError(String),                                // Well formed, but breaks a rule of the protocol
+    Malformed(String),                                    // Fields that contradict each other
+    PacketTooLarge(usize),                                // Packet of more bytes than the maximum packet size
+}
+
+impl DecodeError {
+    /// Reason code of the DISCONNECT that closes a connection sending the packet
+    pub fn disconnect_reason(&self) -> DisconnectReasonCode {
+        match self {
+            DecodeError::ProtocolError(_) => DisconnectReasonCode::ProtocolError,
+            DecodeError::InvalidTopic(_) => DisconnectReasonCode::TopicNameInvalid,
+            DecodeError::PacketTooLarge(_) => DisconnectReasonCode::PacketTooLarge,
+            _ => DisconnectReasonCode::MalformedPacket,
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEof => write!(f, "Packet ends before the end of its fields"),
+            DecodeError::InvalidPacketType { got } => write!(f, "Invalid packet type: 0x{:02x}", got),
+            DecodeError::InvalidUtf8 => write!(f, "String is not valid UTF-8"),
+            DecodeError::MalformedRemainingLength => write!(f, "Malformed variable length"),
+            DecodeError::InvalidFlags(reason) => write!(f, "Invalid flags: {}", reason),
+            DecodeError::LengthExceeded { declared, available } => {
+                write!(f, "Declared length {} exceeds the {} bytes left in the packet", declared, available)
+            }
+            DecodeError::InvalidQoS(qos) => write!(f, "Invalid QoS: {}", qos),
+            DecodeError::InvalidReasonCode(code) => write!(f, "Unknown reason code: 0x{:02x}", code),
+            DecodeError::UnsupportedProperty(identifier) => write!(f, "Unsupported property identifier: 0x{:02x}", identifier),
+            DecodeError::InvalidTopic(topic) => write!(f, "Invalid topic: {:?}", topic),
+            DecodeError::ProtocolError(reason) => write!(f, "Protocol error: {}", reason),
+            DecodeError::Malformed(reason) => write!(f, "Malformed packet: {}", reason),
+            DecodeError::PacketTooLarge(size) => write!(f, "Packet of {} bytes exceeds the maximum packet size", size),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<io::Error> for DecodeError {
+    fn from(_: io::Error) -> Self {
+        DecodeError::UnexpectedEof
+    }
+}
+
+impl From<FromUtf8Error> for DecodeError {
+    fn from(_: FromUtf8Error) -> Self {
+        DecodeError::InvalidUtf8
+    }
+}
+
+/// Reason a packet could not be encoded
+#[derive(Debug, Clone, PartialEq)]
+pub enum EncodeError {
+    ZeroPacketId(PacketType), // Packet ID 0 in a packet of a type that requires a non-zero one
+    TopicTooLong(usize),      // Topic name of more bytes than its 2-byte length can hold
+    InvalidTopic(String),     // Topic name with wildcards or a null character
+    DupOnQoS0,                // DUP flag set on a QoS 0 PUBLISH, which is never sent again
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::ZeroPacketId(packet_type) => write!(f, "{:?} packet with packet ID 0", packet_type),
+            EncodeError::TopicTooLong(len) => write!(f, "Topic name of {} bytes, longer than {}", len, u16::MAX),
+            EncodeError::InvalidTopic(topic) => write!(f, "Invalid topic name: {}", topic),
+            EncodeError::DupOnQoS0 => write!(f, "DUP flag set on a QoS 0 PUBLISH"),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+// Code writing to a connection reports a packet it cannot encode as invalid input
+impl From<EncodeError> for io::Error {
+    fn from(e: EncodeError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+}
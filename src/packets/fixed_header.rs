@@ -0,0 +1,285 @@
+//! MQTT fixed header parsing shared by every packet type.
+
+/*
+Every MQTT packet starts with a fixed header: one byte holding the packet type
+(upper 4 bits) and its flags (lower 4 bits), followed by the remaining length
+encoded as a Variable Length Quantity of 1 to 4 bytes.
+*/
+
+use super::DecodeError;
+
+/// MQTT control packet types, as stored in the upper 4 bits of the first byte
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PacketType {
+    Connect = 1,
+    ConnAck = 2,
+    Publish = 3,
+    PubAck = 4,
+    PubRec = 5,
+    PubRel = 6,
+    PubComp = 7,
+    Subscribe = 8,
+    SubAck = 9,
+    Unsubscribe = 10,
+    UnsubAck = 11,
+    PingReq = 12,
+    PingResp = 13,
+    Disconnect = 14,
+    Auth = 15,
+}
+
+impl PacketType {
+    /// Decodes a packet type from the upper 4 bits of the first byte.
+    pub fn from_u8(value: u8) -> Result<Self, DecodeError> {
+        match value {
+            1 => Ok(PacketType::Connect),
+            2 => Ok(PacketType::ConnAck),
+            3 => Ok(PacketType::Publish),
+            4 => Ok(PacketType::PubAck),
+            5 => Ok(PacketType::PubRec),
+            6 => Ok(PacketType::PubRel),
+            7 => Ok(PacketType::PubComp),
+            8 => Ok(PacketType::Subscribe),
+            9 => Ok(PacketType::SubAck),
+            10 => Ok(PacketType::Unsubscribe),
+            11 => Ok(PacketType::UnsubAck),
+            12 => Ok(PacketType::PingReq),
+            13 => Ok(PacketType::PingResp),
+            14 => Ok(PacketType::Disconnect),
+            15 => Ok(PacketType::Auth),
+            _ => Err(DecodeError::InvalidPacketType { got: value }),
+        }
+    }
+}
+
+/// The decoded fixed header of a packet
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct FixedHeader {
+    pub packet_type: PacketType, // Type of the packet
+    pub flags: u8,               // Lower 4 bits of the first byte
+    pub remaining_length: usize, // Length of the variable header and the payload
+    pub header_len: usize,       // Bytes taken by the fixed header itself (2 to 5)
+}
+
+impl FixedHeader {
+    /// Total length of the packet, fixed header included
+    pub fn packet_len(&self) -> usize {
+        self.header_len + self.remaining_length
+    }
+}
+
+/// Parses the fixed header at the start of a byte slice.
+///
+/// # Arguments
+///
+/// * `data` - The bytes of the packet, only the fixed header needs to be present.
+///
+/// # Returns
+///
+/// The packet type, flags, remaining length and header length, or an error if the
+/// header is incomplete, the packet type is invalid or the remaining length takes
+/// more than 4 bytes.
+pub fn parse_fixed_header(data: &[u8]) -> Result<FixedHeader, DecodeError> {
+    let first_byte = *data.first().ok_or(DecodeError::UnexpectedEof)?;
+    let packet_type = PacketType::from_u8(first_byte >> 4)
+        .map_err(|_| DecodeError::InvalidPacketType { got: first_byte })?;
+    let flags = first_byte & 0x0F;
+
+    // Decode the remaining length in VLQ, at most 4 bytes
+    let mut remaining_length = 0usize;
+    let mut multiplier = 1usize;
+    let mut index = 1;
+    loop {
+        if index > 4 {
+            return Err(DecodeError::MalformedRemainingLength);
+        }
+        let byte = *data.get(index).ok_or(DecodeError::UnexpectedEof)?;
+        remaining_length += (byte & 0x7F) as usize * multiplier;
+        multiplier *= 128;
+        index += 1;
+        if (byte & 0x80) == 0 {
+            break;
+        }
+    }
+
+    Ok(FixedHeader {
+        packet_type,
+        flags,
+        remaining_length,
+        header_len: index,
+    })
+}
+
+/// Checks that the buffer holds exactly one whole packet with a valid fixed header,
+/// without decoding its fields. Cheap enough to reject garbage before a full decode.
+///
+/// # Returns
+///
+/// An error if the header is invalid, the flags are not the ones the packet type
+/// requires, the remaining length is too short for the packet type, or the buffer
+/// ends before the packet or goes on after it.
+pub fn validate_packet(data: &[u8]) -> Result<(), DecodeError> {
+    let header = parse_fixed_header(data)?;
+
+    let packet_len = header.packet_len();
+    if data.len() < packet_len {
+        return Err(DecodeError::LengthExceeded { declared: packet_len, available: data.len() });
+    }
+    if data.len() > packet_len {
+        return Err(DecodeError::Malformed(format!("{} bytes after the end of the packet", data.len() - packet_len)));
+    }
+
+    // Only PUBLISH uses its flags, PUBREL, SUBSCRIBE and UNSUBSCRIBE require 0b0010
+    match header.packet_type {
+        PacketType::Publish => {
+            if header.flags & 0x06 == 0x06 {
+                return Err(DecodeError::InvalidQoS(3));
+            }
+        }
+        PacketType::PubRel | PacketType::Subscribe | PacketType::Unsubscribe => {
+            if header.flags != 0x02 {
+                return Err(DecodeError::InvalidFlags(format!("fixed header flags 0x{:x}, expected 0x2", header.flags)));
+            }
+        }
+        _ => {
+            if header.flags != 0 {
+                return Err(DecodeError::InvalidFlags(format!("fixed header flags 0x{:x}, expected 0x0", header.flags)));
+            }
+        }
+    }
+
+    // Smallest remaining length of each packet type: the packet ID of the acknowledgements,
+    // a packet ID and a topic filter for SUBSCRIBE and UNSUBSCRIBE, a topic length for PUBLISH
+    let minimum = match header.packet_type {
+        PacketType::Connect => 10,
+        PacketType::ConnAck => 2,
+        PacketType::Publish => 2,
+        PacketType::PubAck | PacketType::PubRec | PacketType::PubRel | PacketType::PubComp => 2,
+        PacketType::Subscribe => 6,
+        PacketType::SubAck | PacketType::UnsubAck => 3,
+        PacketType::Unsubscribe => 5,
+        PacketType::PingReq | PacketType::PingResp | PacketType::Disconnect | PacketType::Auth => 0,
+    };
+    if header.remaining_length < minimum {
+        return Err(DecodeError::Malformed(format!(
+            "remaining length {} of {:?} below its minimum of {}",
+            header.remaining_length, header.packet_type, minimum
+        )));
+    }
+    // PINGREQ and PINGRESP have no variable header nor payload
+    if matches!(header.packet_type, PacketType::PingReq | PacketType::PingResp) && header.remaining_length != 0 {
+        return Err(DecodeError::Malformed(format!("{:?} with a remaining length of {}", header.packet_type, header.remaining_length)));
+    }
+
+    Ok(())
+}
+
+/// Appends a length encoded as a Variable Length Quantity, as used for the remaining
+/// length and the property lengths.
+pub fn write_variable_length(buffer: &mut Vec<u8>, mut length: usize) {
+    loop {
+        // Takes the 7 less significant bits, the most significant one tells if more bytes follow
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        buffer.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+}
+
+/// Reads a Variable Length Quantity of at most 4 bytes at the cursor position.
+pub fn read_variable_length(cursor: &mut std::io::Cursor<&[u8]>) -> Result<usize, DecodeError> {
+    use byteorder::ReadBytesExt;
+
+    let mut multiplier = 1;
+    let mut value = 0;
+
+    for _ in 0..4 {
+        let byte = cursor.read_u8()?;
+        value += (byte & 0x7F) as usize * multiplier;
+        if (byte & 0x80) == 0 {
+            return Ok(value);
+        }
+        multiplier *= 128;
+    }
+
+    Err(DecodeError::MalformedRemainingLength)
+}
+
+/// Returns the bytes of the first packet in the buffer, as delimited by the remaining
+/// length of its fixed header. Anything after it belongs to the next packet and is
+/// left out, so a decoder never reads past the packet it decodes.
+pub fn first_packet(data: &[u8]) -> Result<&[u8], DecodeError> {
+    let packet_len = parse_fixed_header(data)?.packet_len();
+    data.get(..packet_len)
+        .ok_or(DecodeError::LengthExceeded { declared: packet_len, available: data.len() })
+}
+
+/// Reads `len` bytes at the cursor position. The length comes from the packet itself,
+/// so it is checked against the bytes left before allocating the buffer.
+pub fn read_bytes(cursor: &mut std::io::Cursor<&[u8]>, len: usize) -> Result<Vec<u8>, DecodeError> {
+    use std::io::Read;
+
+    let available = cursor.get_ref().len().saturating_sub(cursor.position() as usize);
+    if len > available {
+        return Err(DecodeError::LengthExceeded { declared: len, available });
+    }
+
+    let mut data = vec![0; len];
+    cursor.read_exact(&mut data)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_byte_remaining_length() {
+        let header = parse_fixed_header(&[0x32, 0x7F]).unwrap();
+        assert_eq!(header.packet_type, PacketType::Publish);
+        assert_eq!(header.flags, 0x02);
+        assert_eq!(header.remaining_length, 127);
+        assert_eq!(header.header_len, 2);
+
+        let header = parse_fixed_header(&[0xC0, 0x00]).unwrap();
+        assert_eq!((header.packet_type, header.remaining_length, header.packet_len()), (PacketType::PingReq, 0, 2));
+    }
+
+    #[test]
+    fn multi_byte_remaining_length() {
+        // 128 and 16 383, the smallest and largest lengths taking 2 bytes
+        assert_eq!(parse_fixed_header(&[0x30, 0x80, 0x01]).unwrap().remaining_length, 128);
+        let header = parse_fixed_header(&[0x30, 0xFF, 0x7F]).unwrap();
+        assert_eq!((header.remaining_length, header.header_len), (16_383, 3));
+
+        // 16 384 takes 3 bytes
+        let header = parse_fixed_header(&[0x30, 0x80, 0x80, 0x01]).unwrap();
+        assert_eq!((header.remaining_length, header.header_len), (16_384, 4));
+    }
+
+    #[test]
+    fn four_byte_maximum_remaining_length() {
+        let header = parse_fixed_header(&[0x30, 0xFF, 0xFF, 0xFF, 0x7F]).unwrap();
+        assert_eq!(header.remaining_length, 268_435_455);
+        assert_eq!(header.header_len, 5);
+    }
+
+    #[test]
+    fn fifth_length_byte_is_refused() {
+        let result = parse_fixed_header(&[0x30, 0xFF, 0xFF, 0xFF, 0xFF, 0x01]);
+        assert_eq!(result, Err(DecodeError::MalformedRemainingLength));
+    }
+
+    #[test]
+    fn truncated_header() {
+        assert_eq!(parse_fixed_header(&[]), Err(DecodeError::UnexpectedEof));
+        assert_eq!(parse_fixed_header(&[0x30]), Err(DecodeError::UnexpectedEof));
+        // The continuation bit announces a length byte that is missing
+        assert_eq!(parse_fixed_header(&[0x30, 0x80, 0x80]), Err(DecodeError::UnexpectedEof));
+    }
+}
@@ -0,0 +1,114 @@
+//! Splits the bytes read from a connection into whole MQTT packets.
+
+/*
+A single read may return part of a packet, one packet or several of them. The
+framer keeps the bytes read so far in a buffer that grows as needed and hands out
+one packet at a time, using the remaining length of the fixed header to know
+where each one ends. A packet larger than the maximum packet size is refused as
+soon as its fixed header is read, before its bytes are buffered.
+*/
+
+use std::fmt;
+use std::io::{self, Read};
+use super::fixed_header::parse_fixed_header;
+use super::DecodeError;
+
+/// Largest packet MQTT can frame: the maximum remaining length and a 5-byte fixed header
+pub const PROTOCOL_MAXIMUM_PACKET_SIZE: usize = 268_435_455 + 5;
+
+// Bytes requested from the connection by every read
+const READ_CHUNK: usize = 4096;
+
+/// Reason the framer could not return the next packet
+#[derive(Debug)]
+pub enum FrameError {
+    Io(io::Error),          // The read failed, a timeout included
+    Closed,                 // The peer closed the connection
+    TooLarge(usize),        // The packet of the given size exceeds the maximum packet size
+    Malformed(DecodeError), // The fixed header is invalid, the rest of the stream cannot be framed
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Io(e) => write!(f, "{}", e),
+            FrameError::Closed => write!(f, "Connection closed"),
+            FrameError::TooLarge(size) => write!(f, "Packet of {} bytes exceeds the maximum packet size", size),
+            FrameError::Malformed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Buffers the bytes of a connection and returns them packet by packet
+pub struct Framer {
+    buffer: Vec<u8>,            // Bytes read and not returned yet
+    maximum_packet_size: usize, // Largest packet accepted, fixed header included
+}
+
+impl Framer {
+    /// Creates a framer refusing the packets larger than `maximum_packet_size` bytes
+    pub fn new(maximum_packet_size: usize) -> Self {
+        Framer {
+            buffer: Vec::new(),
+            maximum_packet_size,
+        }
+    }
+
+    /// Returns the next whole packet, reading from the connection until one is buffered.
+    ///
+    /// A read that times out returns `FrameError::Io` and keeps the bytes of a partial
+    /// packet, so the next call goes on where it stopped.
+    pub fn read_packet<R: Read + ?Sized>(&mut self, reader: &mut R) -> Result<Vec<u8>, FrameError> {
+        loop {
+            if let Some(packet) = self.next_packet()? {
+                return Ok(packet);
+            }
+
+            let mut chunk = [0u8; READ_CHUNK];
+            match reader.read(&mut chunk) {
+                Ok(0) => return Err(FrameError::Closed),
+                Ok(size) => self.buffer.extend_from_slice(&chunk[..size]),
+                Err(e) => return Err(FrameError::Io(e)),
+            }
+        }
+    }
+
+    /// Appends bytes read from the connection by the caller, for readers that are not
+    /// `Read`, such as the asynchronous ones
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Returns the bytes read and not returned as a packet yet, which start with the
+    /// packet a `FrameError::TooLarge` or `FrameError::Malformed` refused
+    pub fn buffered(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// Takes the first packet out of the buffer if all of its bytes are there
+    pub fn next_packet(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        let header = match parse_fixed_header(&self.buffer) {
+            Ok(header) => header,
+            // The remaining length may continue in the bytes not read yet
+            Err(_) if self.header_incomplete() => return Ok(None),
+            Err(e) => return Err(FrameError::Malformed(e)),
+        };
+
+        let packet_len = header.packet_len();
+        if packet_len > self.maximum_packet_size {
+            return Err(FrameError::TooLarge(packet_len));
+        }
+        if self.buffer.len() < packet_len {
+            return Ok(None);
+        }
+
+        let rest = self.buffer.split_off(packet_len);
+        Ok(Some(std::mem::replace(&mut self.buffer, rest)))
+    }
+
+    // Whether the buffer ends within the fixed header: the type byte and up to 4 length
+    // bytes, every one of them with the continuation bit set
+    fn header_incomplete(&self) -> bool {
+        self.buffer.len() < 5 && self.buffer.iter().skip(1).all(|byte| byte & 0x80 != 0)
+    }
+}
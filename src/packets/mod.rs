@@ -1,8 +1,147 @@
+pub mod fixed_header;
+pub mod framer;
 pub mod connect;
 pub mod connack;
 pub mod publish;
 pub mod puback;
+pub mod qos;
+pub mod qos2;
 pub mod subscribe;
 pub mod suback;
+pub mod unsubscribe;
 pub mod ping;
-pub mod disconnect;
\ No newline at end of file
+pub mod disconnect;
+pub mod error;
+
+use std::io::Cursor;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+pub use error::{DecodeError, EncodeError};
+
+use fixed_header::{read_bytes, read_variable_length, write_variable_length};
+
+// Property identifiers allowed in the acknowledgements
+const REASON_STRING: u8 = 0x1F;
+const USER_PROPERTY: u8 = 0x26;
+
+/// Packets that can be encoded into bytes to write them to a connection
+pub trait Encode {
+    /// Encodes the packet, fixed header included, or returns why it cannot be sent
+    fn encode(&self) -> Result<Vec<u8>, EncodeError>;
+}
+
+// Every packet already has an inherent encode method, the trait forwards to it
+macro_rules! impl_encode {
+    ($($packet:ty),* $(,)?) => {
+        $(
+            impl Encode for $packet {
+                fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+                    Ok(<$packet>::encode(self))
+                }
+            }
+        )*
+    };
+}
+
+// The packets with a packet ID that must not be 0 validate it when encoded
+macro_rules! impl_fallible_encode {
+    ($($packet:ty),* $(,)?) => {
+        $(
+            impl Encode for $packet {
+                fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+                    <$packet>::encode(self)
+                }
+            }
+        )*
+    };
+}
+
+impl_fallible_encode!(
+    publish::PublishPacket,
+    subscribe::SubscribePacket,
+    unsubscribe::UnsubscribePacket,
+);
+
+impl_encode!(
+    connect::ConnectPacket,
+    connack::ConnAckPacket,
+    puback::PubAckPacket,
+    qos2::PubRecPacket,
+    qos2::PubRelPacket,
+    qos2::PubCompPacket,
+    suback::SubAckPacket,
+    unsubscribe::UnsubAckPacket,
+    ping::PingReqPacket,
+    ping::PingRespPacket,
+    disconnect::DisconnectPacket,
+);
+
+/// Reason string and user properties of a PUBACK, PUBREC, SUBACK or UNSUBACK
+#[derive(Debug, Default)]
+pub(crate) struct AckProperties {
+    pub(crate) reason_string: Option<String>,
+    pub(crate) user_properties: Vec<(String, String)>, // Name and value pairs, in order
+}
+
+/// Appends the property block of an acknowledgement, its length included
+pub(crate) fn write_ack_properties(buffer: &mut Vec<u8>, reason_string: Option<&str>, user_properties: &[(String, String)]) {
+    let mut properties = Vec::new();
+    if let Some(reason) = reason_string {
+        properties.push(REASON_STRING);
+        write_string(&mut properties, reason);
+    }
+    for (name, value) in user_properties {
+        properties.push(USER_PROPERTY);
+        write_string(&mut properties, name);
+        write_string(&mut properties, value);
+    }
+
+    write_variable_length(buffer, properties.len());
+    buffer.extend(properties);
+}
+
+/// Reads the property block of an acknowledgement
+pub(crate) fn read_ack_properties(cursor: &mut Cursor<&[u8]>) -> Result<AckProperties, DecodeError> {
+    let properties_length = read_variable_length(cursor)?;
+    let end = cursor.position() + properties_length as u64;
+    let mut properties = AckProperties::default();
+
+    while cursor.position() < end {
+        let identifier = cursor.read_u8()?;
+        match identifier {
+            REASON_STRING => properties.reason_string = Some(read_string(cursor)?),
+            USER_PROPERTY => {
+                let name = read_string(cursor)?;
+                properties.user_properties.push((name, read_string(cursor)?));
+            }
+            _ => return Err(DecodeError::UnsupportedProperty(identifier)),
+        }
+    }
+
+    if cursor.position() != end {
+        return Err(DecodeError::Malformed("properties overrun their length".to_string()));
+    }
+    Ok(properties)
+}
+
+// Writes a length-prefixed UTF-8 string
+pub(crate) fn write_string(buffer: &mut Vec<u8>, value: &str) {
+    buffer.write_u16::<BigEndian>(value.len() as u16).unwrap();
+    buffer.extend_from_slice(value.as_bytes());
+}
+
+// Reads a length-prefixed UTF-8 string
+pub(crate) fn read_string(cursor: &mut Cursor<&[u8]>) -> Result<String, DecodeError> {
+    Ok(String::from_utf8(read_binary(cursor)?)?)
+}
+
+// Writes length-prefixed binary data
+pub(crate) fn write_binary(buffer: &mut Vec<u8>, data: &[u8]) {
+    buffer.write_u16::<BigEndian>(data.len() as u16).unwrap();
+    buffer.extend_from_slice(data);
+}
+
+// Reads length-prefixed binary data
+pub(crate) fn read_binary(cursor: &mut Cursor<&[u8]>) -> Result<Vec<u8>, DecodeError> {
+    let len = cursor.read_u16::<BigEndian>()? as usize;
+    read_bytes(cursor, len)
+}
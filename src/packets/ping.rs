@@ -1,43 +1,79 @@
-use bytes::Bytes;
-use bytes::{BytesMut, BufMut};
+use super::DecodeError;
 
 /// MQTT Packet Type
 const PINGREQ: u8 = 0b1100_0000; // Packet type for PINGREQ with flags (0b1100)
 const PINGRESP: u8 = 0b1101_0000; // Packet type for PINGRESP with flags (0b1101)
 
 /// Represents an MQTT PINGREQ Packet
+#[derive(Debug, PartialEq, Clone)]
 pub struct PingReqPacket;
 
 impl PingReqPacket {
     /// Encodes the PINGREQ packet into bytes
-    pub fn encode(&self) -> BytesMut {
-        let mut buf = BytesMut::new();
-        buf.put_u8(PINGREQ); // Fixed header byte 1
-        buf.put_u8(0x00);    // Remaining length is 0 for PINGREQ
-        buf
+    pub fn encode(&self) -> Vec<u8> {
+        vec![
+            PINGREQ, // Fixed header byte 1
+            0x00,    // Remaining length is 0 for PINGREQ
+        ]
+    }
+
+    /// Decodes a PINGREQ packet from bytes
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        if bytes.len() != 2 {
+            return Err(DecodeError::Malformed(format!("PINGREQ of {} bytes instead of 2", bytes.len())));
+        }
+        if bytes[0] != PINGREQ {
+            return Err(DecodeError::InvalidPacketType { got: bytes[0] });
+        }
+        if bytes[1] != 0x00 {
+            return Err(DecodeError::Malformed("PINGREQ with a remaining length".to_string()));
+        }
+        Ok(PingReqPacket)
     }
 }
 
 /// Represents an MQTT PINGRESP Packet
+#[derive(Debug, PartialEq, Clone)]
 pub struct PingRespPacket;
 
 impl PingRespPacket {
     /// Encodes the PINGRESP packet into bytes
-    pub fn encode(&self) -> BytesMut {
-        let mut buf = BytesMut::new();
-        buf.put_u8(PINGRESP); // Fixed header byte 1
-        buf.put_u8(0x00);     // Remaining length is 0 for PINGRESP
-        buf
+    pub fn encode(&self) -> Vec<u8> {
+        vec![
+            PINGRESP, // Fixed header byte 1
+            0x00,     // Remaining length is 0 for PINGRESP
+        ]
     }
 
     /// Decodes a PINGRESP packet from bytes
-    pub fn decode(bytes: &Bytes) -> Result<Self, &'static str> {
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
         if bytes.len() != 2 {
-            return Err("Invalid PINGRESP packet length");
+            return Err(DecodeError::Malformed(format!("PINGRESP of {} bytes instead of 2", bytes.len())));
+        }
+        if bytes[0] != PINGRESP {
+            return Err(DecodeError::InvalidPacketType { got: bytes[0] });
         }
-        if bytes[0] != PINGRESP || bytes[1] != 0x00 {
-            return Err("Invalid PINGRESP packet format");
+        if bytes[1] != 0x00 {
+            return Err(DecodeError::Malformed("PINGRESP with a remaining length".to_string()));
         }
         Ok(PingRespPacket)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ping_request_and_response() {
+        assert_eq!(PingReqPacket::decode(&PingReqPacket.encode()).unwrap(), PingReqPacket);
+        assert_eq!(PingRespPacket::decode(&PingRespPacket.encode()).unwrap(), PingRespPacket);
+    }
+
+    #[test]
+    fn ping_bytes_on_the_wire() {
+        // The same two bytes as when the packets were encoded into a BytesMut
+        assert_eq!(PingReqPacket.encode(), vec![0xC0, 0x00]);
+        assert_eq!(PingRespPacket.encode(), vec![0xD0, 0x00]);
+    }
+}
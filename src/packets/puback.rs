@@ -1,13 +1,23 @@
-/// MQTT PUBACK packet implementation for MQTT version 5.0.
+//! MQTT PUBACK packet implementation for MQTT version 5.0.
 
-/// 
-/// The PUBACK packet is used to acknowledge receipt of a published message.
-/// When a client sends a message with QoS 1 (at least once delivery), 
-/// it expects a PUBACK packet from the receiver (broker or client).
-/// The PUBACK packet includes the message identifier (Packet ID) to match the message it acknowledges.
-///
+//! 
+//! The PUBACK packet is used to acknowledge receipt of a published message.
+//! When a client sends a message with QoS 1 (at least once delivery), 
+//! it expects a PUBACK packet from the receiver (broker or client).
+//! The PUBACK packet includes the message identifier (Packet ID) to match the message it acknowledges.
+//! In MQTT 5 it may also carry a reason code and a property block with a reason string
+//! and user properties, both omitted when the message was accepted without remarks.
+//!
 
 use byteorder::{BigEndian, WriteBytesExt, ReadBytesExt};
+use super::fixed_header::{first_packet, read_variable_length};
+use super::{read_ack_properties, write_ack_properties};
+use super::DecodeError;
+
+// Reason codes of the PUBACK, also used by the PUBREC
+pub const SUCCESS: u8 = 0x00; // The message is accepted
+pub const NO_MATCHING_SUBSCRIBERS: u8 = 0x10; // The message is accepted but nobody is subscribed to it
+pub const NOT_AUTHORIZED: u8 = 0x87; // The sender may not publish to the topic
 
 /*
 Implementing traits for:
@@ -19,6 +29,9 @@ Implementing traits for:
 // The PUBACK packet structure as defined in MQTT 5.0
 pub struct PubAckPacket {
     pub packet_id: u16, // Unique identifier for the message to acknowledge
+    pub reason_code: u8, // Result of the publish, 0x00 is Success
+    pub reason_string: Option<String>, // Human-readable reason of the result
+    pub user_properties: Vec<(String, String)>, // Name and value pairs, in order
 }
 
 impl PubAckPacket {
@@ -26,6 +39,17 @@ impl PubAckPacket {
     pub fn new(packet_id: u16) -> Self {
         PubAckPacket {
             packet_id,
+            reason_code: SUCCESS,
+            reason_string: None,
+            user_properties: Vec::new(),
+        }
+    }
+
+    // Constructor for a PUBACK with the given reason code
+    pub fn with_reason(packet_id: u16, reason_code: u8) -> Self {
+        PubAckPacket {
+            reason_code,
+            ..PubAckPacket::new(packet_id)
         }
     }
 
@@ -40,9 +64,18 @@ impl PubAckPacket {
         // Fixed header (first byte): PUBACK packet type (0x40)
         packet.push(0x40);  // PUBACK packet type (MQTT Control Packet type for PUBACK)
 
-        // Remaining length (2 bytes): The length of the variable header (just the packet ID)
-        // In MQTT v5.0, this is a simple 2-byte integer (the packet ID).
-        let remaining_length = 2; // Remaining length = 2 bytes for packet_id
+        // The variable header is the packet ID, followed by the reason code and the
+        // properties only when they are needed
+        let mut variable_header = Vec::new();
+        variable_header.write_u16::<BigEndian>(self.packet_id).unwrap();
+        let has_properties = self.reason_string.is_some() || !self.user_properties.is_empty();
+        if self.reason_code != SUCCESS || has_properties {
+            variable_header.push(self.reason_code);
+        }
+        if has_properties {
+            write_ack_properties(&mut variable_header, self.reason_string.as_deref(), &self.user_properties);
+        }
+        let remaining_length = variable_header.len();
 
         // Encode the remaining length with VLQ (Variable Length Quantity) encoding
         let mut len_buffer = Vec::new();
@@ -62,9 +95,8 @@ impl PubAckPacket {
         // Add the remaining length bytes to the packet
         packet.extend(len_buffer);
 
-        // The variable header contains the packet identifier (2 bytes)
         // The packet_id uniquely identifies the message being acknowledged
-        packet.write_u16::<BigEndian>(self.packet_id).unwrap();
+        packet.extend(variable_header);
 
         // Return the encoded packet as a byte vector
         packet
@@ -80,53 +112,55 @@ impl PubAckPacket {
     ///
     /// This function returns a Result that contains either the decoded `PubAckPacket` 
     /// or an error if the decoding fails.
-    pub fn decode(data: &[u8]) -> Result<Self, String> {
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        // Bytes after the remaining length belong to the next packet
+        let data = first_packet(data)?;
         let mut cursor = std::io::Cursor::new(data);
 
         // Read the fixed header (first byte), it should be 0x40 for PUBACK
-        let packet_type = cursor.read_u8().map_err(|e| e.to_string())?;
+        let packet_type = cursor.read_u8()?;
         if packet_type != 0x40 {
-            return Err(format!("Invalid packet type: 0x{:02x}", packet_type));
+            return Err(DecodeError::InvalidPacketType { got: packet_type });
         }
 
         // Read the remaining length (skip the length bytes in the header)
-        let remaining_length = read_remaining_length(&mut cursor)?;
+        let remaining_length = read_variable_length(&mut cursor)?;
 
-        // Check if the remaining length matches the expected value for PUBACK (2 bytes for packet_id)
-        if remaining_length != 2 {
-            return Err(format!("Invalid remaining length: {}", remaining_length));
+        // At least the packet_id must be present
+        if remaining_length < 2 {
+            return Err(DecodeError::Malformed(format!("remaining length {} leaves no room for the packet ID", remaining_length)));
         }
 
         // Read the Packet ID (2 bytes)
-        let packet_id = cursor.read_u16::<BigEndian>().map_err(|e| e.to_string())?;
+        let packet_id = cursor.read_u16::<BigEndian>()?;
+
+        // A missing reason code is Success, missing properties are empty
+        let mut packet = PubAckPacket::new(packet_id);
+        if remaining_length > 2 {
+            packet.reason_code = cursor.read_u8()?;
+        }
+        if remaining_length > 3 {
+            let properties = read_ack_properties(&mut cursor)?;
+            packet.reason_string = properties.reason_string;
+            packet.user_properties = properties.user_properties;
+        }
 
         // Return the decoded PUBACK packet
-        Ok(PubAckPacket { packet_id })
+        Ok(packet)
     }
 }
 
-/// Helper function to read the remaining length field (Variable Length Quantity encoding)
-/// This function reads the remaining length from the MQTT packet header.
-/// 
-/// # Arguments
-///
-/// * `cursor` - A cursor over the byte slice from which to read the remaining length.
-///
-/// # Returns
-/// The remaining length value as an integer.
-///
-fn read_remaining_length(cursor: &mut std::io::Cursor<&[u8]>) -> Result<usize, String> {
-    let mut multiplier = 1;
-    let mut value = 0;
-
-    loop {
-        let byte = cursor.read_u8().map_err(|e| e.to_string())?;
-        value += (byte & 0x7F) as usize * multiplier;
-        if (byte & 0x80) == 0 {
-            break;
-        }
-        multiplier *= 128;
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok(value)
-}
\ No newline at end of file
+    #[test]
+    fn puback_without_and_with_reason() {
+        let packet = PubAckPacket::new(1);
+        assert_eq!(PubAckPacket::decode(&packet.encode()).unwrap(), packet);
+
+        let mut packet = PubAckPacket::with_reason(65535, 0x87);
+        packet.reason_string = Some("not authorized".to_string());
+        assert_eq!(PubAckPacket::decode(&packet.encode()).unwrap(), packet);
+    }
+}
@@ -1,4 +1,4 @@
-/// MQTT Publish packet implementation for MQTT version 5.0.
+//! MQTT Publish packet implementation for MQTT version 5.0.
 
 /*
 The PUBLISH packet is used to send messages from a client to a broker, or from a broker to a client.
@@ -7,6 +7,10 @@ This packet includes the message content, topic name, and various flags that con
 
 use std::io::Read;
 use byteorder::{BigEndian, WriteBytesExt, ReadBytesExt};
+use super::fixed_header::{first_packet, read_bytes, read_variable_length, write_variable_length, PacketType};
+use super::qos::QoS;
+use super::subscribe::is_valid_topic_name;
+use super::{read_string, write_string, DecodeError, EncodeError};
 
 /*
 Implement traits for:
@@ -19,18 +23,56 @@ Implement traits for:
 pub struct PublishPacket {
     pub topic_name: String,       // The topic to which the message is being sent
     pub message_id: u16,  // The message ID (optional, only used for QoS 1 and 2)
-    pub qos: u8,                  // Quality of Service level (0, 1, or 2)
+    pub qos: QoS,                 // Quality of Service level
     pub retain: bool,             // Retain flag (whether the message should be retained by the broker)
     pub dup: bool,                // Duplicate delivery flag (for QoS 1 and 2)
     pub payload: Vec<u8>,         // The actual message payload (data)
+    pub topic_alias: Option<u16>, // Topic Alias property, stands for the topic name on the connection
+    pub user_properties: Vec<(String, String)>, // User Property name and value pairs, in order
+}
+
+// Property identifiers of the PUBLISH, only the topic alias and user properties are kept when decoding
+const PAYLOAD_FORMAT_INDICATOR: u8 = 0x01;
+const MESSAGE_EXPIRY_INTERVAL: u8 = 0x02;
+const CONTENT_TYPE: u8 = 0x03;
+const RESPONSE_TOPIC: u8 = 0x08;
+const CORRELATION_DATA: u8 = 0x09;
+const SUBSCRIPTION_IDENTIFIER: u8 = 0x0B;
+const TOPIC_ALIAS: u8 = 0x23;
+const USER_PROPERTY: u8 = 0x26;
+
+/// An application message, without the fields that only matter on the wire
+#[derive(Debug, PartialEq, Clone)]
+pub struct Message {
+    pub topic: String,    // Topic the message is published to
+    pub payload: Vec<u8>, // Content of the message
+    pub qos: QoS,         // Delivery guarantee
+    pub retain: bool,     // Whether the broker keeps it for future subscribers
 }
 
 impl PublishPacket {
+    /// Creates the PUBLISH packet carrying the message with the given message ID,
+    /// which QoS 0 packets do not use so it is set to 0 for them
+    pub fn from_message(message: &Message, message_id: u16) -> Self {
+        let message_id = if message.qos == QoS::AtMostOnce { 0 } else { message_id };
+        PublishPacket::new(message.topic.clone(), message_id, message.qos, message.retain, false, message.payload.clone())
+    }
+
+    /// Returns the message carried by the packet, the message ID and DUP flag are left out
+    pub fn to_message(&self) -> Message {
+        Message {
+            topic: self.topic_name.clone(),
+            payload: self.payload.clone(),
+            qos: self.qos,
+            retain: self.retain,
+        }
+    }
+
     // Constructor for a PublishPacket, with all fields as parameters
     pub fn new(
         topic_name: String,
         message_id: u16,
-        qos: u8,
+        qos: QoS,
         retain: bool,
         dup: bool,
         payload: Vec<u8>,
@@ -42,18 +84,45 @@ impl PublishPacket {
             retain,
             dup,
             payload,
+            topic_alias: None,
+            user_properties: Vec::new(),
         }
     }
 
+    /// Returns a builder for a QoS 0 packet of the message, which the chainable
+    /// setters complete. Unlike `new`, no two arguments can be swapped.
+    pub fn builder(topic_name: impl Into<String>, payload: impl Into<Vec<u8>>) -> PublishBuilder {
+        PublishBuilder {
+            packet: PublishPacket::new(topic_name.into(), 0, QoS::AtMostOnce, false, false, payload.into()),
+        }
+    }
+
+    /// Returns true if the flags are a valid combination: the DUP flag is only set on
+    /// QoS 1 and 2 packets, since QoS 0 messages are never resent
+    pub fn is_valid(&self) -> bool {
+        !(self.dup && self.qos == QoS::AtMostOnce)
+    }
+
     /// Encodes the Publish packet into bytes to send to the broker.
-    pub fn encode(&self) -> Vec<u8> {
+    ///
+    /// # Returns
+    ///
+    /// The bytes of the packet, or an error for a QoS 1 or 2 packet with message ID 0.
+    pub fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        if self.qos != QoS::AtMostOnce && self.message_id == 0 {
+            return Err(EncodeError::ZeroPacketId(PacketType::Publish));
+        }
+        if self.topic_name.len() > u16::MAX as usize {
+            return Err(EncodeError::TopicTooLong(self.topic_name.len()));
+        }
+
         let mut packet = Vec::new();
 
         // Fixed header (first byte): Publish packet type (0x30)
         let mut first_byte = 0x30;
 
         // Set QoS level, retain, and dup flags
-        first_byte |= (self.qos & 0x03) << 1; // QoS is stored in bits 1-2
+        first_byte |= self.qos.to_u8() << 1; // QoS is stored in bits 1-2
         if self.retain {
             first_byte |= 0x01; // Retain flag is in bit 0
         }
@@ -64,14 +133,29 @@ impl PublishPacket {
         // Add the first byte to the packet
         packet.push(first_byte);
 
-        // Variable header length calculation
-        let mut remaining_length = 2 + self.topic_name.len() as u16 + self.payload.len() as u16;
+        // Variable header length calculation, a large payload takes it beyond 65535 bytes
+        let mut remaining_length = 2 + self.topic_name.len() + self.payload.len();
 
-        if self.qos > 0 {
+        if self.qos != QoS::AtMostOnce {
             // Add message ID field (2 bytes) for QoS 1 and 2
             remaining_length += 2;
         }
 
+        // Properties, only the topic alias and user properties are sent
+        let mut properties = Vec::new();
+        if let Some(alias) = self.topic_alias {
+            properties.push(TOPIC_ALIAS);
+            properties.write_u16::<BigEndian>(alias).unwrap();
+        }
+        for (name, value) in &self.user_properties {
+            properties.push(USER_PROPERTY);
+            write_string(&mut properties, name);
+            write_string(&mut properties, value);
+        }
+        let mut properties_length = Vec::new();
+        write_variable_length(&mut properties_length, properties.len());
+        remaining_length += properties_length.len() + properties.len();
+
         // Encode the remaining length with VLQ codification
         let mut len_buffer = Vec::new();
         let mut length = remaining_length;
@@ -92,15 +176,22 @@ impl PublishPacket {
 
         // Topic Name: Encode the topic length (2 bytes) followed by the topic itself
         packet.push((self.topic_name.len() >> 8) as u8); // High byte of topic length
-        packet.push(self.topic_name.len() as u8 & 0xFF); // Low byte of topic length
+        packet.push((self.topic_name.len() & 0xFF) as u8); // Low byte of topic length
         packet.extend_from_slice(self.topic_name.as_bytes());
 
-        packet.write_u16::<BigEndian>(self.message_id).unwrap();
+        // Message ID, only present for QoS 1 and 2
+        if self.qos != QoS::AtMostOnce {
+            packet.write_u16::<BigEndian>(self.message_id).unwrap();
+        }
+
+        // Property length (VLQ), followed by the properties
+        packet.extend(properties_length);
+        packet.extend(properties);
 
         // Payload: Add the actual message content
         packet.extend_from_slice(&self.payload);
 
-        packet
+        Ok(packet)
     }
 
     /// Decodes a byte slice into a Publish packet.
@@ -112,41 +203,51 @@ impl PublishPacket {
     /// # Returns
     ///
     /// This function returns a result containing either a decoded `PublishPacket` or an error if decoding fails.
-    pub fn decode(data: &[u8]) -> Result<Self, String> {
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        // Bytes after the remaining length belong to the next packet
+        let data = first_packet(data)?;
         let mut cursor = std::io::Cursor::new(data);
     
         //Read the first byte (packet type and flags)
-        let first_byte = cursor.read_u8().map_err(|e| e.to_string())?;
-    
-        //Decode the rest of the package in VLQ
-        let mut remaining_length = 0u16;
-        let mut multiplier = 1u16;
-        loop {
-            let byte = cursor.read_u8().map_err(|e| e.to_string())?;
-            remaining_length += (byte & 127) as u16 * multiplier;
-            multiplier *= 128;
-            if (byte & 128) == 0 {
-                break;
-            }
+        let first_byte = cursor.read_u8()?;
+
+        //Reject the flag combinations MQTT forbids before reading the rest
+        let qos = QoS::from_u8((first_byte >> 1) & 0x03)?;
+        if qos == QoS::AtMostOnce && first_byte & 0x08 != 0 {
+            return Err(DecodeError::InvalidFlags("DUP flag set on a QoS 0 message".to_string()));
         }
     
+        //Skip the remaining length (VLQ), the payload is read until the end
+        read_variable_length(&mut cursor)?;
+    
         //Read the topic lenght (2 bytes) and the topic name
-        let topic_name_len = cursor.read_u16::<BigEndian>().map_err(|e| e.to_string())? as usize;
-        let mut topic_name = vec![0; topic_name_len];
-        cursor.read_exact(&mut topic_name).map_err(|e| e.to_string())?;
-        let topic_name = String::from_utf8(topic_name).map_err(|e| e.to_string())?;
+        let topic_name_len = cursor.read_u16::<BigEndian>()? as usize;
+        let topic_name = read_bytes(&mut cursor, topic_name_len)?;
+        let topic_name = String::from_utf8(topic_name)?;
+        //Wildcards belong to topic filters, a PUBLISH goes to a single topic
+        if !is_valid_topic_name(&topic_name) {
+            return Err(DecodeError::InvalidTopic(topic_name));
+        }
     
         //Read the message ID if qos is > 0)
-        let qos = (first_byte >> 1) & 0x03;
-        let message_id = if qos > 0 {
-            cursor.read_u16::<BigEndian>().map_err(|e| e.to_string())?
+        let message_id = if qos != QoS::AtMostOnce {
+            cursor.read_u16::<BigEndian>()?
         } else {
             0
         };
+        //QoS 1 and 2 messages are acknowledged by their message ID, which cannot be 0
+        if qos != QoS::AtMostOnce && message_id == 0 {
+            return Err(DecodeError::ProtocolError(format!("{:?} PUBLISH with message ID 0", qos)));
+        }
+
+        //Read the properties, their length (VLQ) follows the message ID or the topic for QoS 0
+        let properties_length = read_variable_length(&mut cursor)?;
+        let properties = read_bytes(&mut cursor, properties_length)?;
+        let properties = decode_properties(&properties)?;
     
         // Read the payload (remaining data)
         let mut payload = Vec::new();
-        cursor.read_to_end(&mut payload).map_err(|e| e.to_string())?;
+        cursor.read_to_end(&mut payload)?;
     
         Ok(PublishPacket {
             topic_name,
@@ -155,6 +256,156 @@ impl PublishPacket {
             retain: first_byte & 0x01 != 0,
             dup: first_byte & 0x08 != 0,
             payload,
+            topic_alias: properties.topic_alias,
+            user_properties: properties.user_properties,
         })
     }
 }
+
+/// Builder for a PUBLISH packet, which checks the fields once they are all set.
+#[derive(Debug, Clone)]
+pub struct PublishBuilder {
+    packet: PublishPacket,
+}
+
+impl PublishBuilder {
+    /// Sets the Quality of Service level
+    pub fn qos(mut self, qos: QoS) -> Self {
+        self.packet.qos = qos;
+        self
+    }
+
+    /// Sets the retain flag
+    pub fn retain(mut self, retain: bool) -> Self {
+        self.packet.retain = retain;
+        self
+    }
+
+    /// Sets the DUP flag of a packet sent again
+    pub fn dup(mut self, dup: bool) -> Self {
+        self.packet.dup = dup;
+        self
+    }
+
+    /// Sets the message ID, which QoS 1 and 2 packets require
+    pub fn message_id(mut self, message_id: u16) -> Self {
+        self.packet.message_id = message_id;
+        self
+    }
+
+    /// Builds the packet.
+    ///
+    /// # Returns
+    ///
+    /// The packet, or an error if the topic name is invalid, a QoS 1 or 2 packet has
+    /// no message ID, or a QoS 0 packet has the DUP flag set.
+    pub fn build(self) -> Result<PublishPacket, EncodeError> {
+        let packet = self.packet;
+        if !is_valid_topic_name(&packet.topic_name) {
+            return Err(EncodeError::InvalidTopic(packet.topic_name));
+        }
+        if packet.qos != QoS::AtMostOnce && packet.message_id == 0 {
+            return Err(EncodeError::ZeroPacketId(PacketType::Publish));
+        }
+        if !packet.is_valid() {
+            return Err(EncodeError::DupOnQoS0);
+        }
+        Ok(packet)
+    }
+}
+
+// Properties of a PUBLISH the packet keeps
+#[derive(Default)]
+struct PublishProperties {
+    topic_alias: Option<u16>,
+    user_properties: Vec<(String, String)>,
+}
+
+/// Walks the PUBLISH properties, without their length, and returns the topic alias
+/// if there is one and the user properties. The other properties are skipped.
+fn decode_properties(data: &[u8]) -> Result<PublishProperties, DecodeError> {
+    let mut cursor = std::io::Cursor::new(data);
+    let mut properties = PublishProperties::default();
+
+    while (cursor.position() as usize) < data.len() {
+        let identifier = cursor.read_u8()?;
+        match identifier {
+            TOPIC_ALIAS => properties.topic_alias = Some(cursor.read_u16::<BigEndian>()?),
+            PAYLOAD_FORMAT_INDICATOR => {
+                cursor.read_u8()?;
+            }
+            MESSAGE_EXPIRY_INTERVAL => {
+                cursor.read_u32::<BigEndian>()?;
+            }
+            SUBSCRIPTION_IDENTIFIER => {
+                read_variable_length(&mut cursor)?;
+            }
+            CONTENT_TYPE | RESPONSE_TOPIC | CORRELATION_DATA => skip_length_prefixed(&mut cursor)?,
+            USER_PROPERTY => {
+                let name = read_string(&mut cursor)?;
+                properties.user_properties.push((name, read_string(&mut cursor)?));
+            }
+            _ => return Err(DecodeError::UnsupportedProperty(identifier)),
+        }
+    }
+
+    Ok(properties)
+}
+
+// Skips a length-prefixed string or binary data
+fn skip_length_prefixed(cursor: &mut std::io::Cursor<&[u8]>) -> Result<(), DecodeError> {
+    let len = cursor.read_u16::<BigEndian>()? as usize;
+    read_bytes(cursor, len)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::fixed_header::parse_fixed_header;
+
+    #[test]
+    fn publish_smallest_and_with_every_field() {
+        // QoS 0 without payload, which has no message ID on the wire
+        let packet = PublishPacket::new("a".to_string(), 0, QoS::AtMostOnce, false, false, Vec::new());
+        assert_eq!(PublishPacket::decode(&packet.encode().unwrap()).unwrap(), packet);
+
+        let mut packet = PublishPacket::new("sensors/temp".to_string(), 65535, QoS::ExactlyOnce, true, true, vec![0x00, 0xFF, 0x7F]);
+        packet.topic_alias = Some(3);
+        assert_eq!(PublishPacket::decode(&packet.encode().unwrap()).unwrap(), packet);
+    }
+
+    #[test]
+    fn publish_with_properties_at_qos_0_and_1() {
+        let mut packet = PublishPacket::new("sensors/temp".to_string(), 0, QoS::AtMostOnce, false, false, b"21.5".to_vec());
+        packet.topic_alias = Some(1);
+        packet.user_properties = vec![("unit".to_string(), "celsius".to_string())];
+        assert_eq!(PublishPacket::decode(&packet.encode().unwrap()).unwrap(), packet);
+
+        let packet = PublishPacket { qos: QoS::AtLeastOnce, message_id: 42, retain: true, ..packet };
+        assert_eq!(PublishPacket::decode(&packet.encode().unwrap()).unwrap(), packet);
+    }
+
+    #[test]
+    fn properties_longer_than_127_bytes_take_a_two_byte_length() {
+        let mut packet = PublishPacket::new("t".to_string(), 0, QoS::AtMostOnce, false, false, b"x".to_vec());
+        packet.user_properties = vec![("trace".to_string(), "0".repeat(200))];
+        let data = packet.encode().unwrap();
+
+        // Identifier, name and value: 1 + 2 + 5 + 2 + 200 = 210 bytes, 0xD2 0x01 as a VLQ
+        // after the fixed header and the topic
+        let header_len = parse_fixed_header(&data).unwrap().header_len;
+        assert_eq!(&data[header_len + 3..header_len + 5], &[0xD2, 0x01]);
+        assert_eq!(PublishPacket::decode(&data).unwrap(), packet);
+    }
+
+    #[test]
+    fn topic_longer_than_its_length_field_is_not_encoded() {
+        let packet = PublishPacket::new("t".repeat(65535), 0, QoS::AtMostOnce, false, false, Vec::new());
+        assert_eq!(PublishPacket::decode(&packet.encode().unwrap()).unwrap(), packet);
+
+        let packet = PublishPacket::new("t".repeat(65536), 0, QoS::AtMostOnce, false, false, Vec::new());
+        assert_eq!(packet.encode(), Err(EncodeError::TopicTooLong(65536)));
+        assert_eq!(EncodeError::TopicTooLong(65536).to_string(), "Topic name of 65536 bytes, longer than 65535");
+    }
+}
@@ -0,0 +1,35 @@
+//! Quality of Service levels of MQTT messages.
+
+/*
+The QoS takes two bits on the wire, in the PUBLISH fixed header and in the
+subscription options, where the value 3 is reserved. Packets hold the enum so
+an invalid level can only appear while decoding, where it is rejected.
+*/
+
+use super::DecodeError;
+
+/// Delivery guarantee of a message
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Default)]
+pub enum QoS {
+    #[default]
+    AtMostOnce = 0,  // QoS 0, sent once without acknowledgement
+    AtLeastOnce = 1, // QoS 1, acknowledged with a PUBACK and resent until then
+    ExactlyOnce = 2, // QoS 2, delivered once through the PUBREC, PUBREL and PUBCOMP exchange
+}
+
+impl QoS {
+    /// Converts the value used on the wire, 3 and above are not valid levels
+    pub fn from_u8(value: u8) -> Result<Self, DecodeError> {
+        match value {
+            0 => Ok(QoS::AtMostOnce),
+            1 => Ok(QoS::AtLeastOnce),
+            2 => Ok(QoS::ExactlyOnce),
+            _ => Err(DecodeError::InvalidQoS(value)),
+        }
+    }
+
+    /// Returns the value used on the wire
+    pub fn to_u8(self) -> u8 {
+        self as u8
+    }
+}
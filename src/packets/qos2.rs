@@ -0,0 +1,171 @@
+//! MQTT PUBREC, PUBREL and PUBCOMP packet implementation for MQTT version 5.0.
+
+/*
+A QoS 2 PUBLISH is delivered exactly once through a four step exchange:
+    PUBLISH  -> sender to receiver
+    PUBREC   <- the receiver has the message and stores its packet ID
+    PUBREL   -> the sender releases the packet ID
+    PUBCOMP  <- the receiver forgets the packet ID, the exchange is complete
+The three acknowledgements only carry the packet ID. Like the PUBACK, a PUBREC
+refusing the message adds its reason code, and a PUBREC with a reason string or
+user properties adds them after it.
+*/
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use super::fixed_header::{first_packet, read_variable_length, write_variable_length};
+use super::{read_ack_properties, write_ack_properties, AckProperties};
+use super::DecodeError;
+
+const PUBREC: u8 = 0x50; // Packet type for PUBREC
+const PUBREL: u8 = 0x62; // Packet type for PUBREL, its flags must be 0010
+const PUBCOMP: u8 = 0x70; // Packet type for PUBCOMP
+
+/// PUBREC packet, the receiver of a QoS 2 PUBLISH has stored it
+#[derive(Debug, PartialEq, Clone)]
+pub struct PubRecPacket {
+    pub packet_id: u16, // Packet ID of the QoS 2 PUBLISH
+    pub reason_code: u8, // Result of the publish, 0x00 is Success
+    pub reason_string: Option<String>, // Human-readable reason of the result
+    pub user_properties: Vec<(String, String)>, // Name and value pairs, in order
+}
+
+/// PUBREL packet, the sender of a QoS 2 PUBLISH releases its packet ID
+#[derive(Debug, PartialEq, Clone)]
+pub struct PubRelPacket {
+    pub packet_id: u16, // Packet ID of the QoS 2 PUBLISH
+}
+
+/// PUBCOMP packet, the QoS 2 exchange is complete
+#[derive(Debug, PartialEq, Clone)]
+pub struct PubCompPacket {
+    pub packet_id: u16, // Packet ID of the QoS 2 PUBLISH
+}
+
+impl PubRecPacket {
+    pub fn new(packet_id: u16) -> Self {
+        PubRecPacket::with_reason(packet_id, 0x00)
+    }
+
+    /// Creates a PUBREC with the given reason code, from 0x80 the message is refused
+    pub fn with_reason(packet_id: u16, reason_code: u8) -> Self {
+        PubRecPacket { packet_id, reason_code, reason_string: None, user_properties: Vec::new() }
+    }
+
+    /// Encodes the PUBREC packet into bytes, the reason code is omitted on success
+    /// without properties
+    pub fn encode(&self) -> Vec<u8> {
+        let mut variable_header = Vec::new();
+        variable_header.write_u16::<BigEndian>(self.packet_id).unwrap();
+        let has_properties = self.reason_string.is_some() || !self.user_properties.is_empty();
+        if self.reason_code != 0x00 || has_properties {
+            variable_header.push(self.reason_code);
+        }
+        if has_properties {
+            write_ack_properties(&mut variable_header, self.reason_string.as_deref(), &self.user_properties);
+        }
+
+        let mut packet = vec![PUBREC];
+        write_variable_length(&mut packet, variable_header.len());
+        packet.extend(variable_header);
+        packet
+    }
+
+    /// Decodes a PUBREC packet from bytes
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        let (packet_id, reason_code, properties) = decode_ack(PUBREC, data)?;
+        Ok(PubRecPacket {
+            reason_string: properties.reason_string,
+            user_properties: properties.user_properties,
+            ..PubRecPacket::with_reason(packet_id, reason_code)
+        })
+    }
+}
+
+impl PubRelPacket {
+    pub fn new(packet_id: u16) -> Self {
+        PubRelPacket { packet_id }
+    }
+
+    /// Encodes the PUBREL packet into bytes
+    pub fn encode(&self) -> Vec<u8> {
+        encode_ack(PUBREL, self.packet_id)
+    }
+
+    /// Decodes a PUBREL packet from bytes
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        decode_ack(PUBREL, data).map(|(packet_id, _, _)| PubRelPacket::new(packet_id))
+    }
+}
+
+impl PubCompPacket {
+    pub fn new(packet_id: u16) -> Self {
+        PubCompPacket { packet_id }
+    }
+
+    /// Encodes the PUBCOMP packet into bytes
+    pub fn encode(&self) -> Vec<u8> {
+        encode_ack(PUBCOMP, self.packet_id)
+    }
+
+    /// Decodes a PUBCOMP packet from bytes
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        decode_ack(PUBCOMP, data).map(|(packet_id, _, _)| PubCompPacket::new(packet_id))
+    }
+}
+
+// Encodes the fixed header and the packet ID, the success reason code is omitted
+fn encode_ack(first_byte: u8, packet_id: u16) -> Vec<u8> {
+    let mut packet = vec![first_byte, 0x02]; // Remaining length: the packet ID
+    packet.write_u16::<BigEndian>(packet_id).unwrap();
+    packet
+}
+
+// Decodes the packet ID, the reason code, Success when omitted, and the properties
+fn decode_ack(first_byte: u8, data: &[u8]) -> Result<(u16, u8, AckProperties), DecodeError> {
+    // Bytes after the remaining length belong to the next packet
+    let data = first_packet(data)?;
+    let mut cursor = std::io::Cursor::new(data);
+
+    let packet_type = cursor.read_u8()?;
+    if packet_type != first_byte {
+        return Err(DecodeError::InvalidPacketType { got: packet_type });
+    }
+
+    let remaining_length = read_variable_length(&mut cursor)?;
+    if remaining_length < 2 {
+        return Err(DecodeError::Malformed(format!("remaining length {} leaves no room for the packet ID", remaining_length)));
+    }
+
+    let packet_id = cursor.read_u16::<BigEndian>()?;
+    let reason_code = if remaining_length > 2 {
+        cursor.read_u8()?
+    } else {
+        0x00
+    };
+    let properties = if remaining_length > 3 {
+        read_ack_properties(&mut cursor)?
+    } else {
+        AckProperties::default()
+    };
+    Ok((packet_id, reason_code, properties))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pubrec_pubrel_and_pubcomp() {
+        let packet = PubRecPacket::new(1);
+        assert_eq!(PubRecPacket::decode(&packet.encode()).unwrap(), packet);
+
+        let packet = PubRecPacket::with_reason(65535, 0x80);
+        assert_eq!(PubRecPacket::decode(&packet.encode()).unwrap(), packet);
+
+        let packet = PubRelPacket::new(42);
+        assert_eq!(PubRelPacket::decode(&packet.encode()).unwrap(), packet);
+
+        let packet = PubCompPacket::new(42);
+        assert_eq!(PubCompPacket::decode(&packet.encode()).unwrap(), packet);
+    }
+}
@@ -1,23 +1,37 @@
-/// MQTT SUBACK packet implementation for MQTT version 5.0.
-///
-/// The SUBACK packet is used to acknowledge a subscription request.
-/// It is sent in response to a SUBSCRIBE packet from the client.
-/// The SUBACK packet includes a Packet Identifier and a list of return codes
-/// that indicate the result of the subscription request for each Topic Filter.
-///
-/// Return codes:
-/// - 0x00: Success, QoS 0
-/// - 0x01: Success, QoS 1
-/// - 0x02: Success, QoS 2
-/// - 0x80: Failure (Invalid Topic Filter)
-///
+//! MQTT SUBACK packet implementation for MQTT version 5.0.
+//!
+//! The SUBACK packet is used to acknowledge a subscription request.
+//! It is sent in response to a SUBSCRIBE packet from the client.
+//! The SUBACK packet includes a Packet Identifier and a list of return codes
+//! that indicate the result of the subscription request for each Topic Filter.
+//!
+//! Return codes:
+//! - 0x00: Success, QoS 0
+//! - 0x01: Success, QoS 1
+//! - 0x02: Success, QoS 2
+//! - 0x80: Failure (Unspecified error, such as invalid subscription options)
+//! - 0x87: Failure (Not authorized)
+//! - 0x8F: Failure (Invalid Topic Filter)
+//!
+//! In MQTT 5 a property block, which may hold a reason string, sits between the
+//! Packet Identifier and the return codes.
+//!
 
 use byteorder::{BigEndian, WriteBytesExt, ReadBytesExt};
+use super::fixed_header::{first_packet, read_variable_length};
+use super::{read_ack_properties, write_ack_properties};
+use super::DecodeError;
+
+// Failure return codes of a topic filter
+pub const UNSPECIFIED_ERROR: u8 = 0x80; // The subscription is refused without a specific reason
+pub const NOT_AUTHORIZED: u8 = 0x87; // The client may not subscribe to the filter
+pub const TOPIC_FILTER_INVALID: u8 = 0x8F; // The filter is not well formed
 
 #[derive(Debug, PartialEq, Clone)]
 /// The SUBACK packet structure as defined in MQTT 5.0
 pub struct SubAckPacket {
     pub packet_id: u16,          // Unique identifier for the subscription
+    pub reason_string: Option<String>, // Human-readable reason of the result
     pub return_codes: Vec<u8>,   // List of return codes for each Topic Filter
 }
 
@@ -30,6 +44,7 @@ impl SubAckPacket {
     pub fn new(packet_id: u16, return_codes: Vec<u8>) -> Self {
         SubAckPacket {
             packet_id,
+            reason_string: None,
             return_codes,
         }
     }
@@ -49,6 +64,8 @@ impl SubAckPacket {
         // Packet Identifier (2 bytes)
         let mut variable_header = Vec::new();
         variable_header.write_u16::<BigEndian>(self.packet_id).unwrap();
+        // Properties (length and reason string)
+        write_ack_properties(&mut variable_header, self.reason_string.as_deref(), &[]);
 
         // Payload:
         // Return codes (1 byte for each topic filter's result)
@@ -90,27 +107,34 @@ impl SubAckPacket {
     /// # Returns
     /// This function returns a Result that contains either the decoded `SubAckPacket` 
     /// or an error if the decoding fails.
-    pub fn decode(data: &[u8]) -> Result<Self, String> {
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        // Bytes after the remaining length belong to the next packet
+        let data = first_packet(data)?;
         let mut cursor = std::io::Cursor::new(data);
 
         // Read the fixed header (first byte), it should be 0x90 for SUBACK
-        let packet_type = cursor.read_u8().map_err(|e| e.to_string())?;
+        let packet_type = cursor.read_u8()?;
         if packet_type != 0x90 {
-            return Err(format!("Invalid packet type: 0x{:02x}", packet_type));
+            return Err(DecodeError::InvalidPacketType { got: packet_type });
         }
 
         // Read the remaining length
-        let remaining_length = read_remaining_length(&mut cursor)?;
+        let remaining_length = read_variable_length(&mut cursor)?;
+
+        let variable_header_start = cursor.position() as usize;
 
         // Read the Packet Identifier (2 bytes)
-        let packet_id = cursor.read_u16::<BigEndian>().map_err(|e| e.to_string())?;
+        let packet_id = cursor.read_u16::<BigEndian>()?;
+
+        // Read the properties
+        let reason_string = read_ack_properties(&mut cursor)?.reason_string;
 
         // Read the payload (Return Codes)
         let mut return_codes = Vec::new();
-        let mut bytes_read = 2; // Start with the 2 bytes of the packet_id
+        let mut bytes_read = cursor.position() as usize - variable_header_start;
         while bytes_read < remaining_length {
             // Read each return code (1 byte per Topic Filter)
-            let return_code = cursor.read_u8().map_err(|e| e.to_string())?;
+            let return_code = cursor.read_u8()?;
             bytes_read += 1;
             return_codes.push(return_code);
         }
@@ -118,24 +142,23 @@ impl SubAckPacket {
         // Return the decoded SubAckPacket
         Ok(SubAckPacket {
             packet_id,
+            reason_string,
             return_codes,
         })
     }
 }
 
-/// Helper function to read the remaining length field (Variable Length Quantity encoding)
-fn read_remaining_length(cursor: &mut std::io::Cursor<&[u8]>) -> Result<usize, String> {
-    let mut multiplier = 1;
-    let mut value = 0;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    loop {
-        let byte = cursor.read_u8().map_err(|e| e.to_string())?;
-        value += (byte & 0x7F) as usize * multiplier;
-        if (byte & 0x80) == 0 {
-            break;
-        }
-        multiplier *= 128;
-    }
+    #[test]
+    fn suback_without_and_with_reason_string() {
+        let packet = SubAckPacket::new(1, vec![0x00]);
+        assert_eq!(SubAckPacket::decode(&packet.encode()).unwrap(), packet);
 
-    Ok(value)
-}
\ No newline at end of file
+        let mut packet = SubAckPacket::new(65535, vec![0x00, 0x01, 0x02, 0x8F]);
+        packet.reason_string = Some("one filter refused".to_string());
+        assert_eq!(SubAckPacket::decode(&packet.encode()).unwrap(), packet);
+    }
+}
@@ -1,11 +1,130 @@
-use std::io::{Cursor, Read}; // Importing necessary traits
+use std::io::Cursor; // Importing necessary traits
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use super::fixed_header::{first_packet, read_bytes, read_variable_length, PacketType};
+use super::qos::QoS;
+use super::{DecodeError, EncodeError};
+
+// First level of the filters of the shared subscriptions
+pub const SHARED_PREFIX: &str = "$share/";
+
+// Decode error of a SUBSCRIBE without topic filters, a protocol error that closes the connection
+pub const NO_TOPIC_FILTERS: &str = "SUBSCRIBE packet without topic filters";
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct SubscribePacket {
     pub packet_id: u16,         // Packet ID
     pub topic_filters: Vec<String>, // Topics being subscribed to
-    pub qos_values: Vec<u8>,       // QoS values for each topic
+    pub qos_values: Vec<u8>,       // Subscription options byte for each topic, QoS in bits 0-1
+}
+
+/*
+In MQTT 5 the byte after each topic filter is not only the QoS but a set of options:
+    Bits 0-1: Maximum QoS
+    Bit 2: No Local, messages are not sent back to the client that published them
+    Bit 3: Retain As Published, forwarded messages keep their retain flag
+    Bits 4-5: Retain Handling, when retained messages are sent on subscribe
+    Bits 6-7: Reserved, must be 0
+*/
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct SubscriptionOptions {
+    pub qos: QoS,                 // Maximum QoS of the messages sent to the subscription
+    pub no_local: bool,           // Do not send back messages published by the same client
+    pub retain_as_published: bool, // Keep the retain flag of forwarded messages
+    pub retain_handling: u8,      // 0: always send retained, 1: only on new subscription, 2: never
+}
+
+/// Returns true if the topic filter is well formed: not empty, and the wildcards take
+/// a whole level, with the multi-level wildcard `#` only as the last one. A shared
+/// subscription needs a share name and a filter after it.
+pub fn is_valid_topic_filter(filter: &str) -> bool {
+    if filter.is_empty() || filter.contains('\0') {
+        return false;
+    }
+    if filter == "$share" || filter.starts_with(SHARED_PREFIX) {
+        return parse_shared_filter(filter).is_some_and(|(_, filter)| is_valid_topic_filter(filter));
+    }
+
+    let levels: Vec<&str> = filter.split('/').collect();
+    levels.iter().enumerate().all(|(i, level)| {
+        match *level {
+            "#" => i == levels.len() - 1,
+            "+" => true,
+            _ => !level.contains('#') && !level.contains('+'),
+        }
+    })
+}
+
+/// Splits a shared subscription, `$share/{share name}/{filter}`, into its share name
+/// and the filter the members of the share are subscribed to. Returns None for the
+/// other filters, and for a share name that is empty or holds a wildcard.
+pub fn parse_shared_filter(filter: &str) -> Option<(&str, &str)> {
+    let (share_name, filter) = filter.strip_prefix(SHARED_PREFIX)?.split_once('/')?;
+    if share_name.is_empty() || share_name.contains(['+', '#']) || filter.is_empty() {
+        return None;
+    }
+    Some((share_name, filter))
+}
+
+/// Returns true if the topic name can be published to: it holds no wildcard, which
+/// only topic filters may use, and no null character.
+pub fn is_valid_topic_name(topic: &str) -> bool {
+    !topic.contains(['+', '#', '\0'])
+}
+
+/// Returns true if the topic name matches the topic filter: `+` matches exactly one
+/// level and `#` any number of them, its parent level included. Topics starting with
+/// `$` are not matched by a filter starting with a wildcard.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut topic_levels = topic.split('/');
+    for level in filter.split('/') {
+        match (level, topic_levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (level, Some(topic_level)) if level == topic_level => {}
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+impl SubscriptionOptions {
+    /// Parses the subscription options byte of a topic filter.
+    ///
+    /// # Returns
+    ///
+    /// The options, or an error if the reserved bits are set or the QoS or the
+    /// Retain Handling take the reserved value 3.
+    pub fn from_byte(byte: u8) -> Result<Self, DecodeError> {
+        if byte & 0xC0 != 0 {
+            return Err(DecodeError::InvalidFlags(format!("reserved subscription option bits set: 0x{:02x}", byte)));
+        }
+
+        let qos = QoS::from_u8(byte & 0x03)?;
+        let options = SubscriptionOptions {
+            qos,
+            no_local: byte & 0x04 != 0,
+            retain_as_published: byte & 0x08 != 0,
+            retain_handling: (byte >> 4) & 0x03,
+        };
+
+        if options.retain_handling > 2 {
+            return Err(DecodeError::InvalidFlags(format!("Retain Handling 3 in subscription options: 0x{:02x}", byte)));
+        }
+
+        Ok(options)
+    }
+
+    /// Encodes the options into the byte that follows the topic filter
+    pub fn to_byte(&self) -> u8 {
+        self.qos.to_u8()
+            | ((self.no_local as u8) << 2)
+            | ((self.retain_as_published as u8) << 3)
+            | ((self.retain_handling & 0x03) << 4)
+    }
 }
 
 impl SubscribePacket {
@@ -18,11 +137,32 @@ impl SubscribePacket {
         }
     }
 
+    /// Creates a SubscribePacket from topic filters paired with their subscription options
+    pub fn with_options(packet_id: u16, filters: Vec<(String, SubscriptionOptions)>) -> Self {
+        let (topic_filters, options): (Vec<String>, Vec<SubscriptionOptions>) = filters.into_iter().unzip();
+        SubscribePacket {
+            packet_id,
+            topic_filters,
+            qos_values: options.iter().map(SubscriptionOptions::to_byte).collect(),
+        }
+    }
+
+    /// Parses the subscription options byte of every topic filter, in order. Each byte
+    /// is parsed on its own so the invalid ones can be refused in the SUBACK without
+    /// failing the whole packet.
+    pub fn options(&self) -> Vec<Result<SubscriptionOptions, DecodeError>> {
+        self.qos_values.iter().map(|&byte| SubscriptionOptions::from_byte(byte)).collect()
+    }
+
     /// Encodes the SUBSCRIBE packet into bytes for transmission over the network.
     ///
     /// # Returns
-    /// A byte vector representing the SUBSCRIBE packet.
-    pub fn encode(&self) -> Vec<u8> {
+    /// A byte vector representing the SUBSCRIBE packet, or an error if its packet ID is 0.
+    pub fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        if self.packet_id == 0 {
+            return Err(EncodeError::ZeroPacketId(PacketType::Subscribe));
+        }
+
         let mut packet = Vec::new();
 
         // Fixed header (first byte): SUBSCRIBE packet type (0x82)
@@ -30,7 +170,7 @@ impl SubscribePacket {
 
         // Calculate remaining length, which includes the length of the packet ID and topic filters
         let mut remaining_length = 2; // 2 bytes for packet ID
-        for (_i, topic) in self.topic_filters.iter().enumerate() {
+        for topic in self.topic_filters.iter() {
             remaining_length += 2 + topic.len() + 1; // 2 bytes for topic length, topic bytes, 1 byte for QoS
         }
 
@@ -66,7 +206,7 @@ impl SubscribePacket {
         }
 
         // Return the encoded packet as a byte vector
-        packet
+        Ok(packet)
     }
 
     /// Decodes a byte slice into a SUBSCRIBE packet.
@@ -79,51 +219,61 @@ impl SubscribePacket {
     ///
     /// This function returns a Result that contains either the decoded `SubscribePacket`
     /// or an error if the decoding fails.
-    pub fn decode(data: &[u8]) -> Result<Self, String> {
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        // Bytes after the remaining length belong to the next packet
+        let data = first_packet(data)?;
         let mut cursor = Cursor::new(data);
 
         // Read the fixed header (first byte), it should be 0x82 for SUBSCRIBE
-        let packet_type = cursor.read_u8().map_err(|e| e.to_string())?;
+        let packet_type = cursor.read_u8()?;
         if packet_type != 0x82 {
-            return Err(format!("Invalid packet type: 0x{:02x}", packet_type));
+            return Err(DecodeError::InvalidPacketType { got: packet_type });
         }
 
-        // Read the remaining length (variable length encoding)
-        let remaining_length = read_remaining_length(&mut cursor)?;
+        // Read the remaining length (variable length encoding), it counts the bytes
+        // from the packet ID on, whatever the size of the length field itself
+        let remaining_length = read_variable_length(&mut cursor)?;
+        let end = cursor.position() as usize + remaining_length;
 
-        // Read the Packet Identifier (2 bytes)
-        let packet_id = cursor.read_u16::<BigEndian>().map_err(|e| e.to_string())?;
+        // Read the Packet Identifier (2 bytes), the SUBACK is matched by it so it cannot be 0
+        let packet_id = cursor.read_u16::<BigEndian>()?;
+        if packet_id == 0 {
+            return Err(DecodeError::ProtocolError("SUBSCRIBE with packet ID 0".to_string()));
+        }
 
         // Parse the topic filters and QoS values
         let mut topic_filters = Vec::new();
         let mut qos_values = Vec::new();
-        let mut bytes_read = 2 + 2; // Starting from the packet ID and length field
 
-        while bytes_read < remaining_length {
+        while (cursor.position() as usize) < end {
             // Read the length of the topic filter (2 bytes)
-            let topic_len = cursor.read_u16::<BigEndian>().map_err(|e| e.to_string())?;
-            bytes_read += 2;
+            let topic_len = cursor.read_u16::<BigEndian>()?;
 
             // Ensure that the length is valid
             if topic_len == 0 {
-                return Err("Topic length cannot be zero".to_string());
+                return Err(DecodeError::ProtocolError("empty topic filter".to_string()));
             }
 
             // Read the topic filter itself (topic_len bytes)
-            let mut topic_bytes = vec![0; topic_len as usize];
-            cursor.read_exact(&mut topic_bytes).map_err(|e| e.to_string())?;
-            bytes_read += topic_len as usize;
+            let topic_bytes = read_bytes(&mut cursor, topic_len as usize)?;
 
-            let topic = String::from_utf8(topic_bytes).map_err(|e| e.to_string())?;
+            let topic = String::from_utf8(topic_bytes)?;
+            if topic.contains('\0') {
+                return Err(DecodeError::InvalidTopic(topic));
+            }
 
             // Read the QoS value (1 byte)
-            let qos = cursor.read_u8().map_err(|e| e.to_string())?;
-            bytes_read += 1;
+            let qos = cursor.read_u8()?;
 
             topic_filters.push(topic);
             qos_values.push(qos);
         }
 
+        // A SUBSCRIBE must subscribe to at least one topic filter
+        if topic_filters.is_empty() {
+            return Err(DecodeError::ProtocolError(NO_TOPIC_FILTERS.to_string()));
+        }
+
         // Return the decoded SubscribePacket
         Ok(SubscribePacket {
             packet_id,
@@ -133,19 +283,23 @@ impl SubscribePacket {
     }
 }
 
-/// Helper function to read the remaining length field (Variable Length Quantity encoding)
-fn read_remaining_length(cursor: &mut Cursor<&[u8]>) -> Result<usize, String> {
-    let mut multiplier = 1;
-    let mut value = 0;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    loop {
-        let byte = cursor.read_u8().map_err(|e| e.to_string())?;
-        value += (byte & 0x7F) as usize * multiplier;
-        if (byte & 0x80) == 0 {
-            break;
-        }
-        multiplier *= 128;
-    }
+    #[test]
+    fn subscribe_with_one_and_several_filters() {
+        let packet = SubscribePacket::new(1, vec!["a".to_string()], vec![0]);
+        assert_eq!(SubscribePacket::decode(&packet.encode().unwrap()).unwrap(), packet);
 
-    Ok(value)
+        let options = SubscriptionOptions { qos: QoS::ExactlyOnce, no_local: true, retain_as_published: true, retain_handling: 2 };
+        let packet = SubscribePacket::with_options(
+            65535,
+            vec![
+                ("sensors/+/temp".to_string(), options),
+                ("logs/#".to_string(), SubscriptionOptions::default()),
+            ],
+        );
+        assert_eq!(SubscribePacket::decode(&packet.encode().unwrap()).unwrap(), packet);
+    }
 }
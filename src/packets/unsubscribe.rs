@@ -0,0 +1,190 @@
+//! MQTT UNSUBSCRIBE and UNSUBACK packets.
+
+/*
+The UNSUBSCRIBE packet lists the topic filters a client no longer wants messages
+from, the broker answers it with an UNSUBACK holding one reason code per filter:
+    0x00: Success, the subscription was removed
+    0x11: No subscription existed for the filter
+    0x8F: The topic filter is not well formed
+As with the SUBSCRIBE, the filters follow the packet ID without a property block,
+while the UNSUBACK has one between the packet ID and the reason codes.
+*/
+
+use std::io::Cursor;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use super::fixed_header::{first_packet, read_bytes, read_variable_length, write_variable_length, PacketType};
+use super::{read_ack_properties, write_ack_properties};
+use super::{DecodeError, EncodeError};
+
+const UNSUBSCRIBE: u8 = 0xA2; // Packet type for UNSUBSCRIBE, its flags must be 0010
+const UNSUBACK: u8 = 0xB0; // Packet type for UNSUBACK
+
+// Reason codes of a topic filter in the UNSUBACK
+pub const SUCCESS: u8 = 0x00; // The subscription was removed
+pub const NO_SUBSCRIPTION_EXISTED: u8 = 0x11; // The client was not subscribed to the filter
+pub const TOPIC_FILTER_INVALID: u8 = 0x8F; // The filter is not well formed
+
+// Decode error of an UNSUBSCRIBE without topic filters, a protocol error that closes the connection
+pub const NO_TOPIC_FILTERS: &str = "UNSUBSCRIBE packet without topic filters";
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct UnsubscribePacket {
+    pub packet_id: u16,             // Packet ID, echoed in the UNSUBACK
+    pub topic_filters: Vec<String>, // Topic filters to unsubscribe from
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct UnsubAckPacket {
+    pub packet_id: u16,                // Packet ID of the UNSUBSCRIBE acknowledged
+    pub reason_string: Option<String>, // Human-readable reason of the result
+    pub reason_codes: Vec<u8>,         // Result for each topic filter, in order
+}
+
+impl UnsubscribePacket {
+    // Constructor for creating an UnsubscribePacket
+    pub fn new(packet_id: u16, topic_filters: Vec<String>) -> Self {
+        UnsubscribePacket {
+            packet_id,
+            topic_filters,
+        }
+    }
+
+    /// Encodes the UNSUBSCRIBE packet, fixed header included, or returns an error if
+    /// its packet ID is 0
+    pub fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        if self.packet_id == 0 {
+            return Err(EncodeError::ZeroPacketId(PacketType::Unsubscribe));
+        }
+
+        let mut body = Vec::new();
+        body.write_u16::<BigEndian>(self.packet_id).unwrap();
+        for topic in &self.topic_filters {
+            body.write_u16::<BigEndian>(topic.len() as u16).unwrap();
+            body.extend_from_slice(topic.as_bytes());
+        }
+
+        let mut packet = vec![UNSUBSCRIBE];
+        write_variable_length(&mut packet, body.len());
+        packet.extend(body);
+        Ok(packet)
+    }
+
+    /// Decodes a byte slice into an UNSUBSCRIBE packet.
+    ///
+    /// # Returns
+    ///
+    /// The packet, or an error if the bytes are not a well formed UNSUBSCRIBE, an
+    /// UNSUBSCRIBE without topic filters included.
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        // Bytes after the remaining length belong to the next packet
+        let data = first_packet(data)?;
+        let mut cursor = Cursor::new(data);
+
+        let packet_type = cursor.read_u8()?;
+        if packet_type != UNSUBSCRIBE {
+            return Err(DecodeError::InvalidPacketType { got: packet_type });
+        }
+        read_variable_length(&mut cursor)?;
+
+        // The UNSUBACK is matched by the packet ID, so it cannot be 0
+        let packet_id = cursor.read_u16::<BigEndian>()?;
+        if packet_id == 0 {
+            return Err(DecodeError::ProtocolError("UNSUBSCRIBE with packet ID 0".to_string()));
+        }
+
+        let mut topic_filters = Vec::new();
+        while (cursor.position() as usize) < data.len() {
+            let topic_len = cursor.read_u16::<BigEndian>()? as usize;
+            if topic_len == 0 {
+                return Err(DecodeError::ProtocolError("empty topic filter".to_string()));
+            }
+
+            let topic = String::from_utf8(read_bytes(&mut cursor, topic_len)?)?;
+            if topic.contains('\0') {
+                return Err(DecodeError::InvalidTopic(topic));
+            }
+            topic_filters.push(topic);
+        }
+
+        // An UNSUBSCRIBE must name at least one topic filter
+        if topic_filters.is_empty() {
+            return Err(DecodeError::ProtocolError(NO_TOPIC_FILTERS.to_string()));
+        }
+
+        Ok(UnsubscribePacket {
+            packet_id,
+            topic_filters,
+        })
+    }
+}
+
+impl UnsubAckPacket {
+    // Constructor for creating an UnsubAckPacket without reason string
+    pub fn new(packet_id: u16, reason_codes: Vec<u8>) -> Self {
+        UnsubAckPacket {
+            packet_id,
+            reason_string: None,
+            reason_codes,
+        }
+    }
+
+    /// Encodes the UNSUBACK packet, fixed header included
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.write_u16::<BigEndian>(self.packet_id).unwrap();
+        write_ack_properties(&mut body, self.reason_string.as_deref(), &[]);
+        body.extend(&self.reason_codes);
+
+        let mut packet = vec![UNSUBACK];
+        write_variable_length(&mut packet, body.len());
+        packet.extend(body);
+        packet
+    }
+
+    /// Decodes a byte slice into an UNSUBACK packet.
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        // Bytes after the remaining length belong to the next packet
+        let data = first_packet(data)?;
+        let mut cursor = Cursor::new(data);
+
+        let packet_type = cursor.read_u8()?;
+        if packet_type != UNSUBACK {
+            return Err(DecodeError::InvalidPacketType { got: packet_type });
+        }
+        read_variable_length(&mut cursor)?;
+
+        let packet_id = cursor.read_u16::<BigEndian>()?;
+        let reason_string = read_ack_properties(&mut cursor)?.reason_string;
+        let reason_codes = data[cursor.position() as usize..].to_vec();
+
+        Ok(UnsubAckPacket {
+            packet_id,
+            reason_string,
+            reason_codes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsubscribe_with_one_and_several_filters() {
+        let packet = UnsubscribePacket::new(1, vec!["a".to_string()]);
+        assert_eq!(UnsubscribePacket::decode(&packet.encode().unwrap()).unwrap(), packet);
+
+        let packet = UnsubscribePacket::new(65535, vec!["sensors/+/temp".to_string(), "logs/#".to_string()]);
+        assert_eq!(UnsubscribePacket::decode(&packet.encode().unwrap()).unwrap(), packet);
+    }
+
+    #[test]
+    fn unsuback_without_and_with_reason_string() {
+        let packet = UnsubAckPacket::new(1, Vec::new());
+        assert_eq!(UnsubAckPacket::decode(&packet.encode()).unwrap(), packet);
+
+        let mut packet = UnsubAckPacket::new(65535, vec![0x00, 0x11, 0x8F]);
+        packet.reason_string = Some("one filter invalid".to_string());
+        assert_eq!(UnsubAckPacket::decode(&packet.encode()).unwrap(), packet);
+    }
+}
@@ -0,0 +1,72 @@
+//! The broker on Tokio tasks, driven by clients over real TCP connections.
+
+use std::net::SocketAddr;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use mqtt_broker::broker::async_server;
+use mqtt_broker::packets::{
+    connect::ConnectPacket,
+    fixed_header::{parse_fixed_header, PacketType},
+    framer::{Framer, PROTOCOL_MAXIMUM_PACKET_SIZE},
+    puback::PubAckPacket,
+    publish::PublishPacket,
+    qos::QoS,
+    suback::SubAckPacket,
+    subscribe::SubscribePacket,
+};
+
+// Client end of a connection, reading whole packets
+struct Client {
+    stream: TcpStream,
+    framer: Framer,
+}
+
+impl Client {
+    // Connects and completes the CONNECT / CONNACK exchange
+    async fn connect(addr: SocketAddr, client_id: &str) -> Self {
+        let mut client = Client { stream: TcpStream::connect(addr).await.unwrap(), framer: Framer::new(PROTOCOL_MAXIMUM_PACKET_SIZE) };
+        let connect = ConnectPacket::new("MQTT".to_string(), 5, 0x02, 60, client_id.to_string(), None, None, None, None);
+        client.stream.write_all(&connect.encode()).await.unwrap();
+        let connack = client.read_packet().await;
+        assert_eq!(parse_fixed_header(&connack).unwrap().packet_type, PacketType::ConnAck);
+        client
+    }
+
+    async fn read_packet(&mut self) -> Vec<u8> {
+        loop {
+            if let Some(packet) = self.framer.next_packet().unwrap() {
+                return packet;
+            }
+            let mut chunk = [0u8; 1024];
+            let size = self.stream.read(&mut chunk).await.unwrap();
+            assert!(size > 0, "connection closed by the broker");
+            self.framer.extend(&chunk[..size]);
+        }
+    }
+}
+
+#[tokio::test]
+async fn two_clients_exchange_a_publish() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async_server::serve(listener));
+
+    let mut subscriber = Client::connect(addr, "async-subscriber").await;
+    let subscribe = SubscribePacket::new(1, vec!["sensors/+".to_string()], vec![1]);
+    subscriber.stream.write_all(&subscribe.encode().unwrap()).await.unwrap();
+    let suback = SubAckPacket::decode(&subscriber.read_packet().await).unwrap();
+    assert_eq!(suback.return_codes, vec![1]);
+
+    let mut publisher = Client::connect(addr, "async-publisher").await;
+    let publish = PublishPacket::new("sensors/temperature".to_string(), 9, QoS::AtLeastOnce, false, false, b"21.5".to_vec());
+    publisher.stream.write_all(&publish.encode().unwrap()).await.unwrap();
+    let puback = PubAckPacket::decode(&publisher.read_packet().await).unwrap();
+    assert_eq!(puback.packet_id, 9);
+
+    let received = PublishPacket::decode(&subscriber.read_packet().await).unwrap();
+    assert_eq!(received.topic_name, "sensors/temperature");
+    assert_eq!(received.payload, b"21.5".to_vec());
+    assert_eq!(received.qos, QoS::AtLeastOnce);
+}
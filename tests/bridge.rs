@@ -0,0 +1,90 @@
+//! Bridging of topics with an upstream broker, a second broker in the same process.
+
+mod common;
+
+use std::io::{ErrorKind, Write};
+use std::thread;
+use std::time::Duration;
+
+use common::read_packet;
+use mqtt_broker::broker::{
+    transport::DuplexStream, Bridge, BridgeConfig, BridgeDirection, Broker, BrokerConfig, Transport,
+};
+use mqtt_broker::packets::{
+    fixed_header::{parse_fixed_header, PacketType},
+    publish::PublishPacket,
+    qos::QoS,
+    subscribe::{SubscribePacket, SubscriptionOptions},
+};
+
+// Connects a client and subscribes it to the topic filter
+fn subscriber(broker: &Broker, client_id: &str, filter: &str) -> DuplexStream {
+    let mut client = common::connect(broker, client_id);
+
+    let subscribe = SubscribePacket::with_options(1, vec![(filter.to_string(), SubscriptionOptions::default())]);
+    client.write_all(&subscribe.encode().unwrap()).unwrap();
+    let suback = read_packet(&mut client).unwrap();
+    assert_eq!(parse_fixed_header(&suback).unwrap().packet_type, PacketType::SubAck);
+
+    // The SUBACK is written before the subscription is registered
+    while !broker.active_topics().contains(&filter.to_string()) {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    client
+}
+
+// Bridge whose connections are accepted by the upstream broker over the in-memory transport
+fn bridge_to(upstream: &Broker, topic_filters: &[&str], direction: BridgeDirection) -> Bridge {
+    let upstream = upstream.clone();
+    let filters = topic_filters.iter().map(|filter| filter.to_string()).collect();
+    Bridge::new(
+        BridgeConfig::new("in-memory", filters, direction),
+        Box::new(move || {
+            let (local, remote) = DuplexStream::pair();
+            upstream.accept(remote);
+            let stream: Box<dyn Transport> = Box::new(local);
+            Ok(stream)
+        }),
+    )
+}
+
+#[test]
+fn matching_local_publishes_are_forwarded_upstream() {
+    let upstream = Broker::new(BrokerConfig::default());
+    let mut remote_client = subscriber(&upstream, "remote", "sensors/temperature");
+
+    let mut local = Broker::new(BrokerConfig::default());
+    local.set_bridge(bridge_to(&upstream, &["sensors/#"], BridgeDirection::Out));
+
+    let message = PublishPacket::new("sensors/temperature".to_string(), 0, QoS::AtMostOnce, false, false, b"21.5".to_vec());
+    local.publish(message.clone());
+    let bridged = PublishPacket::decode(&read_packet(&mut remote_client).unwrap()).unwrap();
+    assert_eq!(bridged, message);
+
+    // Topics outside the filters stay local
+    let mut remote_other = subscriber(&upstream, "remote-other", "alerts");
+    local.publish(PublishPacket::new("alerts".to_string(), 0, QoS::AtMostOnce, false, false, b"fire".to_vec()));
+    remote_other.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    assert_eq!(read_packet(&mut remote_other).unwrap_err().kind(), ErrorKind::WouldBlock);
+}
+
+#[test]
+fn upstream_publishes_reach_local_subscribers() {
+    let upstream = Broker::new(BrokerConfig::default());
+
+    let mut local = Broker::new(BrokerConfig::default());
+    let mut local_client = subscriber(&local, "local", "commands/lamp");
+    local.set_bridge(bridge_to(&upstream, &["commands/lamp"], BridgeDirection::In));
+
+    // The bridge subscribes upstream as soon as it is set, the upstream broker
+    // of the test only routes exact topics so the filter is one
+    while !upstream.active_topics().contains(&"commands/lamp".to_string()) {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let message = PublishPacket::new("commands/lamp".to_string(), 0, QoS::AtMostOnce, false, false, b"on".to_vec());
+    upstream.publish(message.clone());
+    let bridged = PublishPacket::decode(&read_packet(&mut local_client).unwrap()).unwrap();
+    assert_eq!(bridged, message);
+}
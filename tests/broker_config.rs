@@ -0,0 +1,207 @@
+//! Listening address, anonymous access, client ID policy, CONNECT timeout, redirect,
+//! maximum QoS and default and maximum keep alive of the broker configuration.
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{connect, read_packet};
+use mqtt_broker::broker::{transport::DuplexStream, Broker, BrokerConfig, ClientIdPolicy, Redirect, Transport};
+use mqtt_broker::packets::{
+    connack::{ConnAckPacket, ConnAckReasonCode},
+    connect::ConnectPacket,
+    disconnect::DisconnectReasonCode,
+    publish::PublishPacket,
+    qos::QoS,
+    suback::SubAckPacket,
+    subscribe::SubscribePacket,
+};
+
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+// Connects a client with the keep alive and returns the CONNACK of the broker
+fn connack_for(broker: &Broker, keep_alive: u16) -> ConnAckPacket {
+    let connect = ConnectPacket::new("MQTT".to_string(), 5, 0x02, keep_alive, "client".to_string(), None, None, None, None);
+    connack_of(broker, &connect)
+}
+
+// Sends the CONNECT on a new connection and returns the CONNACK of the broker
+fn connack_of(broker: &Broker, connect: &ConnectPacket) -> ConnAckPacket {
+    let (mut client, server) = DuplexStream::pair();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    broker.accept(server);
+
+    client.write_all(&connect.encode()).unwrap();
+    ConnAckPacket::decode(&read_packet(&mut client).unwrap()).unwrap()
+}
+
+#[test]
+fn broker_reports_the_port_it_was_bound_to() {
+    let config = BrokerConfig { bind_addr: "127.0.0.1:0".parse().unwrap(), ..BrokerConfig::default() };
+    let broker = Broker::new(config);
+    assert_eq!(broker.local_addr(), None);
+    let server = {
+        let broker = broker.clone();
+        thread::spawn(move || broker.run())
+    };
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let addr = loop {
+        if let Some(addr) = broker.local_addr() {
+            break addr;
+        }
+        assert!(Instant::now() < deadline, "broker not serving");
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert_ne!(addr.port(), 0);
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let connect = ConnectPacket::new("MQTT".to_string(), 5, 0x02, 60, "bound".to_string(), None, None, None, None);
+    client.write_all(&connect.encode()).unwrap();
+    assert!(ConnAckPacket::decode(&read_packet(&mut client).unwrap()).is_ok());
+
+    broker.shutdown();
+    server.join().unwrap();
+}
+
+#[test]
+fn options_are_read_from_the_arguments() {
+    let config = BrokerConfig::from_args(&args(&["--bind", "127.0.0.1:1884", "--max-qos", "1", "--keep-alive-default", "30"]));
+    assert_eq!(config.bind_addr, "127.0.0.1:1884".parse::<SocketAddr>().unwrap());
+    assert_eq!(config.max_qos, QoS::AtLeastOnce);
+    assert_eq!(config.keep_alive_default, 30);
+
+    // Invalid values leave the defaults
+    let config = BrokerConfig::from_args(&args(&["--bind", "localhost", "--max-qos", "3"]));
+    assert_eq!(config.bind_addr, BrokerConfig::default().bind_addr);
+    assert_eq!(config.max_qos, QoS::ExactlyOnce);
+}
+
+#[test]
+fn connack_announces_a_maximum_qos_below_2() {
+    let connack = connack_for(&Broker::new(BrokerConfig::default()), 60);
+    assert_eq!(connack.properties.unwrap().maximum_qos, None);
+
+    let broker = Broker::new(BrokerConfig { max_qos: QoS::AtMostOnce, ..BrokerConfig::default() });
+    assert_eq!(connack_for(&broker, 60).properties.unwrap().maximum_qos, Some(0));
+}
+
+#[test]
+fn subscription_is_granted_at_most_the_maximum_qos() {
+    let broker = Broker::new(BrokerConfig { max_qos: QoS::AtLeastOnce, ..BrokerConfig::default() });
+    let mut client = connect(&broker, "subscriber");
+
+    let subscribe = SubscribePacket::new(1, vec!["a".to_string(), "b".to_string()], vec![0x02, 0x00]);
+    client.write_all(&subscribe.encode().unwrap()).unwrap();
+    let suback = SubAckPacket::decode(&read_packet(&mut client).unwrap()).unwrap();
+    assert_eq!(suback.return_codes, vec![0x01, 0x00]);
+}
+
+#[test]
+fn publish_above_the_maximum_qos_disconnects_the_client() {
+    let broker = Broker::new(BrokerConfig { max_qos: QoS::AtLeastOnce, ..BrokerConfig::default() });
+    let mut client = connect(&broker, "publisher");
+
+    let publish = PublishPacket::new("a".to_string(), 1, QoS::ExactlyOnce, false, false, b"hello".to_vec());
+    client.write_all(&publish.encode().unwrap()).unwrap();
+    let disconnect = read_packet(&mut client).unwrap();
+    assert_eq!(disconnect[0], 0xE0);
+    assert_eq!(disconnect[2], DisconnectReasonCode::QoSNotSupported as u8);
+}
+
+#[test]
+fn client_without_keep_alive_gets_the_default() {
+    let broker = Broker::new(BrokerConfig { keep_alive_default: 30, ..BrokerConfig::default() });
+    assert_eq!(connack_for(&broker, 0).properties.unwrap().server_keep_alive, Some(30));
+
+    // A client with its own keep alive keeps it
+    assert_eq!(connack_for(&broker, 60).properties.unwrap().server_keep_alive, None);
+}
+
+#[test]
+fn keep_alive_above_the_maximum_is_capped() {
+    let broker = Broker::new(BrokerConfig { max_keep_alive: Some(1), ..BrokerConfig::default() });
+    assert_eq!(connack_for(&broker, 60).properties.unwrap().server_keep_alive, Some(1));
+
+    // The capped keep alive is the one enforced: a silent client is dropped after 1.5 seconds
+    let (mut client, server) = DuplexStream::pair();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    broker.accept(server);
+    let connect = ConnectPacket::new("MQTT".to_string(), 5, 0x02, 60, "silent".to_string(), None, None, None, None);
+    client.write_all(&connect.encode()).unwrap();
+    read_packet(&mut client).unwrap(); // CONNACK
+    let disconnect = read_packet(&mut client).unwrap();
+    assert_eq!(disconnect[0], 0xE0);
+    assert_eq!(disconnect[2], DisconnectReasonCode::KeepAliveTimeout as u8);
+}
+
+#[test]
+fn client_without_credentials_is_refused_when_anonymous_access_is_off() {
+    let broker = Broker::new(BrokerConfig { allow_anonymous: false, ..BrokerConfig::default() });
+
+    let anonymous = ConnectPacket::new("MQTT".to_string(), 5, 0x02, 60, "anonymous".to_string(), None, None, None, None);
+    assert_eq!(connack_of(&broker, &anonymous).reason_code, ConnAckReasonCode::NotAuthorized);
+
+    // Username and password flags, with the credentials
+    let known = ConnectPacket::new(
+        "MQTT".to_string(),
+        5,
+        0xC2,
+        60,
+        "known".to_string(),
+        None,
+        None,
+        Some("user".to_string()),
+        Some("secret".to_string()),
+    );
+    assert_eq!(connack_of(&broker, &known).reason_code, ConnAckReasonCode::Success);
+}
+
+#[test]
+fn connection_without_a_connect_is_closed_after_the_connect_timeout() {
+    let broker = Broker::new(BrokerConfig { connect_timeout: Duration::from_millis(200), ..BrokerConfig::default() });
+    let (mut client, server) = DuplexStream::pair();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let opened_at = Instant::now();
+    broker.accept(server);
+
+    // The broker closes the connection without writing anything
+    let mut received = Vec::new();
+    assert!(matches!(client.read_to_end(&mut received), Ok(0)));
+    assert!(opened_at.elapsed() >= Duration::from_millis(200));
+    assert!(opened_at.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn redirect_refuses_the_client_with_the_server_reference() {
+    let redirect = Redirect::UseAnotherServer("other.example:1883".to_string());
+    let broker = Broker::new(BrokerConfig { redirect: Some(redirect), ..BrokerConfig::default() });
+    let connack = connack_for(&broker, 60);
+    assert_eq!(connack.reason_code, ConnAckReasonCode::UseAnotherServer);
+    assert_eq!(connack.properties.unwrap().server_reference.as_deref(), Some("other.example:1883"));
+
+    let redirect = Redirect::ServerMoved("new.example:1883".to_string());
+    let broker = Broker::new(BrokerConfig { redirect: Some(redirect), ..BrokerConfig::default() });
+    let connack = connack_for(&broker, 60);
+    assert_eq!(connack.reason_code, ConnAckReasonCode::ServerMoved);
+    assert_eq!(connack.properties.unwrap().server_reference.as_deref(), Some("new.example:1883"));
+}
+
+#[test]
+fn strict_policy_refuses_client_ids_outside_the_mqtt_3_rules() {
+    let broker = Broker::new(BrokerConfig { client_id_policy: ClientIdPolicy::Strict23, ..BrokerConfig::default() });
+    let connack_of_id = |client_id: &str| {
+        let connect = ConnectPacket::new("MQTT".to_string(), 5, 0x02, 60, client_id.to_string(), None, None, None, None);
+        connack_of(&broker, &connect).reason_code
+    };
+
+    assert_eq!(connack_of_id("meter-1"), ConnAckReasonCode::ClientIdentifierNotValid);
+    assert_eq!(connack_of_id(&"a".repeat(24)), ConnAckReasonCode::ClientIdentifierNotValid);
+    assert_eq!(connack_of_id("meter1"), ConnAckReasonCode::Success);
+}
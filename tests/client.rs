@@ -0,0 +1,473 @@
+//! MqttClient against a scripted broker on a loopback socket, and against the broker
+//! of the crate.
+
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mqtt_broker::broker::{Broker, BrokerConfig};
+use mqtt_broker::client::{ClientEvent, ClientOptions, MqttClient, ProtocolMode, PublishError, SubscribeError, Will};
+use mqtt_broker::packets::{
+    connack::ConnAckPacket,
+    connect::{ConnectPacket, WillProperties},
+    disconnect::{DisconnectPacket, DisconnectReasonCode},
+    fixed_header::{parse_fixed_header, PacketType},
+    framer::{Framer, PROTOCOL_MAXIMUM_PACKET_SIZE},
+    ping::PingRespPacket,
+    puback::PubAckPacket,
+    publish::PublishPacket,
+    qos::QoS,
+};
+use mqtt_broker::MqttError;
+
+// Loopback listener of a scripted broker and the address the client connects to
+fn bind() -> (TcpListener, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    (listener, addr)
+}
+
+// Waits up to 5 seconds for the listener of the client to stop
+fn wait_until_disconnected(client: &MqttClient) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while client.is_connected() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn can_publish_waits_for_an_ack_once_the_window_is_full() {
+    let (listener, addr) = bind();
+    let (release, released) = mpsc::channel::<()>();
+
+    // Broker with a receive maximum of 2 that acknowledges the first publish when released
+    let broker = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut framer = Framer::new(PROTOCOL_MAXIMUM_PACKET_SIZE);
+        framer.read_packet(&mut stream).unwrap();
+        stream.write_all(&ConnAckPacket::builder().receive_maximum(2).build().encode()).unwrap();
+
+        let mut message_ids = Vec::new();
+        while message_ids.len() < 2 {
+            let packet = framer.read_packet(&mut stream).unwrap();
+            if let Ok(publish) = PublishPacket::decode(&packet) {
+                message_ids.push(publish.message_id);
+            }
+        }
+
+        released.recv().unwrap();
+        stream.write_all(&PubAckPacket::new(message_ids[0]).encode()).unwrap();
+        // Keeps the connection open until the client sends its DISCONNECT
+        while let Ok(packet) = framer.read_packet(&mut stream) {
+            if parse_fixed_header(&packet).map(|header| header.packet_type) == Ok(PacketType::Disconnect) {
+                break;
+            }
+        }
+    });
+
+    let client = MqttClient::connect(&addr, ClientOptions::new("backpressure")).unwrap();
+    assert!(client.can_publish());
+
+    for _ in 0..2 {
+        client.publish_with_ack("test", b"data", QoS::AtLeastOnce, |_| {});
+    }
+    assert_eq!(client.inflight_count(), 2);
+    assert!(!client.can_publish());
+
+    release.send(()).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !client.can_publish() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(client.can_publish());
+    assert_eq!(client.inflight_count(), 1);
+
+    client.disconnect();
+    broker.join().unwrap();
+}
+
+// Accepts the client, answers its CONNECT and its first PINGREQ, then sends one
+// more PINGRESP that no PINGREQ asked for
+fn accept_and_send_unsolicited_pingresp(listener: &TcpListener) -> (TcpStream, Framer) {
+    let (mut stream, _) = listener.accept().unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut framer = Framer::new(PROTOCOL_MAXIMUM_PACKET_SIZE);
+    framer.read_packet(&mut stream).unwrap();
+    stream.write_all(&ConnAckPacket::builder().build().encode()).unwrap();
+
+    let pingreq = framer.read_packet(&mut stream).unwrap();
+    assert_eq!(parse_fixed_header(&pingreq).unwrap().packet_type, PacketType::PingReq);
+    stream.write_all(&PingRespPacket.encode()).unwrap();
+    stream.write_all(&PingRespPacket.encode()).unwrap();
+    (stream, framer)
+}
+
+#[test]
+fn strict_client_disconnects_on_an_unsolicited_pingresp() {
+    let (listener, addr) = bind();
+    let broker = thread::spawn(move || {
+        let (mut stream, mut framer) = accept_and_send_unsolicited_pingresp(&listener);
+        framer.read_packet(&mut stream).unwrap()
+    });
+
+    let options = ClientOptions { mode: ProtocolMode::Strict, ..ClientOptions::new("strict") };
+    let client = MqttClient::connect(&addr, options).unwrap();
+
+    let disconnect = broker.join().unwrap();
+    assert_eq!(parse_fixed_header(&disconnect).unwrap().packet_type, PacketType::Disconnect);
+    assert_eq!(disconnect[2], DisconnectReasonCode::ProtocolError as u8);
+
+    wait_until_disconnected(&client);
+    assert!(!client.is_connected());
+}
+
+#[test]
+fn lenient_client_ignores_an_unsolicited_pingresp() {
+    let (listener, addr) = bind();
+    let broker = thread::spawn(move || {
+        let (mut stream, mut framer) = accept_and_send_unsolicited_pingresp(&listener);
+
+        // The client still acknowledges messages after the PINGRESP
+        let publish = PublishPacket::new("test".to_string(), 7, QoS::AtLeastOnce, false, false, b"on".to_vec());
+        stream.write_all(&publish.encode().unwrap()).unwrap();
+        let puback = framer.read_packet(&mut stream).unwrap();
+        assert_eq!(PubAckPacket::decode(&puback).unwrap().packet_id, 7);
+        framer.read_packet(&mut stream).unwrap()
+    });
+
+    let client = MqttClient::connect(&addr, ClientOptions::new("lenient")).unwrap();
+
+    // Time for the listener to read the PINGRESP and the PUBLISH
+    thread::sleep(Duration::from_millis(200));
+    assert!(client.is_connected());
+    client.disconnect();
+
+    let disconnect = broker.join().unwrap();
+    assert_eq!(disconnect[2], DisconnectReasonCode::NormalDisconnection as u8);
+}
+
+#[test]
+fn client_tears_down_when_the_broker_stops_answering() {
+    let (listener, addr) = bind();
+
+    // Broker that completes the CONNECT, then reads the PINGREQs without answering them
+    let broker = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut framer = Framer::new(PROTOCOL_MAXIMUM_PACKET_SIZE);
+        framer.read_packet(&mut stream).unwrap();
+        stream.write_all(&ConnAckPacket::builder().build().encode()).unwrap();
+
+        let mut pingreqs = 0;
+        while let Ok(packet) = framer.read_packet(&mut stream) {
+            if parse_fixed_header(&packet).map(|header| header.packet_type) == Ok(PacketType::PingReq) {
+                pingreqs += 1;
+            }
+        }
+        pingreqs
+    });
+
+    let keep_alive = Duration::from_secs(1);
+    let started = Instant::now();
+    let options = ClientOptions { keep_alive: keep_alive.as_secs() as u16, ..ClientOptions::new("silent") };
+    let client = MqttClient::connect(&addr, options).unwrap();
+
+    wait_until_disconnected(&client);
+    assert!(!client.is_connected());
+    assert!(started.elapsed() < keep_alive, "torn down after {:?}", started.elapsed());
+
+    // The connection is closed, which ends the read loop of the broker
+    assert!(broker.join().unwrap() >= 1);
+}
+
+#[test]
+fn disconnect_stops_the_listener_promptly() {
+    let (listener, addr) = bind();
+    let (release, released) = mpsc::channel::<()>();
+
+    // Broker that keeps the connection open after the DISCONNECT, so only the
+    // client can end its listener
+    let broker = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut framer = Framer::new(PROTOCOL_MAXIMUM_PACKET_SIZE);
+        framer.read_packet(&mut stream).unwrap();
+        stream.write_all(&ConnAckPacket::builder().build().encode()).unwrap();
+        while let Ok(packet) = framer.read_packet(&mut stream) {
+            if parse_fixed_header(&packet).map(|header| header.packet_type) == Ok(PacketType::Disconnect) {
+                break;
+            }
+        }
+        released.recv().unwrap();
+    });
+
+    let client = MqttClient::connect(&addr, ClientOptions::new("stopping")).unwrap();
+    // Time for the listener to wait on a read, which would last a quarter of the keep alive
+    thread::sleep(Duration::from_millis(100));
+
+    // The listener thread is joined before disconnect returns
+    let started = Instant::now();
+    client.disconnect();
+    assert!(started.elapsed() < Duration::from_secs(1), "listener stopped after {:?}", started.elapsed());
+
+    release.send(()).unwrap();
+    broker.join().unwrap();
+}
+
+#[test]
+fn dropping_the_client_sends_a_normal_disconnect() {
+    let (listener, addr) = bind();
+    let broker = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut framer = Framer::new(PROTOCOL_MAXIMUM_PACKET_SIZE);
+        framer.read_packet(&mut stream).unwrap();
+        stream.write_all(&ConnAckPacket::builder().build().encode()).unwrap();
+        loop {
+            let packet = framer.read_packet(&mut stream).unwrap();
+            if parse_fixed_header(&packet).unwrap().packet_type == PacketType::Disconnect {
+                return DisconnectPacket::decode(&packet).unwrap();
+            }
+        }
+    });
+
+    let client = MqttClient::connect(&addr, ClientOptions::new("dropped")).unwrap();
+    drop(client);
+
+    let disconnect = broker.join().unwrap();
+    assert!(matches!(disconnect.reason_code(), DisconnectReasonCode::NormalDisconnection));
+}
+
+#[test]
+fn publishes_of_several_threads_arrive_whole() {
+    const THREADS: u8 = 4;
+    const MESSAGES: usize = 25;
+    let (listener, addr) = bind();
+
+    // Broker reading every frame the client writes, each must be a whole PUBLISH
+    let broker = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut framer = Framer::new(PROTOCOL_MAXIMUM_PACKET_SIZE);
+        framer.read_packet(&mut stream).unwrap();
+        stream.write_all(&ConnAckPacket::builder().build().encode()).unwrap();
+
+        let mut received = [0; THREADS as usize];
+        while received.iter().sum::<usize>() < THREADS as usize * MESSAGES {
+            let packet = framer.read_packet(&mut stream).unwrap();
+            // The keep alive of the client may go in between
+            if parse_fixed_header(&packet).unwrap().packet_type == PacketType::PingReq {
+                stream.write_all(&PingRespPacket.encode()).unwrap();
+                continue;
+            }
+            let packet = PublishPacket::decode(&packet).unwrap();
+            let writer = packet.payload[0];
+            // A payload of the byte of its thread throughout, nothing of another write in it
+            assert!(packet.payload.iter().all(|&byte| byte == writer));
+            assert_eq!(packet.payload.len(), 8192);
+            received[writer as usize] += 1;
+        }
+        received
+    });
+
+    let client = MqttClient::connect(&addr, ClientOptions::new("busy")).unwrap();
+    thread::scope(|scope| {
+        for writer in 0..THREADS {
+            let client = &client;
+            scope.spawn(move || {
+                for _ in 0..MESSAGES {
+                    client.publish("bulk", &[writer; 8192], QoS::AtMostOnce).unwrap();
+                }
+            });
+        }
+    });
+
+    assert_eq!(broker.join().unwrap(), [MESSAGES; THREADS as usize]);
+}
+
+#[test]
+fn will_properties_reach_the_broker() {
+    let (listener, addr) = bind();
+    let broker = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut framer = Framer::new(PROTOCOL_MAXIMUM_PACKET_SIZE);
+        let connect = framer.read_packet(&mut stream).unwrap();
+        stream.write_all(&ConnAckPacket::builder().build().encode()).unwrap();
+        ConnectPacket::decode(&connect).unwrap()
+    });
+
+    let will = Will {
+        topic: "clients/will/status".to_string(),
+        message: b"offline".to_vec(),
+        qos: QoS::AtLeastOnce,
+        retain: true,
+        properties: WillProperties {
+            will_delay_interval: Some(30),
+            message_expiry_interval: Some(600),
+            content_type: Some("text/plain".to_string()),
+            ..WillProperties::default()
+        },
+    };
+    let client = MqttClient::connect(&addr, ClientOptions { will: Some(will), ..ClientOptions::new("will") }).unwrap();
+
+    let connect = broker.join().unwrap();
+    assert_eq!(connect.will_topic.as_deref(), Some("clients/will/status"));
+    assert_eq!(connect.will_message.as_deref(), Some(&b"offline"[..]));
+    assert_eq!(connect.will_qos, 1);
+    assert!(connect.will_retain);
+    let properties = connect.will_properties.unwrap();
+    assert_eq!(properties.will_delay_interval, Some(30));
+    assert_eq!(properties.message_expiry_interval, Some(600));
+    assert_eq!(properties.content_type.as_deref(), Some("text/plain"));
+    drop(client);
+}
+
+// Serves a broker of the crate on a loopback socket
+fn start_broker(config: BrokerConfig) -> (Broker, String) {
+    let (listener, addr) = bind();
+    let broker = Broker::new(config);
+    let serving = broker.clone();
+    thread::spawn(move || serving.serve(listener));
+    (broker, addr)
+}
+
+#[test]
+fn subscribes_and_publishes_through_the_broker() {
+    let (broker, addr) = start_broker(BrokerConfig::default());
+    let subscriber = MqttClient::connect(&addr, ClientOptions::new("subscriber")).unwrap();
+    let publisher = MqttClient::connect(&addr, ClientOptions::new("publisher")).unwrap();
+
+    assert_eq!(subscriber.subscribe("sensors/+/temperature", QoS::AtLeastOnce).unwrap(), QoS::AtLeastOnce);
+    assert_eq!(subscriber.subscribe("alerts/#", QoS::AtMostOnce).unwrap(), QoS::AtMostOnce);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while broker.active_topics().len() < 2 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(broker.active_topics(), vec!["alerts/#".to_string(), "sensors/+/temperature".to_string()]);
+
+    // QoS 1 returns once the PUBACK arrived, QoS 0 once the packet is written
+    publisher.publish("sensors/kitchen/temperature", b"21.5", QoS::AtLeastOnce).unwrap();
+    assert_eq!(publisher.inflight_count(), 0);
+    publisher.publish("alerts/fire", b"none", QoS::AtMostOnce).unwrap();
+
+    subscriber.disconnect();
+    publisher.disconnect();
+    broker.shutdown();
+}
+
+#[test]
+fn subscribe_returns_the_qos_granted_by_the_broker() {
+    let (broker, addr) = start_broker(BrokerConfig::default());
+    let client = MqttClient::connect(&addr, ClientOptions::new("greedy")).unwrap();
+
+    // The broker sends at most QoS 1, a request for QoS 2 is granted QoS 1
+    assert_eq!(client.subscribe("orders", QoS::ExactlyOnce).unwrap(), QoS::AtLeastOnce);
+    assert_eq!(client.subscribe("news", QoS::AtMostOnce).unwrap(), QoS::AtMostOnce);
+
+    client.disconnect();
+    broker.shutdown();
+}
+
+#[test]
+fn publish_the_broker_refuses_is_an_error() {
+    let (broker, addr) = start_broker(BrokerConfig::default());
+    let client = MqttClient::connect(&addr, ClientOptions::new("intruder")).unwrap();
+
+    // Topics starting with $ are reserved to the broker, which answers Not Authorized
+    let (sender, receiver) = mpsc::channel();
+    client.publish_with_ack("$SYS/uptime", b"0", QoS::AtLeastOnce, move |result| {
+        let _ = sender.send(result);
+    });
+    let result = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(matches!(result, Err(PublishError::Rejected(0x87))), "{:?}", result);
+    assert_eq!(client.inflight_count(), 0);
+
+    client.disconnect();
+    broker.shutdown();
+}
+
+#[test]
+fn invalid_topics_are_refused_without_sending_anything() {
+    let (broker, addr) = start_broker(BrokerConfig::default());
+    let client = MqttClient::connect(&addr, ClientOptions::new("careless")).unwrap();
+
+    assert!(matches!(client.subscribe("sensors/#/temperature", QoS::AtMostOnce), Err(SubscribeError::InvalidFilter(_))));
+    assert!(matches!(client.subscribe("", QoS::AtMostOnce), Err(SubscribeError::InvalidFilter(_))));
+    assert!(matches!(client.publish("sensors/+", b"21.5", QoS::AtLeastOnce), Err(PublishError::InvalidTopic(_))));
+    assert!(matches!(client.publish("sensors", b"21.5", QoS::ExactlyOnce), Err(PublishError::QoSNotSupported)));
+
+    // The broker saw none of it and the connection is still up
+    assert!(broker.active_topics().is_empty());
+    assert!(client.is_connected());
+    client.disconnect();
+    broker.shutdown();
+}
+
+#[test]
+fn refused_connection_is_an_error() {
+    let (broker, addr) = start_broker(BrokerConfig { allow_anonymous: false, ..BrokerConfig::default() });
+
+    match MqttClient::connect(&addr, ClientOptions::new("anonymous")) {
+        Err(MqttError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::ConnectionRefused),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("anonymous client accepted"),
+    }
+
+    let options = ClientOptions { username: Some("user".to_string()), ..ClientOptions::new("known") };
+    MqttClient::connect(&addr, options).unwrap().disconnect();
+    broker.shutdown();
+}
+
+#[test]
+fn operations_fail_once_the_connection_is_lost() {
+    let (broker, addr) = start_broker(BrokerConfig::default());
+    let client = MqttClient::connect(&addr, ClientOptions::new("abandoned")).unwrap();
+
+    broker.shutdown();
+    wait_until_disconnected(&client);
+    assert!(!client.is_connected());
+
+    assert!(matches!(client.subscribe("news", QoS::AtLeastOnce), Err(SubscribeError::ConnectionLost)));
+    assert!(matches!(client.publish("news", b"late", QoS::AtLeastOnce), Err(PublishError::ConnectionLost)));
+}
+
+#[test]
+fn messages_of_the_subscriptions_reach_the_events_receiver() {
+    let (broker, addr) = start_broker(BrokerConfig::default());
+    let subscriber = MqttClient::connect(&addr, ClientOptions::new("listening")).unwrap();
+    let publisher = MqttClient::connect(&addr, ClientOptions::new("talking")).unwrap();
+
+    let events = subscriber.take_events().unwrap();
+    assert!(subscriber.take_events().is_none()); // Only one receiver per client
+    subscriber.subscribe("sensors/+/temperature", QoS::AtLeastOnce).unwrap();
+
+    publisher.publish("sensors/kitchen/temperature", b"21.5", QoS::AtLeastOnce).unwrap();
+    match events.recv_timeout(Duration::from_secs(5)).unwrap() {
+        ClientEvent::Message(packet) => {
+            assert_eq!(packet.topic_name, "sensors/kitchen/temperature");
+            assert_eq!(packet.payload, b"21.5".to_vec());
+        }
+        event => panic!("unexpected event: {:?}", event),
+    }
+
+    // Disconnecting is not a lost connection, the receiver just ends
+    subscriber.disconnect();
+    assert!(events.recv_timeout(Duration::from_secs(5)).is_err());
+    publisher.disconnect();
+    broker.shutdown();
+}
+
+#[test]
+fn broker_shutdown_is_a_connection_lost_event() {
+    let (broker, addr) = start_broker(BrokerConfig::default());
+    let client = MqttClient::connect(&addr, ClientOptions::new("left-behind")).unwrap();
+    let events = client.take_events().unwrap();
+
+    broker.shutdown();
+    assert_eq!(
+        events.recv_timeout(Duration::from_secs(5)).unwrap(),
+        ClientEvent::ConnectionLost(Some(DisconnectReasonCode::ServerShuttingDown))
+    );
+    assert_eq!(events.recv_timeout(Duration::from_secs(5)), Err(mpsc::RecvTimeoutError::Disconnected));
+
+    // Nothing would ever arrive on a new receiver
+    assert!(client.take_events().is_none());
+}
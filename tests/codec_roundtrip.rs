@@ -0,0 +1,203 @@
+//! Property-based round trips of the packet codecs: every valid packet must
+//! decode back to itself once encoded.
+
+use proptest::collection::vec;
+use proptest::option;
+use proptest::prelude::*;
+
+use mqtt_broker::packets::{
+    connect::{ConnectPacket, WillProperties},
+    publish::{Message, PublishPacket},
+    qos::QoS,
+    subscribe::{SubscribePacket, SubscriptionOptions},
+    suback::SubAckPacket,
+    unsubscribe::{UnsubAckPacket, UnsubscribePacket},
+};
+
+// Short UTF-8 strings, multi-byte characters included, shrinking towards the empty string
+fn string() -> impl Strategy<Value = String> {
+    "\\PC{0,32}"
+}
+
+// Topic names cannot hold the wildcards of the topic filters
+fn topic_name() -> impl Strategy<Value = String> {
+    "[^+#\\p{C}]{0,32}"
+}
+
+// Topic filters cannot be empty
+fn topic_filter() -> impl Strategy<Value = String> {
+    "\\PC{1,32}"
+}
+
+fn will_properties() -> impl Strategy<Value = WillProperties> {
+    (
+        option::of(any::<u32>()),
+        option::of(0u8..=1),
+        option::of(any::<u32>()),
+        option::of(string()),
+        option::of(string()),
+        option::of(vec(any::<u8>(), 0..32)),
+        vec((string(), string()), 0..4),
+    )
+        .prop_map(|(will_delay_interval, payload_format_indicator, message_expiry_interval, content_type, response_topic, correlation_data, user_properties)| {
+            WillProperties {
+                will_delay_interval,
+                payload_format_indicator,
+                message_expiry_interval,
+                content_type,
+                response_topic,
+                correlation_data,
+                user_properties,
+            }
+        })
+}
+
+/*
+The connect flags follow the optional fields: the will flag (bit 2) is set with the
+will topic and message, and only then the will QoS (bits 3-4) and retain (bit 5) may be,
+the username and password flags (bits 7 and 6) with their fields. Bit 0 is reserved.
+*/
+fn connect_packet() -> impl Strategy<Value = ConnectPacket> {
+    (
+        prop_oneof![Just(4u8), Just(5u8)],
+        any::<bool>(),
+        0u8..=2,
+        any::<bool>(),
+        any::<u16>(),
+        string(),
+        option::of((string(), vec(any::<u8>(), 0..32), will_properties())),
+        option::of(string()),
+        option::of(string()),
+    )
+        .prop_map(|(protocol_level, clean_start, will_qos, will_retain, keep_alive, client_id, will, username, password)| {
+            let mut connect_flags = (clean_start as u8) << 1;
+            if will.is_some() {
+                connect_flags |= 0x04 | will_qos << 3 | (will_retain as u8) << 5;
+            }
+            if username.is_some() {
+                connect_flags |= 0x80;
+            }
+            if password.is_some() {
+                connect_flags |= 0x40;
+            }
+
+            let (will_topic, will_message, will_properties) = match will {
+                Some((topic, message, properties)) => (Some(topic), Some(message), Some(properties)),
+                None => (None, None, None),
+            };
+
+            let mut packet = ConnectPacket::new(
+                "MQTT".to_string(),
+                protocol_level,
+                connect_flags,
+                keep_alive,
+                client_id,
+                will_topic,
+                will_message,
+                username,
+                password,
+            );
+            // The will properties are only part of MQTT 5 packets
+            if protocol_level == 5 {
+                packet.will_properties = will_properties;
+            }
+            packet
+        })
+}
+
+fn qos() -> impl Strategy<Value = QoS> {
+    prop_oneof![Just(QoS::AtMostOnce), Just(QoS::AtLeastOnce), Just(QoS::ExactlyOnce)]
+}
+
+// QoS 0 messages carry neither a message ID nor the DUP flag, the others a non-zero message ID
+fn publish_packet() -> impl Strategy<Value = PublishPacket> {
+    (
+        option::of(1u16..),
+        qos(),
+        1u16..,
+        any::<bool>(),
+        any::<bool>(),
+        topic_name(),
+        prop_oneof![vec(any::<u8>(), 0..256), vec(any::<u8>(), 65_000..70_000)],
+    )
+        .prop_map(|(topic_alias, qos, message_id, retain, dup, topic_name, payload)| {
+            let (message_id, dup) = if qos == QoS::AtMostOnce { (0, false) } else { (message_id, dup) };
+            PublishPacket { topic_alias, ..PublishPacket::new(topic_name, message_id, qos, retain, dup, payload) }
+        })
+}
+
+fn subscription_options() -> impl Strategy<Value = SubscriptionOptions> {
+    (qos(), any::<bool>(), any::<bool>(), 0u8..=2).prop_map(|(qos, no_local, retain_as_published, retain_handling)| {
+        SubscriptionOptions { qos, no_local, retain_as_published, retain_handling }
+    })
+}
+
+fn subscribe_packet() -> impl Strategy<Value = SubscribePacket> {
+    (1u16.., vec((topic_filter(), subscription_options()), 1..8))
+        .prop_map(|(packet_id, filters)| SubscribePacket::with_options(packet_id, filters))
+}
+
+fn suback_packet() -> impl Strategy<Value = SubAckPacket> {
+    (any::<u16>(), option::of(string()), vec(any::<u8>(), 0..8)).prop_map(|(packet_id, reason_string, return_codes)| {
+        SubAckPacket { reason_string, ..SubAckPacket::new(packet_id, return_codes) }
+    })
+}
+
+fn unsubscribe_packet() -> impl Strategy<Value = UnsubscribePacket> {
+    (1u16.., vec(topic_filter(), 1..8)).prop_map(|(packet_id, filters)| UnsubscribePacket::new(packet_id, filters))
+}
+
+fn unsuback_packet() -> impl Strategy<Value = UnsubAckPacket> {
+    (any::<u16>(), option::of(string()), vec(any::<u8>(), 0..8)).prop_map(|(packet_id, reason_string, reason_codes)| {
+        UnsubAckPacket { reason_string, ..UnsubAckPacket::new(packet_id, reason_codes) }
+    })
+}
+
+proptest! {
+    #[test]
+    fn connect_round_trip(packet in connect_packet()) {
+        prop_assert_eq!(ConnectPacket::decode(&packet.encode()), Ok(packet));
+    }
+
+    #[test]
+    fn publish_round_trip(packet in publish_packet()) {
+        prop_assert_eq!(PublishPacket::decode(&packet.encode().unwrap()), Ok(packet));
+    }
+
+    #[test]
+    fn subscribe_round_trip(packet in subscribe_packet()) {
+        prop_assert_eq!(SubscribePacket::decode(&packet.encode().unwrap()), Ok(packet));
+    }
+
+    #[test]
+    fn suback_round_trip(packet in suback_packet()) {
+        prop_assert_eq!(SubAckPacket::decode(&packet.encode()), Ok(packet));
+    }
+
+    #[test]
+    fn unsubscribe_round_trip(packet in unsubscribe_packet()) {
+        prop_assert_eq!(UnsubscribePacket::decode(&packet.encode().unwrap()), Ok(packet));
+    }
+
+    #[test]
+    fn unsuback_round_trip(packet in unsuback_packet()) {
+        prop_assert_eq!(UnsubAckPacket::decode(&packet.encode()), Ok(packet));
+    }
+}
+
+#[test]
+fn message_round_trip_through_publish_packet() {
+    let message = Message {
+        topic: "sensors/temperature".to_string(),
+        payload: b"21.5".to_vec(),
+        qos: QoS::AtLeastOnce,
+        retain: true,
+    };
+
+    let packet = PublishPacket::from_message(&message, 42);
+    assert_eq!(packet.message_id, 42);
+    assert!(!packet.dup);
+
+    let decoded = PublishPacket::decode(&packet.encode().unwrap()).unwrap();
+    assert_eq!(decoded.to_message(), message);
+}
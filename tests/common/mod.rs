@@ -0,0 +1,50 @@
+//! Helpers shared by the tests that drive the broker over the in-memory transport.
+
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use mqtt_broker::broker::{transport::DuplexStream, Broker, Transport};
+use mqtt_broker::packets::{
+    connect::ConnectPacket,
+    fixed_header::{parse_fixed_header, PacketType},
+};
+
+// Reads one whole packet, its fixed header gives the bytes left to read
+pub fn read_packet<S: Read>(stream: &mut S) -> std::io::Result<Vec<u8>> {
+    let mut packet = vec![0; 1];
+    stream.read_exact(&mut packet)?;
+    loop {
+        let mut byte = [0; 1];
+        stream.read_exact(&mut byte)?;
+        packet.push(byte[0]);
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+
+    let header = parse_fixed_header(&packet).unwrap();
+    let mut rest = vec![0; header.remaining_length];
+    stream.read_exact(&mut rest)?;
+    packet.extend(rest);
+    Ok(packet)
+}
+
+// Opens a connection to the broker and completes the CONNECT / CONNACK exchange
+#[allow(dead_code)] // Not every test file connects with the default keep alive
+pub fn connect(broker: &Broker, client_id: &str) -> DuplexStream {
+    connect_with_keep_alive(broker, client_id, 60)
+}
+
+// Same as connect, announcing the given keep alive in seconds
+pub fn connect_with_keep_alive(broker: &Broker, client_id: &str, keep_alive: u16) -> DuplexStream {
+    let (mut client, server) = DuplexStream::pair();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    broker.accept(server);
+
+    let connect = ConnectPacket::new("MQTT".to_string(), 5, 0x02, keep_alive, client_id.to_string(), None, None, None, None);
+    client.write_all(&connect.encode()).unwrap();
+    let connack = read_packet(&mut client).unwrap();
+    assert_eq!(parse_fixed_header(&connack).unwrap().packet_type, PacketType::ConnAck);
+
+    client
+}
@@ -0,0 +1,225 @@
+//! Decoders report why a packet was rejected, and the broker answers each reason
+//! with its own reason code.
+
+mod common;
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use common::{connect, read_packet};
+use mqtt_broker::broker::{error_response, Broker, BrokerConfig, ConnectionPhase, DeadLetterSink, ServerAction};
+use mqtt_broker::packets::{
+    connack::ConnAckReasonCode,
+    disconnect::{DisconnectPacket, DisconnectReasonCode},
+    fixed_header::parse_fixed_header,
+    ping::{PingReqPacket, PingRespPacket},
+    puback::PubAckPacket,
+    publish::PublishPacket,
+    qos::QoS,
+    subscribe::{SubscribePacket, SubscriptionOptions},
+    DecodeError,
+};
+
+#[test]
+fn empty_packet_is_unexpected_eof() {
+    assert_eq!(parse_fixed_header(&[]), Err(DecodeError::UnexpectedEof));
+    assert_eq!(PubAckPacket::decode(&[0x40]), Err(DecodeError::UnexpectedEof));
+}
+
+#[test]
+fn reserved_packet_type_is_reported() {
+    assert_eq!(parse_fixed_header(&[0x00, 0x00]), Err(DecodeError::InvalidPacketType { got: 0x00 }));
+}
+
+#[test]
+fn packet_of_another_type_is_reported() {
+    let publish = PublishPacket::new("sensors".to_string(), 1, QoS::AtLeastOnce, false, false, Vec::new()).encode().unwrap();
+
+    assert_eq!(PubAckPacket::decode(&publish), Err(DecodeError::InvalidPacketType { got: 0x32 }));
+}
+
+#[test]
+fn remaining_length_over_four_bytes_is_malformed() {
+    let packet = [0x30, 0xFF, 0xFF, 0xFF, 0xFF, 0x01];
+
+    assert_eq!(parse_fixed_header(&packet), Err(DecodeError::MalformedRemainingLength));
+}
+
+#[test]
+fn property_lengths_over_four_bytes_are_malformed() {
+    // QoS 0 PUBLISH to "t" whose property length never ends
+    let mut packet = vec![0x30, 0x0E, 0x00, 0x01, b't'];
+    packet.extend([0xFF; 11]);
+    assert_eq!(PublishPacket::decode(&packet), Err(DecodeError::MalformedRemainingLength));
+
+    // Subscription identifier that never ends, within a property block of 11 bytes
+    let mut packet = vec![0x30, 0x0F, 0x00, 0x01, b't', 0x0B, 0x0B];
+    packet.extend([0xFF; 10]);
+    assert_eq!(PublishPacket::decode(&packet), Err(DecodeError::MalformedRemainingLength));
+}
+
+#[test]
+fn qos_3_publish_is_invalid_qos() {
+    let packet = [0x36, 0x05, 0x00, 0x01, b't', 0x00, 0x01];
+
+    assert_eq!(PublishPacket::decode(&packet), Err(DecodeError::InvalidQoS(3)));
+}
+
+#[test]
+fn dup_on_qos_0_publish_is_invalid_flags() {
+    let mut packet = PublishPacket::new("sensors".to_string(), 0, QoS::AtMostOnce, false, false, Vec::new()).encode().unwrap();
+    packet[0] |= 0x08;
+
+    let err = PublishPacket::decode(&packet).unwrap_err();
+    assert!(matches!(err, DecodeError::InvalidFlags(_)), "unexpected error: {}", err);
+    assert_eq!(err.disconnect_reason() as u8, DisconnectReasonCode::MalformedPacket as u8);
+}
+
+#[test]
+fn topic_that_is_not_utf8_is_reported() {
+    // QoS 0 PUBLISH with a 2-byte topic that is not valid UTF-8
+    let packet = [0x30, 0x05, 0x00, 0x02, 0xC3, 0x28, 0x00];
+
+    assert_eq!(PublishPacket::decode(&packet), Err(DecodeError::InvalidUtf8));
+}
+
+#[test]
+fn reserved_subscription_options_are_invalid_flags() {
+    let err = SubscriptionOptions::from_byte(0x40).unwrap_err();
+
+    assert!(matches!(err, DecodeError::InvalidFlags(_)), "unexpected error: {}", err);
+}
+
+#[test]
+fn subscribe_without_filters_is_a_protocol_error() {
+    let packet = [0x82, 0x02, 0x00, 0x01];
+
+    let err = SubscribePacket::decode(&packet).unwrap_err();
+    assert!(matches!(err, DecodeError::ProtocolError(_)), "unexpected error: {}", err);
+    assert_eq!(err.disconnect_reason() as u8, DisconnectReasonCode::ProtocolError as u8);
+}
+
+#[test]
+fn unknown_disconnect_reason_code_is_reported() {
+    let packet = [0xE0, 0x01, 0x7F];
+
+    assert!(matches!(DisconnectPacket::decode(&packet), Err(DecodeError::InvalidReasonCode(0x7F))));
+}
+
+#[test]
+fn errors_refuse_the_connect_with_a_connack() {
+    for err in [DecodeError::UnexpectedEof, DecodeError::InvalidUtf8, DecodeError::MalformedRemainingLength] {
+        assert_eq!(
+            error_response(&err, ConnectionPhase::Connect),
+            ServerAction::ConnAck(ConnAckReasonCode::MalformedPacket),
+            "{}",
+            err
+        );
+    }
+    assert_eq!(
+        error_response(&DecodeError::ProtocolError("receive maximum of 0".to_string()), ConnectionPhase::Connect),
+        ServerAction::ConnAck(ConnAckReasonCode::ProtocolError)
+    );
+}
+
+#[test]
+fn errors_end_the_session_with_a_disconnect() {
+    for err in [DecodeError::UnexpectedEof, DecodeError::InvalidUtf8, DecodeError::MalformedRemainingLength] {
+        assert_eq!(
+            error_response(&err, ConnectionPhase::Session),
+            ServerAction::Disconnect(DisconnectReasonCode::MalformedPacket),
+            "{}",
+            err
+        );
+    }
+    assert_eq!(
+        error_response(&DecodeError::InvalidTopic("a/#".to_string()), ConnectionPhase::Session),
+        ServerAction::Disconnect(DisconnectReasonCode::TopicNameInvalid)
+    );
+}
+
+#[test]
+fn truncated_puback_disconnects_the_client() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut client = connect(&broker, "truncated");
+
+    // PUBACK with a single byte of packet ID
+    client.write_all(&[0x40, 0x01, 0x00]).unwrap();
+    let disconnect = read_packet(&mut client).unwrap();
+    assert_eq!(disconnect[0], 0xE0);
+    assert_eq!(disconnect[2], DisconnectReasonCode::MalformedPacket as u8);
+}
+
+// Keeps every rejected packet with its error
+#[derive(Default)]
+struct RecordDeadLetters(Mutex<Vec<(Vec<u8>, DecodeError)>>);
+
+impl DeadLetterSink for RecordDeadLetters {
+    fn on_rejected(&self, raw: &[u8], err: &DecodeError) {
+        self.0.lock().unwrap().push((raw.to_vec(), err.clone()));
+    }
+}
+
+#[test]
+fn rejected_packet_is_handed_to_the_dead_letter_sink() {
+    let mut broker = Broker::new(BrokerConfig::default());
+    let sink = Arc::new(RecordDeadLetters::default());
+    broker.set_dead_letter_sink(sink.clone());
+    let mut client = connect(&broker, "truncated");
+
+    client.write_all(&[0x40, 0x01, 0x00]).unwrap();
+    read_packet(&mut client).unwrap(); // DISCONNECT
+
+    // The sink had the packet before the client was disconnected
+    let rejected = sink.0.lock().unwrap();
+    assert_eq!(rejected.len(), 1);
+    assert_eq!(rejected[0].0, vec![0x40, 0x01, 0x00]);
+    assert_eq!(Err(rejected[0].1.clone()), PubAckPacket::decode(&[0x40, 0x01, 0x00]));
+}
+
+#[test]
+fn packet_that_cannot_be_framed_is_handed_to_the_dead_letter_sink() {
+    let mut broker = Broker::new(BrokerConfig { max_packet_size: 64, ..BrokerConfig::default() });
+    let sink = Arc::new(RecordDeadLetters::default());
+    broker.set_dead_letter_sink(sink.clone());
+
+    // Remaining length continued over a fifth byte
+    let mut client = connect(&broker, "malformed");
+    client.write_all(&[0x30, 0xFF, 0xFF, 0xFF, 0xFF, 0x01]).unwrap();
+    let disconnect = read_packet(&mut client).unwrap();
+    assert_eq!(disconnect[2], DisconnectReasonCode::MalformedPacket as u8);
+
+    // PUBLISH announcing more bytes than the maximum packet size
+    let mut client = connect(&broker, "oversized");
+    client.write_all(&[0x30, 0x80, 0x01]).unwrap();
+    let disconnect = read_packet(&mut client).unwrap();
+    assert_eq!(disconnect[2], DisconnectReasonCode::PacketTooLarge as u8);
+
+    let rejected = sink.0.lock().unwrap();
+    assert_eq!(rejected.len(), 2);
+    assert_eq!(rejected[0], (vec![0x30, 0xFF, 0xFF, 0xFF, 0xFF, 0x01], DecodeError::MalformedRemainingLength));
+    assert_eq!(rejected[1], (vec![0x30, 0x80, 0x01], DecodeError::PacketTooLarge(131)));
+}
+
+#[test]
+fn ping_packets_are_only_their_fixed_header() {
+    assert_eq!(PingReqPacket::decode(&[0xC0, 0x00]), Ok(PingReqPacket));
+    assert_eq!(PingRespPacket::decode(&[0xD0, 0x00]), Ok(PingRespPacket));
+
+    assert!(matches!(PingReqPacket::decode(&[0xC0]), Err(DecodeError::Malformed(_))));
+    assert!(matches!(PingReqPacket::decode(&[0xC0, 0x01, 0x00]), Err(DecodeError::Malformed(_))));
+    assert!(matches!(PingRespPacket::decode(&[0xD0, 0x01]), Err(DecodeError::Malformed(_))));
+    assert_eq!(PingReqPacket::decode(&[0xD0, 0x00]), Err(DecodeError::InvalidPacketType { got: 0xD0 }));
+    assert_eq!(PingRespPacket::decode(&[0xC0, 0x00]), Err(DecodeError::InvalidPacketType { got: 0xC0 }));
+}
+
+#[test]
+fn pingreq_with_a_payload_disconnects_the_client() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut client = connect(&broker, "chatty-ping");
+
+    client.write_all(&[0xC0, 0x01, 0x00]).unwrap();
+    let disconnect = read_packet(&mut client).unwrap();
+    assert_eq!(disconnect[0], 0xE0);
+    assert_eq!(disconnect[2], DisconnectReasonCode::MalformedPacket as u8);
+}
@@ -0,0 +1,253 @@
+//! Delivery of messages to subscribers, driven over the in-memory transport.
+
+mod common;
+
+use std::io::{ErrorKind, Write};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use common::read_packet;
+use mqtt_broker::broker::{transport::DuplexStream, Broker, BrokerConfig, Interceptor, Transport};
+use mqtt_broker::packets::{
+    connect::ConnectPacket,
+    fixed_header::{parse_fixed_header, PacketType},
+    ping::PingReqPacket,
+    puback::PubAckPacket,
+    publish::PublishPacket,
+    qos::QoS,
+    subscribe::{SubscribePacket, SubscriptionOptions},
+};
+
+// Connects a client and subscribes it to the topic
+fn subscriber(broker: &Broker, client_id: &str, topic: &str) -> DuplexStream {
+    let mut client = common::connect(broker, client_id);
+
+    let subscribe = SubscribePacket::with_options(1, vec![(topic.to_string(), SubscriptionOptions::default())]);
+    client.write_all(&subscribe.encode().unwrap()).unwrap();
+    let suback = read_packet(&mut client).unwrap();
+    assert_eq!(parse_fixed_header(&suback).unwrap().packet_type, PacketType::SubAck);
+
+    // The SUBACK is written before the subscription is registered
+    while !broker.active_topics().contains(&topic.to_string()) {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    client
+}
+
+#[test]
+fn paused_subscriber_receives_messages_after_resume() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut client = subscriber(&broker, "paused", "batch");
+
+    assert!(broker.pause_delivery("paused"));
+    let message = PublishPacket::new("batch".to_string(), 0, QoS::AtMostOnce, false, false, b"queued".to_vec());
+    broker.publish(message.clone());
+
+    client.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    let err = read_packet(&mut client).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+    assert!(broker.resume_delivery("paused"));
+    let delivered = PublishPacket::decode(&read_packet(&mut client).unwrap()).unwrap();
+    assert_eq!(delivered, message);
+}
+
+#[test]
+fn pausing_an_unknown_client_fails() {
+    let broker = Broker::new(BrokerConfig::default());
+
+    assert!(!broker.pause_delivery("nobody"));
+    assert!(!broker.resume_delivery("nobody"));
+}
+
+#[test]
+fn unacknowledged_message_is_sent_again_as_a_duplicate() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut client = common::connect(&broker, "forgetful");
+    let options = SubscriptionOptions { qos: QoS::AtLeastOnce, ..Default::default() };
+    let subscribe = SubscribePacket::with_options(1, vec![("orders".to_string(), options)]);
+    client.write_all(&subscribe.encode().unwrap()).unwrap();
+    read_packet(&mut client).unwrap();
+
+    broker.publish(PublishPacket::new("orders".to_string(), 0, QoS::AtLeastOnce, false, false, b"1 pizza".to_vec()));
+    let first = PublishPacket::decode(&read_packet(&mut client).unwrap()).unwrap();
+    assert!(!first.dup);
+
+    // No PUBACK: the broker sends it again once the retransmit timeout of 5 seconds is over
+    client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let again = PublishPacket::decode(&read_packet(&mut client).unwrap()).unwrap();
+    assert!(again.dup);
+    assert_eq!(again.message_id, first.message_id);
+    assert_eq!(again.payload, b"1 pizza");
+}
+
+#[test]
+fn message_without_subscribers_is_counted_as_dropped() {
+    let broker = Broker::new(BrokerConfig::default());
+    assert_eq!(broker.stats().dropped_no_subscriber, 0);
+
+    broker.publish(PublishPacket::new("void".to_string(), 0, QoS::AtMostOnce, false, false, b"lost".to_vec()));
+    assert_eq!(broker.stats().dropped_no_subscriber, 1);
+
+    // A message that reaches a subscriber is not counted
+    let mut client = subscriber(&broker, "listener", "heard");
+    broker.publish(PublishPacket::new("heard".to_string(), 0, QoS::AtMostOnce, false, false, b"kept".to_vec()));
+    assert_eq!(PublishPacket::decode(&read_packet(&mut client).unwrap()).unwrap().payload, b"kept");
+    assert_eq!(broker.stats().dropped_no_subscriber, 1);
+}
+
+// Fails while routing the messages of one topic
+struct PanicOnTopic(&'static str);
+
+impl Interceptor for PanicOnTopic {
+    fn on_publish(&self, packet: PublishPacket) -> Option<PublishPacket> {
+        if packet.topic_name == self.0 {
+            panic!("routing failure on {}", self.0);
+        }
+        Some(packet)
+    }
+}
+
+#[test]
+fn puback_is_sent_once_the_message_is_routed() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut subscriber = subscriber(&broker, "subscriber", "orders");
+    let mut publisher = common::connect(&broker, "publisher");
+
+    let message = PublishPacket::new("orders".to_string(), 7, QoS::AtLeastOnce, false, false, b"1 pizza".to_vec());
+    publisher.write_all(&message.encode().unwrap()).unwrap();
+    let puback = PubAckPacket::decode(&read_packet(&mut publisher).unwrap()).unwrap();
+    assert_eq!(puback.packet_id, 7);
+
+    // The message was queued for the subscriber before the PUBACK was written
+    subscriber.set_read_timeout(Some(Duration::from_millis(1))).unwrap();
+    let delivered = PublishPacket::decode(&read_packet(&mut subscriber).unwrap()).unwrap();
+    assert_eq!(delivered.payload, b"1 pizza");
+}
+
+#[test]
+fn failed_routing_is_never_acknowledged() {
+    let mut broker = Broker::new(BrokerConfig::default());
+    broker.set_interceptor(Arc::new(PanicOnTopic("boom")));
+    let mut publisher = common::connect(&broker, "publisher");
+
+    let message = PublishPacket::new("boom".to_string(), 1, QoS::AtLeastOnce, false, false, b"lost".to_vec());
+    publisher.write_all(&message.encode().unwrap()).unwrap();
+
+    publisher.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    let err = read_packet(&mut publisher).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+    // Other clients are still served
+    let mut other = subscriber(&broker, "other", "calm");
+    broker.publish(PublishPacket::new("calm".to_string(), 0, QoS::AtMostOnce, false, false, b"ok".to_vec()));
+    assert_eq!(PublishPacket::decode(&read_packet(&mut other).unwrap()).unwrap().payload, b"ok");
+}
+
+#[test]
+fn payload_larger_than_a_read_arrives_intact() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut subscriber = subscriber(&broker, "subscriber", "firmware");
+    let mut publisher = common::connect(&broker, "publisher");
+
+    let payload: Vec<u8> = (0..4096).map(|i| (i % 251) as u8).collect();
+    let message = PublishPacket::new("firmware".to_string(), 0, QoS::AtMostOnce, false, false, payload.clone());
+    publisher.write_all(&message.encode().unwrap()).unwrap();
+
+    let delivered = PublishPacket::decode(&read_packet(&mut subscriber).unwrap()).unwrap();
+    assert_eq!(delivered.payload, payload);
+}
+
+#[test]
+fn packet_above_the_maximum_size_disconnects_the_client() {
+    let broker = Broker::new(BrokerConfig { max_packet_size: 1024, ..BrokerConfig::default() });
+    let mut publisher = common::connect(&broker, "publisher");
+
+    let message = PublishPacket::new("firmware".to_string(), 0, QoS::AtMostOnce, false, false, vec![0; 2048]);
+    publisher.write_all(&message.encode().unwrap()).unwrap();
+
+    let disconnect = read_packet(&mut publisher).unwrap();
+    assert_eq!(parse_fixed_header(&disconnect).unwrap().packet_type, PacketType::Disconnect);
+    assert_eq!(disconnect[2], 0x95); // Packet too large
+}
+
+#[test]
+fn subscriber_gets_no_more_messages_in_flight_than_its_receive_maximum() {
+    let broker = Broker::new(BrokerConfig::default());
+
+    // Subscriber announcing it accepts 2 unacknowledged QoS 1 messages
+    let (mut client, server) = DuplexStream::pair();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    broker.accept(server);
+    let mut connect = ConnectPacket::new("MQTT".to_string(), 5, 0x02, 60, "narrow".to_string(), None, None, None, None);
+    connect.properties.receive_maximum = Some(2);
+    client.write_all(&connect.encode()).unwrap();
+    read_packet(&mut client).unwrap(); // CONNACK
+    let subscribe = SubscribePacket::with_options(
+        1,
+        vec![("burst".to_string(), SubscriptionOptions { qos: QoS::AtLeastOnce, ..Default::default() })],
+    );
+    client.write_all(&subscribe.encode().unwrap()).unwrap();
+    read_packet(&mut client).unwrap(); // SUBACK
+    while broker.active_topics().is_empty() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // Every message is routed, and queued for the subscriber, before it is acknowledged
+    let mut publisher = common::connect(&broker, "publisher");
+    for i in 1..=5u8 {
+        let message = PublishPacket::new("burst".to_string(), i as u16, QoS::AtLeastOnce, false, false, vec![i]);
+        publisher.write_all(&message.encode().unwrap()).unwrap();
+        assert_eq!(PubAckPacket::decode(&read_packet(&mut publisher).unwrap()).unwrap().packet_id, i as u16);
+    }
+
+    // Two messages are in flight, the next packet is the answer to the PINGREQ
+    let mut in_flight: Vec<PublishPacket> = (0..2)
+        .map(|_| PublishPacket::decode(&read_packet(&mut client).unwrap()).unwrap())
+        .collect();
+    client.write_all(&PingReqPacket.encode()).unwrap();
+    assert_eq!(read_packet(&mut client).unwrap(), vec![0xD0, 0x00]);
+
+    // Each PUBACK lets exactly one more message go, in the order they were published
+    let mut payloads: Vec<u8> = in_flight.iter().map(|packet| packet.payload[0]).collect();
+    while !in_flight.is_empty() {
+        let acknowledged = in_flight.remove(0);
+        client.write_all(&PubAckPacket::new(acknowledged.message_id).encode()).unwrap();
+        if payloads.len() < 5 {
+            let next = PublishPacket::decode(&read_packet(&mut client).unwrap()).unwrap();
+            payloads.push(next.payload[0]);
+            in_flight.push(next);
+        }
+        client.write_all(&PingReqPacket.encode()).unwrap();
+        assert_eq!(read_packet(&mut client).unwrap(), vec![0xD0, 0x00]);
+    }
+    assert_eq!(payloads, vec![1, 2, 3, 4, 5]);
+}
+
+#[test]
+fn interleaved_qos_0_and_qos_1_messages_keep_their_order() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut subscriber = common::connect(&broker, "subscriber");
+    let options = SubscriptionOptions { qos: QoS::AtLeastOnce, ..Default::default() };
+    subscriber.write_all(&SubscribePacket::with_options(1, vec![("ticks".to_string(), options)]).encode().unwrap()).unwrap();
+    read_packet(&mut subscriber).unwrap(); // SUBACK
+    let mut publisher = common::connect(&broker, "publisher");
+
+    // Even messages at QoS 0, odd ones at QoS 1, written back to back
+    for i in 0..20u16 {
+        let qos = if i % 2 == 0 { QoS::AtMostOnce } else { QoS::AtLeastOnce };
+        let message = PublishPacket::new("ticks".to_string(), i, qos, false, false, i.to_string().into_bytes());
+        publisher.write_all(&message.encode().unwrap()).unwrap();
+    }
+
+    for i in 0..20u16 {
+        let delivered = PublishPacket::decode(&read_packet(&mut subscriber).unwrap()).unwrap();
+        assert_eq!(delivered.payload, i.to_string().into_bytes());
+        assert_eq!(delivered.qos, if i % 2 == 0 { QoS::AtMostOnce } else { QoS::AtLeastOnce });
+        if delivered.qos == QoS::AtLeastOnce {
+            subscriber.write_all(&PubAckPacket::new(delivered.message_id).encode()).unwrap();
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! DISCONNECT sent by the broker over real TCP connections, which must reach the
+//! client before the connection closes.
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::read_packet;
+use mqtt_broker::broker::{Broker, BrokerConfig};
+use mqtt_broker::packets::{
+    connect::ConnectPacket,
+    fixed_header::{parse_fixed_header, PacketType},
+};
+
+// Starts a broker on an ephemeral port and connects a client to it
+fn connect(config: BrokerConfig, client_id: &str) -> TcpStream {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let broker = Broker::new(config);
+    thread::spawn(move || broker.serve(listener));
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let connect = ConnectPacket::new("MQTT".to_string(), 5, 0x02, 60, client_id.to_string(), None, None, None, None);
+    client.write_all(&connect.encode()).unwrap();
+    let connack = read_packet(&mut client).unwrap();
+    assert_eq!(parse_fixed_header(&connack).unwrap().packet_type, PacketType::ConnAck);
+    client
+}
+
+#[test]
+fn client_reads_the_disconnect_before_the_end_of_the_connection() {
+    for attempt in 0..10 {
+        let mut client = connect(BrokerConfig::default(), &format!("malformed-{}", attempt));
+
+        // QoS 3 PUBLISH, a malformed packet the broker answers with a DISCONNECT
+        client.write_all(&[0x36, 0x05, 0x00, 0x01, b't', 0x00, 0x01]).unwrap();
+
+        let disconnect = read_packet(&mut client).unwrap();
+        assert_eq!(parse_fixed_header(&disconnect).unwrap().packet_type, PacketType::Disconnect);
+        assert_eq!(disconnect[2], 0x81); // Malformed packet
+
+        let mut rest = Vec::new();
+        assert_eq!(client.read_to_end(&mut rest).unwrap(), 0);
+    }
+}
+
+#[test]
+fn connection_stays_open_for_the_grace_period() {
+    let config = BrokerConfig { disconnect_grace: Duration::from_millis(300), ..BrokerConfig::default() };
+    let mut client = connect(config, "too-large");
+
+    // Fixed header of a PUBLISH larger than the maximum packet size, nothing is left unread
+    client.write_all(&[0x30, 0x80, 0x80, 0x80, 0x01]).unwrap();
+
+    let disconnect = read_packet(&mut client).unwrap();
+    let sent = Instant::now();
+    assert_eq!(disconnect[2], 0x95); // Packet too large
+
+    let mut rest = Vec::new();
+    assert_eq!(client.read_to_end(&mut rest).unwrap(), 0);
+    assert!(sent.elapsed() >= Duration::from_millis(200), "closed after {:?}", sent.elapsed());
+}
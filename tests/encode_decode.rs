@@ -0,0 +1,26 @@
+//! Bytes on the wire of the packets whose encoding the round trips of the packet
+//! modules cannot show: lengths over several bytes and fixed encodings.
+
+use mqtt_broker::packets::{
+    ping::{PingReqPacket, PingRespPacket},
+    subscribe::SubscribePacket,
+};
+
+#[test]
+fn subscribe_with_a_multi_byte_remaining_length() {
+    // 2 + 3 * (2 + 50 + 1) = 161 bytes, written over two remaining length bytes
+    let topic_filters: Vec<String> = ["a", "b", "c"].iter().map(|prefix| format!("{}/{}", prefix, "x".repeat(48))).collect();
+    let packet = SubscribePacket::new(3, topic_filters.clone(), vec![0, 1, 2]);
+    let encoded = packet.encode().unwrap();
+    assert_eq!(&encoded[1..3], &[0xA1, 0x01]);
+
+    let decoded = SubscribePacket::decode(&encoded).unwrap();
+    assert_eq!(decoded.topic_filters, topic_filters);
+    assert_eq!(decoded.qos_values, vec![0, 1, 2]);
+}
+
+#[test]
+fn ping_request_and_response() {
+    assert_eq!(PingReqPacket.encode(), vec![0xC0, 0x00]);
+    assert_eq!(PingRespPacket.encode(), vec![0xD0, 0x00]);
+}
@@ -0,0 +1,222 @@
+//! Routing of a PUBLISH to its subscribers, which never waits on a subscriber slow
+//! to read while the subscriptions are locked.
+
+mod common;
+
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{connect, read_packet};
+use mqtt_broker::broker::{transport::DuplexStream, Broker, BrokerConfig, Transport};
+use mqtt_broker::packets::{
+    connect::ConnectPacket,
+    fixed_header::{parse_fixed_header, PacketType},
+    puback::PubAckPacket,
+    publish::PublishPacket,
+    qos::QoS,
+    subscribe::SubscribePacket,
+};
+
+// Shared by the handles of a connection: while closed, writing a PUBLISH blocks
+#[derive(Default)]
+struct Gate {
+    state: Mutex<(bool, bool)>, // Whether the gate is closed and whether a write waits on it
+    changed: Condvar,
+}
+
+impl Gate {
+    fn close(&self) {
+        self.state.lock().unwrap().0 = true;
+    }
+
+    fn open(&self) {
+        self.state.lock().unwrap().0 = false;
+        self.changed.notify_all();
+    }
+
+    fn pass(&self) {
+        let mut state = self.state.lock().unwrap();
+        while state.0 {
+            state.1 = true;
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    fn has_waiting_write(&self) -> bool {
+        self.state.lock().unwrap().1
+    }
+}
+
+// Server end of a connection whose PUBLISH writes block while its gate is closed,
+// like a subscriber that stopped reading once the socket buffers are full
+struct BlockingSubscriber {
+    stream: DuplexStream,
+    gate: Arc<Gate>,
+}
+
+impl Read for BlockingSubscriber {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for BlockingSubscriber {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.first().is_some_and(|byte| byte >> 4 == 3) {
+            self.gate.pass();
+        }
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Transport for BlockingSubscriber {
+    fn box_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(BlockingSubscriber { stream: self.stream.clone(), gate: Arc::clone(&self.gate) }))
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Transport::peer_addr(&self.stream)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        Transport::set_read_timeout(&self.stream, timeout)
+    }
+
+    fn close(&self) -> io::Result<()> {
+        self.stream.close()
+    }
+}
+
+// Server end of a connection whose PUBLISH writes fail, like a peer that went away
+struct DeadSubscriber(DuplexStream);
+
+impl Read for DeadSubscriber {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for DeadSubscriber {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.first().is_some_and(|byte| byte >> 4 == 3) {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "connection reset"));
+        }
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Transport for DeadSubscriber {
+    fn box_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(DeadSubscriber(self.0.clone())))
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Transport::peer_addr(&self.0)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        Transport::set_read_timeout(&self.0, timeout)
+    }
+
+    fn close(&self) -> io::Result<()> {
+        self.0.close()
+    }
+}
+
+// Completes the CONNECT / CONNACK exchange over a connection already accepted by the broker
+fn connect_over(client: &mut DuplexStream, client_id: &str) {
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let connect = ConnectPacket::new("MQTT".to_string(), 5, 0x02, 60, client_id.to_string(), None, None, None, None);
+    client.write_all(&connect.encode()).unwrap();
+    let connack = read_packet(client).unwrap();
+    assert_eq!(parse_fixed_header(&connack).unwrap().packet_type, PacketType::ConnAck);
+}
+
+// Subscribes the client to the topic at QoS 1 and waits for the SUBACK
+fn subscribe(client: &mut DuplexStream, topic: &str) {
+    client.write_all(&SubscribePacket::new(1, vec![topic.to_string()], vec![0x01]).encode().unwrap()).unwrap();
+    let suback = read_packet(client).unwrap();
+    assert_eq!(parse_fixed_header(&suback).unwrap().packet_type, PacketType::SubAck);
+}
+
+fn publish(client: &mut DuplexStream, topic: &str, message_id: u16) {
+    let packet = PublishPacket::builder(topic, "hello").qos(QoS::AtLeastOnce).message_id(message_id).build().unwrap();
+    client.write_all(&packet.encode().unwrap()).unwrap();
+}
+
+#[test]
+fn blocked_subscriber_does_not_hold_up_other_topics() {
+    let broker = Broker::new(BrokerConfig::default());
+
+    let gate = Arc::new(Gate::default());
+    let (mut slow, server) = DuplexStream::pair();
+    broker.accept(BlockingSubscriber { stream: server, gate: Arc::clone(&gate) });
+    connect_over(&mut slow, "slow");
+    subscribe(&mut slow, "slow/topic");
+
+    let mut fast = connect(&broker, "fast");
+    subscribe(&mut fast, "fast/topic");
+    while broker.active_topics().len() < 2 {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // The first publisher's thread blocks writing to the slow subscriber
+    gate.close();
+    let mut first = connect(&broker, "first");
+    publish(&mut first, "slow/topic", 1);
+    while !gate.has_waiting_write() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // A publish to another topic goes through meanwhile
+    let started = Instant::now();
+    let mut second = connect(&broker, "second");
+    publish(&mut second, "fast/topic", 2);
+    let received = PublishPacket::decode(&read_packet(&mut fast).unwrap()).unwrap();
+    assert_eq!(received.topic_name, "fast/topic");
+    assert_eq!(PubAckPacket::decode(&read_packet(&mut second).unwrap()).unwrap().packet_id, 2);
+    assert!(started.elapsed() < Duration::from_secs(1), "publish took {:?}", started.elapsed());
+
+    // Once the subscriber reads again it gets its message and the publisher its PUBACK
+    gate.open();
+    let received = PublishPacket::decode(&read_packet(&mut slow).unwrap()).unwrap();
+    assert_eq!(received.topic_name, "slow/topic");
+    assert_eq!(PubAckPacket::decode(&read_packet(&mut first).unwrap()).unwrap().packet_id, 1);
+}
+
+#[test]
+fn subscriber_failing_a_write_is_removed() {
+    let broker = Broker::new(BrokerConfig::default());
+
+    let (mut dead, server) = DuplexStream::pair();
+    broker.accept(DeadSubscriber(server));
+    connect_over(&mut dead, "dead");
+    subscribe(&mut dead, "news");
+    // The subscription is registered right after the SUBACK is written
+    while broker.active_topics().is_empty() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let mut publisher = connect(&broker, "publisher");
+    publish(&mut publisher, "news", 1);
+    assert_eq!(PubAckPacket::decode(&read_packet(&mut publisher).unwrap()).unwrap().packet_id, 1);
+
+    // The failed write closes the connection, whose thread then removes the subscriptions
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !broker.active_topics().is_empty() {
+        assert!(Instant::now() < deadline, "subscriber still registered");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(read_packet(&mut dead).is_err());
+}
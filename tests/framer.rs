@@ -0,0 +1,93 @@
+//! Splitting of the bytes read from a connection into whole packets.
+
+use std::io::{self, Read};
+
+use mqtt_broker::packets::{
+    framer::{FrameError, Framer},
+    ping::PingReqPacket,
+    publish::PublishPacket,
+    qos::QoS,
+};
+
+// Returns the bytes in chunks of at most `chunk` bytes per read
+struct Chunked {
+    data: Vec<u8>,
+    chunk: usize,
+}
+
+impl Read for Chunked {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.chunk.min(buf.len()).min(self.data.len());
+        buf[..size].copy_from_slice(&self.data[..size]);
+        self.data.drain(..size);
+        Ok(size)
+    }
+}
+
+#[test]
+fn packets_split_across_reads_and_sharing_reads_are_framed() {
+    let publish = PublishPacket::new("sensors".to_string(), 1, QoS::AtLeastOnce, false, false, vec![7; 300]);
+    let mut data = publish.encode().unwrap();
+    data.extend(PingReqPacket.encode());
+    data.extend(publish.encode().unwrap());
+
+    for chunk in [1, 3, 128, 4096] {
+        let mut reader = Chunked { data: data.clone(), chunk };
+        let mut framer = Framer::new(1024);
+
+        assert_eq!(PublishPacket::decode(&framer.read_packet(&mut reader).unwrap()), Ok(publish.clone()));
+        assert_eq!(framer.read_packet(&mut reader).unwrap(), PingReqPacket.encode());
+        assert_eq!(PublishPacket::decode(&framer.read_packet(&mut reader).unwrap()), Ok(publish.clone()));
+        assert!(matches!(framer.read_packet(&mut reader), Err(FrameError::Closed)));
+    }
+}
+
+#[test]
+fn packet_above_the_maximum_is_refused_from_its_header() {
+    let publish = PublishPacket::new("sensors".to_string(), 0, QoS::AtMostOnce, false, false, vec![0; 2000]);
+    // Only the fixed header is available, the size is known before the rest arrives
+    let mut reader = Chunked { data: publish.encode().unwrap()[..3].to_vec(), chunk: 3 };
+    let mut framer = Framer::new(1024);
+
+    assert!(matches!(framer.read_packet(&mut reader), Err(FrameError::TooLarge(size)) if size > 1024));
+}
+
+// Answers each read with the next step: some bytes or a timeout
+struct Steps(Vec<Option<Vec<u8>>>);
+
+impl Read for Steps {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.0.is_empty() {
+            return Ok(0);
+        }
+        match self.0.remove(0) {
+            Some(data) => {
+                buf[..data.len()].copy_from_slice(&data);
+                Ok(data.len())
+            }
+            None => Err(io::Error::new(io::ErrorKind::WouldBlock, "timed out")),
+        }
+    }
+}
+
+#[test]
+fn zero_byte_read_is_a_closed_connection() {
+    let mut framer = Framer::new(1024);
+    assert!(matches!(framer.read_packet(&mut Steps(Vec::new())), Err(FrameError::Closed)));
+
+    // Also in the middle of a packet
+    let mut reader = Steps(vec![Some(vec![0xC0])]);
+    assert!(matches!(framer.read_packet(&mut reader), Err(FrameError::Closed)));
+}
+
+#[test]
+fn partial_read_is_completed_by_the_next_call() {
+    let data = PublishPacket::new("sensors".to_string(), 1, QoS::AtLeastOnce, false, false, b"21.5".to_vec()).encode().unwrap();
+    let mut reader = Steps(vec![Some(data[..5].to_vec()), None, Some(data[5..].to_vec())]);
+    let mut framer = Framer::new(1024);
+
+    // The read times out with part of the packet buffered, which the next call keeps
+    assert!(matches!(framer.read_packet(&mut reader), Err(FrameError::Io(e)) if e.kind() == io::ErrorKind::WouldBlock));
+    assert_eq!(framer.buffered(), &data[..5]);
+    assert_eq!(framer.read_packet(&mut reader).unwrap(), data);
+}
@@ -0,0 +1,80 @@
+//! Interceptors transforming or dropping the PUBLISH packets before the broker routes them.
+
+mod common;
+
+use std::io::Write;
+use std::sync::Arc;
+
+use common::{connect, read_packet};
+use mqtt_broker::broker::{transport::DuplexStream, Broker, BrokerConfig, Interceptor};
+use mqtt_broker::packets::{
+    puback::PubAckPacket,
+    publish::PublishPacket,
+    qos::QoS,
+    subscribe::SubscribePacket,
+};
+
+// Routes every message with its payload in upper case
+struct Uppercase;
+
+impl Interceptor for Uppercase {
+    fn on_publish(&self, mut packet: PublishPacket) -> Option<PublishPacket> {
+        packet.payload = packet.payload.to_ascii_uppercase();
+        Some(packet)
+    }
+}
+
+// Drops the messages published under spam/
+struct DropSpam;
+
+impl Interceptor for DropSpam {
+    fn on_publish(&self, packet: PublishPacket) -> Option<PublishPacket> {
+        if packet.topic_name.starts_with("spam/") {
+            return None;
+        }
+        Some(packet)
+    }
+}
+
+// Connects a client subscribed to every topic
+fn subscriber(broker: &Broker) -> DuplexStream {
+    let mut client = connect(broker, "subscriber");
+    client.write_all(&SubscribePacket::new(1, vec!["#".to_string()], vec![0]).encode().unwrap()).unwrap();
+    read_packet(&mut client).unwrap(); // SUBACK
+    client
+}
+
+// Publishes a QoS 1 message and waits for its PUBACK, which comes once it was routed
+fn publish(publisher: &mut DuplexStream, topic: &str, message_id: u16, payload: &[u8]) {
+    let packet = PublishPacket::new(topic.to_string(), message_id, QoS::AtLeastOnce, false, false, payload.to_vec());
+    publisher.write_all(&packet.encode().unwrap()).unwrap();
+    assert_eq!(PubAckPacket::decode(&read_packet(publisher).unwrap()).unwrap().packet_id, message_id);
+}
+
+#[test]
+fn interceptor_transforms_the_payload() {
+    let mut broker = Broker::new(BrokerConfig::default());
+    broker.set_interceptor(Arc::new(Uppercase));
+    let mut subscriber = subscriber(&broker);
+    let mut publisher = connect(&broker, "publisher");
+
+    publish(&mut publisher, "greetings", 1, b"hello");
+    let delivered = PublishPacket::decode(&read_packet(&mut subscriber).unwrap()).unwrap();
+    assert_eq!(delivered.topic_name, "greetings");
+    assert_eq!(delivered.payload, b"HELLO");
+}
+
+#[test]
+fn interceptor_drops_the_message() {
+    let mut broker = Broker::new(BrokerConfig::default());
+    broker.set_interceptor(Arc::new(DropSpam));
+    let mut subscriber = subscriber(&broker);
+    let mut publisher = connect(&broker, "publisher");
+
+    // The dropped message is still acknowledged, it never reaches the subscriber
+    publish(&mut publisher, "spam/offers", 1, b"buy now");
+    publish(&mut publisher, "news", 2, b"headline");
+    let delivered = PublishPacket::decode(&read_packet(&mut subscriber).unwrap()).unwrap();
+    assert_eq!(delivered.topic_name, "news");
+    assert_eq!(delivered.payload, b"headline");
+}
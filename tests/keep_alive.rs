@@ -0,0 +1,42 @@
+//! Keep alive of the connections, driven over the in-memory transport.
+
+mod common;
+
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+
+use common::{connect_with_keep_alive, read_packet};
+use mqtt_broker::broker::{Broker, BrokerConfig, Transport};
+use mqtt_broker::packets::{
+    fixed_header::{parse_fixed_header, PacketType},
+    publish::PublishPacket,
+    qos::QoS,
+};
+
+#[test]
+fn publishing_resets_the_keep_alive_timer() {
+    let broker = Broker::new(BrokerConfig::default());
+    // Without activity the broker closes the connection after 1.5 seconds
+    let mut client = connect_with_keep_alive(&broker, "busy", 1);
+    client.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+    // Publish for longer than the keep alive allows without ever sending a PINGREQ
+    let publish = PublishPacket::new("busy/topic".to_string(), 1, QoS::AtLeastOnce, false, false, b"work".to_vec());
+    for _ in 0..6 {
+        client.write_all(&publish.encode().unwrap()).unwrap();
+        let ack = read_packet(&mut client).unwrap();
+        assert_eq!(parse_fixed_header(&ack).unwrap().packet_type, PacketType::PubAck);
+        thread::sleep(Duration::from_millis(400));
+    }
+
+    let last_activity = broker.last_activity("busy").expect("the client is connected");
+    assert!(last_activity.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn unknown_client_has_no_activity() {
+    let broker = Broker::new(BrokerConfig::default());
+
+    assert!(broker.last_activity("nobody").is_none());
+}
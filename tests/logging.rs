@@ -0,0 +1,88 @@
+//! Records of the connection handler, which carry the client ID and peer address.
+
+mod common;
+
+use std::io::Write;
+use std::sync::{Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{connect, read_packet};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use mqtt_broker::broker::{Broker, BrokerConfig, LogContext};
+use mqtt_broker::packets::subscribe::SubscribePacket;
+
+// Keeps every record logged, since the logger of a process can only be set once
+struct CapturingLogger {
+    lines: Mutex<Vec<String>>,
+}
+
+impl Log for CapturingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Info
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.lines.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CapturingLogger = CapturingLogger { lines: Mutex::new(Vec::new()) };
+static INIT: Once = Once::new();
+
+fn capture() {
+    INIT.call_once(|| {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(LevelFilter::Info);
+    });
+}
+
+// Returns the first line logged that contains every part, waiting for it since
+// the handler may log a packet after the client already received it
+fn find_line(parts: &[&str]) -> String {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let lines = LOGGER.lines.lock().unwrap().clone();
+        if let Some(line) = lines.iter().find(|line| parts.iter().all(|part| line.contains(part))) {
+            return line.clone();
+        }
+        assert!(Instant::now() < deadline, "no line with {:?} in {:?}", parts, lines);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn context_is_written_as_client_at_address() {
+    let mut context = LogContext::new("127.0.0.1:50000".parse().unwrap());
+    assert_eq!(context.to_string(), "127.0.0.1:50000");
+
+    context.set_client_id("sensor-1");
+    assert_eq!(context.client_id(), Some("sensor-1"));
+    assert_eq!(context.to_string(), "sensor-1@127.0.0.1:50000");
+}
+
+#[test]
+fn connect_is_logged_with_the_client_id() {
+    capture();
+    let broker = Broker::new(BrokerConfig::default());
+    let _client = connect(&broker, "logged-client");
+
+    let line = find_line(&["Received CONNECT", "logged-client"]);
+    assert!(line.starts_with("logged-client@127.0.0.1:"), "unexpected line: {}", line);
+    find_line(&["logged-client@", "Sent CONNACK"]);
+}
+
+#[test]
+fn later_packets_keep_the_context_of_the_connection() {
+    capture();
+    let broker = Broker::new(BrokerConfig::default());
+    let mut client = connect(&broker, "subscribing-client");
+
+    client.write_all(&SubscribePacket::new(1, vec!["logs".to_string()], vec![0x00]).encode().unwrap()).unwrap();
+    read_packet(&mut client).unwrap(); // SUBACK
+    find_line(&["subscribing-client@127.0.0.1:", "Received SUBSCRIBE"]);
+}
@@ -0,0 +1,88 @@
+//! Decoding of packets whose declared lengths do not match their bytes.
+
+use mqtt_broker::packets::{
+    connect::ConnectPacket,
+    ping::PingReqPacket,
+    publish::PublishPacket,
+    qos::QoS,
+    DecodeError,
+};
+
+#[test]
+fn publish_topic_longer_than_packet_is_rejected_before_allocating() {
+    // QoS 0 PUBLISH declaring a 65535-byte topic with only 6 bytes after it
+    let packet = [0x30, 0x08, 0xFF, 0xFF, b't', b'o', b'p', b'i', b'c', 0x00];
+
+    let err = PublishPacket::decode(&packet).unwrap_err();
+    assert_eq!(err, DecodeError::LengthExceeded { declared: 65535, available: 6 });
+}
+
+#[test]
+fn connect_client_id_longer_than_packet_is_rejected() {
+    let mut packet = ConnectPacket::new("MQTT".to_string(), 5, 0, 60, "id".to_string(), None, None, None, None).encode();
+    // The client ID length is the last field before the ID itself
+    let len_index = packet.len() - 4;
+    packet[len_index] = 0xFF;
+
+    let err = ConnectPacket::decode(&packet).unwrap_err();
+    assert!(matches!(err, DecodeError::LengthExceeded { .. }), "unexpected error: {}", err);
+}
+
+#[test]
+fn connect_with_will_fields_but_no_will_flag_is_rejected() {
+    // The will topic and message are encoded, then the will flag is cleared
+    let mut packet = ConnectPacket::new(
+        "MQTT".to_string(),
+        4,
+        0x04,
+        60,
+        "id".to_string(),
+        Some("will/topic".to_string()),
+        Some(b"bye".to_vec()),
+        None,
+        None,
+    )
+    .encode();
+    let flags_index = 2 + 2 + 4 + 1; // Fixed header, protocol name and protocol level
+    packet[flags_index] = 0x00;
+
+    let err = ConnectPacket::decode(&packet).unwrap_err();
+    assert!(matches!(err, DecodeError::Malformed(_)), "unexpected error: {}", err);
+}
+
+#[test]
+fn connect_with_will_qos_but_no_will_flag_is_rejected() {
+    let packet = ConnectPacket::new("MQTT".to_string(), 5, 0x08, 60, "id".to_string(), None, None, None, None).encode();
+
+    let err = ConnectPacket::decode(&packet).unwrap_err();
+    assert!(matches!(err, DecodeError::InvalidFlags(_)), "unexpected error: {}", err);
+}
+
+#[test]
+fn publish_payload_stops_at_the_remaining_length() {
+    let packet = PublishPacket::new("sensors".to_string(), 3, QoS::AtLeastOnce, false, false, b"21.5".to_vec());
+    let second = PublishPacket::new("other".to_string(), 0, QoS::AtMostOnce, false, false, b"next".to_vec());
+    let mut buffer = packet.encode().unwrap();
+    buffer.extend(second.encode().unwrap());
+    buffer.extend(PingReqPacket.encode());
+
+    assert_eq!(PublishPacket::decode(&buffer), Ok(packet));
+}
+
+#[test]
+fn publish_shorter_than_its_remaining_length_is_rejected() {
+    let mut packet = PublishPacket::new("sensors".to_string(), 0, QoS::AtMostOnce, false, false, b"21.5".to_vec()).encode().unwrap();
+    packet.truncate(packet.len() - 2);
+
+    let err = PublishPacket::decode(&packet).unwrap_err();
+    assert_eq!(err, DecodeError::LengthExceeded { declared: packet.len() + 2, available: packet.len() });
+}
+
+#[test]
+fn connect_with_a_receive_maximum_of_0_is_rejected() {
+    let mut packet = ConnectPacket::new("MQTT".to_string(), 5, 0x02, 60, "id".to_string(), None, None, None, None);
+    packet.properties.receive_maximum = Some(0);
+
+    let err = ConnectPacket::decode(&packet.encode()).unwrap_err();
+    assert!(matches!(err, DecodeError::ProtocolError(_)), "unexpected error: {}", err);
+}
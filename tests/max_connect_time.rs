@@ -0,0 +1,41 @@
+//! Connections closed once they reach the maximum connection duration.
+
+mod common;
+
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+use common::{connect, read_packet};
+use mqtt_broker::broker::{Broker, BrokerConfig};
+use mqtt_broker::packets::{
+    fixed_header::{parse_fixed_header, PacketType},
+    ping::PingReqPacket,
+};
+
+#[test]
+fn connection_is_closed_with_maximum_connect_time() {
+    let config = BrokerConfig { max_connection_duration: Some(Duration::from_millis(300)), ..Default::default() };
+    let broker = Broker::new(config);
+    let connected_at = Instant::now();
+    let mut client = connect(&broker, "short-lived");
+
+    // An active client is disconnected too
+    client.write_all(&PingReqPacket.encode()).unwrap();
+    assert_eq!(read_packet(&mut client).unwrap(), vec![0xD0, 0x00]);
+
+    let disconnect = read_packet(&mut client).unwrap();
+    assert_eq!(parse_fixed_header(&disconnect).unwrap().packet_type, PacketType::Disconnect);
+    assert_eq!(disconnect[2], 0xA0); // Maximum connect time
+    assert!(connected_at.elapsed() >= Duration::from_millis(300));
+
+    // Then the connection is closed
+    let mut rest = Vec::new();
+    assert!(matches!(client.read_to_end(&mut rest), Ok(0)));
+}
+
+#[test]
+fn connections_are_not_capped_by_default() {
+    assert_eq!(BrokerConfig::default().max_connection_duration, None);
+    let args = vec!["--max-connection-duration".to_string(), "3600".to_string()];
+    assert_eq!(BrokerConfig::from_args(&args).max_connection_duration, Some(Duration::from_secs(3600)));
+}
@@ -0,0 +1,62 @@
+//! The crate-wide error type: conversions from the errors of each layer and their messages.
+
+use std::fs;
+use std::io;
+
+use mqtt_broker::broker::{FilePersistence, Persistence};
+use mqtt_broker::packets::{disconnect::DisconnectReasonCode, fixed_header::PacketType, framer::FrameError, DecodeError, EncodeError};
+use mqtt_broker::{MqttError, MqttResult};
+
+// Fails the way a caller mixing the layers would, every error converted by `?`
+fn decode_then_io(fail_decode: bool) -> MqttResult<()> {
+    if fail_decode {
+        Err(DecodeError::InvalidQoS(3))?;
+    }
+    Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe"))?
+}
+
+#[test]
+fn errors_of_each_layer_convert_with_the_question_mark() {
+    assert!(matches!(decode_then_io(true), Err(MqttError::Decode(DecodeError::InvalidQoS(3)))));
+    assert!(matches!(decode_then_io(false), Err(MqttError::Io(e)) if e.kind() == io::ErrorKind::BrokenPipe));
+    assert!(matches!(
+        MqttError::from(DisconnectReasonCode::ProtocolError),
+        MqttError::Protocol(DisconnectReasonCode::ProtocolError)
+    ));
+}
+
+#[test]
+fn frame_errors_convert_to_their_layer() {
+    assert!(matches!(MqttError::from(FrameError::Closed), MqttError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof));
+    assert!(matches!(
+        MqttError::from(FrameError::TooLarge(1 << 20)),
+        MqttError::Protocol(DisconnectReasonCode::PacketTooLarge)
+    ));
+    assert!(matches!(
+        MqttError::from(FrameError::Malformed(DecodeError::MalformedRemainingLength)),
+        MqttError::Decode(DecodeError::MalformedRemainingLength)
+    ));
+}
+
+#[test]
+fn display_names_the_layer_and_the_cause() {
+    assert_eq!(MqttError::from(DecodeError::InvalidQoS(3)).to_string(), "Decode error: Invalid QoS: 3");
+    assert_eq!(MqttError::from(io::Error::other("disk full")).to_string(), "I/O error: disk full");
+    assert_eq!(
+        MqttError::from(EncodeError::ZeroPacketId(PacketType::Subscribe)).to_string(),
+        "Encode error: Subscribe packet with packet ID 0"
+    );
+    assert_eq!(MqttError::Protocol(DisconnectReasonCode::ProtocolError).to_string(), "Protocol error: ProtocolError");
+}
+
+#[test]
+fn corrupt_persistence_file_is_a_decode_error() {
+    let dir = std::env::temp_dir().join(format!("mqtt-error-{}", std::process::id()));
+    let persistence = FilePersistence::new(&dir).unwrap();
+    // A PUBLISH header announcing more bytes than the file holds
+    fs::write(dir.join("retained.mqtt"), [0x30, 0x10, 0x00]).unwrap();
+
+    let result = persistence.load_retained();
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(matches!(result, Err(MqttError::Decode(DecodeError::UnexpectedEof))));
+}
@@ -0,0 +1,85 @@
+//! Packet ID 0 in the packets that are acknowledged by their packet ID, refused when
+//! encoded and when decoded.
+
+mod common;
+
+use std::io::Write;
+
+use common::{connect, read_packet};
+use mqtt_broker::broker::{Broker, BrokerConfig};
+use mqtt_broker::packets::{
+    fixed_header::{parse_fixed_header, PacketType},
+    publish::PublishPacket,
+    qos::QoS,
+    subscribe::SubscribePacket,
+    unsubscribe::UnsubscribePacket,
+    DecodeError,
+    EncodeError,
+};
+
+// PUBLISH of the QoS with message ID 0, encoded by hand since the encoder refuses it
+fn publish_with_zero_id(qos: QoS) -> Vec<u8> {
+    let mut packet = PublishPacket::new("lamp".to_string(), 1, qos, false, false, b"on".to_vec()).encode().unwrap();
+    // Fixed header (2 bytes) and topic (2 + 4 bytes) come before the message ID
+    packet[8] = 0;
+    packet[9] = 0;
+    packet
+}
+
+#[test]
+fn encoders_refuse_packet_id_0() {
+    let publish = PublishPacket::new("lamp".to_string(), 0, QoS::AtLeastOnce, false, false, b"on".to_vec());
+    let subscribe = SubscribePacket::new(0, vec!["lamp".to_string()], vec![1]);
+    let unsubscribe = UnsubscribePacket::new(0, vec!["lamp".to_string()]);
+
+    assert_eq!(publish.encode(), Err(EncodeError::ZeroPacketId(PacketType::Publish)));
+    assert_eq!(subscribe.encode(), Err(EncodeError::ZeroPacketId(PacketType::Subscribe)));
+    assert_eq!(unsubscribe.encode(), Err(EncodeError::ZeroPacketId(PacketType::Unsubscribe)));
+}
+
+#[test]
+fn qos_0_publish_has_no_message_id_to_check() {
+    let publish = PublishPacket::new("lamp".to_string(), 0, QoS::AtMostOnce, false, false, b"on".to_vec());
+
+    assert!(publish.encode().is_ok());
+}
+
+#[test]
+fn decoders_refuse_packet_id_0() {
+    let mut subscribe = SubscribePacket::new(1, vec!["lamp".to_string()], vec![1]).encode().unwrap();
+    subscribe[3] = 0;
+    let mut unsubscribe = UnsubscribePacket::new(1, vec!["lamp".to_string()]).encode().unwrap();
+    unsubscribe[3] = 0;
+
+    for qos in [QoS::AtLeastOnce, QoS::ExactlyOnce] {
+        assert!(matches!(PublishPacket::decode(&publish_with_zero_id(qos)), Err(DecodeError::ProtocolError(_))));
+    }
+    assert!(matches!(SubscribePacket::decode(&subscribe), Err(DecodeError::ProtocolError(_))));
+    assert!(matches!(UnsubscribePacket::decode(&unsubscribe), Err(DecodeError::ProtocolError(_))));
+}
+
+#[test]
+fn broker_disconnects_a_client_publishing_with_message_id_0() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut client = connect(&broker, "zero-id-publisher");
+
+    client.write_all(&publish_with_zero_id(QoS::AtLeastOnce)).unwrap();
+
+    let disconnect = read_packet(&mut client).unwrap();
+    assert_eq!(parse_fixed_header(&disconnect).unwrap().packet_type, PacketType::Disconnect);
+    assert_eq!(disconnect[2], 0x82); // Protocol Error
+}
+
+#[test]
+fn broker_disconnects_a_client_subscribing_with_packet_id_0() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut client = connect(&broker, "zero-id-subscriber");
+
+    let mut subscribe = SubscribePacket::new(1, vec!["lamp".to_string()], vec![0]).encode().unwrap();
+    subscribe[3] = 0;
+    client.write_all(&subscribe).unwrap();
+
+    let disconnect = read_packet(&mut client).unwrap();
+    assert_eq!(parse_fixed_header(&disconnect).unwrap().packet_type, PacketType::Disconnect);
+    assert_eq!(disconnect[2], 0x82);
+}
@@ -0,0 +1,81 @@
+//! Client-side tracking of the QoS 1 publishes waiting for their PUBACK.
+
+use std::thread;
+use std::time::Duration;
+
+use mqtt_broker::client::PendingPublishes;
+use mqtt_broker::packets::{publish::PublishPacket, qos::QoS};
+
+fn publish(message_id: u16, qos: QoS) -> PublishPacket {
+    PublishPacket::new("sensors/temp".to_string(), message_id, qos, false, false, b"21.5".to_vec())
+}
+
+#[test]
+fn puback_clears_the_matching_publish() {
+    let mut pending = PendingPublishes::new();
+    assert!(pending.track(publish(1, QoS::AtLeastOnce)));
+    assert!(pending.track(publish(2, QoS::AtLeastOnce)));
+    assert_eq!(pending.packet_ids(), vec![1, 2]);
+
+    assert_eq!(pending.acknowledge(1), Some(publish(1, QoS::AtLeastOnce)));
+    assert_eq!(pending.packet_ids(), vec![2]);
+
+    // A PUBACK for a message that is not in flight changes nothing
+    assert_eq!(pending.acknowledge(1), None);
+    assert_eq!(pending.acknowledge(3), None);
+    assert_eq!(pending.len(), 1);
+
+    assert!(pending.acknowledge(2).is_some());
+    assert!(pending.is_empty());
+}
+
+#[test]
+fn only_qos_1_publishes_are_tracked() {
+    let mut pending = PendingPublishes::new();
+    assert!(!pending.track(publish(0, QoS::AtMostOnce)));
+    assert!(!pending.track(publish(1, QoS::ExactlyOnce)));
+    assert!(pending.is_empty());
+}
+
+#[test]
+fn publishes_past_the_timeout_are_reported() {
+    let mut pending = PendingPublishes::new();
+    pending.track(publish(1, QoS::AtLeastOnce));
+    assert!(pending.timed_out(Duration::from_millis(100)).is_empty());
+
+    thread::sleep(Duration::from_millis(150));
+    pending.track(publish(2, QoS::AtLeastOnce));
+
+    // Only the message sent before the sleep has waited long enough
+    assert_eq!(pending.timed_out(Duration::from_millis(100)), vec![1]);
+    assert_eq!(pending.timed_out(Duration::ZERO), vec![1, 2]);
+
+    // An acknowledged message is no longer reported
+    pending.acknowledge(1);
+    assert!(pending.timed_out(Duration::from_millis(100)).is_empty());
+}
+
+#[test]
+fn retransmission_sets_dup_and_restarts_the_timeout() {
+    let mut pending = PendingPublishes::new();
+    pending.track(publish(7, QoS::AtLeastOnce));
+    assert_eq!(pending.retransmits(7), Some(0));
+    assert!(!pending.get(7).unwrap().dup);
+
+    thread::sleep(Duration::from_millis(150));
+    assert_eq!(pending.timed_out(Duration::from_millis(100)), vec![7]);
+
+    // Same packet ID and content, with the DUP flag
+    let packet = pending.retransmit(7).unwrap();
+    assert!(packet.dup);
+    assert_eq!(packet.message_id, 7);
+    assert_eq!(packet.payload, b"21.5".to_vec());
+    assert!(pending.get(7).unwrap().dup);
+    assert_eq!(pending.retransmits(7), Some(1));
+    assert!(pending.timed_out(Duration::from_millis(100)).is_empty());
+
+    // The retransmitted packet is still matched by its PUBACK
+    assert_eq!(pending.acknowledge(7), Some(packet));
+    assert_eq!(pending.retransmit(7), None);
+    assert_eq!(pending.retransmits(7), None);
+}
@@ -0,0 +1,102 @@
+//! A client thread that panics while holding the shared state must not take the
+//! other connections down with it.
+
+mod common;
+
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+use common::read_packet;
+use mqtt_broker::broker::{transport::DuplexStream, Broker, BrokerConfig, Transport};
+use mqtt_broker::packets::{
+    connect::ConnectPacket,
+    fixed_header::{parse_fixed_header, PacketType},
+    publish::PublishPacket,
+    qos::QoS,
+    subscribe::{SubscribePacket, SubscriptionOptions},
+};
+
+// Server end of a connection that panics when the broker writes a PUBLISH to it,
+// which happens while the broker holds the writer of the connection
+struct PanicOnPublish(DuplexStream);
+
+impl Read for PanicOnPublish {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for PanicOnPublish {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.first().is_some_and(|byte| byte >> 4 == 3) {
+            panic!("failing subscriber");
+        }
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Transport for PanicOnPublish {
+    fn box_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(PanicOnPublish(self.0.clone())))
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Transport::peer_addr(&self.0)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        Transport::set_read_timeout(&self.0, timeout)
+    }
+
+    fn close(&self) -> io::Result<()> {
+        self.0.close()
+    }
+}
+
+// Subscribes the client and waits until the broker registered the subscription
+fn subscribe(broker: &Broker, client: &mut DuplexStream, topic: &str) {
+    let subscribe = SubscribePacket::with_options(1, vec![(topic.to_string(), SubscriptionOptions::default())]);
+    client.write_all(&subscribe.encode().unwrap()).unwrap();
+    let suback = read_packet(client).unwrap();
+    assert_eq!(parse_fixed_header(&suback).unwrap().packet_type, PacketType::SubAck);
+    while !broker.active_topics().contains(&topic.to_string()) {
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn panic_while_holding_the_subscriptions_is_not_fatal() {
+    let broker = Broker::new(BrokerConfig::default());
+
+    // A subscriber whose connection panics on the first message it is sent
+    let (mut failing, server) = DuplexStream::pair();
+    failing.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    broker.accept(PanicOnPublish(server));
+    let connect = ConnectPacket::new("MQTT".to_string(), 5, 0x02, 60, "failing".to_string(), None, None, None, None);
+    failing.write_all(&connect.encode()).unwrap();
+    read_packet(&mut failing).unwrap();
+    subscribe(&broker, &mut failing, "doomed");
+
+    // The thread of the publisher panics while delivering, with the broker state locked
+    let mut publisher = common::connect(&broker, "publisher");
+    let message = PublishPacket::new("doomed".to_string(), 1, QoS::AtLeastOnce, false, false, b"boom".to_vec());
+    publisher.write_all(&message.encode().unwrap()).unwrap();
+    publisher.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    assert!(read_packet(&mut publisher).is_err());
+
+    // New clients still subscribe, publish and receive
+    let mut subscriber = common::connect(&broker, "subscriber");
+    subscribe(&broker, &mut subscriber, "healthy");
+    let mut other_publisher = common::connect(&broker, "other-publisher");
+    let message = PublishPacket::new("healthy".to_string(), 0, QoS::AtMostOnce, false, false, b"fine".to_vec());
+    other_publisher.write_all(&message.encode().unwrap()).unwrap();
+
+    let delivered = PublishPacket::decode(&read_packet(&mut subscriber).unwrap()).unwrap();
+    assert_eq!(delivered.payload, b"fine");
+}
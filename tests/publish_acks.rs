@@ -0,0 +1,53 @@
+//! Reason codes and properties of the PUBACK and PUBREC, which a successful
+//! acknowledgement without properties leaves out.
+
+use mqtt_broker::packets::{
+    puback::{PubAckPacket, NO_MATCHING_SUBSCRIBERS, SUCCESS},
+    qos2::PubRecPacket,
+};
+
+#[test]
+fn success_puback_is_only_the_packet_id() {
+    let packet = PubAckPacket::new(7);
+    assert_eq!(packet.encode(), vec![0x40, 0x02, 0x00, 0x07]);
+
+    let decoded = PubAckPacket::decode(&[0x40, 0x02, 0x00, 0x07]).unwrap();
+    assert_eq!(decoded.reason_code, SUCCESS);
+    assert_eq!(decoded.reason_string, None);
+    assert!(decoded.user_properties.is_empty());
+}
+
+#[test]
+fn puback_with_a_reason_code_and_no_properties() {
+    let packet = PubAckPacket::with_reason(7, NO_MATCHING_SUBSCRIBERS);
+    assert_eq!(packet.encode(), vec![0x40, 0x03, 0x00, 0x07, 0x10]);
+    assert_eq!(PubAckPacket::decode(&packet.encode()).unwrap(), packet);
+
+    // The property length may be written even when there are no properties
+    let decoded = PubAckPacket::decode(&[0x40, 0x04, 0x00, 0x07, 0x10, 0x00]).unwrap();
+    assert_eq!(decoded, packet);
+}
+
+#[test]
+fn puback_with_a_reason_string_and_user_properties() {
+    let mut packet = PubAckPacket::new(7);
+    packet.reason_string = Some("ok".to_string());
+    let encoded = packet.encode();
+    // Success is written since the properties follow it
+    assert_eq!(encoded, vec![0x40, 0x09, 0x00, 0x07, 0x00, 0x05, 0x1F, 0x00, 0x02, b'o', b'k']);
+    assert_eq!(PubAckPacket::decode(&encoded).unwrap(), packet);
+
+    packet.user_properties = vec![("region".to_string(), "eu".to_string()), ("region".to_string(), "us".to_string())];
+    assert_eq!(PubAckPacket::decode(&packet.encode()).unwrap(), packet);
+}
+
+#[test]
+fn pubrec_carries_the_same_fields() {
+    let packet = PubRecPacket::new(9);
+    assert_eq!(packet.encode(), vec![0x50, 0x02, 0x00, 0x09]);
+
+    let mut packet = PubRecPacket::with_reason(9, 0x87);
+    packet.reason_string = Some("not authorized".to_string());
+    packet.user_properties = vec![("trace".to_string(), "abc".to_string())];
+    assert_eq!(PubRecPacket::decode(&packet.encode()).unwrap(), packet);
+}
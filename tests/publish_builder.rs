@@ -0,0 +1,55 @@
+//! The PUBLISH builder produces the same packets as the positional constructor and
+//! refuses the field combinations the protocol forbids.
+
+use proptest::prelude::*;
+
+use mqtt_broker::packets::{fixed_header::PacketType, publish::PublishPacket, qos::QoS, EncodeError};
+
+fn qos() -> impl Strategy<Value = QoS> {
+    prop_oneof![Just(QoS::AtMostOnce), Just(QoS::AtLeastOnce), Just(QoS::ExactlyOnce)]
+}
+
+proptest! {
+    #[test]
+    fn builder_encodes_like_new(qos in qos(), message_id in 1u16.., retain in any::<bool>(), dup in any::<bool>(), payload in proptest::collection::vec(any::<u8>(), 0..64)) {
+        let dup = dup && qos != QoS::AtMostOnce;
+        let built = PublishPacket::builder("sensors/temperature", payload.clone())
+            .qos(qos)
+            .retain(retain)
+            .dup(dup)
+            .message_id(message_id)
+            .build()
+            .unwrap();
+        let constructed = PublishPacket::new("sensors/temperature".to_string(), message_id, qos, retain, dup, payload);
+
+        prop_assert_eq!(built.encode().unwrap(), constructed.encode().unwrap());
+    }
+}
+
+#[test]
+fn builder_defaults_to_a_qos_0_message() {
+    let packet = PublishPacket::builder("lamp/state", b"on".to_vec()).build().unwrap();
+
+    assert_eq!(packet, PublishPacket::new("lamp/state".to_string(), 0, QoS::AtMostOnce, false, false, b"on".to_vec()));
+}
+
+#[test]
+fn builder_rejects_qos_1_without_message_id() {
+    let result = PublishPacket::builder("lamp/state", b"on".to_vec()).qos(QoS::AtLeastOnce).build();
+
+    assert_eq!(result, Err(EncodeError::ZeroPacketId(PacketType::Publish)));
+}
+
+#[test]
+fn builder_rejects_dup_on_qos_0() {
+    let result = PublishPacket::builder("lamp/state", b"on".to_vec()).dup(true).build();
+
+    assert_eq!(result, Err(EncodeError::DupOnQoS0));
+}
+
+#[test]
+fn builder_rejects_wildcard_topics() {
+    let result = PublishPacket::builder("lamp/+", b"on".to_vec()).build();
+
+    assert_eq!(result, Err(EncodeError::InvalidTopic("lamp/+".to_string())));
+}
@@ -0,0 +1,16 @@
+//! Conversions of the QoS levels to and from their wire value.
+
+use mqtt_broker::packets::qos::QoS;
+
+#[test]
+fn qos_converts_to_and_from_its_wire_value() {
+    for (value, qos) in [(0, QoS::AtMostOnce), (1, QoS::AtLeastOnce), (2, QoS::ExactlyOnce)] {
+        assert_eq!(QoS::from_u8(value), Ok(qos));
+        assert_eq!(qos.to_u8(), value);
+    }
+}
+
+#[test]
+fn qos_3_is_not_valid() {
+    assert!(QoS::from_u8(3).is_err());
+}
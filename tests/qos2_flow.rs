@@ -0,0 +1,35 @@
+//! QoS 2 exchange of a publisher with the broker, PUBLISH / PUBREC then PUBREL / PUBCOMP,
+//! with other packets of the connection in between.
+
+mod common;
+
+use std::io::Write;
+
+use common::{connect, read_packet};
+use mqtt_broker::broker::{Broker, BrokerConfig};
+use mqtt_broker::packets::{
+    publish::PublishPacket,
+    qos::QoS,
+    qos2::{PubCompPacket, PubRecPacket, PubRelPacket},
+    suback::SubAckPacket,
+    subscribe::SubscribePacket,
+};
+
+#[test]
+fn subscribe_between_pubrec_and_pubrel_keeps_the_qos_2_exchange() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut client = connect(&broker, "ledger");
+
+    let publish = PublishPacket::new("ledger/entries".to_string(), 5, QoS::ExactlyOnce, false, false, b"+10".to_vec());
+    client.write_all(&publish.encode().unwrap()).unwrap();
+    assert_eq!(PubRecPacket::decode(&read_packet(&mut client).unwrap()).unwrap(), PubRecPacket::new(5));
+
+    // A SUBSCRIBE, even with the same packet ID, does not end the exchange
+    client.write_all(&SubscribePacket::new(5, vec!["ledger/#".to_string()], vec![0]).encode().unwrap()).unwrap();
+    let suback = SubAckPacket::decode(&read_packet(&mut client).unwrap()).unwrap();
+    assert_eq!(suback.packet_id, 5);
+    assert_eq!(suback.return_codes, vec![0x00]);
+
+    client.write_all(&PubRelPacket::new(5).encode()).unwrap();
+    assert_eq!(PubCompPacket::decode(&read_packet(&mut client).unwrap()).unwrap(), PubCompPacket::new(5));
+}
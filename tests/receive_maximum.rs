@@ -0,0 +1,110 @@
+//! Receive Maximum of both ends: the broker announces its own in the CONNACK and
+//! never has more messages in flight to a client than the client announced.
+
+mod common;
+
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+
+use common::{connect, read_packet};
+use mqtt_broker::broker::{transport::DuplexStream, Broker, BrokerConfig, Transport};
+use mqtt_broker::packets::{
+    connack::ConnAckPacket,
+    connect::ConnectPacket,
+    disconnect::DisconnectReasonCode,
+    ping::PingReqPacket,
+    puback::PubAckPacket,
+    publish::PublishPacket,
+    qos::QoS,
+    qos2::PubRecPacket,
+    subscribe::SubscribePacket,
+};
+
+fn publish(message_id: u16, qos: QoS) -> PublishPacket {
+    PublishPacket::new("jobs".to_string(), message_id, qos, false, false, vec![message_id as u8])
+}
+
+// The next packet the client receives is the answer to a PINGREQ, nothing else was sent
+fn assert_nothing_pending(client: &mut DuplexStream) {
+    client.write_all(&PingReqPacket.encode()).unwrap();
+    assert_eq!(read_packet(client).unwrap(), vec![0xD0, 0x00]);
+}
+
+#[test]
+fn connack_announces_the_receive_maximum_of_the_broker() {
+    let broker = Broker::new(BrokerConfig { receive_maximum: 20, ..BrokerConfig::default() });
+    let (mut client, server) = DuplexStream::pair();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    broker.accept(server);
+
+    let connect = ConnectPacket::new("MQTT".to_string(), 5, 0x02, 60, "client".to_string(), None, None, None, None);
+    client.write_all(&connect.encode()).unwrap();
+    let connack = ConnAckPacket::decode(&read_packet(&mut client).unwrap()).unwrap();
+    assert_eq!(connack.properties.unwrap().receive_maximum, Some(20));
+}
+
+#[test]
+fn third_message_waits_until_the_first_is_acknowledged() {
+    let broker = Broker::new(BrokerConfig::default());
+
+    let (mut worker, server) = DuplexStream::pair();
+    worker.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    broker.accept(server);
+    let mut connect_packet = ConnectPacket::new("MQTT".to_string(), 5, 0x02, 60, "worker".to_string(), None, None, None, None);
+    connect_packet.properties.receive_maximum = Some(2);
+    worker.write_all(&connect_packet.encode()).unwrap();
+    read_packet(&mut worker).unwrap(); // CONNACK
+    worker.write_all(&SubscribePacket::new(1, vec!["jobs".to_string()], vec![0x01]).encode().unwrap()).unwrap();
+    read_packet(&mut worker).unwrap(); // SUBACK
+    while broker.active_topics().is_empty() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let mut publisher = connect(&broker, "publisher");
+    for message_id in 1..=3 {
+        publisher.write_all(&publish(message_id, QoS::AtLeastOnce).encode().unwrap()).unwrap();
+        assert_eq!(PubAckPacket::decode(&read_packet(&mut publisher).unwrap()).unwrap().packet_id, message_id);
+    }
+
+    let first = PublishPacket::decode(&read_packet(&mut worker).unwrap()).unwrap();
+    let second = PublishPacket::decode(&read_packet(&mut worker).unwrap()).unwrap();
+    assert_eq!((first.payload[0], second.payload[0]), (1, 2));
+    assert_nothing_pending(&mut worker);
+
+    // Acknowledging the first message frees the slot the third one takes
+    worker.write_all(&PubAckPacket::new(first.message_id).encode()).unwrap();
+    let third = PublishPacket::decode(&read_packet(&mut worker).unwrap()).unwrap();
+    assert_eq!(third.payload[0], 3);
+    assert_nothing_pending(&mut worker);
+}
+
+#[test]
+fn client_past_the_receive_maximum_of_the_broker_is_disconnected() {
+    let broker = Broker::new(BrokerConfig { receive_maximum: 2, ..BrokerConfig::default() });
+    let mut client = connect(&broker, "eager");
+
+    // Two QoS 2 messages waiting for their PUBREL fill the window
+    for message_id in 1..=2 {
+        client.write_all(&publish(message_id, QoS::ExactlyOnce).encode().unwrap()).unwrap();
+        assert_eq!(PubRecPacket::decode(&read_packet(&mut client).unwrap()).unwrap().packet_id, message_id);
+    }
+
+    // Sending one again does not take another slot, a new one is over the limit
+    client.write_all(&publish(2, QoS::ExactlyOnce).encode().unwrap()).unwrap();
+    assert_eq!(PubRecPacket::decode(&read_packet(&mut client).unwrap()).unwrap().packet_id, 2);
+    client.write_all(&publish(3, QoS::ExactlyOnce).encode().unwrap()).unwrap();
+    let disconnect = read_packet(&mut client).unwrap();
+    assert_eq!(disconnect[0], 0xE0);
+    assert_eq!(disconnect[2], DisconnectReasonCode::ReceiveMaximumExceeded as u8);
+}
+
+#[test]
+fn receive_maximum_is_read_from_the_arguments() {
+    let args: Vec<String> = ["--receive-maximum", "5"].iter().map(|arg| arg.to_string()).collect();
+    assert_eq!(BrokerConfig::from_args(&args).receive_maximum, 5);
+
+    // 0 would let the client send no QoS 2 message at all
+    let args: Vec<String> = ["--receive-maximum", "0"].iter().map(|arg| arg.to_string()).collect();
+    assert_eq!(BrokerConfig::from_args(&args).receive_maximum, BrokerConfig::default().receive_maximum);
+}
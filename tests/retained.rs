@@ -0,0 +1,185 @@
+//! Retained messages sent to the subscriptions whose filter matches their topic.
+
+mod common;
+
+use std::io::Write;
+
+use common::{connect, read_packet};
+use mqtt_broker::broker::{transport::DuplexStream, Broker, BrokerConfig};
+use mqtt_broker::packets::{
+    ping::PingReqPacket,
+    puback::PubAckPacket,
+    publish::PublishPacket,
+    qos::QoS,
+    suback::SubAckPacket,
+    subscribe::SubscribePacket,
+};
+
+#[test]
+fn wildcard_subscription_gets_every_matching_retained_message() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut publisher = connect(&broker, "publisher");
+
+    let retained = [
+        ("sensors/temperature", "21.5"),
+        ("sensors/humidity", "40"),
+        ("sensors/kitchen/pressure", "1013"),
+        ("actuators/valve", "open"), // Outside of the filter
+    ];
+    for (message_id, (topic, payload)) in (1..).zip(retained) {
+        let packet = PublishPacket::builder(topic, payload)
+            .qos(QoS::AtLeastOnce)
+            .message_id(message_id)
+            .retain(true)
+            .build()
+            .unwrap();
+        publisher.write_all(&packet.encode().unwrap()).unwrap();
+        // The message is retained by the time it is acknowledged
+        let puback = PubAckPacket::decode(&read_packet(&mut publisher).unwrap()).unwrap();
+        assert_eq!(puback.packet_id, message_id);
+    }
+
+    let mut subscriber = connect(&broker, "subscriber");
+    let subscribe = SubscribePacket::new(1, vec!["sensors/#".to_string()], vec![0x01]);
+    subscriber.write_all(&subscribe.encode().unwrap()).unwrap();
+    let suback = SubAckPacket::decode(&read_packet(&mut subscriber).unwrap()).unwrap();
+    assert_eq!(suback.return_codes, vec![0x01]);
+
+    // Every retained message under sensors/, at any depth, sorted by topic
+    let mut received = Vec::new();
+    for _ in 0..3 {
+        let packet = PublishPacket::decode(&read_packet(&mut subscriber).unwrap()).unwrap();
+        assert!(packet.retain);
+        assert_eq!(packet.qos, QoS::AtLeastOnce);
+        received.push((packet.topic_name, String::from_utf8(packet.payload).unwrap()));
+    }
+    assert_eq!(
+        received,
+        vec![
+            ("sensors/humidity".to_string(), "40".to_string()),
+            ("sensors/kitchen/pressure".to_string(), "1013".to_string()),
+            ("sensors/temperature".to_string(), "21.5".to_string()),
+        ]
+    );
+
+    // Nothing else is sent: the next packet is the answer to the PINGREQ
+    subscriber.write_all(&PingReqPacket.encode()).unwrap();
+    assert_eq!(read_packet(&mut subscriber).unwrap(), vec![0xD0, 0x00]);
+}
+
+// Publishes a retained QoS 1 message to status and waits for its PUBACK
+fn publish_retained(publisher: &mut DuplexStream, message_id: u16, payload: &str) {
+    let packet = PublishPacket::builder("status", payload)
+        .qos(QoS::AtLeastOnce)
+        .message_id(message_id)
+        .retain(true)
+        .build()
+        .unwrap();
+    publisher.write_all(&packet.encode().unwrap()).unwrap();
+    assert_eq!(PubAckPacket::decode(&read_packet(publisher).unwrap()).unwrap().packet_id, message_id);
+}
+
+#[test]
+fn retain_handling_2_sends_no_retained_message_at_subscribe_time() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut publisher = connect(&broker, "publisher");
+    publish_retained(&mut publisher, 1, "online");
+
+    // QoS 1 with Retain Handling 2
+    let mut subscriber = connect(&broker, "subscriber");
+    let subscribe = SubscribePacket::new(1, vec!["status".to_string()], vec![0x21]);
+    subscriber.write_all(&subscribe.encode().unwrap()).unwrap();
+    let suback = SubAckPacket::decode(&read_packet(&mut subscriber).unwrap()).unwrap();
+    assert_eq!(suback.return_codes, vec![0x01]);
+
+    // The retained message is not sent: the next packet is the answer to the PINGREQ
+    subscriber.write_all(&PingReqPacket.encode()).unwrap();
+    assert_eq!(read_packet(&mut subscriber).unwrap(), vec![0xD0, 0x00]);
+
+    // A new publish is delivered as usual
+    publish_retained(&mut publisher, 2, "offline");
+    let packet = PublishPacket::decode(&read_packet(&mut subscriber).unwrap()).unwrap();
+    assert_eq!(packet.payload, b"offline");
+}
+
+// Subscribes to status with the options byte and returns the payloads of the retained
+// messages sent for it, the PINGRESP after the SUBACK marks the end of them
+fn subscribe_status(subscriber: &mut DuplexStream, packet_id: u16, options: u8) -> Vec<Vec<u8>> {
+    let subscribe = SubscribePacket::new(packet_id, vec!["status".to_string()], vec![options]);
+    subscriber.write_all(&subscribe.encode().unwrap()).unwrap();
+    let suback = SubAckPacket::decode(&read_packet(subscriber).unwrap()).unwrap();
+    assert_eq!(suback.packet_id, packet_id);
+
+    subscriber.write_all(&PingReqPacket.encode()).unwrap();
+    let mut retained = Vec::new();
+    loop {
+        let packet = read_packet(subscriber).unwrap();
+        if packet == [0xD0, 0x00] {
+            return retained;
+        }
+        let packet = PublishPacket::decode(&packet).unwrap();
+        assert!(packet.retain);
+        retained.push(packet.payload);
+    }
+}
+
+#[test]
+fn retain_handling_0_sends_the_retained_message_on_every_subscribe() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut publisher = connect(&broker, "publisher");
+    publish_retained(&mut publisher, 1, "online");
+
+    // QoS 0 with Retain Handling 0, a re-subscribe gets the message again
+    let mut subscriber = connect(&broker, "subscriber");
+    assert_eq!(subscribe_status(&mut subscriber, 1, 0x00), vec![b"online".to_vec()]);
+    assert_eq!(subscribe_status(&mut subscriber, 2, 0x00), vec![b"online".to_vec()]);
+}
+
+#[test]
+fn retain_handling_1_sends_the_retained_message_only_on_a_new_subscription() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut publisher = connect(&broker, "publisher");
+    publish_retained(&mut publisher, 1, "online");
+
+    // QoS 0 with Retain Handling 1, the re-subscribe replaces the subscription silently
+    let mut subscriber = connect(&broker, "subscriber");
+    assert_eq!(subscribe_status(&mut subscriber, 1, 0x10), vec![b"online".to_vec()]);
+    assert_eq!(subscribe_status(&mut subscriber, 2, 0x10), Vec::<Vec<u8>>::new());
+}
+
+#[test]
+fn retain_flag_is_set_only_for_the_subscriber_getting_the_stored_message() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut existing = connect(&broker, "existing");
+    assert!(subscribe_status(&mut existing, 1, 0x01).is_empty());
+
+    let mut publisher = connect(&broker, "publisher");
+    publish_retained(&mut publisher, 1, "online");
+
+    // The subscriber already there gets the live message with the flag cleared
+    let live = PublishPacket::decode(&read_packet(&mut existing).unwrap()).unwrap();
+    assert_eq!(live.payload, b"online");
+    assert!(!live.retain);
+
+    // A new subscriber gets the same message from the store, with the flag set
+    let mut newcomer = connect(&broker, "newcomer");
+    assert_eq!(subscribe_status(&mut newcomer, 1, 0x01), vec![b"online".to_vec()]);
+}
+
+#[test]
+fn active_topics_lists_the_subscribed_filters_and_the_retained_topics() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut subscriber = connect(&broker, "subscriber");
+    let subscribe = SubscribePacket::new(1, vec!["sensors/+".to_string(), "alerts/#".to_string()], vec![0x00, 0x01]);
+    subscriber.write_all(&subscribe.encode().unwrap()).unwrap();
+    SubAckPacket::decode(&read_packet(&mut subscriber).unwrap()).unwrap();
+
+    // Retained on a topic none of the filters matches
+    let mut publisher = connect(&broker, "publisher");
+    publish_retained(&mut publisher, 1, "online");
+
+    assert_eq!(
+        broker.active_topics(),
+        vec!["alerts/#".to_string(), "sensors/+".to_string(), "status".to_string()]
+    );
+}
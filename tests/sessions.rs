@@ -0,0 +1,309 @@
+//! Sessions kept across reconnects of the clients connecting without Clean Start, and
+//! across restarts of the broker.
+
+mod common;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use common::read_packet;
+use mqtt_broker::broker::{transport::DuplexStream, Broker, BrokerConfig, Persistence, StoredSession, Transport};
+use mqtt_broker::error::MqttResult;
+use mqtt_broker::packets::{
+    connack::ConnAckPacket,
+    connect::ConnectPacket,
+    disconnect::{DisconnectPacket, DisconnectReasonCode},
+    ping::PingReqPacket,
+    puback::PubAckPacket,
+    publish::PublishPacket,
+    qos::QoS,
+    subscribe::SubscribePacket,
+    DecodeError,
+};
+
+// Records the client ID of every session saved, and whether the retained messages were
+struct RecordSaves {
+    sessions: Mutex<Vec<String>>,
+    retained: Mutex<bool>,
+}
+
+impl Persistence for RecordSaves {
+    fn save_retained(&self, _retained: &[PublishPacket]) -> MqttResult<()> {
+        *self.retained.lock().unwrap() = true;
+        Ok(())
+    }
+
+    fn load_retained(&self) -> MqttResult<Vec<PublishPacket>> {
+        Ok(Vec::new())
+    }
+
+    fn save_session(&self, client_id: &str, _session: &StoredSession) -> MqttResult<()> {
+        self.sessions.lock().unwrap().push(client_id.to_string());
+        Ok(())
+    }
+
+    fn remove_session(&self, client_id: &str) -> MqttResult<()> {
+        self.sessions.lock().unwrap().push(client_id.to_string());
+        Ok(())
+    }
+
+    fn load_sessions(&self) -> MqttResult<HashMap<String, StoredSession>> {
+        Ok(HashMap::new())
+    }
+}
+
+// Connects with the given connect flags, returning the stream and whether the session was present
+fn connect(broker: &Broker, client_id: &str, connect_flags: u8) -> (DuplexStream, bool) {
+    connect_with_expiry(broker, client_id, connect_flags, None)
+}
+
+// Same as connect, declaring the session expiry interval in the CONNECT properties
+fn connect_with_expiry(broker: &Broker, client_id: &str, connect_flags: u8, session_expiry: Option<u32>) -> (DuplexStream, bool) {
+    let (mut client, server) = DuplexStream::pair();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    broker.accept(server);
+
+    let mut connect = ConnectPacket::new("MQTT".to_string(), 5, connect_flags, 60, client_id.to_string(), None, None, None, None);
+    connect.properties.session_expiry_interval = session_expiry;
+    client.write_all(&connect.encode()).unwrap();
+    let connack = ConnAckPacket::decode(&read_packet(&mut client).unwrap()).unwrap();
+    (client, connack.session_present)
+}
+
+// Subscribes to the topic at QoS 1 and waits until the broker registered it
+fn subscribe(client: &mut DuplexStream, topic: &str) {
+    let subscribe = SubscribePacket::new(1, vec![topic.to_string()], vec![1]);
+    client.write_all(&subscribe.encode().unwrap()).unwrap();
+    read_packet(client).unwrap();
+    client.write_all(&PingReqPacket.encode()).unwrap();
+    assert_eq!(read_packet(client).unwrap(), vec![0xD0, 0x00]);
+}
+
+// Sends a DISCONNECT and waits until the broker dropped the subscriptions of the connection
+fn disconnect(broker: &Broker, client: &mut DuplexStream) {
+    send_disconnect(broker, client, DisconnectPacket::new(DisconnectReasonCode::NormalDisconnection));
+}
+
+// Same as disconnect, with the given DISCONNECT
+fn send_disconnect(broker: &Broker, client: &mut DuplexStream, packet: DisconnectPacket) {
+    send_disconnect_bytes(broker, client, &packet.encode());
+}
+
+// Same as send_disconnect, with a DISCONNECT already encoded
+fn send_disconnect_bytes(broker: &Broker, client: &mut DuplexStream, bytes: &[u8]) {
+    client.write_all(bytes).unwrap();
+    while !broker.active_topics().is_empty() {
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn session_without_clean_start_keeps_subscriptions_and_queues_messages() {
+    let broker = Broker::new(BrokerConfig::default());
+    let (mut client, session_present) = connect(&broker, "meter", 0x00);
+    assert!(!session_present);
+    subscribe(&mut client, "meters/+");
+    disconnect(&broker, &mut client);
+
+    // Published while the client is away
+    broker.publish(PublishPacket::new("meters/power".to_string(), 0, QoS::AtLeastOnce, false, false, b"230".to_vec()));
+
+    let (mut client, session_present) = connect(&broker, "meter", 0x00);
+    assert!(session_present);
+    let queued = PublishPacket::decode(&read_packet(&mut client).unwrap()).unwrap();
+    assert_eq!(queued.topic_name, "meters/power");
+    assert_eq!(queued.payload, b"230".to_vec());
+
+    // The subscription is back too
+    broker.publish(PublishPacket::new("meters/voltage".to_string(), 0, QoS::AtLeastOnce, false, false, b"12".to_vec()));
+    let live = PublishPacket::decode(&read_packet(&mut client).unwrap()).unwrap();
+    assert_eq!(live.topic_name, "meters/voltage");
+}
+
+#[test]
+fn unacknowledged_message_is_sent_again_as_a_duplicate_after_a_reconnect() {
+    let broker = Broker::new(BrokerConfig::default());
+    let (mut client, _) = connect(&broker, "meter", 0x00);
+    subscribe(&mut client, "meters/+");
+
+    broker.publish(PublishPacket::new("meters/power".to_string(), 0, QoS::AtLeastOnce, false, false, b"230".to_vec()));
+    let first = PublishPacket::decode(&read_packet(&mut client).unwrap()).unwrap();
+    assert!(!first.dup);
+    // Leaves without its PUBACK
+    disconnect(&broker, &mut client);
+
+    let (mut client, session_present) = connect(&broker, "meter", 0x00);
+    assert!(session_present);
+    let again = PublishPacket::decode(&read_packet(&mut client).unwrap()).unwrap();
+    assert!(again.dup);
+    assert_eq!(again.message_id, first.message_id);
+    assert_eq!(again.payload, b"230".to_vec());
+
+    // Acknowledged this time, it is not sent a third time
+    client.write_all(&PubAckPacket::new(again.message_id).encode()).unwrap();
+    disconnect(&broker, &mut client);
+    let (mut client, _) = connect(&broker, "meter", 0x00);
+    client.write_all(&PingReqPacket.encode()).unwrap();
+    assert_eq!(read_packet(&mut client).unwrap(), vec![0xD0, 0x00]);
+}
+
+#[test]
+fn session_survives_a_restart_of_the_broker() {
+    let dir = std::env::temp_dir().join(format!("mqtt-restart-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let config = || BrokerConfig { persistence_dir: Some(dir.clone()), ..BrokerConfig::default() };
+
+    let broker = Broker::new(config());
+    let (mut client, _) = connect(&broker, "meter", 0x00);
+    subscribe(&mut client, "meters/+");
+    disconnect(&broker, &mut client);
+    broker.publish(PublishPacket::new("meters/power".to_string(), 0, QoS::AtLeastOnce, false, false, b"230".to_vec()));
+    drop(broker);
+
+    // The restarted broker queues the messages of the subscription it loaded back
+    let broker = Broker::new(config());
+    broker.publish(PublishPacket::new("meters/voltage".to_string(), 0, QoS::AtLeastOnce, false, false, b"12".to_vec()));
+
+    let (mut client, session_present) = connect(&broker, "meter", 0x00);
+    assert!(session_present);
+    let queued: Vec<PublishPacket> =
+        (0..2).map(|_| PublishPacket::decode(&read_packet(&mut client).unwrap()).unwrap()).collect();
+    assert_eq!(queued[0].topic_name, "meters/power");
+    assert_eq!(queued[1].topic_name, "meters/voltage");
+    assert!(queued.iter().all(|packet| packet.qos == QoS::AtLeastOnce));
+
+    // The subscription is live again too
+    broker.publish(PublishPacket::new("meters/current".to_string(), 0, QoS::AtLeastOnce, false, false, b"5".to_vec()));
+    let live = PublishPacket::decode(&read_packet(&mut client).unwrap()).unwrap();
+    assert_eq!(live.topic_name, "meters/current");
+    drop(broker);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn message_saves_only_the_sessions_it_reaches() {
+    let saves = Arc::new(RecordSaves { sessions: Mutex::new(Vec::new()), retained: Mutex::new(false) });
+    let mut broker = Broker::new(BrokerConfig::default());
+    broker.set_persistence(saves.clone());
+
+    let (mut meter, _) = connect(&broker, "meter", 0x00);
+    subscribe(&mut meter, "meters/+");
+    disconnect(&broker, &mut meter);
+    let (mut valve, _) = connect(&broker, "valve", 0x00);
+    subscribe(&mut valve, "valves/+");
+    let (mut gauge, _) = connect(&broker, "gauge", 0x00);
+    subscribe(&mut gauge, "gauges/+");
+    saves.sessions.lock().unwrap().clear();
+
+    // Queued for the session away and in flight for the connected subscriber, the third
+    // session and the retained messages are left alone
+    broker.publish(PublishPacket::new("meters/power".to_string(), 0, QoS::AtLeastOnce, false, false, b"230".to_vec()));
+    broker.publish(PublishPacket::new("valves/inlet".to_string(), 0, QoS::AtLeastOnce, false, false, b"open".to_vec()));
+    assert_eq!(*saves.sessions.lock().unwrap(), vec!["meter".to_string(), "valve".to_string()]);
+    assert!(!*saves.retained.lock().unwrap());
+
+    // The PUBACK saves the session it acknowledges a message of
+    saves.sessions.lock().unwrap().clear();
+    let delivered = PublishPacket::decode(&read_packet(&mut valve).unwrap()).unwrap();
+    valve.write_all(&PubAckPacket::new(delivered.message_id).encode()).unwrap();
+    valve.write_all(&PingReqPacket.encode()).unwrap();
+    assert_eq!(read_packet(&mut valve).unwrap(), vec![0xD0, 0x00]);
+    assert_eq!(*saves.sessions.lock().unwrap(), vec!["valve".to_string()]);
+}
+
+#[test]
+fn clean_start_discards_the_stored_session() {
+    let broker = Broker::new(BrokerConfig::default());
+    let (mut client, _) = connect(&broker, "meter", 0x00);
+    subscribe(&mut client, "meters/+");
+    disconnect(&broker, &mut client);
+
+    let (mut client, session_present) = connect(&broker, "meter", 0x02);
+    assert!(!session_present);
+    assert!(broker.active_topics().is_empty());
+
+    // Nothing stored for the client anymore
+    client.write_all(&DisconnectPacket::new(DisconnectReasonCode::NormalDisconnection).encode()).unwrap();
+    let (_, session_present) = connect(&broker, "meter", 0x00);
+    assert!(!session_present);
+}
+
+#[test]
+fn disconnect_carries_a_session_expiry_interval() {
+    let mut packet = DisconnectPacket::new(DisconnectReasonCode::NormalDisconnection);
+    assert_eq!(packet.session_expiry_interval(), None);
+    packet.set_session_expiry_interval(3600);
+    assert_eq!(packet.session_expiry_interval(), Some(3600));
+
+    // Property length, then the identifier and the four bytes of the interval
+    let bytes = [0xE0, 0x07, 0x00, 0x05, 0x11, 0x00, 0x00, 0x00, 0x3C];
+    assert_eq!(DisconnectPacket::decode(&bytes).unwrap().session_expiry_interval(), Some(60));
+
+    // An interval of two bytes runs out of the packet
+    let bytes = [0xE0, 0x05, 0x00, 0x03, 0x11, 0x00, 0x3C];
+    assert_eq!(DisconnectPacket::decode(&bytes), Err(DecodeError::UnexpectedEof));
+}
+
+#[test]
+fn disconnect_with_a_zero_interval_discards_the_session() {
+    let broker = Broker::new(BrokerConfig::default());
+    let (mut client, _) = connect_with_expiry(&broker, "meter", 0x00, Some(3600));
+    subscribe(&mut client, "meters/+");
+
+    let mut packet = DisconnectPacket::new(DisconnectReasonCode::NormalDisconnection);
+    packet.set_session_expiry_interval(0);
+    send_disconnect(&broker, &mut client, packet);
+
+    let (_, session_present) = connect(&broker, "meter", 0x00);
+    assert!(!session_present);
+}
+
+#[test]
+fn session_expires_after_the_interval_of_the_disconnect() {
+    let broker = Broker::new(BrokerConfig::default());
+    let (mut client, _) = connect_with_expiry(&broker, "meter", 0x00, Some(3600));
+    subscribe(&mut client, "meters/+");
+
+    let mut packet = DisconnectPacket::new(DisconnectReasonCode::NormalDisconnection);
+    packet.set_session_expiry_interval(1);
+    send_disconnect(&broker, &mut client, packet);
+
+    thread::sleep(Duration::from_millis(1100));
+    let (_, session_present) = connect(&broker, "meter", 0x00);
+    assert!(!session_present);
+}
+
+#[test]
+fn interval_of_a_disconnect_in_the_layout_of_the_specification() {
+    let broker = Broker::new(BrokerConfig::default());
+    let (mut client, _) = connect_with_expiry(&broker, "meter", 0x00, Some(3600));
+    subscribe(&mut client, "meters/+");
+
+    // Normal Disconnection, property length 5 and a session expiry interval of 1 second,
+    // as a client written against the specification sends it
+    send_disconnect_bytes(&broker, &mut client, &[0xE0, 0x07, 0x00, 0x05, 0x11, 0x00, 0x00, 0x00, 0x01]);
+
+    thread::sleep(Duration::from_millis(1100));
+    let (_, session_present) = connect(&broker, "meter", 0x00);
+    assert!(!session_present);
+}
+
+#[test]
+fn interval_on_disconnect_after_a_zero_connect_interval_is_a_protocol_error() {
+    let broker = Broker::new(BrokerConfig::default());
+    let (mut client, _) = connect_with_expiry(&broker, "meter", 0x00, Some(0));
+
+    // Session expiry interval of 60 seconds, in the bytes of the specification
+    client.write_all(&[0xE0, 0x07, 0x00, 0x05, 0x11, 0x00, 0x00, 0x00, 0x3C]).unwrap();
+
+    let disconnect = DisconnectPacket::decode(&read_packet(&mut client).unwrap()).unwrap();
+    assert_eq!(disconnect, DisconnectPacket::new(DisconnectReasonCode::ProtocolError));
+
+    // The session ended with the connection
+    let (_, session_present) = connect(&broker, "meter", 0x00);
+    assert!(!session_present);
+}
@@ -0,0 +1,104 @@
+//! Shared subscriptions, whose messages go to one member of the share at a time.
+
+mod common;
+
+use std::io::Write;
+
+use common::{connect, read_packet};
+use mqtt_broker::broker::{transport::DuplexStream, Broker, BrokerConfig, SubscriptionRegistry};
+use mqtt_broker::packets::{
+    fixed_header::{parse_fixed_header, PacketType},
+    ping::PingReqPacket,
+    publish::PublishPacket,
+    qos::QoS,
+    suback::{SubAckPacket, TOPIC_FILTER_INVALID},
+    subscribe::{is_valid_topic_filter, parse_shared_filter, SubscribePacket},
+};
+
+// Subscribes the client and waits until its filter is registered
+fn subscribe(client: &mut DuplexStream, filter: &str) -> u8 {
+    client.write_all(&SubscribePacket::new(1, vec![filter.to_string()], vec![0]).encode().unwrap()).unwrap();
+    let suback = SubAckPacket::decode(&read_packet(client).unwrap()).unwrap();
+    // By the time the PINGRESP arrives the filter is registered
+    client.write_all(&PingReqPacket.encode()).unwrap();
+    assert_eq!(read_packet(client).unwrap(), vec![0xD0, 0x00]);
+    suback.return_codes[0]
+}
+
+// Payloads of the PUBLISH packets received before the PINGRESP to a new PINGREQ
+fn received_payloads(client: &mut DuplexStream) -> Vec<String> {
+    client.write_all(&PingReqPacket.encode()).unwrap();
+    let mut payloads = Vec::new();
+    loop {
+        let packet = read_packet(client).unwrap();
+        match parse_fixed_header(&packet).unwrap().packet_type {
+            PacketType::Publish => payloads.push(String::from_utf8(PublishPacket::decode(&packet).unwrap().payload).unwrap()),
+            PacketType::PingResp => return payloads,
+            packet_type => panic!("unexpected packet: {:?}", packet_type),
+        }
+    }
+}
+
+#[test]
+fn shared_filters_are_parsed_and_validated() {
+    assert_eq!(parse_shared_filter("$share/workers/jobs/#"), Some(("workers", "jobs/#")));
+    assert_eq!(parse_shared_filter("jobs/#"), None);
+    assert!(is_valid_topic_filter("$share/workers/jobs/+"));
+
+    // Without a share name, with a wildcard in it or without a filter
+    for filter in ["$share", "$share/workers", "$share//jobs", "$share/work+/jobs", "$share/workers/", "$share/workers/jobs/#/done"] {
+        assert!(!is_valid_topic_filter(filter), "{} accepted", filter);
+    }
+}
+
+#[test]
+fn members_of_a_share_take_turns() {
+    let mut registry = SubscriptionRegistry::new();
+    registry.subscribe("worker-1", "$share/workers/jobs/+", QoS::AtMostOnce);
+    registry.subscribe("worker-2", "$share/workers/jobs/+", QoS::AtLeastOnce);
+    registry.subscribe("auditor", "jobs/#", QoS::AtMostOnce);
+
+    let first = registry.matching("jobs/build");
+    let second = registry.matching("jobs/test");
+    assert_eq!(first, vec![("auditor".to_string(), QoS::AtMostOnce), ("worker-1".to_string(), QoS::AtMostOnce)]);
+    assert_eq!(second, vec![("auditor".to_string(), QoS::AtMostOnce), ("worker-2".to_string(), QoS::AtLeastOnce)]);
+
+    // A member that leaves the share no longer gets a turn
+    assert!(registry.unsubscribe("worker-1", "$share/workers/jobs/+"));
+    for _ in 0..2 {
+        assert_eq!(registry.matching("jobs/deploy")[1], ("worker-2".to_string(), QoS::AtLeastOnce));
+    }
+    registry.remove_client("worker-2");
+    assert_eq!(registry.matching("jobs/deploy"), vec![("auditor".to_string(), QoS::AtMostOnce)]);
+}
+
+#[test]
+fn each_message_goes_to_one_member_of_the_share() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut first_member = connect(&broker, "worker-1");
+    let mut second_member = connect(&broker, "worker-2");
+    let mut auditor = connect(&broker, "auditor");
+    let mut publisher = connect(&broker, "producer");
+
+    assert_eq!(subscribe(&mut first_member, "$share/workers/jobs/#"), 0);
+    assert_eq!(subscribe(&mut second_member, "$share/workers/jobs/#"), 0);
+    assert_eq!(subscribe(&mut auditor, "jobs/#"), 0);
+    assert_eq!(subscribe(&mut publisher, "$share//jobs"), TOPIC_FILTER_INVALID);
+
+    // Each PUBACK comes once its message is routed
+    for message_id in 1..=4 {
+        let payload = format!("job-{}", message_id - 1).into_bytes();
+        let publish = PublishPacket::new("jobs/build".to_string(), message_id, QoS::AtLeastOnce, false, false, payload);
+        publisher.write_all(&publish.encode().unwrap()).unwrap();
+        assert_eq!(parse_fixed_header(&read_packet(&mut publisher).unwrap()).unwrap().packet_type, PacketType::PubAck);
+    }
+
+    // The members split the messages, the other subscription gets them all
+    let first = received_payloads(&mut first_member);
+    let second = received_payloads(&mut second_member);
+    assert_eq!((first.len(), second.len()), (2, 2));
+    let mut shared: Vec<String> = first.into_iter().chain(second).collect();
+    shared.sort();
+    assert_eq!(shared, vec!["job-0", "job-1", "job-2", "job-3"]);
+    assert_eq!(received_payloads(&mut auditor), vec!["job-0", "job-1", "job-2", "job-3"]);
+}
@@ -0,0 +1,44 @@
+//! Graceful shutdown of a broker serving real TCP connections.
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use common::read_packet;
+use mqtt_broker::broker::{Broker, BrokerConfig};
+use mqtt_broker::packets::{
+    connect::ConnectPacket,
+    fixed_header::{parse_fixed_header, PacketType},
+};
+
+#[test]
+fn shutdown_disconnects_clients_and_stops_the_listener() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let broker = Broker::new(BrokerConfig::default());
+    let server = {
+        let broker = broker.clone();
+        thread::spawn(move || broker.serve(listener))
+    };
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let connect = ConnectPacket::new("MQTT".to_string(), 5, 0x02, 60, "leaving".to_string(), None, None, None, None);
+    client.write_all(&connect.encode()).unwrap();
+    let connack = read_packet(&mut client).unwrap();
+    assert_eq!(parse_fixed_header(&connack).unwrap().packet_type, PacketType::ConnAck);
+
+    broker.shutdown();
+
+    let disconnect = read_packet(&mut client).unwrap();
+    assert_eq!(parse_fixed_header(&disconnect).unwrap().packet_type, PacketType::Disconnect);
+    assert_eq!(disconnect[2], 0x8B); // Server shutting down
+
+    // The connection is closed and the listener thread returns
+    let mut rest = Vec::new();
+    assert_eq!(client.read_to_end(&mut rest).unwrap(), 0);
+    server.join().unwrap();
+}
@@ -0,0 +1,113 @@
+//! Outcome of every topic filter of a SUBSCRIBE, driven over the in-memory transport.
+
+mod common;
+
+use std::io::Write;
+use std::sync::Arc;
+
+use common::{connect, read_packet};
+use mqtt_broker::broker::{Broker, BrokerConfig, Interceptor};
+use mqtt_broker::packets::{
+    ping::PingReqPacket,
+    publish::PublishPacket,
+    qos::QoS,
+    suback::SubAckPacket,
+    subscribe::{SubscribePacket, SubscriptionOptions},
+};
+
+// Refuses the filters under private/
+struct NoPrivateTopics;
+
+impl Interceptor for NoPrivateTopics {
+    fn on_publish(&self, packet: PublishPacket) -> Option<PublishPacket> {
+        Some(packet)
+    }
+
+    fn on_subscribe(&self, _client_id: &str, topic_filter: &str) -> bool {
+        !topic_filter.starts_with("private/")
+    }
+}
+
+#[test]
+fn suback_reports_each_filter_and_keeps_the_connection() {
+    let mut broker = Broker::new(BrokerConfig::default());
+    broker.set_interceptor(Arc::new(NoPrivateTopics));
+    let mut client = connect(&broker, "mixed");
+
+    let subscribe = SubscribePacket::new(
+        7,
+        vec![
+            "sensors/+/temperature".to_string(), // Valid, QoS 1
+            "sensors/#/temperature".to_string(), // # is not the last level
+            "private/notes".to_string(),         // Refused by the interceptor
+            "alerts".to_string(),                // Reserved option bits set
+            "alerts/#".to_string(),              // Valid, QoS 2 granted as QoS 1
+        ],
+        vec![0x01, 0x00, 0x00, 0xC0, 0x02],
+    );
+    client.write_all(&subscribe.encode().unwrap()).unwrap();
+
+    let suback = SubAckPacket::decode(&read_packet(&mut client).unwrap()).unwrap();
+    assert_eq!(suback.packet_id, 7);
+    assert_eq!(suback.return_codes, vec![0x01, 0x8F, 0x87, 0x80, 0x01]);
+
+    // The connection is still served after the partial failure, and by the time the
+    // PINGRESP arrives the granted filters are registered
+    client.write_all(&PingReqPacket.encode()).unwrap();
+    assert_eq!(read_packet(&mut client).unwrap(), vec![0xD0, 0x00]);
+    assert_eq!(broker.active_topics(), vec!["alerts/#".to_string(), "sensors/+/temperature".to_string()]);
+}
+
+#[test]
+fn subscription_is_registered_by_the_time_of_its_suback() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut client = connect(&broker, "eager");
+
+    // A message published right after each SUBACK, with no round trip between, is delivered
+    for (i, topic) in (1..=20).map(|i| (i, format!("eager/{}", i))) {
+        let subscribe = SubscribePacket::new(i, vec![topic.clone()], vec![0]);
+        client.write_all(&subscribe.encode().unwrap()).unwrap();
+        SubAckPacket::decode(&read_packet(&mut client).unwrap()).unwrap();
+
+        broker.publish(PublishPacket::new(topic.clone(), 0, QoS::AtMostOnce, false, false, b"now".to_vec()));
+        assert_eq!(PublishPacket::decode(&read_packet(&mut client).unwrap()).unwrap().topic_name, topic);
+    }
+}
+
+#[test]
+fn qos_2_subscription_is_granted_the_qos_messages_are_sent_with() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut client = connect(&broker, "exact");
+
+    client.write_all(&SubscribePacket::new(1, vec!["ledger".to_string()], vec![0x02]).encode().unwrap()).unwrap();
+    let suback = SubAckPacket::decode(&read_packet(&mut client).unwrap()).unwrap();
+    assert_eq!(suback.return_codes, vec![0x01]);
+
+    // A QoS 2 message reaches the subscriber at the QoS of its SUBACK
+    broker.publish(PublishPacket::new("ledger".to_string(), 1, QoS::ExactlyOnce, false, false, b"+10".to_vec()));
+    let delivered = PublishPacket::decode(&read_packet(&mut client).unwrap()).unwrap();
+    assert_eq!(delivered.qos, QoS::AtLeastOnce);
+}
+
+#[test]
+fn three_filters_at_qos_0_1_and_2_in_one_subscribe() {
+    let filters: Vec<(String, SubscriptionOptions)> = [QoS::AtMostOnce, QoS::AtLeastOnce, QoS::ExactlyOnce]
+        .into_iter()
+        .enumerate()
+        .map(|(i, qos)| (format!("levels/{}", i), SubscriptionOptions { qos, ..Default::default() }))
+        .collect();
+    let subscribe = SubscribePacket::with_options(4, filters.clone());
+
+    // Each filter keeps its own options on the wire
+    let decoded = SubscribePacket::decode(&subscribe.encode().unwrap()).unwrap();
+    assert_eq!(decoded.topic_filters, filters.iter().map(|(filter, _)| filter.clone()).collect::<Vec<_>>());
+    assert_eq!(decoded.options(), filters.iter().map(|(_, options)| Ok(*options)).collect::<Vec<_>>());
+
+    // The broker grants each one, QoS 2 as the QoS 1 it sends messages with
+    let broker = Broker::new(BrokerConfig::default());
+    let mut client = connect(&broker, "levels");
+    client.write_all(&subscribe.encode().unwrap()).unwrap();
+    let suback = SubAckPacket::decode(&read_packet(&mut client).unwrap()).unwrap();
+    assert_eq!(suback.packet_id, 4);
+    assert_eq!(suback.return_codes, vec![0x00, 0x01, 0x01]);
+}
@@ -0,0 +1,173 @@
+//! Subscription options of the SUBSCRIBE, and the No Local and Retain As Published
+//! options honored by the broker.
+
+mod common;
+
+use std::io::Write;
+
+use common::{connect, read_packet};
+use mqtt_broker::broker::{transport::DuplexStream, Broker, BrokerConfig, SubscriptionRegistry};
+use mqtt_broker::packets::{
+    ping::PingReqPacket,
+    puback::PubAckPacket,
+    publish::PublishPacket,
+    qos::QoS,
+    suback::SubAckPacket,
+    subscribe::{SubscribePacket, SubscriptionOptions},
+    DecodeError,
+};
+
+#[test]
+fn every_option_bit_is_parsed() {
+    let default = SubscriptionOptions::default();
+    assert_eq!(SubscriptionOptions::from_byte(0x00).unwrap(), default);
+    assert_eq!(SubscriptionOptions::from_byte(0x02).unwrap(), SubscriptionOptions { qos: QoS::ExactlyOnce, ..default });
+    assert_eq!(SubscriptionOptions::from_byte(0x04).unwrap(), SubscriptionOptions { no_local: true, ..default });
+    assert_eq!(SubscriptionOptions::from_byte(0x08).unwrap(), SubscriptionOptions { retain_as_published: true, ..default });
+    assert_eq!(SubscriptionOptions::from_byte(0x10).unwrap(), SubscriptionOptions { retain_handling: 1, ..default });
+    assert_eq!(SubscriptionOptions::from_byte(0x20).unwrap(), SubscriptionOptions { retain_handling: 2, ..default });
+
+    // QoS 3, Retain Handling 3 and the reserved bits are refused
+    assert_eq!(SubscriptionOptions::from_byte(0x03), Err(DecodeError::InvalidQoS(3)));
+    assert!(matches!(SubscriptionOptions::from_byte(0x30), Err(DecodeError::InvalidFlags(_))));
+    assert!(matches!(SubscriptionOptions::from_byte(0x80), Err(DecodeError::InvalidFlags(_))));
+
+    // Every valid byte encodes back to itself
+    for byte in 0x00..=0x2F {
+        if let Ok(options) = SubscriptionOptions::from_byte(byte) {
+            assert_eq!(options.to_byte(), byte);
+        }
+    }
+}
+
+#[test]
+fn options_are_parsed_per_topic_filter() {
+    let packet = SubscribePacket::new(1, vec!["a".to_string(), "b".to_string(), "c".to_string()], vec![0x0D, 0xC0, 0x21]);
+    let packet = SubscribePacket::decode(&packet.encode().unwrap()).unwrap();
+
+    let options = packet.options();
+    assert_eq!(
+        options[0],
+        Ok(SubscriptionOptions { qos: QoS::AtLeastOnce, no_local: true, retain_as_published: true, retain_handling: 0 })
+    );
+    assert!(options[1].is_err());
+    assert_eq!(options[2], Ok(SubscriptionOptions { qos: QoS::AtLeastOnce, retain_handling: 2, ..Default::default() }));
+}
+
+#[test]
+fn no_local_subscriptions_of_the_publisher_are_left_out() {
+    let no_local = SubscriptionOptions { qos: QoS::AtLeastOnce, no_local: true, ..Default::default() };
+    let mut registry = SubscriptionRegistry::new();
+    registry.subscribe_with_options("chat-a", "chat/#", no_local);
+    registry.subscribe("chat-a", "chat/room", QoS::AtMostOnce);
+    registry.subscribe_with_options("chat-b", "chat/#", no_local);
+
+    let matching: Vec<(String, QoS)> = registry
+        .matching_from("chat/room", Some("chat-a"))
+        .into_iter()
+        .map(|(client_id, options)| (client_id, options.qos))
+        .collect();
+    // chat-a still gets its message through its other subscription, at its QoS
+    assert_eq!(matching, vec![("chat-a".to_string(), QoS::AtMostOnce), ("chat-b".to_string(), QoS::AtLeastOnce)]);
+
+    let matching = registry.matching_from("chat/lobby", Some("chat-a"));
+    assert_eq!(matching.len(), 1);
+    assert_eq!(matching[0].0, "chat-b");
+}
+
+// Subscribes the client to the filter and waits for the SUBACK
+fn subscribe(client: &mut DuplexStream, filter: &str, options: SubscriptionOptions) {
+    let subscribe = SubscribePacket::with_options(1, vec![(filter.to_string(), options)]);
+    client.write_all(&subscribe.encode().unwrap()).unwrap();
+    let suback = SubAckPacket::decode(&read_packet(client).unwrap()).unwrap();
+    assert_eq!(suback.return_codes, vec![options.qos.to_u8()]);
+}
+
+// Publishes a QoS 1 message, the publisher gets its own message back before the
+// PUBACK since the broker routes it before acknowledging it
+fn publish(client: &mut DuplexStream, topic: &str, retain: bool) {
+    let packet = PublishPacket::builder(topic, "hello")
+        .qos(QoS::AtLeastOnce)
+        .message_id(10)
+        .retain(retain)
+        .build()
+        .unwrap();
+    client.write_all(&packet.encode().unwrap()).unwrap();
+}
+
+#[test]
+fn publisher_gets_its_message_back_without_no_local() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut client = connect(&broker, "echo");
+    subscribe(&mut client, "chat/room", SubscriptionOptions { qos: QoS::AtLeastOnce, ..Default::default() });
+
+    publish(&mut client, "chat/room", false);
+    let echo = PublishPacket::decode(&read_packet(&mut client).unwrap()).unwrap();
+    assert_eq!(echo.topic_name, "chat/room");
+    assert_eq!(PubAckPacket::decode(&read_packet(&mut client).unwrap()).unwrap().packet_id, 10);
+}
+
+#[test]
+fn no_local_suppresses_the_publisher_own_message() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut publisher = connect(&broker, "talker");
+    let mut listener = connect(&broker, "listener");
+    let no_local = SubscriptionOptions { qos: QoS::AtLeastOnce, no_local: true, ..Default::default() };
+    subscribe(&mut publisher, "chat/#", no_local);
+    subscribe(&mut listener, "chat/#", no_local);
+
+    publish(&mut publisher, "chat/room", false);
+    // The PUBACK comes first, then the PINGRESP: the message was not sent back
+    assert_eq!(PubAckPacket::decode(&read_packet(&mut publisher).unwrap()).unwrap().packet_id, 10);
+    publisher.write_all(&PingReqPacket.encode()).unwrap();
+    assert_eq!(read_packet(&mut publisher).unwrap(), vec![0xD0, 0x00]);
+
+    // Other clients still get it, No Local only concerns the publisher
+    let received = PublishPacket::decode(&read_packet(&mut listener).unwrap()).unwrap();
+    assert_eq!(received.topic_name, "chat/room");
+}
+
+#[test]
+fn loopback_sends_the_publisher_its_own_message_despite_no_local() {
+    for loopback in [false, true] {
+        let broker = Broker::new(BrokerConfig { loopback, ..BrokerConfig::default() });
+        let mut client = connect(&broker, "talker");
+        subscribe(&mut client, "chat/#", SubscriptionOptions { qos: QoS::AtLeastOnce, no_local: true, ..Default::default() });
+
+        publish(&mut client, "chat/room", false);
+        // The message comes back before the PUBACK only in loopback mode
+        let first = read_packet(&mut client).unwrap();
+        if loopback {
+            assert_eq!(PublishPacket::decode(&first).unwrap().topic_name, "chat/room");
+            assert_eq!(PubAckPacket::decode(&read_packet(&mut client).unwrap()).unwrap().packet_id, 10);
+        } else {
+            assert_eq!(PubAckPacket::decode(&first).unwrap().packet_id, 10);
+            client.write_all(&PingReqPacket.encode()).unwrap();
+            assert_eq!(read_packet(&mut client).unwrap(), vec![0xD0, 0x00]);
+        }
+    }
+}
+
+#[test]
+fn retain_as_published_keeps_the_retain_flag_of_live_messages() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut publisher = connect(&broker, "publisher");
+    let mut keeps_flag = connect(&broker, "keeps-flag");
+    let mut clears_flag = connect(&broker, "clears-flag");
+    let options = SubscriptionOptions { qos: QoS::AtLeastOnce, ..Default::default() };
+    subscribe(&mut keeps_flag, "status", SubscriptionOptions { retain_as_published: true, ..options });
+    subscribe(&mut clears_flag, "status", options);
+
+    publish(&mut publisher, "status", true);
+    assert_eq!(PubAckPacket::decode(&read_packet(&mut publisher).unwrap()).unwrap().packet_id, 10);
+
+    assert!(PublishPacket::decode(&read_packet(&mut keeps_flag).unwrap()).unwrap().retain);
+    assert!(!PublishPacket::decode(&read_packet(&mut clears_flag).unwrap()).unwrap().retain);
+
+    // Both were subscribed before the message was published, so none gets it again as a
+    // retained message: the PINGRESP is the next packet
+    for client in [&mut keeps_flag, &mut clears_flag] {
+        client.write_all(&PingReqPacket.encode()).unwrap();
+        assert_eq!(read_packet(client).unwrap(), vec![0xD0, 0x00]);
+    }
+}
@@ -0,0 +1,95 @@
+//! Subscription registry keyed by client ID, and the wildcard routing built on it.
+
+mod common;
+
+use std::io::Write;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{connect, read_packet};
+use mqtt_broker::broker::{Broker, BrokerConfig, SubscriptionRegistry};
+use mqtt_broker::packets::{
+    disconnect::{DisconnectPacket, DisconnectReasonCode},
+    ping::PingReqPacket,
+    publish::PublishPacket,
+    qos::QoS,
+    suback::SubAckPacket,
+    subscribe::SubscribePacket,
+};
+
+#[test]
+fn overlapping_filters_match_a_client_once_at_the_highest_qos() {
+    let mut registry = SubscriptionRegistry::new();
+    registry.subscribe("sensor-panel", "sensors/+", QoS::AtMostOnce);
+    registry.subscribe("sensor-panel", "sensors/#", QoS::AtLeastOnce);
+    registry.subscribe("logger", "#", QoS::AtMostOnce);
+    registry.subscribe("alarms", "alarms/#", QoS::ExactlyOnce);
+
+    assert_eq!(
+        registry.matching("sensors/temperature"),
+        vec![("logger".to_string(), QoS::AtMostOnce), ("sensor-panel".to_string(), QoS::AtLeastOnce)]
+    );
+    assert_eq!(registry.matching("$SYS/uptime"), Vec::new());
+}
+
+#[test]
+fn subscribing_again_replaces_the_qos() {
+    let mut registry = SubscriptionRegistry::new();
+    assert!(registry.subscribe("lamp", "commands/lamp", QoS::AtLeastOnce));
+    assert!(!registry.subscribe("lamp", "commands/lamp", QoS::AtMostOnce));
+
+    assert_eq!(registry.matching("commands/lamp"), vec![("lamp".to_string(), QoS::AtMostOnce)]);
+    assert_eq!(registry.filters(), vec!["commands/lamp".to_string()]);
+}
+
+#[test]
+fn unsubscribe_removes_only_that_filter() {
+    let mut registry = SubscriptionRegistry::new();
+    registry.subscribe("lamp", "commands/lamp", QoS::AtLeastOnce);
+    registry.subscribe("lamp", "commands/all", QoS::AtLeastOnce);
+
+    assert!(registry.unsubscribe("lamp", "commands/lamp"));
+    assert!(!registry.unsubscribe("lamp", "commands/lamp"));
+    assert_eq!(registry.matching("commands/lamp"), Vec::new());
+    assert_eq!(registry.filters(), vec!["commands/all".to_string()]);
+}
+
+#[test]
+fn broker_routes_to_wildcard_subscriptions_once() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut subscriber = connect(&broker, "panel");
+    let subscribe = SubscribePacket::new(1, vec!["sensors/+".to_string(), "sensors/#".to_string()], vec![0, 0]);
+    subscriber.write_all(&subscribe.encode().unwrap()).unwrap();
+    let suback = SubAckPacket::decode(&read_packet(&mut subscriber).unwrap()).unwrap();
+    assert_eq!(suback.return_codes, vec![0, 0]);
+    // By the time the PINGRESP arrives the filters are registered
+    subscriber.write_all(&PingReqPacket.encode()).unwrap();
+    assert_eq!(read_packet(&mut subscriber).unwrap(), vec![0xD0, 0x00]);
+
+    broker.publish(PublishPacket::new("sensors/temperature".to_string(), 0, QoS::AtMostOnce, false, false, b"21.5".to_vec()));
+    broker.publish(PublishPacket::new("sensors/humidity".to_string(), 0, QoS::AtMostOnce, false, false, b"40".to_vec()));
+
+    // One copy of each message, in order
+    let first = PublishPacket::decode(&read_packet(&mut subscriber).unwrap()).unwrap();
+    let second = PublishPacket::decode(&read_packet(&mut subscriber).unwrap()).unwrap();
+    assert_eq!(first.topic_name, "sensors/temperature");
+    assert_eq!(second.topic_name, "sensors/humidity");
+}
+
+#[test]
+fn topic_is_gone_once_its_only_subscriber_disconnects() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut subscriber = connect(&broker, "lonely");
+    subscriber.write_all(&SubscribePacket::new(1, vec!["weather".to_string()], vec![0]).encode().unwrap()).unwrap();
+    read_packet(&mut subscriber).unwrap(); // SUBACK
+    subscriber.write_all(&PingReqPacket.encode()).unwrap();
+    assert_eq!(read_packet(&mut subscriber).unwrap(), vec![0xD0, 0x00]);
+    assert_eq!(broker.active_topics(), vec!["weather".to_string()]);
+
+    subscriber.write_all(&DisconnectPacket::new(DisconnectReasonCode::NormalDisconnection).encode()).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !broker.active_topics().is_empty() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(broker.active_topics().is_empty());
+}
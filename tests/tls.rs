@@ -0,0 +1,120 @@
+//! MQTT over TLS, with a self-signed certificate the client trusts.
+
+mod common;
+
+use std::fs;
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use rcgen::CertifiedKey;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+use common::read_packet;
+use mqtt_broker::broker::tls::{self, TlsConfig};
+use mqtt_broker::broker::{Broker, BrokerConfig};
+use mqtt_broker::packets::{
+    connack::{ConnAckPacket, ConnAckReasonCode},
+    connect::ConnectPacket,
+    ping::PingReqPacket,
+    publish::PublishPacket,
+    qos::QoS,
+    suback::SubAckPacket,
+    subscribe::SubscribePacket,
+};
+
+type TlsClient = StreamOwned<ClientConnection, TcpStream>;
+
+// Writes the certificate and key to PEM files of their own, as the broker is given them
+fn write_pem_files(name: &str, certified: &CertifiedKey) -> (PathBuf, PathBuf) {
+    let dir = std::env::temp_dir().join(format!("mqtt-broker-tls-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let cert_path = dir.join("cert.pem");
+    let key_path = dir.join("key.pem");
+    fs::write(&cert_path, certified.cert.pem()).unwrap();
+    fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+    (cert_path, key_path)
+}
+
+// Serves the TLS listener of a broker with a new self-signed certificate for localhost
+fn start_broker(name: &str) -> (Broker, String, CertifiedKey) {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let (cert_path, key_path) = write_pem_files(name, &certified);
+    let server_config = tls::load_server_config(&cert_path, &key_path).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let broker = Broker::new(BrokerConfig::default());
+    let serving = broker.clone();
+    thread::spawn(move || serving.serve_tls(listener, server_config));
+    (broker, addr, certified)
+}
+
+// Opens a TLS connection trusting only the certificate of the broker, and completes
+// the CONNECT / CONNACK exchange
+fn connect(addr: &str, certified: &CertifiedKey, client_id: &str) -> TlsClient {
+    let mut roots = RootCertStore::empty();
+    roots.add(certified.cert.der().clone()).unwrap();
+    let config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connection = ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap()).unwrap();
+
+    let socket = TcpStream::connect(addr).unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut client = StreamOwned::new(connection, socket);
+
+    let connect = ConnectPacket::new("MQTT".to_string(), 5, 0x02, 60, client_id.to_string(), None, None, None, None);
+    client.write_all(&connect.encode()).unwrap();
+    let connack = ConnAckPacket::decode(&read_packet(&mut client).unwrap()).unwrap();
+    assert_eq!(connack.reason_code, ConnAckReasonCode::Success);
+    client
+}
+
+#[test]
+fn tls_options_are_read_from_the_arguments() {
+    let args: Vec<String> = ["--tls", "cert.pem", "key.pem", "--tls-bind", "127.0.0.1:8884"].iter().map(|arg| arg.to_string()).collect();
+    let config = BrokerConfig::from_args(&args);
+    let expected = TlsConfig { bind_addr: "127.0.0.1:8884".parse().unwrap(), ..TlsConfig::new("cert.pem".into(), "key.pem".into()) };
+    assert_eq!(config.tls, Some(expected));
+
+    // The address alone does not enable TLS
+    let config = BrokerConfig::from_args(&["--tls-bind".to_string(), "127.0.0.1:8884".to_string()]);
+    assert_eq!(config.tls, None);
+}
+
+#[test]
+fn missing_key_file_is_an_error() {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let (cert_path, _) = write_pem_files("missing-key", &certified);
+    assert!(tls::load_server_config(&cert_path, &cert_path.with_file_name("nothing.pem")).is_err());
+    // A certificate is not a key
+    assert!(tls::load_server_config(&cert_path, &cert_path).is_err());
+}
+
+#[test]
+fn connect_and_connack_over_tls() {
+    let (broker, addr, certified) = start_broker("connect");
+    let mut client = connect(&addr, &certified, "encrypted");
+
+    let subscribe = SubscribePacket::new(1, vec!["sensors/#".to_string()], vec![0]).encode().unwrap();
+    client.write_all(&subscribe).unwrap();
+    let suback = SubAckPacket::decode(&read_packet(&mut client).unwrap()).unwrap();
+    assert_eq!(suback.return_codes, vec![0]);
+    // By the time the PINGRESP arrives the filter is registered
+    client.write_all(&PingReqPacket.encode()).unwrap();
+    assert_eq!(read_packet(&mut client).unwrap(), vec![0xD0, 0x00]);
+
+    // The broker writes the messages of the subscription through its own handle
+    broker.publish(PublishPacket::new("sensors/temperature".to_string(), 0, QoS::AtMostOnce, false, false, b"21.5".to_vec()));
+    let publish = PublishPacket::decode(&read_packet(&mut client).unwrap()).unwrap();
+    assert_eq!(publish.topic_name, "sensors/temperature");
+    assert_eq!(publish.payload, b"21.5".to_vec());
+    broker.shutdown();
+}
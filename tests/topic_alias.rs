@@ -0,0 +1,99 @@
+//! Topic aliases in the PUBLISH packets of a client.
+
+mod common;
+
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+
+use common::read_packet;
+use mqtt_broker::broker::{transport::DuplexStream, Broker, BrokerConfig, Transport};
+use mqtt_broker::packets::{
+    connack::ConnAckPacket,
+    connect::ConnectPacket,
+    fixed_header::{parse_fixed_header, PacketType},
+    publish::PublishPacket,
+    qos::QoS,
+    subscribe::{SubscribePacket, SubscriptionOptions},
+};
+
+// Reason code of the DISCONNECT the broker sends before closing the connection
+fn disconnect_reason(client: &mut DuplexStream) -> u8 {
+    let disconnect = read_packet(client).unwrap();
+    assert_eq!(parse_fixed_header(&disconnect).unwrap().packet_type, PacketType::Disconnect);
+    disconnect[2]
+}
+
+#[test]
+fn connack_announces_the_topic_alias_maximum() {
+    let broker = Broker::new(BrokerConfig::default());
+    let (mut client, server) = DuplexStream::pair();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    broker.accept(server);
+
+    let connect = ConnectPacket::new("MQTT".to_string(), 5, 0x02, 60, "aliases".to_string(), None, None, None, None);
+    client.write_all(&connect.encode()).unwrap();
+    let connack = ConnAckPacket::decode(&read_packet(&mut client).unwrap()).unwrap();
+    assert_eq!(connack.properties.unwrap().topic_alias_maximum, Some(10));
+}
+
+#[test]
+fn empty_topic_without_alias_is_a_protocol_error() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut client = common::connect(&broker, "no-alias");
+
+    let publish = PublishPacket::new(String::new(), 0, QoS::AtMostOnce, false, false, b"lost".to_vec());
+    client.write_all(&publish.encode().unwrap()).unwrap();
+
+    assert_eq!(disconnect_reason(&mut client), 0x82);
+}
+
+#[test]
+fn empty_topic_with_unknown_alias_is_a_protocol_error() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut client = common::connect(&broker, "unknown-alias");
+
+    let publish = PublishPacket { topic_alias: Some(3), ..PublishPacket::new(String::new(), 0, QoS::AtMostOnce, false, false, b"lost".to_vec()) };
+    client.write_all(&publish.encode().unwrap()).unwrap();
+
+    assert_eq!(disconnect_reason(&mut client), 0x82);
+}
+
+#[test]
+fn alias_above_the_maximum_is_invalid() {
+    let broker = Broker::new(BrokerConfig { topic_alias_maximum: 2, ..BrokerConfig::default() });
+    let mut client = common::connect(&broker, "big-alias");
+
+    let publish = PublishPacket { topic_alias: Some(3), ..PublishPacket::new("a".to_string(), 0, QoS::AtMostOnce, false, false, b"x".to_vec()) };
+    client.write_all(&publish.encode().unwrap()).unwrap();
+
+    assert_eq!(disconnect_reason(&mut client), 0x94);
+}
+
+#[test]
+fn empty_topic_resolves_through_the_alias() {
+    let broker = Broker::new(BrokerConfig::default());
+
+    let mut subscriber = common::connect(&broker, "subscriber");
+    let subscribe = SubscribePacket::with_options(1, vec![("sensors/temperature".to_string(), SubscriptionOptions::default())]);
+    subscriber.write_all(&subscribe.encode().unwrap()).unwrap();
+    read_packet(&mut subscriber).unwrap();
+    // The SUBACK is written before the subscription is registered
+    while broker.active_topics().is_empty() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let mut publisher = common::connect(&broker, "publisher");
+    let first = PublishPacket { topic_alias: Some(1), ..PublishPacket::new("sensors/temperature".to_string(), 0, QoS::AtMostOnce, false, false, b"21".to_vec()) };
+    let second = PublishPacket { topic_alias: Some(1), ..PublishPacket::new(String::new(), 0, QoS::AtMostOnce, false, false, b"22".to_vec()) };
+    publisher.write_all(&first.encode().unwrap()).unwrap();
+    let delivered = PublishPacket::decode(&read_packet(&mut subscriber).unwrap()).unwrap();
+    assert_eq!(delivered.payload, b"21");
+    publisher.write_all(&second.encode().unwrap()).unwrap();
+
+    // The subscriber gets the topic name, never the alias of the publisher
+    let delivered = PublishPacket::decode(&read_packet(&mut subscriber).unwrap()).unwrap();
+    assert_eq!(delivered.topic_name, "sensors/temperature");
+    assert_eq!(delivered.topic_alias, None);
+    assert_eq!(delivered.payload, b"22");
+}
@@ -0,0 +1,80 @@
+//! Topics with characters MQTT forbids in them, rejected when the packet is decoded,
+//! and the topics starting with `$` that clients may not publish to.
+
+mod common;
+
+use std::io::{ErrorKind, Write};
+use std::time::Duration;
+
+use common::{connect, read_packet};
+use mqtt_broker::broker::{Broker, BrokerConfig, Transport};
+use mqtt_broker::packets::{
+    fixed_header::{parse_fixed_header, PacketType},
+    puback::PubAckPacket,
+    publish::PublishPacket,
+    qos::QoS,
+    suback::SubAckPacket,
+    subscribe::SubscribePacket,
+    DecodeError,
+};
+
+#[test]
+fn publish_to_a_wildcard_topic_is_rejected() {
+    let packet = PublishPacket::new("a/+/b".to_string(), 0, QoS::AtMostOnce, false, false, b"on".to_vec()).encode().unwrap();
+
+    assert_eq!(PublishPacket::decode(&packet), Err(DecodeError::InvalidTopic("a/+/b".to_string())));
+}
+
+#[test]
+fn topics_with_a_null_character_are_rejected() {
+    let publish = PublishPacket::new("a\0b".to_string(), 0, QoS::AtMostOnce, false, false, b"on".to_vec()).encode().unwrap();
+    let subscribe = SubscribePacket::new(1, vec!["a\0b".to_string()], vec![0]).encode().unwrap();
+
+    assert_eq!(PublishPacket::decode(&publish), Err(DecodeError::InvalidTopic("a\0b".to_string())));
+    assert_eq!(SubscribePacket::decode(&subscribe), Err(DecodeError::InvalidTopic("a\0b".to_string())));
+}
+
+#[test]
+fn broker_disconnects_a_client_publishing_to_a_wildcard_topic() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut client = connect(&broker, "wildcard-publisher");
+
+    let packet = PublishPacket::new("a/#".to_string(), 1, QoS::AtLeastOnce, false, false, b"on".to_vec());
+    client.write_all(&packet.encode().unwrap()).unwrap();
+
+    let disconnect = read_packet(&mut client).unwrap();
+    assert_eq!(parse_fixed_header(&disconnect).unwrap().packet_type, PacketType::Disconnect);
+    assert_eq!(disconnect[2], 0x90); // Topic Name invalid
+}
+
+#[test]
+fn broker_disconnects_a_client_subscribing_to_a_topic_with_a_null_character() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut client = connect(&broker, "null-subscriber");
+
+    client.write_all(&SubscribePacket::new(1, vec!["a\0b".to_string()], vec![0]).encode().unwrap()).unwrap();
+
+    let disconnect = read_packet(&mut client).unwrap();
+    assert_eq!(parse_fixed_header(&disconnect).unwrap().packet_type, PacketType::Disconnect);
+    assert_eq!(disconnect[2], 0x90);
+}
+
+#[test]
+fn publish_to_a_reserved_topic_is_refused_as_not_authorized() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut subscriber = connect(&broker, "sys-watcher");
+    subscriber.write_all(&SubscribePacket::new(1, vec!["$SYS/#".to_string()], vec![1]).encode().unwrap()).unwrap();
+    let suback = SubAckPacket::decode(&read_packet(&mut subscriber).unwrap()).unwrap();
+    assert_eq!(suback.return_codes, vec![0x01]);
+    let mut client = connect(&broker, "sys-publisher");
+
+    let packet = PublishPacket::new("$SYS/uptime".to_string(), 1, QoS::AtLeastOnce, false, false, b"0".to_vec());
+    client.write_all(&packet.encode().unwrap()).unwrap();
+    let puback = PubAckPacket::decode(&read_packet(&mut client).unwrap()).unwrap();
+    assert_eq!(puback.packet_id, 1);
+    assert_eq!(puback.reason_code, 0x87); // Not authorized
+
+    // The message is never routed
+    subscriber.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    assert_eq!(read_packet(&mut subscriber).unwrap_err().kind(), ErrorKind::WouldBlock);
+}
@@ -0,0 +1,109 @@
+//! Matching of the topic tree, checked against topic_matches, which tests every filter.
+
+use std::collections::HashMap;
+
+use proptest::collection::vec;
+use proptest::prelude::*;
+
+use mqtt_broker::broker::TopicTree;
+use mqtt_broker::packets::{qos::QoS, subscribe::{topic_matches, SubscriptionOptions}};
+
+// Clients subscribed to the filter, sorted and once per subscription
+fn matching_clients(tree: &TopicTree, topic: &str) -> Vec<String> {
+    let mut clients: Vec<String> = tree.matching(topic).map(|(client_id, _)| client_id.to_string()).collect();
+    clients.sort();
+    clients
+}
+
+// Same as matching_clients, testing every subscription with topic_matches
+fn naive_matching_clients(subscriptions: &HashMap<(String, String), SubscriptionOptions>, topic: &str) -> Vec<String> {
+    let mut clients: Vec<String> = subscriptions
+        .keys()
+        .filter(|(_, filter)| topic_matches(filter, topic))
+        .map(|(client_id, _)| client_id.clone())
+        .collect();
+    clients.sort();
+    clients
+}
+
+// Few level names so that the filters and the topics often share levels
+fn topic_level() -> impl Strategy<Value = String> {
+    prop_oneof![Just("a"), Just("b"), Just("c"), Just("$SYS"), Just("")].prop_map(String::from)
+}
+
+fn topic_name() -> impl Strategy<Value = String> {
+    vec(topic_level(), 1..5).prop_map(|levels| levels.join("/"))
+}
+
+// The + may stand for any level, the # only ends a filter
+fn topic_filter() -> impl Strategy<Value = String> {
+    (vec(prop_oneof![4 => topic_level(), 1 => Just("+".to_string())], 0..4), any::<bool>()).prop_filter_map(
+        "empty filter",
+        |(mut levels, multi_level)| {
+            if multi_level {
+                levels.push("#".to_string());
+            }
+            Some(levels.join("/")).filter(|filter| !filter.is_empty())
+        },
+    )
+}
+
+#[test]
+fn wildcards_match_like_the_specification() {
+    let mut tree = TopicTree::new();
+    let options = SubscriptionOptions::default();
+    tree.insert("sensors/+/temperature", "single", options);
+    tree.insert("sensors/#", "multi", options);
+    tree.insert("#", "everything", options);
+    tree.insert("$SYS/#", "monitor", options);
+
+    assert_eq!(matching_clients(&tree, "sensors/kitchen/temperature"), vec!["everything", "multi", "single"]);
+    assert_eq!(matching_clients(&tree, "sensors"), vec!["everything", "multi"]); // # matches the parent level
+    assert_eq!(matching_clients(&tree, "sensors/kitchen"), vec!["everything", "multi"]);
+    assert_eq!(matching_clients(&tree, "$SYS/uptime"), vec!["monitor"]); // Wildcards at the root skip $ topics
+}
+
+#[test]
+fn insert_replaces_and_remove_prunes() {
+    let mut tree = TopicTree::new();
+    let qos_1 = SubscriptionOptions { qos: QoS::AtLeastOnce, ..Default::default() };
+    assert!(tree.insert("a/b", "client", SubscriptionOptions::default()));
+    assert!(!tree.insert("a/b", "client", qos_1));
+    assert_eq!(tree.matching("a/b").collect::<Vec<_>>(), vec![("client", &qos_1)]);
+
+    assert!(!tree.remove("a", "client")); // Only a level of the filter
+    assert!(tree.remove("a/b", "client"));
+    assert!(!tree.remove("a/b", "client"));
+    assert!(tree.is_empty());
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn hundreds_of_filters_match_like_topic_matches(
+        filters in vec(topic_filter(), 200..400),
+        topics in vec(topic_name(), 1..20),
+    ) {
+        let mut tree = TopicTree::new();
+        let mut subscriptions = HashMap::new();
+        for (i, filter) in filters.iter().enumerate() {
+            let client_id = format!("client-{}", i % 17);
+            tree.insert(filter, &client_id, SubscriptionOptions::default());
+            subscriptions.insert((client_id, filter.clone()), SubscriptionOptions::default());
+        }
+        for topic in &topics {
+            prop_assert_eq!(matching_clients(&tree, topic), naive_matching_clients(&subscriptions, topic));
+        }
+
+        // Removing half of the subscriptions leaves the others matching
+        for (i, filter) in filters.iter().enumerate().step_by(2) {
+            let client_id = format!("client-{}", i % 17);
+            let existed = subscriptions.remove(&(client_id.clone(), filter.clone())).is_some();
+            prop_assert_eq!(tree.remove(filter, &client_id), existed);
+        }
+        for topic in &topics {
+            prop_assert_eq!(matching_clients(&tree, topic), naive_matching_clients(&subscriptions, topic));
+        }
+    }
+}
@@ -0,0 +1,50 @@
+//! Hex dumps of the packets on the wire, written to the logger at trace level.
+
+mod common;
+
+use std::sync::Mutex;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use mqtt_broker::broker::{Broker, BrokerConfig};
+
+// Keeps every trace line logged, since the logger of a process can only be set once
+struct CapturingLogger {
+    lines: Mutex<Vec<String>>,
+}
+
+impl Log for CapturingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Trace
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() == Level::Trace {
+            self.lines.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CapturingLogger = CapturingLogger { lines: Mutex::new(Vec::new()) };
+
+#[test]
+fn connect_and_connack_are_dumped_in_hex() {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(LevelFilter::Trace);
+
+    let broker = Broker::new(BrokerConfig::default());
+    let _client = common::connect(&broker, "traced");
+
+    let lines = LOGGER.lines.lock().unwrap();
+    let connect = lines
+        .iter()
+        .find(|line| line.starts_with("received from") && line.contains("Connect ("))
+        .unwrap_or_else(|| panic!("no CONNECT dump in {:?}", lines));
+    // Packet type 1, then the protocol name "MQTT" after its length
+    assert!(connect.contains(": 10 "), "unexpected dump: {}", connect);
+    assert!(connect.contains("00 04 4d 51 54 54 05"), "unexpected dump: {}", connect);
+    assert!(connect.starts_with("received from 127.0.0.1:"), "unexpected dump: {}", connect);
+
+    assert!(lines.iter().any(|line| line.starts_with("sent to") && line.contains("ConnAck (") && line.contains(": 20 ")));
+}
@@ -0,0 +1,68 @@
+//! Clients of different transports served by the same broker, which routes the
+//! messages between them.
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use common::{connect, read_packet};
+use mqtt_broker::broker::{Broker, BrokerConfig};
+use mqtt_broker::packets::{
+    connack::ConnAckPacket,
+    connect::ConnectPacket,
+    puback::PubAckPacket,
+    publish::PublishPacket,
+    qos::QoS,
+    suback::SubAckPacket,
+    subscribe::SubscribePacket,
+};
+
+// Connects a client to the broker listening on the address over TCP
+fn connect_tcp(addr: SocketAddr, client_id: &str) -> TcpStream {
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let connect = ConnectPacket::new("MQTT".to_string(), 5, 0x02, 60, client_id.to_string(), None, None, None, None);
+    client.write_all(&connect.encode()).unwrap();
+    ConnAckPacket::decode(&read_packet(&mut client).unwrap()).unwrap();
+    client
+}
+
+// Subscribes the client to the topic at QoS 1 and waits for the SUBACK
+fn subscribe<S: Read + Write>(client: &mut S, topic: &str) {
+    client.write_all(&SubscribePacket::new(1, vec![topic.to_string()], vec![1]).encode().unwrap()).unwrap();
+    assert_eq!(SubAckPacket::decode(&read_packet(client).unwrap()).unwrap().return_codes, vec![0x01]);
+}
+
+// Publishes a QoS 1 message and waits for its PUBACK, sent once the message was routed
+fn publish<S: Read + Write>(client: &mut S, topic: &str, payload: &[u8]) {
+    let packet = PublishPacket::new(topic.to_string(), 1, QoS::AtLeastOnce, false, false, payload.to_vec());
+    client.write_all(&packet.encode().unwrap()).unwrap();
+    assert_eq!(PubAckPacket::decode(&read_packet(client).unwrap()).unwrap().packet_id, 1);
+}
+
+#[test]
+fn messages_cross_between_tcp_and_in_memory_clients() {
+    let broker = Broker::new(BrokerConfig::default());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let serving = broker.clone();
+    thread::spawn(move || serving.serve(listener));
+
+    let mut tcp = connect_tcp(addr, "tcp-client");
+    let mut in_memory = connect(&broker, "in-memory-client");
+    subscribe(&mut tcp, "to-tcp");
+    subscribe(&mut in_memory, "to-memory");
+
+    publish(&mut in_memory, "to-tcp", b"over the socket");
+    let delivered = PublishPacket::decode(&read_packet(&mut tcp).unwrap()).unwrap();
+    assert_eq!((delivered.topic_name.as_str(), delivered.payload.as_slice()), ("to-tcp", &b"over the socket"[..]));
+
+    publish(&mut tcp, "to-memory", b"over the pipe");
+    let delivered = PublishPacket::decode(&read_packet(&mut in_memory).unwrap()).unwrap();
+    assert_eq!((delivered.topic_name.as_str(), delivered.payload.as_slice()), ("to-memory", &b"over the pipe"[..]));
+
+    broker.shutdown();
+}
@@ -0,0 +1,125 @@
+//! UNSUBSCRIBE handling, and subscriptions changing while messages are routed.
+
+mod common;
+
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use common::{connect, read_packet};
+use mqtt_broker::broker::{transport::DuplexStream, Broker, BrokerConfig};
+use mqtt_broker::packets::{
+    fixed_header::{parse_fixed_header, PacketType},
+    ping::PingReqPacket,
+    publish::PublishPacket,
+    qos::QoS,
+    suback::SubAckPacket,
+    subscribe::SubscribePacket,
+    unsubscribe::{UnsubAckPacket, UnsubscribePacket, NO_SUBSCRIPTION_EXISTED, SUCCESS},
+};
+
+// Reads packets until one of the given type, returning it with the PUBLISH packets read before it
+fn read_until(client: &mut DuplexStream, packet_type: PacketType) -> (Vec<u8>, usize) {
+    let mut publishes = 0;
+    loop {
+        let packet = read_packet(client).unwrap();
+        match parse_fixed_header(&packet).unwrap().packet_type {
+            found if found == packet_type => return (packet, publishes),
+            PacketType::Publish => publishes += 1,
+            other => panic!("unexpected {:?} packet", other),
+        }
+    }
+}
+
+#[test]
+fn unsubscribe_stops_the_delivery_of_the_filter() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut client = connect(&broker, "lamp");
+    client.write_all(&SubscribePacket::new(1, vec!["lamp/+".to_string()], vec![0]).encode().unwrap()).unwrap();
+    read_until(&mut client, PacketType::SubAck);
+
+    client.write_all(&UnsubscribePacket::new(2, vec!["lamp/+".to_string(), "lamp/unknown".to_string()]).encode().unwrap()).unwrap();
+    let (unsuback, _) = read_until(&mut client, PacketType::UnsubAck);
+    let unsuback = UnsubAckPacket::decode(&unsuback).unwrap();
+    assert_eq!(unsuback.packet_id, 2);
+    assert_eq!(unsuback.reason_codes, vec![SUCCESS, NO_SUBSCRIPTION_EXISTED]);
+    assert!(broker.active_topics().is_empty());
+
+    broker.publish(PublishPacket::new("lamp/state".to_string(), 0, QoS::AtMostOnce, false, false, b"on".to_vec()));
+    client.write_all(&PingReqPacket.encode()).unwrap();
+    assert_eq!(read_packet(&mut client).unwrap(), vec![0xD0, 0x00]);
+}
+
+#[test]
+fn unsubscribe_of_a_filter_never_subscribed_reports_no_subscription_existed() {
+    let broker = Broker::new(BrokerConfig::default());
+    let mut client = connect(&broker, "newcomer");
+
+    client.write_all(&UnsubscribePacket::new(1, vec!["never/subscribed".to_string()]).encode().unwrap()).unwrap();
+    let unsuback = UnsubAckPacket::decode(&read_packet(&mut client).unwrap()).unwrap();
+    assert_eq!(unsuback.packet_id, 1);
+    assert_eq!(unsuback.reason_codes, vec![NO_SUBSCRIPTION_EXISTED]);
+    assert_eq!(NO_SUBSCRIPTION_EXISTED, 0x11);
+
+    // Not an error, the connection is still served
+    client.write_all(&PingReqPacket.encode()).unwrap();
+    assert_eq!(read_packet(&mut client).unwrap(), vec![0xD0, 0x00]);
+}
+
+#[test]
+fn interleaved_subscribe_unsubscribe_and_publish_stay_consistent() {
+    const CLIENTS: usize = 4;
+    const ROUNDS: u16 = 50;
+
+    let broker = Broker::new(BrokerConfig::default());
+    let running = Arc::new(AtomicBool::new(true));
+
+    // Publishers keep sending to the topic the clients churn on
+    let publishers: Vec<_> = (0..2)
+        .map(|_| {
+            let broker = broker.clone();
+            let running = Arc::clone(&running);
+            thread::spawn(move || {
+                while running.load(Ordering::Relaxed) {
+                    broker.publish(PublishPacket::new("churn".to_string(), 0, QoS::AtMostOnce, false, false, b"tick".to_vec()));
+                }
+            })
+        })
+        .collect();
+
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|i| {
+            let broker = broker.clone();
+            thread::spawn(move || {
+                let mut client = connect(&broker, &format!("churner-{}", i));
+                for round in 0..ROUNDS {
+                    let packet_id = round * 2 + 1;
+                    client.write_all(&SubscribePacket::new(packet_id, vec!["churn".to_string()], vec![0]).encode().unwrap()).unwrap();
+                    let (suback, _) = read_until(&mut client, PacketType::SubAck);
+                    assert_eq!(SubAckPacket::decode(&suback).unwrap().return_codes, vec![0]);
+
+                    // Every unsubscribe finds the subscription made just before it
+                    client.write_all(&UnsubscribePacket::new(packet_id + 1, vec!["churn".to_string()]).encode().unwrap()).unwrap();
+                    let (unsuback, _) = read_until(&mut client, PacketType::UnsubAck);
+                    assert_eq!(UnsubAckPacket::decode(&unsuback).unwrap().reason_codes, vec![SUCCESS]);
+
+                    // Nothing is delivered once the UNSUBACK was sent
+                    client.write_all(&PingReqPacket.encode()).unwrap();
+                    let (_, late) = read_until(&mut client, PacketType::PingResp);
+                    assert_eq!(late, 0, "message delivered after the UNSUBACK in round {}", round);
+                }
+            })
+        })
+        .collect();
+
+    for client in clients {
+        client.join().unwrap();
+    }
+    running.store(false, Ordering::Relaxed);
+    for publisher in publishers {
+        publisher.join().unwrap();
+    }
+
+    assert!(broker.active_topics().is_empty());
+}
@@ -0,0 +1,45 @@
+//! Packets of a valid type the broker does not handle, such as an AUTH.
+
+mod common;
+
+use std::io::{Read, Write};
+
+use common::{connect, read_packet};
+use mqtt_broker::broker::{Broker, BrokerConfig, UnsupportedPacketPolicy};
+use mqtt_broker::packets::{
+    fixed_header::{parse_fixed_header, PacketType},
+    ping::PingReqPacket,
+};
+
+// AUTH with the Success reason code and no properties, the broker does not support it
+const AUTH: [u8; 2] = [0xF0, 0x00];
+
+#[test]
+fn unsupported_packet_disconnects_when_configured() {
+    let config = BrokerConfig { unsupported_packet_policy: UnsupportedPacketPolicy::Disconnect, ..Default::default() };
+    let broker = Broker::new(config);
+    let mut client = connect(&broker, "auth");
+
+    client.write_all(&AUTH).unwrap();
+    let disconnect = read_packet(&mut client).unwrap();
+    assert_eq!(parse_fixed_header(&disconnect).unwrap().packet_type, PacketType::Disconnect);
+    assert_eq!(disconnect[2], 0x83); // Implementation specific error
+
+    let mut rest = Vec::new();
+    assert!(matches!(client.read_to_end(&mut rest), Ok(0)));
+}
+
+#[test]
+fn unsupported_packet_is_ignored_by_default() {
+    assert_eq!(BrokerConfig::default().unsupported_packet_policy, UnsupportedPacketPolicy::Ignore);
+    let args = vec!["--disconnect-unsupported".to_string()];
+    assert_eq!(BrokerConfig::from_args(&args).unsupported_packet_policy, UnsupportedPacketPolicy::Disconnect);
+
+    let broker = Broker::new(BrokerConfig::default());
+    let mut client = connect(&broker, "auth");
+
+    // The connection is still served after the AUTH
+    client.write_all(&AUTH).unwrap();
+    client.write_all(&PingReqPacket.encode()).unwrap();
+    assert_eq!(read_packet(&mut client).unwrap(), vec![0xD0, 0x00]);
+}
@@ -0,0 +1,64 @@
+//! Validation of a whole packet buffer from its fixed header, before decoding it.
+
+use mqtt_broker::packets::{
+    connect::ConnectPacket,
+    disconnect::{DisconnectPacket, DisconnectReasonCode},
+    fixed_header::validate_packet,
+    ping::PingReqPacket,
+    puback::PubAckPacket,
+    publish::PublishPacket,
+    qos::QoS,
+    qos2::PubRelPacket,
+    suback::SubAckPacket,
+    subscribe::SubscribePacket,
+    DecodeError,
+};
+
+#[test]
+fn encoded_packets_are_valid() {
+    let packets = vec![
+        ConnectPacket::new("MQTT".to_string(), 5, 0x02, 60, "meter".to_string(), None, None, None, None).encode(),
+        PublishPacket::new("meters/power".to_string(), 1, QoS::AtLeastOnce, false, false, b"230".to_vec()).encode().unwrap(),
+        PubAckPacket::new(1).encode(),
+        PubRelPacket::new(1).encode(),
+        SubscribePacket::new(1, vec!["meters/+".to_string()], vec![1]).encode().unwrap(),
+        SubAckPacket::new(1, vec![1]).encode(),
+        PingReqPacket.encode(),
+        DisconnectPacket::new(DisconnectReasonCode::NormalDisconnection).encode(),
+    ];
+
+    for packet in packets {
+        assert_eq!(validate_packet(&packet), Ok(()), "{:02x?}", packet);
+    }
+}
+
+#[test]
+fn truncated_packet_is_rejected() {
+    let packet = PublishPacket::new("meters/power".to_string(), 0, QoS::AtMostOnce, false, false, b"230".to_vec()).encode().unwrap();
+
+    assert_eq!(
+        validate_packet(&packet[..packet.len() - 1]),
+        Err(DecodeError::LengthExceeded { declared: packet.len(), available: packet.len() - 1 })
+    );
+    // The remaining length itself cut in the middle
+    assert_eq!(validate_packet(&[0x30, 0x80]), Err(DecodeError::UnexpectedEof));
+}
+
+#[test]
+fn trailing_bytes_are_rejected() {
+    let mut packet = PubAckPacket::new(7).encode();
+    packet.extend(PingReqPacket.encode());
+
+    assert!(matches!(validate_packet(&packet), Err(DecodeError::Malformed(_))));
+}
+
+#[test]
+fn invalid_fixed_headers_are_rejected() {
+    assert_eq!(validate_packet(&[0x00, 0x00]), Err(DecodeError::InvalidPacketType { got: 0x00 }));
+    assert_eq!(validate_packet(&[0x36, 0x02, 0x00, 0x00]), Err(DecodeError::InvalidQoS(3)));
+    // PUBREL without its 0010 flags, PINGREQ with flags
+    assert!(matches!(validate_packet(&[0x60, 0x02, 0x00, 0x01]), Err(DecodeError::InvalidFlags(_))));
+    assert!(matches!(validate_packet(&[0xC1, 0x00]), Err(DecodeError::InvalidFlags(_))));
+    // A PUBACK too short for its packet ID
+    assert!(matches!(validate_packet(&[0x40, 0x01, 0x00]), Err(DecodeError::Malformed(_))));
+}
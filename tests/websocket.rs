@@ -0,0 +1,115 @@
+//! MQTT over WebSocket, driven by a WebSocket client over a real TCP connection.
+
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use tungstenite::client::IntoClientRequest;
+use tungstenite::http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderValue, StatusCode};
+use tungstenite::{Error, HandshakeError, Message, WebSocket};
+
+use mqtt_broker::broker::{Broker, BrokerConfig};
+use mqtt_broker::packets::{
+    connack::{ConnAckPacket, ConnAckReasonCode},
+    connect::ConnectPacket,
+    ping::PingReqPacket,
+    publish::PublishPacket,
+    qos::QoS,
+    suback::SubAckPacket,
+    subscribe::SubscribePacket,
+};
+
+// Serves the WebSocket listener of a broker on a loopback socket
+fn start_broker() -> (Broker, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let broker = Broker::new(BrokerConfig::default());
+    let serving = broker.clone();
+    thread::spawn(move || serving.serve_websocket(listener));
+    (broker, addr)
+}
+
+// Upgrades a new connection, offering the given subprotocol if any, or returns the
+// status the broker refused the upgrade with
+fn upgrade(addr: &str, subprotocol: Option<&'static str>) -> Result<WebSocket<TcpStream>, StatusCode> {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut request = format!("ws://{}/mqtt", addr).into_client_request().unwrap();
+    if let Some(subprotocol) = subprotocol {
+        request.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(subprotocol));
+    }
+
+    let (socket, response) = tungstenite::client(request, stream).map_err(|e| match e {
+        HandshakeError::Failure(Error::Http(response)) => response.status(),
+        e => panic!("unexpected handshake error: {}", e),
+    })?;
+    assert_eq!(response.headers().get(SEC_WEBSOCKET_PROTOCOL).unwrap(), "mqtt");
+    Ok(socket)
+}
+
+// Reads the next binary frame, skipping the control frames
+fn read_frame(socket: &mut WebSocket<TcpStream>) -> Vec<u8> {
+    loop {
+        match socket.read().unwrap() {
+            Message::Binary(data) => return data,
+            Message::Ping(_) | Message::Pong(_) => {}
+            message => panic!("unexpected message: {:?}", message),
+        }
+    }
+}
+
+fn connect(addr: &str, client_id: &str) -> WebSocket<TcpStream> {
+    let mut socket = upgrade(addr, Some("mqtt")).unwrap();
+    let connect = ConnectPacket::new("MQTT".to_string(), 5, 0x02, 60, client_id.to_string(), None, None, None, None);
+    socket.send(Message::Binary(connect.encode())).unwrap();
+
+    let connack = ConnAckPacket::decode(&read_frame(&mut socket)).unwrap();
+    assert_eq!(connack.reason_code, ConnAckReasonCode::Success);
+    socket
+}
+
+#[test]
+fn websocket_address_is_read_from_the_arguments() {
+    let config = BrokerConfig::from_args(&["--websocket".to_string(), "127.0.0.1:8083".to_string()]);
+    assert_eq!(config.websocket_bind_addr, Some("127.0.0.1:8083".parse().unwrap()));
+    assert_eq!(BrokerConfig::default().websocket_bind_addr, None);
+}
+
+#[test]
+fn connect_and_connack_over_websocket() {
+    let (broker, addr) = start_broker();
+    let mut socket = connect(&addr, "browser");
+    socket.close(None).unwrap();
+    broker.shutdown();
+}
+
+#[test]
+fn packets_split_across_frames_are_reassembled() {
+    let (broker, addr) = start_broker();
+    let mut socket = connect(&addr, "dashboard");
+
+    // The SUBSCRIBE arrives in two frames
+    let subscribe = SubscribePacket::new(1, vec!["sensors/#".to_string()], vec![0]).encode().unwrap();
+    let (first, second) = subscribe.split_at(3);
+    socket.send(Message::Binary(first.to_vec())).unwrap();
+    socket.send(Message::Binary(second.to_vec())).unwrap();
+    let suback = SubAckPacket::decode(&read_frame(&mut socket)).unwrap();
+    assert_eq!(suback.return_codes, vec![0]);
+    // By the time the PINGRESP arrives the filter is registered
+    socket.send(Message::Binary(PingReqPacket.encode())).unwrap();
+    assert_eq!(read_frame(&mut socket), vec![0xD0, 0x00]);
+
+    // The messages of the subscription come back in binary frames
+    broker.publish(PublishPacket::new("sensors/temperature".to_string(), 0, QoS::AtMostOnce, false, false, b"21.5".to_vec()));
+    let publish = PublishPacket::decode(&read_frame(&mut socket)).unwrap();
+    assert_eq!(publish.topic_name, "sensors/temperature");
+    assert_eq!(publish.payload, b"21.5".to_vec());
+    broker.shutdown();
+}
+
+#[test]
+fn upgrade_without_the_mqtt_subprotocol_is_refused() {
+    let (broker, addr) = start_broker();
+    assert_eq!(upgrade(&addr, None).err(), Some(StatusCode::BAD_REQUEST));
+    broker.shutdown();
+}
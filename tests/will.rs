@@ -0,0 +1,91 @@
+//! Will QoS, retain flag and properties of the CONNECT packet.
+
+use mqtt_broker::packets::{
+    connect::{ConnectPacket, WillProperties},
+    DecodeError,
+};
+
+// MQTT 5 CONNECT with a will and clean start
+fn connect_with_will(connect_flags: u8) -> ConnectPacket {
+    ConnectPacket::new(
+        "MQTT".to_string(),
+        5,
+        connect_flags,
+        60,
+        "will".to_string(),
+        Some("status/will".to_string()),
+        Some(b"offline".to_vec()),
+        None,
+        None,
+    )
+}
+
+// Offset of the connect flags: fixed header, protocol name and protocol level
+const FLAGS_INDEX: usize = 2 + 2 + 4 + 1;
+
+#[test]
+fn will_qos_retain_and_delay_round_trip() {
+    let mut packet = connect_with_will(0x02 | 0x04 | 0x08 | 0x20);
+    packet.will_properties = Some(WillProperties {
+        will_delay_interval: Some(30),
+        message_expiry_interval: Some(3600),
+        ..WillProperties::default()
+    });
+    assert_eq!(packet.will_qos, 1);
+    assert!(packet.will_retain);
+
+    let decoded = ConnectPacket::decode(&packet.encode()).unwrap();
+    assert_eq!(decoded.will_qos, 1);
+    assert!(decoded.will_retain);
+    let properties = decoded.will_properties.clone().unwrap();
+    assert_eq!(properties.will_delay_interval, Some(30));
+    assert_eq!(properties.message_expiry_interval, Some(3600));
+    assert_eq!(decoded, packet);
+}
+
+#[test]
+fn will_fields_set_the_connect_flags() {
+    let mut packet = connect_with_will(0x02 | 0x04);
+    packet.will_qos = 2;
+    packet.will_retain = true;
+
+    let encoded = packet.encode();
+    assert_eq!(encoded[FLAGS_INDEX], 0x02 | 0x04 | 0x10 | 0x20);
+
+    let decoded = ConnectPacket::decode(&encoded).unwrap();
+    assert_eq!(decoded.will_qos, 2);
+    assert!(decoded.will_retain);
+}
+
+#[test]
+fn will_qos_3_is_rejected() {
+    let mut encoded = connect_with_will(0x02 | 0x04).encode();
+    encoded[FLAGS_INDEX] |= 0x18;
+
+    assert_eq!(ConnectPacket::decode(&encoded), Err(DecodeError::InvalidQoS(3)));
+}
+
+#[test]
+fn binary_will_message_round_trips() {
+    let mut packet = connect_with_will(0x02 | 0x04);
+    packet.will_message = Some(vec![0xFF, 0x00, 0xFE]);
+    packet.will_properties = Some(WillProperties::default());
+
+    let encoded = packet.encode();
+    // The message is length-prefixed binary, after the will topic
+    assert!(encoded.ends_with(&[0x00, 0x03, 0xFF, 0x00, 0xFE]));
+
+    let decoded = ConnectPacket::decode(&encoded).unwrap();
+    assert_eq!(decoded.will_message, Some(vec![0xFF, 0x00, 0xFE]));
+    assert_eq!(decoded, packet);
+}
+
+#[test]
+fn text_will_sets_the_will_flag() {
+    let packet = ConnectPacket::new("MQTT".to_string(), 5, 0x02, 60, "will".to_string(), None, None, None, None)
+        .with_text_will("status/will", "offline");
+    assert_eq!(packet, connect_with_will(0x02 | 0x04));
+
+    let decoded = ConnectPacket::decode(&packet.encode()).unwrap();
+    assert_eq!(decoded.will_message.as_deref(), Some(&b"offline"[..]));
+}